@@ -0,0 +1,182 @@
+//! The parts of 32 bit little endian ELF files the loader uses
+
+use crate::LoadError;
+
+pub const HEADER_SIZE: usize = 52;
+pub const PROGRAM_HEADER_SIZE: usize = 32;
+pub const SECTION_HEADER_SIZE: usize = 40;
+pub const SYM_SIZE: usize = 16;
+pub const REL_SIZE: usize = 8;
+pub const RELA_SIZE: usize = 12;
+
+const MAGIC: &[u8; 4] = b"\x7fELF";
+const ELFCLASS32: u8 = 1;
+const ELFDATA2LSB: u8 = 1;
+const ET_DYN: u16 = 3;
+const EM_ARM: u16 = 40;
+const EV_CURRENT: u32 = 1;
+
+pub const PT_LOAD: u32 = 1;
+pub const PT_DYNAMIC: u32 = 2;
+
+pub const SHT_SYMTAB: u32 = 2;
+pub const SHT_NOBITS: u32 = 8;
+pub const SHT_REL: u32 = 9;
+pub const SHN_UNDEF: u16 = 0;
+pub const STN_UNDEF: u32 = 0;
+
+pub const STB_WEAK: u8 = 2;
+
+pub const DT_NULL: u32 = 0;
+pub const DT_PLTRELSZ: u32 = 2;
+pub const DT_STRTAB: u32 = 5;
+pub const DT_SYMTAB: u32 = 6;
+pub const DT_RELA: u32 = 7;
+pub const DT_RELASZ: u32 = 8;
+pub const DT_RELAENT: u32 = 9;
+pub const DT_STRSZ: u32 = 10;
+pub const DT_SYMENT: u32 = 11;
+pub const DT_REL: u32 = 17;
+pub const DT_RELSZ: u32 = 18;
+pub const DT_RELENT: u32 = 19;
+pub const DT_PLTREL: u32 = 20;
+pub const DT_JMPREL: u32 = 23;
+
+pub const R_ARM_NONE: u32 = 0;
+pub const R_ARM_ABS32: u32 = 2;
+pub const R_ARM_GLOB_DAT: u32 = 21;
+pub const R_ARM_JUMP_SLOT: u32 = 22;
+pub const R_ARM_RELATIVE: u32 = 23;
+
+/// Reads the little endian word at `offset`
+pub fn word(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([
+        bytes[offset],
+        bytes[offset + 1],
+        bytes[offset + 2],
+        bytes[offset + 3],
+    ])
+}
+
+fn half(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Header {
+    pub e_entry: u32,
+    pub e_phoff: u32,
+    pub e_shoff: u32,
+    pub e_phentsize: u16,
+    pub e_phnum: u16,
+    pub e_shentsize: u16,
+    pub e_shnum: u16,
+    pub e_shstrndx: u16,
+}
+
+impl Header {
+    /// Parses the header, refusing anything but position independent ARM
+    /// executables
+    pub fn parse(buf: &[u8; HEADER_SIZE]) -> Result<Self, LoadError> {
+        if &buf[..4] != MAGIC || buf[4] != ELFCLASS32 || buf[5] != ELFDATA2LSB {
+            return Err(LoadError::InvalidElf);
+        }
+        if half(buf, 16) != ET_DYN {
+            return Err(LoadError::ElfIsNotPie);
+        }
+        if half(buf, 18) != EM_ARM {
+            return Err(LoadError::WrongMachine);
+        }
+        if word(buf, 20) != EV_CURRENT {
+            return Err(LoadError::InvalidElf);
+        }
+
+        Ok(Self {
+            e_entry: word(buf, 24),
+            e_phoff: word(buf, 28),
+            e_shoff: word(buf, 32),
+            e_phentsize: half(buf, 42),
+            e_phnum: half(buf, 44),
+            e_shentsize: half(buf, 46),
+            e_shnum: half(buf, 48),
+            e_shstrndx: half(buf, 50),
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct ProgramHeader {
+    pub p_type: u32,
+    pub p_offset: u32,
+    pub p_vaddr: u32,
+    pub p_filesz: u32,
+    pub p_memsz: u32,
+}
+
+impl ProgramHeader {
+    pub fn parse(buf: &[u8]) -> Self {
+        Self {
+            p_type: word(buf, 0),
+            p_offset: word(buf, 4),
+            p_vaddr: word(buf, 8),
+            p_filesz: word(buf, 16),
+            p_memsz: word(buf, 20),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct SectionHeader {
+    pub sh_name: u32,
+    pub sh_type: u32,
+    pub sh_addr: u32,
+    pub sh_offset: u32,
+    pub sh_size: u32,
+    pub sh_link: u32,
+    pub sh_entsize: u32,
+}
+
+impl SectionHeader {
+    pub fn parse(buf: &[u8]) -> Self {
+        Self {
+            sh_name: word(buf, 0),
+            sh_type: word(buf, 4),
+            sh_addr: word(buf, 12),
+            sh_offset: word(buf, 16),
+            sh_size: word(buf, 20),
+            sh_link: word(buf, 24),
+            sh_entsize: word(buf, 36),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Sym {
+    pub st_name: u32,
+    pub st_value: u32,
+    pub st_size: u32,
+    pub st_info: u8,
+    pub st_shndx: u16,
+}
+
+impl Sym {
+    pub fn parse(buf: &[u8]) -> Self {
+        Self {
+            st_name: word(buf, 0),
+            st_value: word(buf, 4),
+            st_size: word(buf, 8),
+            st_info: buf[12],
+            st_shndx: half(buf, 14),
+        }
+    }
+
+    pub fn bind(&self) -> u8 {
+        self.st_info >> 4
+    }
+}
+
+/// The null terminated name at `offset` in a string table
+pub fn name_at(strtab: &[u8], offset: u32) -> &[u8] {
+    let name = strtab.get(offset as usize..).unwrap_or_default();
+    name.split(|&b| b == 0).next().unwrap_or_default()
+}
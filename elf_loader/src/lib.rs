@@ -0,0 +1,60 @@
+//! Loads position independent ARM apps into memory.
+//!
+//! The kernel hands `Elf` the app's file and the memory to load it into. Apps
+//! are relocated to wherever that memory is, their imports resolved against
+//! what the kernel exports, and their syscall table filled in. Nothing here
+//! knows about the SD card, which keeps the loader testable on the host.
+
+#![no_std]
+
+extern crate alloc;
+
+mod header;
+mod loader;
+mod relocate;
+
+pub use loader::{Elf, Symbol};
+
+/// Where an app is read from
+pub trait File {
+    type Error;
+
+    fn seek(&mut self, offset: u32) -> Result<(), Self::Error>;
+
+    /// Reads into `buf`, returning how many bytes were read, 0 at the end
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error>;
+}
+
+impl<F: File> File for &mut F {
+    type Error = F::Error;
+
+    fn seek(&mut self, offset: u32) -> Result<(), Self::Error> {
+        (**self).seek(offset)
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        (**self).read(buf)
+    }
+}
+
+/// What the kernel provides to the apps it loads
+pub trait Kernel {
+    /// Addresses the app's syscall table is filled with, in order
+    fn syscalls(&self) -> &[u32];
+
+    /// The address of a function or static apps can import by name
+    fn export(&self, name: &str) -> Option<u32>;
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum LoadError {
+    WrongMachine,
+    InvalidElf,
+    FailedToReadFile,
+    ElfIsNotPie,
+    UnknownRelocationType,
+    /// The app imports a symbol the kernel doesn't export
+    UndefinedSymbol(alloc::string::String),
+    SyscallTableNotFound,
+    SyscallTableSizeMismatch,
+}
@@ -0,0 +1,301 @@
+use alloc::{vec, vec::Vec};
+
+use crate::{
+    File, Kernel, LoadError,
+    header::{
+        HEADER_SIZE, Header, PROGRAM_HEADER_SIZE, PT_DYNAMIC, PT_LOAD, ProgramHeader, REL_SIZE,
+        SECTION_HEADER_SIZE, SHT_NOBITS, SHT_REL, SHT_SYMTAB, SYM_SIZE, SectionHeader, Sym,
+        name_at, word,
+    },
+    relocate::{Image, Reloc, apply_dynamic_relocations, relocate},
+};
+
+/// A symbol from the app's symbol table
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Symbol {
+    /// Virtual address, see `Elf::min_vaddr`
+    pub value: u32,
+    pub size: u32,
+}
+
+/// An app, once its headers are read and checked
+pub struct Elf<F> {
+    file: F,
+    header: Header,
+    phdrs: Vec<ProgramHeader>,
+    sections: Vec<SectionHeader>,
+    min_vaddr: u32,
+    size: usize,
+}
+
+impl<F: File> Elf<F> {
+    /// Reads the headers, refusing anything but position independent ARM
+    /// executables. Every table is read in one go, seeking around an sd card
+    /// is slow.
+    pub fn open(mut file: F) -> Result<Self, LoadError> {
+        let mut header_buf = [0; HEADER_SIZE];
+        read_exact_at(&mut file, 0, &mut header_buf)?;
+        let header = Header::parse(&header_buf)?;
+
+        let phdrs = read_table(
+            &mut file,
+            (header.e_phoff, header.e_phentsize, header.e_phnum),
+            PROGRAM_HEADER_SIZE,
+            ProgramHeader::parse,
+        )?;
+        let sections = read_table(
+            &mut file,
+            (header.e_shoff, header.e_shentsize, header.e_shnum),
+            SECTION_HEADER_SIZE,
+            SectionHeader::parse,
+        )?;
+
+        let (min_vaddr, max_vaddr) = loadable_range(&phdrs)?;
+        Ok(Self {
+            file,
+            header,
+            phdrs,
+            sections,
+            min_vaddr,
+            size: (max_vaddr - min_vaddr) as usize,
+        })
+    }
+
+    /// How much memory the app needs
+    pub fn image_size(&self) -> usize {
+        self.size
+    }
+
+    /// The lowest virtual address loaded, which ends up at the start of the
+    /// image
+    pub fn min_vaddr(&self) -> u32 {
+        self.min_vaddr
+    }
+
+    /// Loads the app into `image`, which is `image_size` bytes at `address`.
+    /// Returns the offset of the entry point in the image.
+    pub fn load(
+        &mut self,
+        image: &mut [u8],
+        address: u32,
+        kernel: &impl Kernel,
+    ) -> Result<usize, LoadError> {
+        let mut image = Image {
+            bytes: &mut image[..self.size],
+            address,
+            min_vaddr: self.min_vaddr,
+        };
+        let mut dynamic = None;
+
+        for ph in &self.phdrs {
+            if ph.p_type == PT_LOAD {
+                load_segment(&mut self.file, ph, &mut image)?;
+            } else if ph.p_type == PT_DYNAMIC {
+                dynamic = Some(ph);
+            }
+        }
+
+        match dynamic {
+            Some(ph) => apply_dynamic_relocations(&mut image, ph, kernel)?,
+            // without a dynamic segment, only the section headers say where
+            // the relocations are
+            None => {
+                for sh in self.sections.iter().filter(|sh| sh.sh_type == SHT_REL) {
+                    apply_relocations(&mut self.file, sh, &mut image, kernel)?;
+                }
+            }
+        }
+
+        self.patch_syscalls(&mut image, kernel)?;
+
+        let entry = self
+            .header
+            .e_entry
+            .checked_sub(self.min_vaddr)
+            .filter(|&entry| (entry as usize) < self.size)
+            .ok_or(LoadError::InvalidElf)?;
+        Ok(entry as usize)
+    }
+
+    /// Looks `name` up in the symbol table, which stripped binaries don't have
+    pub fn symbol(&mut self, name: &str) -> Result<Option<Symbol>, LoadError> {
+        let Some(sh) = self.sections.iter().find(|sh| sh.sh_type == SHT_SYMTAB) else {
+            return Ok(None);
+        };
+        let str_sh = self
+            .sections
+            .get(sh.sh_link as usize)
+            .ok_or(LoadError::InvalidElf)?;
+        if (sh.sh_entsize as usize) < SYM_SIZE {
+            return Err(LoadError::InvalidElf);
+        }
+
+        let symtab = read_at(&mut self.file, sh.sh_offset, sh.sh_size as usize)?;
+        let strtab = read_at(&mut self.file, str_sh.sh_offset, str_sh.sh_size as usize)?;
+
+        Ok(symtab
+            .chunks_exact(sh.sh_entsize as usize)
+            .map(Sym::parse)
+            .find(|sym| name_at(&strtab, sym.st_name) == name.as_bytes())
+            .map(|sym| Symbol {
+                value: sym.st_value,
+                size: sym.st_size,
+            }))
+    }
+
+    fn patch_syscalls(&mut self, image: &mut Image, kernel: &impl Kernel) -> Result<(), LoadError> {
+        let table = self.find_syscall_table()?;
+        let syscalls = kernel.syscalls();
+
+        // The binary was linked against a different SyscallTable
+        // than this kernel (e.g. built before a syscall was
+        // added) -- writing the full table would overrun its
+        // `.syscall_table` array and corrupt whatever follows it.
+        if table.size as usize != size_of_val(syscalls) {
+            return Err(LoadError::SyscallTableSizeMismatch);
+        }
+
+        for (idx, &call) in syscalls.iter().enumerate() {
+            image.write_word(table.value + (idx * size_of::<u32>()) as u32, call)?;
+        }
+        Ok(())
+    }
+
+    /// Reads the contents of section `name`, without loading the app. Loaded
+    /// sections are still there once a binary is stripped.
+    pub fn section(&mut self, name: &str) -> Result<Option<Vec<u8>>, LoadError> {
+        let Some(sh) = self.find_section(name)? else {
+            return Ok(None);
+        };
+        if sh.sh_type == SHT_NOBITS {
+            return Ok(Some(vec![0; sh.sh_size as usize]));
+        }
+        read_at(&mut self.file, sh.sh_offset, sh.sh_size as usize).map(Some)
+    }
+
+    fn find_section(&mut self, name: &str) -> Result<Option<SectionHeader>, LoadError> {
+        let Some(names) = self.sections.get(self.header.e_shstrndx as usize) else {
+            return Ok(None);
+        };
+        let names = read_at(&mut self.file, names.sh_offset, names.sh_size as usize)?;
+        Ok(self
+            .sections
+            .iter()
+            .find(|sh| name_at(&names, sh.sh_name) == name.as_bytes())
+            .copied())
+    }
+
+    // Stripped binaries have no symbol table, but still have the section the
+    // syscall table is linked into
+    fn find_syscall_table(&mut self) -> Result<Symbol, LoadError> {
+        if let Some(table) = self.symbol("SYS_CALL_TABLE")? {
+            return Ok(table);
+        }
+
+        self.find_section(".syscall_table")?
+            .map(|sh| Symbol {
+                value: sh.sh_addr,
+                size: sh.sh_size,
+            })
+            .ok_or(LoadError::SyscallTableNotFound)
+    }
+}
+
+fn load_segment(
+    file: &mut impl File,
+    ph: &ProgramHeader,
+    image: &mut Image,
+) -> Result<(), LoadError> {
+    if ph.p_filesz > ph.p_memsz {
+        return Err(LoadError::InvalidElf);
+    }
+    let start = (ph.p_vaddr - image.min_vaddr) as usize;
+    let segment = &mut image.bytes[start..start + ph.p_memsz as usize];
+    let (data, bss) = segment.split_at_mut(ph.p_filesz as usize);
+
+    // read file contents straight into place
+    read_exact_at(file, ph.p_offset, data)?;
+    bss.fill(0);
+    Ok(())
+}
+
+fn apply_relocations(
+    file: &mut impl File,
+    sh: &SectionHeader,
+    image: &mut Image,
+    kernel: &impl Kernel,
+) -> Result<(), LoadError> {
+    if (sh.sh_entsize as usize) < REL_SIZE {
+        return Err(LoadError::InvalidElf);
+    }
+    let relocs = read_at(file, sh.sh_offset, sh.sh_size as usize)?;
+
+    for entry in relocs.chunks_exact(sh.sh_entsize as usize) {
+        let reloc = Reloc {
+            offset: word(entry, 0),
+            info: word(entry, 4),
+            addend: None,
+        };
+        relocate(image, &reloc, None, kernel)?;
+    }
+    Ok(())
+}
+
+// Returns the lowest and highest addresses of the loadable segments
+fn loadable_range(phdrs: &[ProgramHeader]) -> Result<(u32, u32), LoadError> {
+    let mut min_vaddr = u32::MAX;
+    let mut max_vaddr = 0u32;
+    for ph in phdrs.iter().filter(|ph| ph.p_type == PT_LOAD) {
+        let end = ph
+            .p_vaddr
+            .checked_add(ph.p_memsz)
+            .ok_or(LoadError::InvalidElf)?;
+        min_vaddr = min_vaddr.min(ph.p_vaddr);
+        max_vaddr = max_vaddr.max(end);
+    }
+
+    if min_vaddr > max_vaddr {
+        // nothing to load
+        return Err(LoadError::InvalidElf);
+    }
+    Ok((min_vaddr, max_vaddr))
+}
+
+// Reads a table of `count` entries of `entsize` bytes at `offset`, each at
+// least `min_size` bytes
+fn read_table<T>(
+    file: &mut impl File,
+    (offset, entsize, count): (u32, u16, u16),
+    min_size: usize,
+    parse: fn(&[u8]) -> T,
+) -> Result<Vec<T>, LoadError> {
+    if count == 0 {
+        return Ok(Vec::new());
+    }
+    if (entsize as usize) < min_size {
+        return Err(LoadError::InvalidElf);
+    }
+
+    let buf = read_at(file, offset, entsize as usize * count as usize)?;
+    Ok(buf.chunks_exact(entsize as usize).map(parse).collect())
+}
+
+// seeks to `offset` and fills `buf`
+fn read_exact_at(file: &mut impl File, offset: u32, buf: &mut [u8]) -> Result<(), LoadError> {
+    file.seek(offset).map_err(|_| LoadError::FailedToReadFile)?;
+
+    let mut read = 0;
+    while read < buf.len() {
+        match file.read(&mut buf[read..]) {
+            Ok(0) | Err(_) => return Err(LoadError::FailedToReadFile),
+            Ok(len) => read += len,
+        }
+    }
+    Ok(())
+}
+
+fn read_at(file: &mut impl File, offset: u32, len: usize) -> Result<Vec<u8>, LoadError> {
+    let mut buf = vec![0_u8; len];
+    read_exact_at(file, offset, &mut buf)?;
+    Ok(buf)
+}
@@ -0,0 +1,237 @@
+use alloc::string::String;
+
+use crate::{
+    Kernel, LoadError,
+    header::{
+        DT_JMPREL, DT_NULL, DT_PLTREL, DT_PLTRELSZ, DT_REL, DT_RELA, DT_RELAENT, DT_RELASZ,
+        DT_RELENT, DT_RELSZ, DT_STRSZ, DT_STRTAB, DT_SYMENT, DT_SYMTAB, ProgramHeader, R_ARM_ABS32,
+        R_ARM_GLOB_DAT, R_ARM_JUMP_SLOT, R_ARM_NONE, R_ARM_RELATIVE, REL_SIZE, RELA_SIZE,
+        SHN_UNDEF, STB_WEAK, STN_UNDEF, SYM_SIZE, Sym, name_at, word,
+    },
+};
+
+/// The loaded image, addressed by the virtual addresses in the ELF
+pub struct Image<'a> {
+    pub bytes: &'a mut [u8],
+    /// Where `bytes` is in memory
+    pub address: u32,
+    pub min_vaddr: u32,
+}
+
+impl Image<'_> {
+    /// What to add to a virtual address to get where it was loaded
+    pub fn bias(&self) -> u32 {
+        self.address.wrapping_sub(self.min_vaddr)
+    }
+
+    fn range(&self, vaddr: u32, len: u32) -> Result<core::ops::Range<usize>, LoadError> {
+        let start = vaddr
+            .checked_sub(self.min_vaddr)
+            .ok_or(LoadError::InvalidElf)? as usize;
+        let end = start
+            .checked_add(len as usize)
+            .filter(|&end| end <= self.bytes.len())
+            .ok_or(LoadError::InvalidElf)?;
+        Ok(start..end)
+    }
+
+    pub fn slice(&self, vaddr: u32, len: u32) -> Result<&[u8], LoadError> {
+        Ok(&self.bytes[self.range(vaddr, len)?])
+    }
+
+    pub fn read_word(&self, vaddr: u32) -> Result<u32, LoadError> {
+        Ok(word(self.slice(vaddr, 4)?, 0))
+    }
+
+    pub fn write_word(&mut self, vaddr: u32, value: u32) -> Result<(), LoadError> {
+        let range = self.range(vaddr, 4)?;
+        self.bytes[range].copy_from_slice(&value.to_le_bytes());
+        Ok(())
+    }
+}
+
+/// Where the dynamic segment says the relocations and the symbols they refer
+/// to are, as virtual addresses
+#[derive(Default)]
+struct Dynamic {
+    rel: Option<u32>,
+    rel_size: u32,
+    rel_ent: u32,
+    rela: Option<u32>,
+    rela_size: u32,
+    rela_ent: u32,
+    // relocations of the procedure linkage table, either rel or rela
+    jmprel: Option<u32>,
+    jmprel_size: u32,
+    jmprel_is_rela: bool,
+    symbols: Symbols,
+}
+
+#[derive(Default)]
+pub struct Symbols {
+    table: Option<u32>,
+    ent: u32,
+    strings: u32,
+    strings_size: u32,
+}
+
+pub struct Reloc {
+    pub offset: u32,
+    pub info: u32,
+    /// `None` for rel entries, which keep the addend in the relocated word
+    pub addend: Option<i32>,
+}
+
+fn read_dynamic(image: &Image, ph: &ProgramHeader) -> Result<Dynamic, LoadError> {
+    let entries = image.slice(ph.p_vaddr, ph.p_filesz)?;
+    let mut dynamic = Dynamic {
+        rel_ent: REL_SIZE as u32,
+        rela_ent: RELA_SIZE as u32,
+        ..Default::default()
+    };
+    dynamic.symbols.ent = SYM_SIZE as u32;
+
+    for entry in entries.chunks_exact(8) {
+        let (tag, val) = (word(entry, 0), word(entry, 4));
+        match tag {
+            DT_NULL => break,
+            DT_REL => dynamic.rel = Some(val),
+            DT_RELSZ => dynamic.rel_size = val,
+            DT_RELENT => dynamic.rel_ent = val,
+            DT_RELA => dynamic.rela = Some(val),
+            DT_RELASZ => dynamic.rela_size = val,
+            DT_RELAENT => dynamic.rela_ent = val,
+            DT_JMPREL => dynamic.jmprel = Some(val),
+            DT_PLTRELSZ => dynamic.jmprel_size = val,
+            DT_PLTREL => dynamic.jmprel_is_rela = val == DT_RELA,
+            DT_SYMTAB => dynamic.symbols.table = Some(val),
+            DT_SYMENT => dynamic.symbols.ent = val,
+            DT_STRTAB => dynamic.symbols.strings = val,
+            DT_STRSZ => dynamic.symbols.strings_size = val,
+            _ => (),
+        }
+    }
+    Ok(dynamic)
+}
+
+/// Applies the relocations the dynamic segment lists, which unlike section
+/// headers are still there once a binary is stripped
+pub fn apply_dynamic_relocations(
+    image: &mut Image,
+    ph: &ProgramHeader,
+    kernel: &impl Kernel,
+) -> Result<(), LoadError> {
+    let dynamic = read_dynamic(image, ph)?;
+
+    let jmprel_ent = if dynamic.jmprel_is_rela {
+        dynamic.rela_ent
+    } else {
+        dynamic.rel_ent
+    };
+    let tables = [
+        (dynamic.rel, dynamic.rel_size, dynamic.rel_ent, false),
+        (dynamic.rela, dynamic.rela_size, dynamic.rela_ent, true),
+        (
+            dynamic.jmprel,
+            dynamic.jmprel_size,
+            jmprel_ent,
+            dynamic.jmprel_is_rela,
+        ),
+    ];
+
+    for (start, size, ent, is_rela) in tables {
+        let Some(start) = start.filter(|_| size > 0) else {
+            continue;
+        };
+        let min_ent = if is_rela { RELA_SIZE } else { REL_SIZE };
+        if (ent as usize) < min_ent {
+            return Err(LoadError::InvalidElf);
+        }
+
+        for i in 0..size / ent {
+            let entry = image.slice(start + i * ent, ent)?;
+            let reloc = Reloc {
+                offset: word(entry, 0),
+                info: word(entry, 4),
+                addend: is_rela.then(|| word(entry, 8) as i32),
+            };
+            relocate(image, &reloc, Some(&dynamic.symbols), kernel)?;
+        }
+    }
+    Ok(())
+}
+
+/// Applies a single relocation. Symbols are only available from the dynamic
+/// segment, relocations found through section headers have to be relative.
+pub fn relocate(
+    image: &mut Image,
+    reloc: &Reloc,
+    symbols: Option<&Symbols>,
+    kernel: &impl Kernel,
+) -> Result<(), LoadError> {
+    let kind = reloc.info & 0xff;
+    let sym = reloc.info >> 8;
+
+    // rel entries add to the word already stored there
+    let addend = |image: &Image| match reloc.addend {
+        Some(addend) => Ok(addend as u32),
+        None => image.read_word(reloc.offset),
+    };
+
+    let value = match kind {
+        R_ARM_NONE => return Ok(()),
+        R_ARM_RELATIVE => image.bias().wrapping_add(addend(image)?),
+        R_ARM_ABS32 => symbol_value(image, symbols, kernel, sym)?.wrapping_add(addend(image)?),
+        // the word holds the address of the lazy binding stub, not an addend
+        R_ARM_GLOB_DAT | R_ARM_JUMP_SLOT => {
+            let addend = reloc.addend.unwrap_or(0) as u32;
+            symbol_value(image, symbols, kernel, sym)?.wrapping_add(addend)
+        }
+        _ => return Err(LoadError::UnknownRelocationType),
+    };
+    image.write_word(reloc.offset, value)
+}
+
+// The value of symbol `index` for a relocation, S in the ARM ELF ABI. Index 0
+// (STN_UNDEF) is no symbol at all, and S is 0.
+fn symbol_value(
+    image: &Image,
+    symbols: Option<&Symbols>,
+    kernel: &impl Kernel,
+    index: u32,
+) -> Result<u32, LoadError> {
+    if index == STN_UNDEF {
+        return Ok(0);
+    }
+    let symbols = symbols.ok_or(LoadError::UnknownRelocationType)?;
+    resolve_symbol(image, symbols, kernel, index)
+}
+
+// The address of symbol `index`, either in the image or exported by the kernel
+fn resolve_symbol(
+    image: &Image,
+    symbols: &Symbols,
+    kernel: &impl Kernel,
+    index: u32,
+) -> Result<u32, LoadError> {
+    let table = symbols.table.ok_or(LoadError::InvalidElf)?;
+    if (symbols.ent as usize) < SYM_SIZE {
+        return Err(LoadError::InvalidElf);
+    }
+    let sym = Sym::parse(image.slice(table + index * symbols.ent, symbols.ent)?);
+
+    if sym.st_shndx != SHN_UNDEF {
+        return Ok(sym.st_value.wrapping_add(image.bias()));
+    }
+
+    let strings = image.slice(symbols.strings, symbols.strings_size)?;
+    let name =
+        core::str::from_utf8(name_at(strings, sym.st_name)).map_err(|_| LoadError::InvalidElf)?;
+
+    match kernel.export(name) {
+        Some(address) => Ok(address),
+        // unresolved weak symbols are null, apps check for them
+        None if sym.bind() == STB_WEAK => Ok(0),
+        None => Err(LoadError::UndefinedSymbol(String::from(name))),
+    }
+}
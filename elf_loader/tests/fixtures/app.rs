@@ -0,0 +1,53 @@
+//! A tiny app exercising every relocation the loader handles. See `build.sh`.
+
+#![no_std]
+#![no_main]
+
+#[unsafe(no_mangle)]
+#[unsafe(link_section = ".syscall_table")]
+pub static mut SYS_CALL_TABLE: [usize; 4] = [0; 4];
+
+/// Read without loading the app, like the launcher reads app metadata
+#[used]
+#[unsafe(link_section = ".picocalc_meta")]
+pub static META: [u8; 16] = *b"PCMT fixture app";
+
+// provided by the kernel, see `kernel.rs`
+unsafe extern "C" {
+    static kernel_value: u32;
+    fn kernel_fn() -> u32;
+}
+
+/// Points into the app, R_ARM_RELATIVE
+#[unsafe(no_mangle)]
+pub static GREETING: &[u8; 5] = b"hello";
+
+/// Points into the kernel, R_ARM_ABS32
+#[unsafe(no_mangle)]
+pub static IMPORTED: &u32 = unsafe { &kernel_value };
+
+/// Zeroed by the loader
+#[unsafe(no_mangle)]
+pub static mut ZEROED: [u8; 256] = [0; 256];
+
+#[unsafe(no_mangle)]
+pub extern "C" fn _start() -> u32 {
+    unsafe {
+        let greeting = core::ptr::read_volatile(&raw const GREETING);
+        let imported = core::ptr::read_volatile(&raw const IMPORTED);
+        // through the global offset table, R_ARM_GLOB_DAT
+        let value = core::ptr::read_volatile(&raw const kernel_value);
+        // through the procedure linkage table, R_ARM_JUMP_SLOT
+        kernel_fn()
+            + value
+            + *imported
+            + greeting[0] as u32
+            + ZEROED[3] as u32
+            + SYS_CALL_TABLE[0] as u32
+    }
+}
+
+#[panic_handler]
+fn panic(_: &core::panic::PanicInfo) -> ! {
+    loop {}
+}
@@ -0,0 +1,32 @@
+/* like user_apps/memory.x, without the memory region */
+ENTRY(_start)
+
+SECTIONS
+{
+  .text : ALIGN(4)
+  {
+      *(.text .text.*);
+      *(.rodata .rodata.*);
+  }
+
+  .picocalc_meta : ALIGN(4)
+  {
+      KEEP(*(.picocalc_meta));
+  }
+
+  .data : ALIGN(4)
+  {
+      *(.data .data.*);
+  }
+
+  .bss : ALIGN(4)
+  {
+      *(.bss .bss.*);
+      *(COMMON);
+  }
+
+  .syscall_table (NOLOAD) : ALIGN(4)
+  {
+      KEEP(*(.syscall_table));
+  }
+}
@@ -0,0 +1,26 @@
+#!/bin/bash
+# Rebuilds the fixture binaries the loader tests run against:
+#   app.elf           a position independent app, like the user apps
+#   app-stripped.elf  the same app without its symbol table
+#   app-static.elf    the same app, not position independent
+set -euo pipefail
+cd "$(dirname "$0")"
+
+target=thumbv6m-none-eabi
+rustc_args=(--edition 2024 --target $target -C opt-level=s -C panic=abort)
+lld="$(rustc --print sysroot)/lib/rustlib/$(rustc -vV | sed -n 's/host: //p')/bin/rust-lld"
+out=$(mktemp -d)
+trap 'rm -rf "$out"' EXIT
+
+rustc "${rustc_args[@]}" -C relocation-model=pic --crate-type lib --emit obj \
+    -o "$out/kernel.o" kernel.rs
+"$lld" -flavor gnu -shared -soname kernel -o "$out/kernel.so" "$out/kernel.o"
+
+rustc "${rustc_args[@]}" -C relocation-model=pic \
+    -C link-arg=-pie -C link-arg=-Tapp.x -C link-arg=-zmax-page-size=4 \
+    -C link-arg="$out/kernel.so" -o app.elf app.rs
+llvm-strip --strip-all -o app-stripped.elf app.elf
+
+rustc "${rustc_args[@]}" -C relocation-model=static \
+    -C link-arg=-Tapp.x -C link-arg=-zmax-page-size=4 \
+    -C link-arg="$out/kernel.so" -o app-static.elf app.rs
@@ -0,0 +1,12 @@
+//! What the fixture app imports, linked as a shared object so the imports
+//! stay dynamic. See `build.sh`.
+
+#![no_std]
+
+#[unsafe(no_mangle)]
+pub static kernel_value: u32 = 7;
+
+#[unsafe(no_mangle)]
+pub extern "C" fn kernel_fn() -> u32 {
+    1
+}
@@ -0,0 +1,238 @@
+//! Loads the fixture apps in `fixtures/`, see `fixtures/build.sh`
+
+use elf_loader::{Elf, File, Kernel, LoadError};
+
+const APP: &[u8] = include_bytes!("fixtures/app.elf");
+const APP_STRIPPED: &[u8] = include_bytes!("fixtures/app-stripped.elf");
+const APP_STATIC: &[u8] = include_bytes!("fixtures/app-static.elf");
+
+/// Where the image pretends to be in memory
+const ADDRESS: u32 = 0x2001_0000;
+const KERNEL_FN: u32 = 0x1000_0101;
+const KERNEL_VALUE: u32 = 0x2000_0040;
+const SYSCALLS: [u32; 4] = [0x1000_1001, 0x1000_2001, 0x1000_3001, 0x1000_4001];
+
+struct Bytes {
+    data: Vec<u8>,
+    pos: usize,
+}
+
+impl Bytes {
+    fn new(data: &[u8]) -> Self {
+        Self {
+            data: data.to_vec(),
+            pos: 0,
+        }
+    }
+}
+
+impl File for Bytes {
+    type Error = ();
+
+    fn seek(&mut self, offset: u32) -> Result<(), ()> {
+        self.pos = offset as usize;
+        Ok(())
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, ()> {
+        let rest = self.data.get(self.pos..).unwrap_or_default();
+        let len = buf.len().min(rest.len());
+        buf[..len].copy_from_slice(&rest[..len]);
+        self.pos += len;
+        Ok(len)
+    }
+}
+
+struct TestKernel {
+    syscalls: Vec<u32>,
+    exports: Vec<(&'static str, u32)>,
+}
+
+impl Default for TestKernel {
+    fn default() -> Self {
+        Self {
+            syscalls: SYSCALLS.to_vec(),
+            exports: vec![("kernel_fn", KERNEL_FN), ("kernel_value", KERNEL_VALUE)],
+        }
+    }
+}
+
+impl Kernel for TestKernel {
+    fn syscalls(&self) -> &[u32] {
+        &self.syscalls
+    }
+
+    fn export(&self, name: &str) -> Option<u32> {
+        self.exports
+            .iter()
+            .find(|(export, _)| *export == name)
+            .map(|&(_, address)| address)
+    }
+}
+
+/// Loads `elf` into an image filled with garbage, returning the image and
+/// the entry point's offset
+fn load(elf: &[u8], kernel: &TestKernel) -> Result<(Vec<u8>, usize), LoadError> {
+    let mut elf = Elf::open(Bytes::new(elf))?;
+    let mut image = vec![0xAA; elf.image_size()];
+    let entry = elf.load(&mut image, ADDRESS, kernel)?;
+    Ok((image, entry))
+}
+
+/// Where a symbol of the unstripped app ended up in the image
+fn symbol(name: &str) -> std::ops::Range<usize> {
+    let mut elf = Elf::open(Bytes::new(APP)).unwrap();
+    let symbol = elf.symbol(name).unwrap().unwrap();
+    let start = (symbol.value - elf.min_vaddr()) as usize;
+    start..start + symbol.size as usize
+}
+
+fn word_at(image: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(image[offset..offset + 4].try_into().unwrap())
+}
+
+fn words(image: &[u8]) -> impl Iterator<Item = u32> + '_ {
+    image
+        .chunks_exact(4)
+        .map(|word| u32::from_le_bytes(word.try_into().unwrap()))
+}
+
+#[test]
+fn relative_relocations_point_into_the_image() {
+    let (image, _) = load(APP, &TestKernel::default()).unwrap();
+
+    let greeting = word_at(&image, symbol("GREETING").start);
+    let offset = (greeting - ADDRESS) as usize;
+    assert_eq!(&image[offset..offset + 5], b"hello");
+}
+
+#[test]
+fn imports_are_resolved_against_kernel_exports() {
+    let (image, _) = load(APP, &TestKernel::default()).unwrap();
+
+    // R_ARM_ABS32
+    assert_eq!(word_at(&image, symbol("IMPORTED").start), KERNEL_VALUE);
+    // R_ARM_GLOB_DAT, R_ARM_JUMP_SLOT, in the global offset table
+    assert_eq!(words(&image).filter(|&w| w == KERNEL_VALUE).count(), 2);
+    assert_eq!(words(&image).filter(|&w| w == KERNEL_FN).count(), 1);
+}
+
+#[test]
+fn missing_exports_fail_the_load() {
+    let mut kernel = TestKernel::default();
+    kernel.exports.retain(|(name, _)| *name != "kernel_fn");
+
+    assert_eq!(
+        load(APP, &kernel).unwrap_err(),
+        LoadError::UndefinedSymbol("kernel_fn".into())
+    );
+}
+
+#[test]
+fn relocations_without_a_symbol_use_zero() {
+    // point the R_ARM_ABS32 relocation of IMPORTED at symbol 0, no symbol
+    let min_vaddr = Elf::open(Bytes::new(APP)).unwrap().min_vaddr();
+    let imported = min_vaddr + symbol("IMPORTED").start as u32;
+    let mut app = APP.to_vec();
+    let entry = (0..app.len() - 8)
+        .step_by(4)
+        .find(|&i| word_at(&app, i) == imported && word_at(&app, i + 4) & 0xff == 2)
+        .unwrap();
+    app[entry + 4..entry + 8].copy_from_slice(&2_u32.to_le_bytes());
+
+    // S is 0, plus the addend of 0 kept in the word
+    let (image, _) = load(&app, &TestKernel::default()).unwrap();
+    assert_eq!(word_at(&image, symbol("IMPORTED").start), 0);
+}
+
+#[test]
+fn syscall_table_is_filled_in() {
+    let (image, _) = load(APP, &TestKernel::default()).unwrap();
+
+    let table = symbol("SYS_CALL_TABLE");
+    let filled: Vec<u32> = words(&image[table]).collect();
+    assert_eq!(filled, SYSCALLS);
+}
+
+#[test]
+fn syscall_table_size_mismatch_is_refused() {
+    let mut kernel = TestKernel::default();
+    kernel.syscalls.push(0x1000_5001);
+
+    assert_eq!(
+        load(APP, &kernel).unwrap_err(),
+        LoadError::SyscallTableSizeMismatch
+    );
+}
+
+#[test]
+fn bss_is_zeroed() {
+    let (image, _) = load(APP, &TestKernel::default()).unwrap();
+    assert!(image[symbol("ZEROED")].iter().all(|&b| b == 0));
+}
+
+#[test]
+fn entry_point_is_returned() {
+    let (_, entry) = load(APP, &TestKernel::default()).unwrap();
+
+    // thumb code, the lowest bit is set
+    assert_eq!(entry, symbol("_start").start);
+    assert_eq!(entry & 1, 1);
+}
+
+#[test]
+fn stripped_apps_load_the_same() {
+    let kernel = TestKernel::default();
+    let mut elf = Elf::open(Bytes::new(APP_STRIPPED)).unwrap();
+    assert_eq!(elf.symbol("SYS_CALL_TABLE").unwrap(), None);
+
+    assert_eq!(load(APP_STRIPPED, &kernel), load(APP, &kernel));
+}
+
+#[test]
+fn sections_are_read_without_loading() {
+    for app in [APP, APP_STRIPPED] {
+        let mut elf = Elf::open(Bytes::new(app)).unwrap();
+        assert_eq!(
+            elf.section(".picocalc_meta").unwrap().as_deref(),
+            Some(&b"PCMT fixture app"[..])
+        );
+        assert_eq!(elf.section(".nonexistent").unwrap(), None);
+    }
+}
+
+#[test]
+fn non_pie_binaries_are_refused() {
+    assert_eq!(
+        Elf::open(Bytes::new(APP_STATIC)).err(),
+        Some(LoadError::ElfIsNotPie)
+    );
+}
+
+#[test]
+fn other_machines_are_refused() {
+    let mut x86 = APP.to_vec();
+    // EM_386
+    x86[18..20].copy_from_slice(&3_u16.to_le_bytes());
+
+    assert_eq!(
+        Elf::open(Bytes::new(&x86)).err(),
+        Some(LoadError::WrongMachine)
+    );
+}
+
+#[test]
+fn garbage_and_truncated_files_are_refused() {
+    assert_eq!(
+        Elf::open(Bytes::new(b"#!/bin/sh\necho hello\n")).err(),
+        Some(LoadError::FailedToReadFile)
+    );
+    assert_eq!(
+        Elf::open(Bytes::new(&[0x42; 64])).err(),
+        Some(LoadError::InvalidElf)
+    );
+    assert_eq!(
+        Elf::open(Bytes::new(&APP[..1024])).err(),
+        Some(LoadError::FailedToReadFile)
+    );
+}
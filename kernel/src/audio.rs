@@ -1,5 +1,5 @@
 use crate::Audio;
-use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use core::sync::atomic::{AtomicBool, AtomicU8, AtomicU32, Ordering};
 use embassy_futures::{join::join, yield_now};
 use embassy_rp::{
     Peri,
@@ -28,6 +28,9 @@ pub static AUDIO_BUFFER_READY: AtomicBool = AtomicBool::new(true);
 pub static AUDIO_BUFFER_WRITTEN: AtomicBool = AtomicBool::new(false);
 pub static AUDIO_BUFFER_SAMPLE_RATE: AtomicU32 = AtomicU32::new(SAMPLE_RATE_HZ);
 
+/// Scales every sample apps send, in percent
+pub static VOLUME: AtomicU8 = AtomicU8::new(100);
+
 /// resets audio buffers after user applications are unloaded
 pub fn clear_audio_buffers() {
     unsafe {
@@ -88,14 +91,20 @@ async fn write_samples<PIO: Instance>(
     static mut PACKED_BUF_L: [u32; AUDIO_BUFFER_SAMPLES / 2] = [0; AUDIO_BUFFER_SAMPLES / 2];
     static mut PACKED_BUF_R: [u32; AUDIO_BUFFER_SAMPLES / 2] = [0; AUDIO_BUFFER_SAMPLES / 2];
 
+    let volume = VOLUME.load(Ordering::Acquire);
+    let scale = |sample: u8| -> u8 {
+        let centered = sample as i32 - SILENCE as i32;
+        (SILENCE as i32 + centered * volume as i32 / 100) as u8
+    };
+
     unsafe {
         for ((pl, pr), sample) in PACKED_BUF_L
             .iter_mut()
             .zip(PACKED_BUF_R.iter_mut())
             .zip(buf.chunks(4))
         {
-            *pl = pack_u8_samples(sample[0], sample[2]);
-            *pr = pack_u8_samples(sample[1], sample[3]);
+            *pl = pack_u8_samples(scale(sample[0]), scale(sample[2]));
+            *pr = pack_u8_samples(scale(sample[1]), scale(sample[3]));
         }
 
         let left_fut = left
@@ -0,0 +1,107 @@
+//! Keeps a report of the last kernel panic or fault across the reset that
+//! follows it.
+//!
+//! The report lives in `.uninit` RAM, which is neither zeroed nor initialized
+//! at boot, so it survives watchdog and software resets. A magic value marks
+//! it as valid. On the next boot the launcher shows it and appends it to
+//! `CRASH_LOG` on the SD card.
+
+use alloc::string::String;
+use core::{
+    fmt::{self, Write},
+    panic::PanicInfo,
+    sync::atomic::{AtomicBool, Ordering},
+};
+use cortex_m::peripheral::SCB;
+
+pub const CRASH_LOG: &str = "CRASH.LOG";
+
+const REPORT_MAGIC: u32 = 0xC0A5_4ED1;
+const REPORT_LEN: usize = 512;
+
+#[repr(C)]
+struct CrashReport {
+    magic: u32,
+    len: usize,
+    text: [u8; REPORT_LEN],
+}
+
+impl Write for CrashReport {
+    // anything past the end of the buffer is dropped
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let len = s.len().min(REPORT_LEN - self.len);
+        self.text[self.len..self.len + len].copy_from_slice(&s.as_bytes()[..len]);
+        self.len += len;
+        Ok(())
+    }
+}
+
+// `.uninit` is never loaded, so this initializer is not applied at boot
+#[unsafe(link_section = ".uninit.CRASH_REPORT")]
+static mut CRASH_REPORT: CrashReport = CrashReport {
+    magic: 0,
+    len: 0,
+    text: [0; REPORT_LEN],
+};
+
+// stops both cores writing a report at once, only the first crash is kept
+static RECORDING: AtomicBool = AtomicBool::new(false);
+
+/// Records a crash report and resets the device
+pub fn crash(args: fmt::Arguments) -> ! {
+    if RECORDING.swap(true, Ordering::AcqRel) {
+        // the other core is recording its crash, and will reset both
+        loop {
+            cortex_m::asm::wfe();
+        }
+    }
+
+    // SAFETY: only the first caller gets here, and never returns
+    let report = unsafe { &mut CRASH_REPORT };
+    report.magic = 0;
+    report.len = 0;
+    let _ = report.write_fmt(args);
+    report.magic = REPORT_MAGIC;
+
+    cortex_m::asm::dsb();
+    SCB::sys_reset()
+}
+
+/// Takes the report left by a crash before the last reset, if any
+pub fn take_report() -> Option<String> {
+    // SAFETY: called once at boot from core0, before anything can crash
+    let report = unsafe { &mut CRASH_REPORT };
+    if report.magic != REPORT_MAGIC || report.len > REPORT_LEN {
+        return None;
+    }
+    report.magic = 0;
+
+    Some(String::from_utf8_lossy(&report.text[..report.len]).into_owned())
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    let core = embassy_rp::pac::SIO.cpuid().read();
+
+    #[cfg(feature = "defmt")]
+    defmt::error!(
+        "kernel panic on core{}: {}",
+        core,
+        defmt::Display2Format(info)
+    );
+
+    match info.location() {
+        Some(location) => crash(format_args!(
+            "Kernel panic on core{}\n{}\nat {}:{}",
+            core,
+            info.message(),
+            location.file(),
+            location.line()
+        )),
+        None => crash(format_args!(
+            "Kernel panic on core{}\n{}",
+            core,
+            info.message()
+        )),
+    }
+}
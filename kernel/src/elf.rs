@@ -1,323 +1,279 @@
 use crate::{
-    storage::{File, SDCARD},
+    CORE1_STACK_SIZE, log,
+    storage::{File, SDCARD, SdCard, SdCardError},
     syscalls,
 };
-use alloc::{vec, vec::Vec};
+use alloc::string::String;
 use bumpalo::Bump;
-use core::ptr;
-use embedded_sdmmc::ShortFileName;
-use goblin::{
-    elf::{
-        header::{EM_ARM, ET_DYN, EV_CURRENT, header32::Header},
-        program_header::program_header32::{PT_LOAD, ProgramHeader},
-        reloc::R_ARM_RELATIVE,
-        section_header::{SHT_REL, SHT_SYMTAB},
-    },
-    elf32::{reloc::Rel, section_header::SectionHeader, sym::Sym},
-};
+use core::{fmt, ops::Range};
+use elf_loader::{Elf, Kernel};
 use strum::IntoEnumIterator;
-use userlib_sys::{EntryFn, SYS_CALL_TABLE_COUNT, SyscallTable};
-
-const ELF32_HDR_SIZE: usize = 52;
+use userlib_sys::{
+    EntryFn, SYS_CALL_TABLE_COUNT, SyscallTable,
+    meta::{APP_META_SECTION, AppMeta},
+};
 
 #[derive(Debug)]
 pub enum LoadError {
-    WrongMachine,
-    InvalidElf,
-    FailedToReadFile,
-    ElfIsNotPie,
-    UnknownRelocationType,
-    SyscallTableNotFound,
-    SyscallTableSizeMismatch,
+    SdCardUnavailable,
+    NotFound,
+    /// The app asks for more stack than apps run with
+    StackTooLarge,
+    /// Not enough heap left for the app's image
+    OutOfMemory,
+    Elf(elf_loader::LoadError),
 }
 
-pub async unsafe fn load_binary(name: &ShortFileName) -> Result<(EntryFn, Bump), LoadError> {
-    let mut sd_lock = SDCARD.get().lock().await;
-    let sd = sd_lock.as_mut().expect("Sdcard locked");
-
-    let mut header_buf = [0; ELF32_HDR_SIZE];
-
-    sd.read_file(name, |mut file| {
-        file.read(&mut header_buf)
-            .map_err(|_| LoadError::FailedToReadFile)?;
-        let elf_header = Header::from_bytes(&header_buf);
-
-        validate_header(&elf_header)?;
-
-        let mut ph_buf = vec![0_u8; elf_header.e_phentsize as usize];
-
-        let (total_size, min_vaddr, _max_vaddr) =
-            total_loadable_size(&mut file, elf_header, &mut ph_buf)?;
-
-        let bump = Bump::with_capacity(total_size);
-        let base = bump.alloc_slice_fill_default::<u8>(total_size);
-
-        // load each segment into bump, relative to base_ptr
-        for i in 0..elf_header.e_phnum {
-            file.seek_from_start(elf_header.e_phoff + (elf_header.e_phentsize * i) as u32)
-                .map_err(|_| LoadError::FailedToReadFile)?;
-            file.read(&mut ph_buf)
-                .map_err(|_| LoadError::FailedToReadFile)?;
-            let ph = cast_phdr(&ph_buf);
-
-            let seg_offset = (ph.p_vaddr - min_vaddr) as usize;
-            let segment = &mut base[seg_offset..seg_offset + ph.p_memsz as usize];
-
-            if ph.p_type == PT_LOAD {
-                load_segment(&mut file, &ph, segment)?;
+/// Why an app can't be loaded, for the launcher to show
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use elf_loader::LoadError as ElfError;
+
+        match self {
+            LoadError::SdCardUnavailable => f.write_str("The SD card is in use by the computer"),
+            LoadError::NotFound => f.write_str("The file is gone from the SD card"),
+            LoadError::StackTooLarge => {
+                write!(
+                    f,
+                    "It needs more than {} KiB of stack",
+                    CORE1_STACK_SIZE / 1024
+                )
             }
-        }
-
-        for i in 0..elf_header.e_shnum {
-            let sh = read_section(&mut file, elf_header, i.into())?;
-
-            if sh.sh_type == SHT_REL {
-                apply_relocations(&sh, min_vaddr, base.as_mut_ptr(), &mut file)?;
+            LoadError::OutOfMemory => f.write_str("Not enough memory to load it"),
+            LoadError::Elf(ElfError::FailedToReadFile) => {
+                f.write_str("Reading it from the SD card failed")
+            }
+            LoadError::Elf(ElfError::WrongMachine) => {
+                f.write_str("It is built for another processor")
+            }
+            LoadError::Elf(ElfError::InvalidElf) => {
+                f.write_str("It is not a program, or it is corrupt")
+            }
+            LoadError::Elf(ElfError::ElfIsNotPie) => {
+                f.write_str("It isn't position independent, relink it as PIE")
+            }
+            LoadError::Elf(ElfError::UnknownRelocationType) => {
+                f.write_str("It uses relocations the loader doesn't support")
+            }
+            LoadError::Elf(ElfError::UndefinedSymbol(symbol)) => {
+                write!(
+                    f,
+                    "It needs `{}`, which this kernel doesn't export. Update the kernel or rebuild the app",
+                    symbol
+                )
+            }
+            LoadError::Elf(ElfError::SyscallTableNotFound) => {
+                f.write_str("It has no syscall table, it isn't built with userlib")
+            }
+            LoadError::Elf(ElfError::SyscallTableSizeMismatch) => {
+                f.write_str("It is built for another kernel version (syscall ABI mismatch)")
             }
         }
-
-        patch_syscalls(elf_header, base.as_mut_ptr(), min_vaddr, &mut file)?;
-
-        // entry pointer is base_ptr + (entry - min_vaddr)
-        let entry_ptr: EntryFn = unsafe {
-            core::mem::transmute(base.as_ptr().add((elf_header.e_entry - min_vaddr) as usize))
-        };
-
-        Ok((entry_ptr, bump))
-    })
-    .await
-    .map_err(|_| LoadError::FailedToReadFile)?
+    }
 }
 
-fn validate_header(h: &Header) -> Result<(), LoadError> {
-    if h.e_type != ET_DYN {
-        return Err(LoadError::ElfIsNotPie);
-    }
-    if h.e_machine != EM_ARM {
-        return Err(LoadError::WrongMachine);
-    }
-    if h.e_version as u8 != EV_CURRENT {
-        return Err(LoadError::InvalidElf);
+impl From<elf_loader::LoadError> for LoadError {
+    fn from(e: elf_loader::LoadError) -> Self {
+        LoadError::Elf(e)
     }
-    Ok(())
 }
 
-fn load_segment(file: &mut File, ph: &ProgramHeader, segment: &mut [u8]) -> Result<(), LoadError> {
-    let filesz = ph.p_filesz as usize;
-    let memsz = ph.p_memsz as usize;
-
-    // read file contents
-    let mut remaining = filesz;
-    let mut dst_offset = 0;
-    let mut file_offset = ph.p_offset;
-    let mut buf = [0u8; 512];
-
-    while remaining > 0 {
-        let to_read = core::cmp::min(remaining, buf.len());
-        file.seek_from_start(file_offset)
-            .map_err(|_| LoadError::FailedToReadFile)?;
-        file.read(&mut buf[..to_read])
-            .map_err(|_| LoadError::FailedToReadFile)?;
-
-        segment[dst_offset..dst_offset + to_read].copy_from_slice(&buf[..to_read]);
-
-        remaining -= to_read;
-        dst_offset += to_read;
-        file_offset += to_read as u32;
-    }
+pub struct LoadedBinary {
+    pub path: String,
+    pub meta: Option<AppMeta>,
+    pub entry: EntryFn,
+    /// Addresses the image was loaded into
+    pub image: Range<usize>,
+    // owns the image, which is freed when this is dropped
+    _bump: Bump,
+}
 
-    // zero BSS if needed
-    if memsz > filesz {
-        segment[filesz..].fill(0);
+impl LoadedBinary {
+    /// Whether the app runs as a background service, see `service`
+    pub fn is_background(&self) -> bool {
+        self.meta.is_some_and(|meta| meta.is_background())
     }
-
-    Ok(())
 }
 
-fn apply_relocations(
-    sh: &SectionHeader,
-    min_vaddr: u32,
-    base: *mut u8,
-    file: &mut File,
-) -> Result<(), LoadError> {
-    let mut reloc = [0_u8; 8];
-
-    let num_relocs = sh.sh_size as usize / sh.sh_entsize as usize;
-
-    for i in 0..num_relocs {
-        file.seek_from_start(sh.sh_offset + (i as u32 * 8))
-            .map_err(|_| LoadError::FailedToReadFile)?;
-        file.read(&mut reloc)
-            .map_err(|_| LoadError::FailedToReadFile)?;
+/// Loads the app at `path`, e.g. "/apps/snake.bin"
+pub async unsafe fn load_binary(path: &str) -> Result<LoadedBinary, LoadError> {
+    let mut sd_lock = SDCARD.get().lock().await;
+    let sd = sd_lock.as_mut().ok_or(LoadError::SdCardUnavailable)?;
+
+    sd.read_file(path, |file| {
+        let mut elf = Elf::open(AppFile(file))?;
+        let meta = app_meta(&mut elf)?;
+        if let Some(meta) = &meta
+            && meta.stack_size as usize > CORE1_STACK_SIZE
+        {
+            return Err(LoadError::StackTooLarge);
+        }
 
-        let rel = cast_rel(&reloc);
+        // a failed allocation would panic the kernel
+        let bump = Bump::try_with_capacity(elf.image_size()).map_err(|_| LoadError::OutOfMemory)?;
+        let base = bump
+            .try_alloc_slice_fill_default::<u8>(elf.image_size())
+            .map_err(|_| LoadError::OutOfMemory)?;
+        let address = base.as_ptr() as u32;
+        let entry = elf.load(base, address, &KernelExports::new())?;
+
+        // entry is an offset into the image
+        let entry_ptr: EntryFn = unsafe { core::mem::transmute(base.as_ptr().add(entry)) };
+
+        let image = base.as_ptr() as usize..base.as_ptr() as usize + base.len();
+        log::debug!("loaded image at {:#x}..{:#x}", image.start, image.end);
+
+        Ok(LoadedBinary {
+            path: String::from(path),
+            meta,
+            entry: entry_ptr,
+            image,
+            _bump: bump,
+        })
+    })
+    .await
+    .map_err(storage_error)?
+}
 
-        let reloc_type = rel.r_info & 0xff;
-        let reloc_addr = unsafe { base.add((rel.r_offset - min_vaddr) as usize) as *mut u32 };
+/// Reads what an app says about itself, without loading it. `None` for apps
+/// built without `userlib::app_meta!`.
+pub async fn read_meta(sd: &mut SdCard, path: &str) -> Result<Option<AppMeta>, LoadError> {
+    sd.read_file(path, |file| {
+        let mut elf = Elf::open(AppFile(file))?;
+        app_meta(&mut elf)
+    })
+    .await
+    .map_err(storage_error)?
+    .map_err(LoadError::from)
+}
 
-        match reloc_type {
-            R_ARM_RELATIVE => {
-                // REL: add base to the word already stored there
-                unsafe {
-                    let val = ptr::read_unaligned(reloc_addr);
-                    ptr::write_unaligned(reloc_addr, val.wrapping_add(base as u32));
-                }
-            }
-            _ => {
-                return Err(LoadError::UnknownRelocationType);
-            }
-        }
+fn storage_error(e: SdCardError) -> LoadError {
+    match e {
+        SdCardError::NotFound => LoadError::NotFound,
+        _ => elf_loader::LoadError::FailedToReadFile.into(),
     }
-    Ok(())
 }
 
-fn patch_syscalls(
-    elf_header: &Header,
-    base: *mut u8,
-    min_vaddr: u32,
-    file: &mut File,
-) -> Result<(), LoadError> {
-    for i in 1..=elf_header.e_shnum {
-        let sh = read_section(file, elf_header, i.into())?;
-
-        // find the symbol table
-        if sh.sh_type == SHT_SYMTAB {
-            let mut symtab_buf = vec![0u8; sh.sh_size as usize];
-            file.seek_from_start(sh.sh_offset)
-                .map_err(|_| LoadError::FailedToReadFile)?;
-            file.read(&mut symtab_buf)
-                .map_err(|_| LoadError::FailedToReadFile)?;
-
-            // Cast buffer into symbols
-            let sym_count = sh.sh_size as usize / sh.sh_entsize as usize;
-            for i in 0..sym_count {
-                let sym_bytes =
-                    &symtab_buf[i * sh.sh_entsize as usize..(i + 1) * sh.sh_entsize as usize];
-                let sym = cast_sym(sym_bytes);
-
-                let str_sh = read_section(file, elf_header, sh.sh_link)?;
-
-                let mut name = Vec::new();
-                file.seek_from_start(str_sh.sh_offset + sym.st_name)
-                    .map_err(|_| LoadError::FailedToReadFile)?;
+fn app_meta(elf: &mut Elf<AppFile>) -> Result<Option<AppMeta>, elf_loader::LoadError> {
+    Ok(elf
+        .section(APP_META_SECTION)?
+        .as_deref()
+        .and_then(AppMeta::from_bytes))
+}
 
-                loop {
-                    let mut byte = [0u8; 1];
-                    file.read(&mut byte)
-                        .map_err(|_| LoadError::FailedToReadFile)?;
-                    if byte[0] == 0 {
-                        break;
-                    }
-                    name.push(byte[0]);
-                }
+// an app on the sd card, for the loader
+struct AppFile<'a>(File<'a>);
 
-                let symbol_name = core::str::from_utf8(&name).expect("symbol was not utf8");
-                if symbol_name == stringify!(SYS_CALL_TABLE) {
-                    // The binary was linked against a different SyscallTable
-                    // than this kernel (e.g. built before a syscall was
-                    // added) -- writing the full table would overrun its
-                    // `.syscall_table` array and corrupt whatever follows it.
-                    let expected_size = SYS_CALL_TABLE_COUNT * size_of::<usize>();
-                    if sym.st_size as usize != expected_size {
-                        return Err(LoadError::SyscallTableSizeMismatch);
-                    }
+impl elf_loader::File for AppFile<'_> {
+    type Error = ();
 
-                    let table_base =
-                        unsafe { base.add((sym.st_value as usize) - min_vaddr as usize) }
-                            as *mut usize;
+    fn seek(&mut self, offset: u32) -> Result<(), ()> {
+        self.0.seek_from_start(offset).map_err(|_| ())
+    }
 
-                    for (idx, call) in SyscallTable::iter().enumerate() {
-                        let ptr = match call {
-                            SyscallTable::Alloc => syscalls::alloc as usize,
-                            SyscallTable::Dealloc => syscalls::dealloc as usize,
-                            SyscallTable::PrintString => syscalls::print as usize,
-                            SyscallTable::SleepMs => syscalls::sleep as usize,
-                            SyscallTable::GetMs => syscalls::get_ms as usize,
-                            SyscallTable::DrawIter => syscalls::draw_iter as usize,
-                            SyscallTable::GetKey => syscalls::get_key as usize,
-                            SyscallTable::GenRand => syscalls::gen_rand as usize,
-                            SyscallTable::ListDir => syscalls::list_dir as usize,
-                            SyscallTable::ReadFile => syscalls::read_file as usize,
-                            SyscallTable::WriteFile => syscalls::write_file as usize,
-                            SyscallTable::FileLen => syscalls::file_len as usize,
-                            SyscallTable::ReconfigureAudioSampleRate => {
-                                syscalls::reconfigure_audio_sample_rate as usize
-                            }
-                            SyscallTable::AudioBufferReady => syscalls::audio_buffer_ready as usize,
-                            SyscallTable::SendAudioBuffer => syscalls::send_audio_buffer as usize,
-                            SyscallTable::FillRect => syscalls::fill_rect as usize,
-                            SyscallTable::Blit => syscalls::blit as usize,
-                        };
-                        unsafe {
-                            table_base.add(idx).write(ptr);
-                        }
-                    }
-                    return Ok(());
-                }
-            }
-        }
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, ()> {
+        self.0.read(buf).map_err(|_| ())
     }
-    Err(LoadError::SyscallTableNotFound)
 }
 
-fn total_loadable_size(
-    file: &mut File,
-    elf_header: &Header,
-    ph_buf: &mut [u8],
-) -> Result<(usize, u32, u32), LoadError> {
-    let mut min_vaddr = u32::MAX;
-    let mut max_vaddr = 0u32;
-    for i in 0..elf_header.e_phnum {
-        file.seek_from_start(elf_header.e_phoff + (elf_header.e_phentsize * i) as u32)
-            .map_err(|_| LoadError::FailedToReadFile)?;
-        file.read(ph_buf).map_err(|_| LoadError::FailedToReadFile)?;
-        let ph = cast_phdr(ph_buf);
+// What apps are linked against: the syscall table, and the functions they
+// can import by name
+struct KernelExports {
+    syscalls: [u32; SYS_CALL_TABLE_COUNT],
+}
 
-        if ph.p_type == PT_LOAD {
-            if ph.p_vaddr < min_vaddr {
-                min_vaddr = ph.p_vaddr;
-            }
-            if ph.p_vaddr + ph.p_memsz > max_vaddr {
-                max_vaddr = ph.p_vaddr + ph.p_memsz;
-            }
+impl KernelExports {
+    fn new() -> Self {
+        let mut syscalls = [0; SYS_CALL_TABLE_COUNT];
+        for (slot, call) in syscalls.iter_mut().zip(SyscallTable::iter()) {
+            *slot = syscall_address(call) as u32;
         }
+        Self { syscalls }
     }
-
-    let total_size = (max_vaddr - min_vaddr) as usize;
-    Ok((total_size, min_vaddr, max_vaddr))
 }
 
-fn read_section(
-    file: &mut File,
-    elf_header: &Header,
-    section: u32,
-) -> Result<SectionHeader, LoadError> {
-    let mut sh_buf = vec![0_u8; elf_header.e_shentsize as usize];
-
-    file.seek_from_start(elf_header.e_shoff + (elf_header.e_shentsize as u32 * section))
-        .map_err(|_| LoadError::FailedToReadFile)?;
-    file.read(&mut sh_buf)
-        .map_err(|_| LoadError::FailedToReadFile)?;
-
-    Ok(cast_shdr(&sh_buf))
-}
+impl Kernel for KernelExports {
+    fn syscalls(&self) -> &[u32] {
+        &self.syscalls
+    }
 
-fn cast_phdr(buf: &[u8]) -> ProgramHeader {
-    assert!(buf.len() >= core::mem::size_of::<ProgramHeader>());
-    unsafe { core::ptr::read(buf.as_ptr() as *const ProgramHeader) }
+    fn export(&self, name: &str) -> Option<u32> {
+        kernel_export(name).map(|address| address as u32)
+    }
 }
 
-fn cast_shdr(buf: &[u8]) -> SectionHeader {
-    assert!(buf.len() >= core::mem::size_of::<SectionHeader>());
-    unsafe { core::ptr::read(buf.as_ptr() as *const SectionHeader) }
+unsafe extern "C" {
+    fn memcpy(dest: *mut u8, src: *const u8, n: usize) -> *mut u8;
+    fn memmove(dest: *mut u8, src: *const u8, n: usize) -> *mut u8;
+    fn memset(s: *mut u8, c: i32, n: usize) -> *mut u8;
+    fn memcmp(s1: *const u8, s2: *const u8, n: usize) -> i32;
 }
 
-fn cast_sym(buf: &[u8]) -> Sym {
-    assert!(buf.len() >= core::mem::size_of::<Sym>());
-    unsafe { core::ptr::read(buf.as_ptr() as *const Sym) }
+// Kernel functions apps can import by name rather than through the syscall
+// table: the syscalls, named like userlib_sys names them for C, and the
+// memory functions C code expects from libc
+fn kernel_export(name: &str) -> Option<usize> {
+    let call = match name {
+        "memcpy" => return Some(memcpy as usize),
+        "memmove" => return Some(memmove as usize),
+        "memset" => return Some(memset as usize),
+        "memcmp" => return Some(memcmp as usize),
+        "alloc" => SyscallTable::Alloc,
+        "dealloc" => SyscallTable::Dealloc,
+        "print" => SyscallTable::PrintString,
+        "sleep" => SyscallTable::SleepMs,
+        "get_ms" => SyscallTable::GetMs,
+        "draw_iter" => SyscallTable::DrawIter,
+        "get_key" => SyscallTable::GetKey,
+        "gen_rand" => SyscallTable::GenRand,
+        "list_dir" => SyscallTable::ListDir,
+        "read_file" => SyscallTable::ReadFile,
+        "write_file" => SyscallTable::WriteFile,
+        "file_len" => SyscallTable::FileLen,
+        "reconfigure_audio_sample_rate" => SyscallTable::ReconfigureAudioSampleRate,
+        "audio_buffer_ready" => SyscallTable::AudioBufferReady,
+        "send_audio_buffer" => SyscallTable::SendAudioBuffer,
+        "fill_rect" => SyscallTable::FillRect,
+        "blit" => SyscallTable::Blit,
+        "read_log" => SyscallTable::ReadLog,
+        "send_midi" => SyscallTable::SendMidi,
+        "receive_midi" => SyscallTable::ReceiveMidi,
+        "set_status_bar" => SyscallTable::SetStatusBar,
+        "sd_card_changed" => SyscallTable::SdCardChanged,
+        "wait_event" => SyscallTable::WaitEvent,
+        "yield_now" => SyscallTable::YieldNow,
+        _ => return None,
+    };
+    Some(syscall_address(call))
 }
 
-fn cast_rel(buf: &[u8]) -> Rel {
-    assert!(buf.len() >= core::mem::size_of::<Rel>());
-    unsafe { core::ptr::read(buf.as_ptr() as *const Rel) }
+fn syscall_address(call: SyscallTable) -> usize {
+    match call {
+        SyscallTable::Alloc => syscalls::alloc as usize,
+        SyscallTable::Dealloc => syscalls::dealloc as usize,
+        SyscallTable::PrintString => syscalls::print as usize,
+        SyscallTable::SleepMs => syscalls::sleep as usize,
+        SyscallTable::GetMs => syscalls::get_ms as usize,
+        SyscallTable::DrawIter => syscalls::draw_iter as usize,
+        SyscallTable::GetKey => syscalls::get_key as usize,
+        SyscallTable::GenRand => syscalls::gen_rand as usize,
+        SyscallTable::ListDir => syscalls::list_dir as usize,
+        SyscallTable::ReadFile => syscalls::read_file as usize,
+        SyscallTable::WriteFile => syscalls::write_file as usize,
+        SyscallTable::FileLen => syscalls::file_len as usize,
+        SyscallTable::ReconfigureAudioSampleRate => {
+            syscalls::reconfigure_audio_sample_rate as usize
+        }
+        SyscallTable::AudioBufferReady => syscalls::audio_buffer_ready as usize,
+        SyscallTable::SendAudioBuffer => syscalls::send_audio_buffer as usize,
+        SyscallTable::FillRect => syscalls::fill_rect as usize,
+        SyscallTable::Blit => syscalls::blit as usize,
+        SyscallTable::ReadLog => syscalls::read_log as usize,
+        SyscallTable::SendMidi => syscalls::send_midi as usize,
+        SyscallTable::ReceiveMidi => syscalls::receive_midi as usize,
+        SyscallTable::SetStatusBar => syscalls::set_status_bar as usize,
+        SyscallTable::SdCardChanged => syscalls::sd_card_changed as usize,
+        SyscallTable::WaitEvent => syscalls::wait_event as usize,
+        SyscallTable::YieldNow => syscalls::yield_now as usize,
+    }
 }
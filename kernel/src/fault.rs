@@ -0,0 +1,586 @@
+//! Recovers from faults raised by user apps on core1.
+//!
+//! `run_user` saves the kernel's callee-saved registers and stack pointer
+//! before calling into the app. If the app faults, the fault handler rewrites
+//! the stacked return address to `user_fault_return`, which restores that
+//! state and returns from `run_user` as if the app had exited, so
+//! `userland_task` can tear it down and go back to the launcher.
+//!
+//! Faults raised anywhere else (core0, or kernel code on core1, such as a
+//! syscall) are not recoverable, since the kernel may be holding locks. They
+//! are recorded as a crash report and the device is reset. Every syscall
+//! holds a `Syscall` while it runs, so that a stack overflow, which has no
+//! faulting pc to tell, is still only unwound when it was the app's.
+//!
+//! The bottom `STACK_GUARD_SIZE` bytes of the stacks apps run on are
+//! read-only, which catches an app or syscall running off the end of its
+//! stack a little at a time. A single frame bigger than the guard can skip
+//! over it though, and write below the stack without faulting.
+//!
+//! Force quitting an app unwinds it the same way. Core0 rings the core1
+//! doorbell, and the doorbell interrupt stops the app if it interrupted user
+//! code. If it landed in a syscall instead, the SysTick on core1 keeps retrying
+//! every millisecond until the app is back in its own code.
+//!
+//! A background service runs on core1 too, on its own stack, see `service`.
+//! The state here always belongs to whichever of the two is running, the
+//! other's is parked with `swap_context`. Each has its own kill request.
+
+use crate::{crash, user_memory::USER_MEMORY};
+use core::{
+    arch::global_asm,
+    fmt,
+    sync::atomic::{AtomicBool, Ordering},
+};
+use cortex_m::peripheral::{MPU, SCB, SYST};
+use cortex_m_rt::ExceptionFrame;
+use embassy_rp::{clocks::clk_sys_freq, interrupt::InterruptExt, pac::SIO};
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, signal::Signal};
+use userlib_sys::PANIC_UDF;
+
+/// Faults caught in user apps, reported to the launcher once it is back up
+pub static APP_FAULT: Signal<CriticalSectionRawMutex, FaultInfo> = Signal::new();
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FaultKind {
+    HardFault,
+    MemManage,
+    BusFault,
+    UsageFault,
+    /// The app panicked, see `userlib::abort`
+    Panic,
+    /// The app was force quit, see `request_kill`
+    Killed,
+}
+
+impl fmt::Display for FaultKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            FaultKind::HardFault => "Hard fault",
+            FaultKind::MemManage => "Memory fault",
+            FaultKind::BusFault => "Bus fault",
+            FaultKind::UsageFault => "Usage fault",
+            FaultKind::Panic => "Panic",
+            FaultKind::Killed => "Force quit",
+        };
+        f.write_str(name)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct FaultInfo {
+    pub kind: FaultKind,
+    /// Instruction that faulted
+    pub pc: u32,
+    /// Data address that faulted, when the hardware reports one
+    pub address: Option<u32>,
+    /// Configurable Fault Status Register at the time of the fault
+    pub cfsr: u32,
+}
+
+const CFSR_ADDR: u32 = 0xE000_ED28;
+const FPCCR_ADDR: u32 = 0xE000_EF34;
+
+// CFSR bits
+const MSTKERR: u32 = 1 << 4;
+const MMARVALID: u32 = 1 << 7;
+const BFARVALID: u32 = 1 << 15;
+const UNDEFINSTR: u32 = 1 << 16;
+
+// SHCSR bits enabling the configurable fault handlers
+const MEMFAULTENA: u32 = 1 << 16;
+const BUSFAULTENA: u32 = 1 << 17;
+const USGFAULTENA: u32 = 1 << 18;
+
+/// How many of the lowest bytes of a stack are made read-only, so an app
+/// overflowing it faults instead of trampling whatever is below it. Big
+/// enough for a frame holding an audio buffer, larger ones can skip it.
+pub const STACK_GUARD_SIZE: usize = 2048;
+
+// MPU regions guarding the bottom of the core1 stack, and of the background
+// service's stack
+pub const CORE1_GUARD_REGION: u32 = 0;
+pub const SERVICE_GUARD_REGION: u32 = 1;
+
+// kernel stack pointer to unwind to, saved by `run_user_entry`
+static mut RECOVERY_SP: u32 = 0;
+static mut USER_RUNNING: bool = false;
+// whether the app running is the background service
+static mut IN_SERVICE: bool = false;
+// how many syscalls the running app is in, see `Syscall`
+static mut SYSCALL_DEPTH: u32 = 0;
+static mut FAULT: Option<FaultInfo> = None;
+
+static KILL_REQUESTED: AtomicBool = AtomicBool::new(false);
+static SERVICE_KILL_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+/// The state of whichever of the foreground app and the background service
+/// isn't running, see `swap_context`
+pub struct ParkedContext {
+    recovery_sp: u32,
+    user_running: bool,
+    in_service: bool,
+    syscall_depth: u32,
+}
+
+impl ParkedContext {
+    /// A background service that hasn't run yet
+    pub const fn service() -> Self {
+        Self {
+            recovery_sp: 0,
+            user_running: false,
+            in_service: true,
+            syscall_depth: 0,
+        }
+    }
+}
+
+/// Parks the state of the app that's running and brings back `parked`'s
+///
+/// # Safety
+/// Must only be called from core1, right before or after switching stacks
+pub unsafe fn swap_context(parked: &mut ParkedContext) {
+    unsafe {
+        core::mem::swap(&mut RECOVERY_SP, &mut parked.recovery_sp);
+        core::mem::swap(&mut USER_RUNNING, &mut parked.user_running);
+        core::mem::swap(&mut IN_SERVICE, &mut parked.in_service);
+        core::mem::swap(&mut SYSCALL_DEPTH, &mut parked.syscall_depth);
+    }
+}
+
+/// Whether the code running on core1 is the background service's
+pub fn in_service() -> bool {
+    unsafe { IN_SERVICE }
+}
+
+/// Held by a syscall while it runs. The kernel may be holding locks until it
+/// is dropped, so the app can't be unwound from a stack overflow in between.
+pub struct Syscall(());
+
+impl Syscall {
+    /// Must only be called on core1
+    pub fn enter() -> Self {
+        unsafe { SYSCALL_DEPTH += 1 };
+        Self(())
+    }
+}
+
+impl Drop for Syscall {
+    fn drop(&mut self) {
+        unsafe { SYSCALL_DEPTH -= 1 };
+    }
+}
+
+// SysTick CSR bits
+const SYST_ENABLE: u32 = 1 << 0;
+const SYST_TICKINT: u32 = 1 << 1;
+const SYST_CLKSOURCE: u32 = 1 << 2;
+
+unsafe extern "C" {
+    // returns 0 if the app returned, or 1 if it faulted
+    fn run_user_entry(entry: *const ()) -> u32;
+    fn user_fault_return();
+}
+
+global_asm!(
+    ".section .text.run_user_entry, \"ax\"",
+    ".global run_user_entry",
+    ".type run_user_entry, %function",
+    ".thumb_func",
+    "run_user_entry:",
+    "push {{r4-r11, lr}}",
+    "sub sp, sp, #4", // keep the stack 8 byte aligned
+    "vpush {{d8-d15}}",
+    "ldr r1, ={recovery_sp}",
+    "str sp, [r1]",
+    "mov r4, r0",
+    "mov r0, sp",
+    "bl {user_entered}",
+    "blx r4",
+    "movs r0, #0",
+    "b .Lrun_user_exit",
+    ".global user_fault_return",
+    ".type user_fault_return, %function",
+    ".thumb_func",
+    "user_fault_return:",
+    "ldr r1, ={recovery_sp}",
+    "ldr r2, [r1]",
+    "mov sp, r2",
+    "movs r0, #1",
+    ".Lrun_user_exit:",
+    "vpop {{d8-d15}}",
+    "add sp, sp, #4",
+    "pop {{r4-r11, pc}}",
+    recovery_sp = sym RECOVERY_SP,
+    user_entered = sym user_entered,
+);
+
+// the app's stack starts at `sp`, what's above belongs to the kernel
+unsafe extern "C" fn user_entered(sp: u32) {
+    unsafe { USER_MEMORY.entered(sp as usize) };
+}
+
+// Overrides the handlers cortex-m-rt provides. cortex-m-rt's
+// `HardFaultTrampoline` has already put the exception frame in r0, for the
+// others it is found from the EXC_RETURN value in lr.
+global_asm!(
+    ".section .HardFault.user, \"ax\"",
+    ".global HardFault",
+    ".type HardFault, %function",
+    ".thumb_func",
+    "HardFault:",
+    "movs r1, #0",
+    "b .Lfault_dispatch",
+    ".global MemoryManagement",
+    ".type MemoryManagement, %function",
+    ".thumb_func",
+    "MemoryManagement:",
+    "movs r1, #1",
+    // if stacking ran into the stack guard the frame is unusable, and so is
+    // the stack. Unwind to the stack `run_user_entry` saved and build the
+    // frame to return through there instead, as a basic frame with no lazy
+    // FP state pending. If it overflowed in a syscall, `handle_fault` resets
+    // from there rather than returning.
+    "ldr r2, ={cfsr}",
+    "ldr r2, [r2]",
+    "tst r2, #{mstkerr}",
+    "beq .Lfault_frame",
+    "ldr r2, ={recovery_sp}",
+    "ldr r2, [r2]",
+    "cbz r2, .Lfault_frame",
+    "subs r2, r2, #32",
+    "mov sp, r2",
+    "orr lr, lr, #0x10",
+    "ldr r2, ={fpccr}",
+    "ldr r3, [r2]",
+    "bic r3, r3, #1",
+    "str r3, [r2]",
+    "mov r0, sp",
+    "b .Lfault_dispatch",
+    ".global BusFault",
+    ".type BusFault, %function",
+    ".thumb_func",
+    "BusFault:",
+    "movs r1, #2",
+    "b .Lfault_frame",
+    ".global UsageFault",
+    ".type UsageFault, %function",
+    ".thumb_func",
+    "UsageFault:",
+    "movs r1, #3",
+    "b .Lfault_frame",
+    ".global SIO_IRQ_BELL",
+    ".type SIO_IRQ_BELL, %function",
+    ".thumb_func",
+    "SIO_IRQ_BELL:",
+    "movs r1, #4",
+    "b .Lfault_frame",
+    ".global SysTick",
+    ".type SysTick, %function",
+    ".thumb_func",
+    "SysTick:",
+    "movs r1, #5",
+    ".Lfault_frame:",
+    "tst lr, #4",
+    "ite eq",
+    "mrseq r0, msp",
+    "mrsne r0, psp",
+    ".Lfault_dispatch:",
+    "push {{r4, lr}}",
+    "bl {handle_fault}",
+    "pop {{r4, pc}}",
+    handle_fault = sym handle_fault,
+    recovery_sp = sym RECOVERY_SP,
+    cfsr = const CFSR_ADDR,
+    mstkerr = const MSTKERR,
+    fpccr = const FPCCR_ADDR,
+);
+
+/// Enables the fault handlers, the stack guard and the force quit doorbell on
+/// core1, must be called from core1 before any user app runs
+pub fn init_core1(stack_start: usize) {
+    SIO.doorbell_in_clr().write_value(u32::MAX);
+    embassy_rp::interrupt::SIO_IRQ_BELL.unpend();
+    unsafe { embassy_rp::interrupt::SIO_IRQ_BELL.enable() };
+
+    unsafe {
+        let scb = &*SCB::PTR;
+        scb.shcsr
+            .modify(|shcsr| shcsr | MEMFAULTENA | BUSFAULTENA | USGFAULTENA);
+
+        // attribute 0: normal memory, non-cacheable
+        (*MPU::PTR).mair[0].write(0x44);
+        set_stack_guard(CORE1_GUARD_REGION, Some(stack_start));
+
+        // the default memory map still applies everywhere else. The MPU
+        // stays off in HardFault, so a fault stacking into the guard
+        // escalates to a handler that can still run.
+        (*MPU::PTR).ctrl.write(1 << 2 | 1);
+
+        cortex_m::asm::dsb();
+        cortex_m::asm::isb();
+    }
+}
+
+/// Where the memory of a stack starting at `stack_start` begins above its guard
+pub fn stack_guard_end(stack_start: usize) -> usize {
+    ((stack_start + 31) & !31) + STACK_GUARD_SIZE
+}
+
+/// Makes the lowest bytes of the stack starting at `stack_start` read-only
+/// with MPU `region`, or lifts the guard with `None`
+///
+/// # Safety
+/// Must only be called from core1. Nothing may be using the memory of a guard
+/// that's lifted, or of one that's set.
+pub unsafe fn set_stack_guard(region: u32, stack_start: Option<usize>) {
+    unsafe {
+        let mpu = &*MPU::PTR;
+        mpu.rnr.write(region);
+        match stack_start {
+            Some(stack_start) => {
+                let guard_start = (stack_start as u32 + 31) & !31;
+                let guard_limit = guard_start + STACK_GUARD_SIZE as u32 - 32;
+
+                // read-only for privileged code, execute never
+                mpu.rbar.write(guard_start | (0b10 << 1) | 1);
+                mpu.rlar.write(guard_limit | 1);
+            }
+            None => mpu.rlar.write(0),
+        }
+
+        cortex_m::asm::dsb();
+        cortex_m::asm::isb();
+    }
+}
+
+/// Runs a user app's entry point, catching any fault it raises
+///
+/// # Safety
+/// Must only be called from core1, after `init_core1`, with `USER_MEMORY`
+/// describing the app
+pub unsafe fn run_user(entry: fn()) -> Result<(), FaultInfo> {
+    unsafe {
+        kill_flag().store(false, Ordering::Release);
+        USER_RUNNING = true;
+        let faulted = run_user_entry(entry as *const ());
+        USER_RUNNING = false;
+
+        // a kill requested after the app returned by itself is moot
+        kill_flag().store(false, Ordering::Release);
+        stop_kill_retry();
+
+        match FAULT.take() {
+            Some(fault) if faulted != 0 => Err(fault),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Asks core1 to force quit the running user app, if any
+pub fn request_kill() {
+    KILL_REQUESTED.store(true, Ordering::Release);
+    SIO.doorbell_out_set().write_value(1);
+}
+
+/// Asks core1 to stop the background service. A parked one is stopped by
+/// `service` the next time it's due, rather than being run to be killed.
+pub fn request_service_kill() {
+    SERVICE_KILL_REQUESTED.store(true, Ordering::Release);
+    SIO.doorbell_out_set().write_value(1);
+}
+
+/// Whether stopping the background service was asked for, which the caller
+/// then takes care of
+pub fn take_service_kill() -> bool {
+    SERVICE_KILL_REQUESTED.swap(false, Ordering::AcqRel)
+}
+
+/// Whether the running user app is being force quit. Syscalls that can wait
+/// for a long time return early when it is, so the app can be stopped.
+pub fn kill_requested() -> bool {
+    kill_flag().load(Ordering::Acquire)
+}
+
+// the kill request of the app that's running
+fn kill_flag() -> &'static AtomicBool {
+    if in_service() {
+        &SERVICE_KILL_REQUESTED
+    } else {
+        &KILL_REQUESTED
+    }
+}
+
+fn start_kill_retry() {
+    unsafe {
+        let syst = &*SYST::PTR;
+        syst.rvr.write(clk_sys_freq() / 1000 - 1);
+        syst.cvr.write(0);
+        syst.csr.write(SYST_CLKSOURCE | SYST_TICKINT | SYST_ENABLE);
+    }
+}
+
+fn stop_kill_retry() {
+    unsafe { (*SYST::PTR).csr.write(0) };
+}
+
+fn current_core() -> u32 {
+    SIO.cpuid().read()
+}
+
+/// Unwinds the running user app by returning from the exception into
+/// `user_fault_return`
+///
+/// # Safety
+/// `frame` must be the stacked frame of user code running under `run_user`
+unsafe fn unwind_user(frame: &mut ExceptionFrame, fault: FaultInfo) {
+    unsafe {
+        FAULT = Some(fault);
+
+        // in thumb state
+        frame.set_pc(user_fault_return as usize as u32 & !1);
+        frame.set_xpsr(1 << 24);
+    }
+}
+
+unsafe fn handle_kill(frame: &mut ExceptionFrame) {
+    SIO.doorbell_in_clr().write_value(u32::MAX);
+
+    if !unsafe { USER_RUNNING } {
+        stop_kill_retry();
+        return;
+    }
+    if !kill_requested() {
+        // the foreground app is killed once the background service gives the
+        // cpu back. The service is stopped while parked.
+        if in_service() && KILL_REQUESTED.load(Ordering::Acquire) {
+            start_kill_retry();
+        } else {
+            stop_kill_retry();
+        }
+        return;
+    }
+
+    let pc = frame.pc();
+    if unsafe { USER_MEMORY.in_image(pc as usize) } {
+        stop_kill_retry();
+        kill_flag().store(false, Ordering::Release);
+        unsafe {
+            unwind_user(
+                frame,
+                FaultInfo {
+                    kind: FaultKind::Killed,
+                    pc,
+                    address: None,
+                    cfsr: 0,
+                },
+            )
+        };
+    } else {
+        // in a syscall, try again once the app is back in its own code
+        start_kill_retry();
+    }
+}
+
+unsafe extern "C" fn handle_fault(frame: &mut ExceptionFrame, kind: u32) {
+    if kind >= 4 {
+        unsafe { handle_kill(frame) };
+        return;
+    }
+
+    let scb = unsafe { &*SCB::PTR };
+    let cfsr = scb.cfsr.read();
+
+    let mut kind = match kind {
+        1 => FaultKind::MemManage,
+        2 => FaultKind::BusFault,
+        3 => FaultKind::UsageFault,
+        _ => FaultKind::HardFault,
+    };
+    let address = match kind {
+        FaultKind::MemManage if cfsr & MMARVALID != 0 => Some(scb.mmfar.read()),
+        FaultKind::BusFault if cfsr & BFARVALID != 0 => Some(scb.bfar.read()),
+        _ => None,
+    };
+
+    // a stack overflow's frame was never stacked, so there is no pc to tell
+    // where it happened, or to report. It's the app's unless it was in a
+    // syscall, which may hold locks that unwinding would never release.
+    let stack_overflow = kind == FaultKind::MemManage && cfsr & MSTKERR != 0;
+    let pc = if stack_overflow { 0 } else { frame.pc() };
+
+    let in_user_app = current_core() == 1
+        && unsafe {
+            USER_RUNNING
+                && if stack_overflow {
+                    SYSCALL_DEPTH == 0
+                } else {
+                    USER_MEMORY.in_image(pc as usize)
+                }
+        };
+    if !in_user_app {
+        kernel_fault(kind, pc, address, cfsr, frame);
+    }
+
+    if kind == FaultKind::UsageFault
+        && cfsr & UNDEFINSTR != 0
+        // SAFETY: pc is within the app's image
+        && unsafe { core::ptr::read_volatile(pc as *const u16) } == 0xDE00 | PANIC_UDF as u16
+    {
+        kind = FaultKind::Panic;
+    }
+
+    unsafe {
+        // status bits are sticky, clear them so the next fault reads cleanly
+        scb.cfsr.write(cfsr);
+        scb.hfsr.write(scb.hfsr.read());
+
+        unwind_user(
+            frame,
+            FaultInfo {
+                kind,
+                pc,
+                address,
+                cfsr,
+            },
+        );
+    }
+}
+
+fn kernel_fault(
+    kind: FaultKind,
+    pc: u32,
+    address: Option<u32>,
+    cfsr: u32,
+    frame: &ExceptionFrame,
+) -> ! {
+    let core = current_core();
+    let hfsr = unsafe { (*SCB::PTR).hfsr.read() };
+
+    #[cfg(feature = "defmt")]
+    defmt::error!(
+        "kernel {} on core{} at {:#x}, address: {:?}, cfsr: {:#x}",
+        defmt::Display2Format(&kind),
+        core,
+        pc,
+        address,
+        cfsr
+    );
+
+    crash::crash(format_args!(
+        "Kernel {} on core{}\npc: {:#010x} lr: {:#010x}\naddress: {:#010x}\ncfsr: {:#010x} hfsr: {:#010x}\nr0: {:#010x} r1: {:#010x}\nr2: {:#010x} r3: {:#010x}\nr12: {:#010x} xpsr: {:#010x}",
+        kind,
+        core,
+        pc,
+        frame.lr(),
+        address.unwrap_or(0),
+        cfsr,
+        hfsr,
+        frame.r0(),
+        frame.r1(),
+        frame.r2(),
+        frame.r3(),
+        frame.r12(),
+        frame.xpsr()
+    ))
+}
@@ -1,4 +1,7 @@
-use crate::display::{SCREEN_HEIGHT, SCREEN_WIDTH};
+use crate::{
+    display::{SCREEN_HEIGHT, SCREEN_WIDTH},
+    status::{STATUS_BAR_HEIGHT, STATUS_CANVAS, STATUS_OVERLAY},
+};
 use core::sync::atomic::{AtomicBool, Ordering};
 use embedded_graphics::{
     draw_target::DrawTarget,
@@ -45,7 +48,13 @@ impl<'a> AtomicFrameBuffer<'a> {
         }
     }
 
-    fn mark_tiles_dirty(&mut self, rect: Rectangle) {
+    /// Every pixel, row by row, as RGB565 with its bytes swapped into the
+    /// order the display takes them
+    pub fn pixels(&self) -> &[u16] {
+        self.fb
+    }
+
+    pub fn mark_tiles_dirty(&mut self, rect: Rectangle) {
         let tiles_x = SCREEN_WIDTH.div_ceil(TILE_SIZE);
         let start_tx = (rect.top_left.x as usize) / TILE_SIZE;
         let end_tx = ((rect.top_left.x + rect.size.width as i32 - 1) as usize) / TILE_SIZE;
@@ -161,6 +170,17 @@ impl<'a> AtomicFrameBuffer<'a> {
         }
     }
 
+    /// Copies the status bar into the top rows of the framebuffer, where it
+    /// stays until something draws over it
+    pub fn draw_status_into_fb(&mut self) {
+        let canvas = unsafe { &STATUS_CANVAS.canvas };
+        self.fb[..canvas.len()].copy_from_slice(canvas);
+        self.mark_tiles_dirty(Rectangle::new(
+            Point::zero(),
+            Size::new(SCREEN_WIDTH as u32, STATUS_BAR_HEIGHT as u32),
+        ));
+    }
+
     // copy N tiles horizontally to the right into batch tile buf
     fn append_tiles_to_batch(
         &mut self,
@@ -181,6 +201,16 @@ impl<'a> AtomicFrameBuffer<'a> {
 
             batch_row.copy_from_slice(fb_row);
 
+            // lay the status bar over the app, if it asked for it
+            let global_y = tile_y as usize * TILE_SIZE + batch_row_num;
+            if global_y < STATUS_BAR_HEIGHT && STATUS_OVERLAY.load(Ordering::Acquire) {
+                let start_x = tile_x as usize * TILE_SIZE;
+                let status_row = global_y * SCREEN_WIDTH + start_x;
+                batch_row.copy_from_slice(unsafe {
+                    &STATUS_CANVAS.canvas[status_row..status_row + batch_row.len()]
+                });
+            }
+
             // override fps pixel region with fps
             // avoids writing to fps, and having it overridden before draw
             #[cfg(feature = "fps")]
@@ -234,6 +264,9 @@ impl<'a> AtomicFrameBuffer<'a> {
         DELAY: DelayNs,
     {
         if self.should_full_draw() {
+            if STATUS_OVERLAY.load(Ordering::Acquire) {
+                self.draw_status_into_fb();
+            }
             #[cfg(feature = "fps")]
             self.draw_fps_into_fb();
             return self.draw(display).await;
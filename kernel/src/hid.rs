@@ -0,0 +1,229 @@
+//! USB keyboard mode, forwards the PicoCalc keyboard to the host as HID
+//! keyboard reports.
+//!
+//! The keyboard MCU already applies Shift and Sym to characters, so e.g. `!`
+//! arrives as its own key. Those are sent as the US layout key plus an implied
+//! Shift.
+
+use crate::{
+    peripherals::keyboard::{KeyCode, KeyEvent, KeyState, read_keyboard_fifo},
+    usb::HID_REPORTS,
+};
+use embassy_time::Timer;
+use heapless::Vec;
+use usbd_hid::descriptor::KeyboardReport;
+
+const MOD_LCTRL: u8 = 0x01;
+const MOD_LSHIFT: u8 = 0x02;
+const MOD_LALT: u8 = 0x04;
+const MOD_RSHIFT: u8 = 0x20;
+
+// HID keyboard usage ids
+const KEY_A: u8 = 0x04;
+const KEY_1: u8 = 0x1E;
+const KEY_0: u8 = 0x27;
+const KEY_ENTER: u8 = 0x28;
+const KEY_ESC: u8 = 0x29;
+const KEY_BACKSPACE: u8 = 0x2A;
+const KEY_TAB: u8 = 0x2B;
+const KEY_SPACE: u8 = 0x2C;
+const KEY_CAPSLOCK: u8 = 0x39;
+const KEY_F1: u8 = 0x3A;
+const KEY_PAUSE: u8 = 0x48;
+const KEY_INSERT: u8 = 0x49;
+const KEY_HOME: u8 = 0x4A;
+const KEY_PAGEUP: u8 = 0x4B;
+const KEY_DELETE: u8 = 0x4C;
+const KEY_END: u8 = 0x4D;
+const KEY_PAGEDOWN: u8 = 0x4E;
+const KEY_RIGHT: u8 = 0x4F;
+const KEY_LEFT: u8 = 0x50;
+const KEY_DOWN: u8 = 0x51;
+const KEY_UP: u8 = 0x52;
+
+/// A key as the host sees it
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Usage {
+    Modifier(u8),
+    /// usage id, and whether it needs Shift to produce the key's character
+    Key(u8, bool),
+}
+
+fn usage(key: KeyCode) -> Option<Usage> {
+    let key = match key {
+        KeyCode::ModCtrl => return Some(Usage::Modifier(MOD_LCTRL)),
+        KeyCode::ModAlt => return Some(Usage::Modifier(MOD_LALT)),
+        KeyCode::ModShiftLeft => return Some(Usage::Modifier(MOD_LSHIFT)),
+        KeyCode::ModShiftRight => return Some(Usage::Modifier(MOD_RSHIFT)),
+        KeyCode::Char(c) => return char_usage(c).map(|(id, shift)| Usage::Key(id, shift)),
+        KeyCode::Enter | KeyCode::JoyCenter => KEY_ENTER,
+        KeyCode::Esc => KEY_ESC,
+        KeyCode::Backspace => KEY_BACKSPACE,
+        KeyCode::Tab => KEY_TAB,
+        KeyCode::CapsLock => KEY_CAPSLOCK,
+        KeyCode::Break => KEY_PAUSE,
+        KeyCode::Insert => KEY_INSERT,
+        KeyCode::Home => KEY_HOME,
+        KeyCode::PageUp => KEY_PAGEUP,
+        KeyCode::Del => KEY_DELETE,
+        KeyCode::End => KEY_END,
+        KeyCode::PageDown => KEY_PAGEDOWN,
+        KeyCode::Right | KeyCode::JoyRight => KEY_RIGHT,
+        KeyCode::Left | KeyCode::JoyLeft => KEY_LEFT,
+        KeyCode::Down | KeyCode::JoyDown => KEY_DOWN,
+        KeyCode::Up | KeyCode::JoyUp => KEY_UP,
+        KeyCode::F1 => KEY_F1,
+        KeyCode::F2 => KEY_F1 + 1,
+        KeyCode::F3 => KEY_F1 + 2,
+        KeyCode::F4 => KEY_F1 + 3,
+        KeyCode::F5 => KEY_F1 + 4,
+        KeyCode::F6 => KEY_F1 + 5,
+        KeyCode::F7 => KEY_F1 + 6,
+        KeyCode::F8 => KEY_F1 + 7,
+        KeyCode::F9 => KEY_F1 + 8,
+        KeyCode::F10 => KEY_F1 + 9,
+        _ => return None,
+    };
+    Some(Usage::Key(key, false))
+}
+
+// usage id for a character on a US layout, and whether it needs Shift
+fn char_usage(c: char) -> Option<(u8, bool)> {
+    let usage = match c {
+        'a'..='z' => (KEY_A + (c as u8 - b'a'), false),
+        'A'..='Z' => (KEY_A + (c as u8 - b'A'), true),
+        '1'..='9' => (KEY_1 + (c as u8 - b'1'), false),
+        '0' => (KEY_0, false),
+        ' ' => (KEY_SPACE, false),
+        '!' => (KEY_1, true),
+        '@' => (KEY_1 + 1, true),
+        '#' => (KEY_1 + 2, true),
+        '$' => (KEY_1 + 3, true),
+        '%' => (KEY_1 + 4, true),
+        '^' => (KEY_1 + 5, true),
+        '&' => (KEY_1 + 6, true),
+        '*' => (KEY_1 + 7, true),
+        '(' => (KEY_1 + 8, true),
+        ')' => (KEY_0, true),
+        '-' => (0x2D, false),
+        '_' => (0x2D, true),
+        '=' => (0x2E, false),
+        '+' => (0x2E, true),
+        '[' => (0x2F, false),
+        '{' => (0x2F, true),
+        ']' => (0x30, false),
+        '}' => (0x30, true),
+        '\\' => (0x31, false),
+        '|' => (0x31, true),
+        ';' => (0x33, false),
+        ':' => (0x33, true),
+        '\'' => (0x34, false),
+        '"' => (0x34, true),
+        '`' => (0x35, false),
+        '~' => (0x35, true),
+        ',' => (0x36, false),
+        '<' => (0x36, true),
+        '.' => (0x37, false),
+        '>' => (0x37, true),
+        '/' => (0x38, false),
+        '?' => (0x38, true),
+        _ => return None,
+    };
+    Some(usage)
+}
+
+fn empty_report() -> KeyboardReport {
+    KeyboardReport {
+        modifier: 0,
+        reserved: 0,
+        leds: 0,
+        keycodes: [0; 6],
+    }
+}
+
+/// Keys currently held, as the host should see them
+struct Keyboard {
+    modifiers: u8,
+    keys: Vec<(u8, bool), 6>,
+}
+
+impl Keyboard {
+    const fn new() -> Self {
+        Self {
+            modifiers: 0,
+            keys: Vec::new(),
+        }
+    }
+
+    // updates the held keys, returns whether the host needs a new report
+    fn update(&mut self, event: &KeyEvent) -> bool {
+        let Some(usage) = usage(event.key) else {
+            return false;
+        };
+
+        match (usage, event.state) {
+            (Usage::Modifier(bit), KeyState::Pressed) => self.modifiers |= bit,
+            (Usage::Modifier(bit), KeyState::Released) => self.modifiers &= !bit,
+            (Usage::Key(id, shift), KeyState::Pressed) => {
+                if self.keys.iter().any(|&(held, _)| held == id) {
+                    return false;
+                }
+                // more than 6 keys at once are dropped
+                if self.keys.push((id, shift)).is_err() {
+                    return false;
+                }
+            }
+            (Usage::Key(id, _), KeyState::Released) => {
+                self.keys.retain(|&(held, _)| held != id);
+            }
+            // the host repeats held keys itself
+            _ => return false,
+        }
+        true
+    }
+
+    fn report(&self) -> KeyboardReport {
+        let mut report = empty_report();
+        report.modifier = self.modifiers;
+
+        // the last key pressed decides whether Shift is implied
+        if let Some(&(_, true)) = self.keys.last() {
+            report.modifier |= MOD_LSHIFT;
+        }
+        for (code, &(id, _)) in report.keycodes.iter_mut().zip(&self.keys) {
+            *code = id;
+        }
+        report
+    }
+}
+
+// releases every key on the host, even if keyboard mode is cancelled
+struct ReleaseOnDrop;
+
+impl Drop for ReleaseOnDrop {
+    fn drop(&mut self) {
+        let _ = HID_REPORTS.try_send(empty_report());
+    }
+}
+
+/// Forwards key events to the host until Break is held.
+///
+/// Reports are dropped rather than queued while the host isn't reading them,
+/// so this keeps running when unplugged.
+pub async fn forward_keys() {
+    let _release = ReleaseOnDrop;
+    let mut keyboard = Keyboard::new();
+    let _ = HID_REPORTS.try_send(keyboard.report());
+
+    loop {
+        if let Some(event) = read_keyboard_fifo().await {
+            if event.key == KeyCode::Break && event.state == KeyState::Hold {
+                return;
+            }
+            if keyboard.update(&event) {
+                let _ = HID_REPORTS.try_send(keyboard.report());
+            }
+        }
+        Timer::after_millis(10).await;
+    }
+}
@@ -0,0 +1,415 @@
+//! What the launcher shows: the apps on the sd card as a grid of tiles, with
+//! a folder per category, pinned favorites and the recently launched apps.
+//!
+//! Favorites, recents and the selection are saved to `LAUNCHER_FILE`, one
+//! `key=value` per line, and restored on the next boot. Apps are remembered by
+//! their path, so two apps with the same file name in different directories
+//! are told apart. `apps_dir=` sets where apps are looked for besides the root.
+
+use crate::storage::FileName;
+use alloc::{
+    collections::{BTreeMap, BTreeSet},
+    format,
+    string::String,
+    vec::Vec,
+};
+use userlib_sys::meta::AppMeta;
+
+pub const LAUNCHER_FILE: &str = "LAUNCHER.TXT";
+/// Searched for apps, along with the root directory
+pub const DEFAULT_APPS_DIR: &str = "/apps";
+
+/// Tiles in a row of the grid
+pub const COLUMNS: usize = 4;
+const MAX_RECENTS: usize = 8;
+
+/// An app on the sd card, and what it says about itself
+#[derive(Clone)]
+pub struct App {
+    pub file: FileName,
+    /// From the app's `.picocalc_meta` section, if it has one
+    pub meta: Option<AppMeta>,
+}
+
+impl App {
+    /// The name the app gives itself, or its file name
+    pub fn name(&self) -> &str {
+        self.meta
+            .as_ref()
+            .map(AppMeta::name_str)
+            .filter(|name| !name.is_empty())
+            .unwrap_or(&self.file.long_name)
+    }
+
+    pub fn category(&self) -> Option<&str> {
+        self.meta
+            .as_ref()
+            .map(AppMeta::category_str)
+            .filter(|category| !category.is_empty())
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Folder {
+    Home,
+    Recent,
+    Category(String),
+}
+
+impl Folder {
+    pub fn title(&self) -> &str {
+        match self {
+            Folder::Home => "Apps",
+            Folder::Recent => "Recent",
+            Folder::Category(category) => category,
+        }
+    }
+
+    fn parse(s: &str) -> Self {
+        match s {
+            "recent" => Folder::Recent,
+            _ => match s.strip_prefix("category:") {
+                Some(category) => Folder::Category(String::from(category)),
+                None => Folder::Home,
+            },
+        }
+    }
+
+    fn key(&self) -> String {
+        match self {
+            Folder::Home => String::from("home"),
+            Folder::Recent => String::from("recent"),
+            Folder::Category(category) => format!("category:{}", category),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Tile {
+    /// Index into the apps
+    App(usize),
+    Folder(Folder),
+}
+
+pub struct SelectionList {
+    apps: Vec<App>,
+    /// App paths, in the order they were pinned
+    favorites: Vec<String>,
+    /// App paths, most recently launched first
+    recents: Vec<String>,
+    // set in `LAUNCHER_FILE`, `DEFAULT_APPS_DIR` if not
+    apps_dir: Option<String>,
+    /// Why apps failed to load, by path. Forgotten once the apps change.
+    failures: BTreeMap<String, String>,
+    /// How long apps took to load the last time, in ms, by path
+    load_times: BTreeMap<String, u32>,
+    folder: Folder,
+    // the tiles of `folder`
+    tiles: Vec<Tile>,
+    current_selection: usize,
+    // first row of tiles shown
+    scroll: usize,
+    // path of the app to select once the apps are listed
+    restore: Option<String>,
+    changed: bool,
+}
+
+impl SelectionList {
+    pub const fn new() -> Self {
+        Self {
+            apps: Vec::new(),
+            favorites: Vec::new(),
+            recents: Vec::new(),
+            apps_dir: None,
+            failures: BTreeMap::new(),
+            load_times: BTreeMap::new(),
+            folder: Folder::Home,
+            tiles: Vec::new(),
+            current_selection: 0,
+            scroll: 0,
+            restore: None,
+            changed: false,
+        }
+    }
+
+    pub fn changed(&self) -> bool {
+        self.changed
+    }
+
+    pub fn set_changed(&mut self, changed: bool) {
+        self.changed = changed
+    }
+
+    /// Where to look for apps, besides the root directory
+    pub fn apps_dir(&self) -> &str {
+        self.apps_dir.as_deref().unwrap_or(DEFAULT_APPS_DIR)
+    }
+
+    pub fn update_selections(&mut self, apps: Vec<App>) {
+        let selected = self.selected_app().map(|app| app.file.path.clone());
+        if selected.is_some() {
+            self.restore = selected;
+        }
+        self.apps = apps;
+        // they may have been replaced with working ones
+        self.failures.clear();
+        self.load_times.clear();
+        self.rebuild();
+    }
+
+    /// Whether the apps listed are the ones in `files`
+    pub fn lists(&self, files: &[FileName]) -> bool {
+        self.apps.iter().map(|app| &app.file).eq(files)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.apps.is_empty()
+    }
+
+    pub fn folder(&self) -> &Folder {
+        &self.folder
+    }
+
+    pub fn tiles(&self) -> &[Tile] {
+        &self.tiles
+    }
+
+    pub fn apps(&self) -> &[App] {
+        &self.apps
+    }
+
+    pub fn app(&self, index: usize) -> &App {
+        &self.apps[index]
+    }
+
+    pub fn current_selection(&self) -> usize {
+        self.current_selection
+    }
+
+    pub fn selected(&self) -> Option<&Tile> {
+        self.tiles.get(self.current_selection)
+    }
+
+    pub fn selected_app(&self) -> Option<&App> {
+        match self.selected()? {
+            Tile::App(index) => Some(&self.apps[*index]),
+            Tile::Folder(_) => None,
+        }
+    }
+
+    pub fn is_favorite(&self, app: &App) -> bool {
+        self.favorites.contains(&app.file.path)
+    }
+
+    /// Why `app` failed to load the last time it was launched
+    pub fn failure(&self, app: &App) -> Option<&str> {
+        self.failures.get(&app.file.path).map(String::as_str)
+    }
+
+    /// Marks `app` as failing to load, for `reason`
+    pub fn failed(&mut self, app: &App, reason: String) {
+        self.failures.insert(app.file.path.clone(), reason);
+        self.changed = true;
+    }
+
+    /// How long `app` took to load the last time it was launched, in ms
+    pub fn load_time(&self, app: &App) -> Option<u32> {
+        self.load_times.get(&app.file.path).copied()
+    }
+
+    /// Records that `app` loaded in `ms`
+    pub fn loaded(&mut self, app: &App, ms: u32) {
+        self.load_times.insert(app.file.path.clone(), ms);
+        self.changed = true;
+    }
+
+    /// How many apps are in `folder`
+    pub fn count(&self, folder: &Folder) -> usize {
+        self.tiles_of(folder).len()
+    }
+
+    /// The first row shown, scrolled so the selection is within `rows` rows
+    pub fn scroll(&mut self, rows: usize) -> usize {
+        let row = self.current_selection / COLUMNS;
+        if row < self.scroll {
+            self.scroll = row;
+        } else if row >= self.scroll + rows {
+            self.scroll = row + 1 - rows;
+        }
+        self.scroll
+    }
+
+    /// Moves the selection by `by` tiles, stopping at either end
+    pub fn move_by(&mut self, by: isize) {
+        let last = self.tiles.len().saturating_sub(1);
+        let selection = self.current_selection.saturating_add_signed(by).min(last);
+        if selection != self.current_selection {
+            self.current_selection = selection;
+            self.changed = true;
+        }
+    }
+
+    pub fn open(&mut self, folder: Folder) {
+        self.folder = folder;
+        self.current_selection = 0;
+        self.scroll = 0;
+        self.rebuild();
+    }
+
+    /// Goes back to the home folder, selecting the folder left. Returns false
+    /// if already there.
+    pub fn back(&mut self) -> bool {
+        if self.folder == Folder::Home {
+            return false;
+        }
+        let left = Tile::Folder(core::mem::replace(&mut self.folder, Folder::Home));
+        self.rebuild();
+        if let Some(pos) = self.tiles.iter().position(|tile| *tile == left) {
+            self.current_selection = pos;
+        }
+        true
+    }
+
+    /// Pins the selected app to the home folder, or unpins it
+    pub fn toggle_favorite(&mut self) {
+        let Some(app) = self.selected_app() else {
+            return;
+        };
+        let path = app.file.path.clone();
+
+        if let Some(pos) = self.favorites.iter().position(|fav| *fav == path) {
+            self.favorites.remove(pos);
+        } else {
+            self.favorites.push(path.clone());
+        }
+        self.restore = Some(path);
+        self.rebuild();
+    }
+
+    /// Moves `app` to the front of the recently launched apps
+    pub fn launched(&mut self, app: &App) {
+        let path = &app.file.path;
+        self.failures.remove(path);
+        self.recents.retain(|recent| recent != path);
+        self.recents.insert(0, path.clone());
+        self.recents.truncate(MAX_RECENTS);
+
+        self.restore = Some(path.clone());
+        self.rebuild();
+    }
+
+    /// The contents of `LAUNCHER_FILE`
+    pub fn state(&self) -> String {
+        let mut state = format!("folder={}\n", self.folder.key());
+        if let Some(apps_dir) = &self.apps_dir {
+            state += &format!("apps_dir={}\n", apps_dir);
+        }
+        if let Some(app) = self.selected_app() {
+            state += &format!("selected={}\n", app.file.path);
+        }
+        for favorite in &self.favorites {
+            state += &format!("favorite={}\n", favorite);
+        }
+        for recent in &self.recents {
+            state += &format!("recent={}\n", recent);
+        }
+        state
+    }
+
+    /// Restores what `state` saved, unknown keys are ignored
+    pub fn restore_state(&mut self, state: &str) {
+        self.favorites.clear();
+        self.recents.clear();
+
+        for line in state.lines() {
+            let Some((key, value)) = line.split_once('=') else {
+                continue;
+            };
+            match key.trim() {
+                "folder" => self.folder = Folder::parse(value.trim()),
+                "apps_dir" => self.apps_dir = Some(String::from(value.trim())),
+                "selected" => self.restore = Some(String::from(value.trim())),
+                "favorite" => self.favorites.push(String::from(value.trim())),
+                "recent" if self.recents.len() < MAX_RECENTS => {
+                    self.recents.push(String::from(value.trim()))
+                }
+                _ => (),
+            }
+        }
+        self.rebuild();
+    }
+
+    // Recomputes the tiles after the apps, folder, favorites or recents
+    // changed, keeping the selection on the same app where possible
+    fn rebuild(&mut self) {
+        self.tiles = self.tiles_of(&self.folder);
+
+        // e.g. the category is gone, or nothing was launched since
+        if self.tiles.is_empty() && !self.apps.is_empty() && self.folder != Folder::Home {
+            self.folder = Folder::Home;
+            self.tiles = self.tiles_of(&self.folder);
+        }
+
+        if !self.apps.is_empty()
+            && let Some(path) = self.restore.take()
+        {
+            let apps = &self.apps;
+            if let Some(pos) = self
+                .tiles
+                .iter()
+                .position(|tile| matches!(tile, Tile::App(i) if apps[*i].file.path == path))
+            {
+                self.current_selection = pos;
+            }
+        }
+        self.current_selection = self
+            .current_selection
+            .min(self.tiles.len().saturating_sub(1));
+        self.changed = true;
+    }
+
+    fn tiles_of(&self, folder: &Folder) -> Vec<Tile> {
+        let find = |path: &String| self.apps.iter().position(|app| app.file.path == *path);
+
+        match folder {
+            Folder::Home => {
+                let mut tiles = Vec::new();
+                if self.recents.iter().any(|path| find(path).is_some()) {
+                    tiles.push(Tile::Folder(Folder::Recent));
+                }
+                tiles.extend(self.favorites.iter().filter_map(find).map(Tile::App));
+
+                let categories: BTreeSet<&str> =
+                    self.apps.iter().filter_map(App::category).collect();
+                tiles.extend(
+                    categories
+                        .into_iter()
+                        .map(|category| Tile::Folder(Folder::Category(String::from(category)))),
+                );
+
+                // apps without a category, unless pinned already
+                tiles.extend(
+                    self.apps
+                        .iter()
+                        .enumerate()
+                        .filter(|(_, app)| app.category().is_none() && !self.is_favorite(app))
+                        .map(|(i, _)| Tile::App(i)),
+                );
+                tiles
+            }
+            Folder::Recent => self
+                .recents
+                .iter()
+                .filter_map(find)
+                .map(Tile::App)
+                .collect(),
+            Folder::Category(category) => self
+                .apps
+                .iter()
+                .enumerate()
+                .filter(|(_, app)| app.category() == Some(category.as_str()))
+                .map(|(i, _)| Tile::App(i))
+                .collect(),
+        }
+    }
+}
@@ -0,0 +1,250 @@
+//! Kernel log, kept in a RAM ring buffer whether or not `defmt` is enabled.
+//!
+//! Entries are stored as text lines, e.g. `[   12.345] INFO  launching GIF.BIN`.
+//! The macros in this module also forward every entry to `defmt`.
+//! `flush_handler` appends new entries to `LOG_FILE` on the SD card, and moves
+//! it to `OLD_LOG_FILE` once it grows past `MAX_LOG_FILE_LEN`. Apps can read
+//! the most recent entries through the `read_log` syscall.
+
+use crate::storage::SDCARD;
+use alloc::vec::Vec;
+use core::{
+    cell::RefCell,
+    fmt::{self, Write},
+};
+use embassy_sync::{
+    blocking_mutex::{Mutex, raw::CriticalSectionRawMutex},
+    signal::Signal,
+};
+use embassy_time::{Instant, Timer};
+
+macro_rules! log {
+    ($level:ident, $defmt:ident, $($arg:tt)*) => {{
+        #[cfg(feature = "defmt")]
+        defmt::$defmt!("{}", defmt::Display2Format(&format_args!($($arg)*)));
+        $crate::log::write($crate::log::Level::$level, format_args!($($arg)*));
+    }};
+}
+
+macro_rules! error {
+    ($($arg:tt)*) => { $crate::log::log!(Error, error, $($arg)*) };
+}
+
+macro_rules! warn {
+    ($($arg:tt)*) => { $crate::log::log!(Warn, warn, $($arg)*) };
+}
+
+macro_rules! info {
+    ($($arg:tt)*) => { $crate::log::log!(Info, info, $($arg)*) };
+}
+
+macro_rules! debug {
+    ($($arg:tt)*) => { $crate::log::log!(Debug, debug, $($arg)*) };
+}
+
+pub(crate) use {debug, error, info, log, warn};
+
+pub const LOG_FILE: &str = "KERNEL.LOG";
+pub const OLD_LOG_FILE: &str = "KERNEL1.LOG";
+const MAX_LOG_FILE_LEN: u32 = 64 * 1024;
+
+const LOG_BUFFER_LEN: usize = 8 * 1024;
+const FLUSH_INTERVAL_SECS: u64 = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Error,
+    Warn,
+    Info,
+    Debug,
+}
+
+impl Level {
+    fn name(self) -> &'static str {
+        match self {
+            Level::Error => "ERROR",
+            Level::Warn => "WARN ",
+            Level::Info => "INFO ",
+            Level::Debug => "DEBUG",
+        }
+    }
+}
+
+// entries less severe than this are dropped
+const MAX_LEVEL: Level = Level::Info;
+
+static LOG: Mutex<CriticalSectionRawMutex, RefCell<LogBuffer>> =
+    Mutex::new(RefCell::new(LogBuffer::new()));
+
+/// Signaled whenever an entry is added, for the USB console to stream it
+pub static LOG_UPDATED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+struct LogBuffer {
+    buf: [u8; LOG_BUFFER_LEN],
+    // total bytes ever written, the write position is `written % LOG_BUFFER_LEN`
+    written: usize,
+    // total bytes ever handed to `flush_handler`
+    flushed: usize,
+}
+
+impl LogBuffer {
+    const fn new() -> Self {
+        Self {
+            buf: [0; LOG_BUFFER_LEN],
+            written: 0,
+            flushed: 0,
+        }
+    }
+
+    // position of the oldest byte still in the buffer
+    fn oldest(&self) -> usize {
+        self.written.saturating_sub(LOG_BUFFER_LEN)
+    }
+
+    fn copy_from(&self, start: usize, out: &mut Vec<u8>) {
+        for pos in start..self.written {
+            out.push(self.buf[pos % LOG_BUFFER_LEN]);
+        }
+    }
+}
+
+impl Write for LogBuffer {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for &byte in s.as_bytes() {
+            self.buf[self.written % LOG_BUFFER_LEN] = byte;
+            self.written += 1;
+        }
+        Ok(())
+    }
+}
+
+/// Appends an entry to the log, use the macros in this module instead
+pub fn write(level: Level, args: fmt::Arguments) {
+    if level > MAX_LEVEL {
+        return;
+    }
+
+    let now = Instant::now().as_millis();
+    LOG.lock(|log| {
+        let mut log = log.borrow_mut();
+        let _ = writeln!(
+            log,
+            "[{:>5}.{:03}] {} {}",
+            now / 1000,
+            now % 1000,
+            level.name(),
+            args
+        );
+    });
+    LOG_UPDATED.signal(());
+}
+
+/// Copies entries written since `pos` into `out`, and moves `pos` past them.
+/// Anything overwritten before it could be read is skipped.
+pub fn read_since(pos: &mut usize, out: &mut [u8]) -> usize {
+    LOG.lock(|log| {
+        let log = log.borrow();
+        let start = (*pos).max(log.oldest());
+        let len = out.len().min(log.written - start);
+
+        for (i, byte) in out[..len].iter_mut().enumerate() {
+            *byte = log.buf[(start + i) % LOG_BUFFER_LEN];
+        }
+        *pos = start + len;
+        len
+    })
+}
+
+/// Copies the most recent entries into `out`, starting at the first whole
+/// line that fits. Returns the number of bytes copied.
+pub fn read_latest(out: &mut [u8]) -> usize {
+    let mut latest = Vec::new();
+    LOG.lock(|log| {
+        let log = log.borrow();
+        let start = log.oldest().max(log.written.saturating_sub(out.len()));
+        log.copy_from(start, &mut latest);
+
+        // drop the partial line at the start, unless it is all there is
+        if start > 0
+            && let Some(line_end) = latest.iter().position(|&b| b == b'\n')
+            && line_end + 1 < latest.len()
+        {
+            latest.drain(..=line_end);
+        }
+    });
+
+    out[..latest.len()].copy_from_slice(&latest);
+    latest.len()
+}
+
+// copies every entry not yet flushed, with where they start and how many
+// bytes were overwritten before they could be
+fn unflushed() -> (Vec<u8>, usize, usize) {
+    let mut unflushed = Vec::new();
+    LOG.lock(|log| {
+        let log = log.borrow();
+        let start = log.flushed.max(log.oldest());
+        let dropped = start - log.flushed;
+        log.copy_from(start, &mut unflushed);
+        (unflushed, start, dropped)
+    })
+}
+
+// records everything before `end` as written to the sd card. Entries only
+// count once they are, a failed write is retried on the next flush.
+fn mark_flushed(end: usize) {
+    LOG.lock(|log| {
+        let mut log = log.borrow_mut();
+        log.flushed = log.flushed.max(end);
+    })
+}
+
+/// Periodically appends new log entries to the log file on the SD card.
+///
+/// Only runs while the launcher is up, since syscalls expect to be the only
+/// ones using the SD card while an app runs.
+pub async fn flush_handler() {
+    loop {
+        Timer::after_secs(FLUSH_INTERVAL_SECS).await;
+
+        let mut guard = SDCARD.get().lock().await;
+        let Some(sd) = guard.as_mut() else {
+            continue;
+        };
+        if !sd.is_attached() {
+            continue;
+        }
+
+        let (unflushed, start, dropped) = unflushed();
+        if unflushed.is_empty() {
+            continue;
+        }
+
+        let mut result = Ok(());
+        if dropped > 0 {
+            let note = alloc::format!("... {} bytes of log dropped ...\n", dropped);
+            result = sd.append_file(LOG_FILE, note.as_bytes());
+            // so the note isn't written again if the entries fail
+            if result.is_ok() {
+                mark_flushed(start);
+            }
+        }
+        result = result.and_then(|_| sd.append_file(LOG_FILE, &unflushed));
+        if result.is_ok() {
+            mark_flushed(start + unflushed.len());
+        }
+
+        if result.is_ok()
+            && sd
+                .file_len(LOG_FILE)
+                .is_ok_and(|len| len > MAX_LOG_FILE_LEN)
+        {
+            result = sd.rotate_file(LOG_FILE, OLD_LOG_FILE);
+        }
+
+        drop(guard);
+        if let Err(e) = result {
+            warn!("failed to flush log to sd card: {:?}", e);
+        }
+    }
+}
@@ -12,19 +12,31 @@
 extern crate alloc;
 
 mod audio;
+mod crash;
 mod display;
 mod elf;
+mod fault;
 mod framebuffer;
+#[cfg(feature = "usb-hid")]
+mod hid;
+mod launcher;
+mod log;
 // TODO: NEED TO UPDATE MCU TO TEST BATTERY READS
 #[allow(unused)]
 mod peripherals;
+#[cfg(feature = "usb-remote")]
+mod remote;
 #[allow(unused)]
 mod scsi;
+mod service;
+mod settings;
+mod status;
 mod storage;
 mod syscalls;
 mod ui;
 #[allow(unused)]
 mod usb;
+mod user_memory;
 mod utils;
 
 #[cfg(feature = "psram")]
@@ -39,17 +51,35 @@ use crate::{heap::init_qmi_psram_heap, psram::init_psram_qmi};
 
 use crate::{
     audio::{AUDIO_BUFFER_WRITTEN, audio_handler, clear_audio_buffers},
+    crash::{CRASH_LOG, take_report},
     display::{FRAMEBUFFER, display_handler, init_display},
-    peripherals::{conf_peripherals, keyboard::read_keyboard_fifo},
-    scsi::MSC_SHUTDOWN,
-    storage::{SDCARD, SdCard},
-    syscalls::{KEY_CACHE, MS_SINCE_LAUNCH},
-    ui::{SELECTIONS, clear_selection, ui_handler},
+    elf::{LoadedBinary, read_meta},
+    fault::{APP_FAULT, FaultKind},
+    launcher::App,
+    peripherals::{
+        conf_peripherals,
+        keyboard::{KeyCode, KeyState, read_keyboard_fifo},
+    },
+    scsi::MSC_ACTIVE,
+    settings::{SETTINGS, boot_clock_mhz, load_settings, restart, restart_needed},
+    status::{STATUS_OVERLAY, status_handler},
+    storage::{FileName, SDCARD, SdCard, SdCardError, clear_app_event, sd_detect_handler},
+    syscalls::{KEY_CACHE, MS_SINCE_LAUNCH, free_user_allocations},
+    ui::{
+        REFRESH_PROGRAMS, SELECTIONS, clear_selection, draw_splash, launch_autostart,
+        restore_launcher, show_fault, show_message, show_usb_connected, ui_handler,
+    },
+    usb::{MIDI_IN, usb_handler},
+    user_memory::USER_MEMORY,
 };
-use bumpalo::Bump;
+use alloc::{format, string::String, vec::Vec};
 use core::sync::atomic::{AtomicBool, Ordering};
+use defmt_rtt as _;
 use embassy_executor::{Executor, Spawner};
-use embassy_futures::{join::join, select::select};
+use embassy_futures::{
+    join::join3,
+    select::{Either, select},
+};
 use embassy_rp::{
     Peri,
     clocks::ClockConfig,
@@ -79,8 +109,6 @@ use embedded_hal_bus::spi::ExclusiveDevice;
 use embedded_sdmmc::SdCard as SdmmcSdCard;
 use static_cell::StaticCell;
 use talc::*;
-use userlib_sys::EntryFn;
-use {defmt_rtt as _, panic_probe as _};
 
 embassy_rp::bind_interrupts!(struct Irqs {
     I2C1_IRQ => i2c::InterruptHandler<I2C1>;
@@ -88,7 +116,15 @@ embassy_rp::bind_interrupts!(struct Irqs {
     PIO0_IRQ_0 => pio::InterruptHandler<PIO0>;
 });
 
-static mut CORE1_STACK: Stack<16384> = Stack::new();
+pub const KERNEL_VERSION: &str = env!("CARGO_PKG_VERSION");
+#[cfg(feature = "pimoroni2w")]
+pub const BOARD: &str = "pimoroni2w";
+#[cfg(not(feature = "pimoroni2w"))]
+pub const BOARD: &str = "rp235xa";
+
+// the stack guard comes out of it too
+const CORE1_STACK_SIZE: usize = 16384 + fault::STACK_GUARD_SIZE;
+static mut CORE1_STACK: Stack<CORE1_STACK_SIZE> = Stack::new();
 static EXECUTOR0: StaticCell<Executor> = StaticCell::new();
 static EXECUTOR1: StaticCell<Executor> = StaticCell::new();
 
@@ -105,12 +141,11 @@ static ALLOCATOR: Talck<spin::Mutex<()>, ClaimOnOom> =
 #[embassy_executor::task]
 async fn watchdog_task(mut watchdog: Watchdog) {
     if let Some(reason) = watchdog.reset_reason() {
-        let _reason = match reason {
+        let reason = match reason {
             ResetReason::Forced => "forced",
             ResetReason::TimedOut => "timed out",
         };
-        #[cfg(feature = "defmt")]
-        defmt::error!("Watchdog reset reason: {}", _reason);
+        log::error!("Watchdog reset reason: {}", reason);
     }
 
     watchdog.start(Duration::from_secs(3));
@@ -125,12 +160,12 @@ async fn watchdog_task(mut watchdog: Watchdog) {
 static ENABLE_UI: AtomicBool = AtomicBool::new(true);
 static UI_CHANGE: Signal<CriticalSectionRawMutex, ()> = Signal::new();
 
-const OVERCLOCK: u32 = 300_000_000;
-
 #[embassy_executor::main]
 async fn main(_spawner: Spawner) {
-    let p = if cfg!(feature = "overclock") {
-        let clocks = ClockConfig::system_freq(OVERCLOCK).unwrap();
+    // picked in the settings, the `overclock` feature changes the default
+    let clock_mhz = boot_clock_mhz();
+    let p = if clock_mhz != 150 {
+        let clocks = ClockConfig::system_freq(clock_mhz * 1_000_000).unwrap();
         let config = Config::new(clocks);
         embassy_rp::init(config)
     } else {
@@ -141,6 +176,7 @@ async fn main(_spawner: Spawner) {
         p.CORE1,
         unsafe { &mut *core::ptr::addr_of_mut!(CORE1_STACK) },
         move || {
+            fault::init_core1(core1_stack_start());
             let executor1 = EXECUTOR1.init(Executor::new());
             executor1.run(|spawner| spawner.spawn(userland_task()).unwrap());
         },
@@ -202,15 +238,28 @@ async fn main(_spawner: Spawner) {
     });
 }
 
-// One-slot channel to pass EntryFn from core1
-static BINARY_CH: Channel<CriticalSectionRawMutex, (EntryFn, Bump), 1> = Channel::new();
+// One-slot channel to pass loaded binaries to core1
+static BINARY_CH: Channel<CriticalSectionRawMutex, LoadedBinary, 1> = Channel::new();
+
+// user apps run on the core1 stack, which starts here
+fn core1_stack_start() -> usize {
+    core::ptr::addr_of!(CORE1_STACK) as usize
+}
 
 // runs dynamically loaded elf files
 #[embassy_executor::task]
 async fn userland_task() {
     let recv = BINARY_CH.receiver();
     loop {
-        let (entry, _bump) = recv.receive().await;
+        // the background service runs while no app does
+        let binary = match select(recv.receive(), service::run_while_idle()).await {
+            Either::First(binary) => binary,
+            Either::Second(never) => match never {},
+        };
+        if binary.is_background() {
+            unsafe { service::start(binary) };
+            continue;
+        }
 
         // disable kernel ui
         {
@@ -218,19 +267,54 @@ async fn userland_task() {
             UI_CHANGE.signal(());
 
             clear_selection().await;
+        }
 
-            MSC_SHUTDOWN.signal(());
+        // midi the host sent while no app was listening is stale, and so
+        // are sd card changes
+        MIDI_IN.clear();
+        clear_app_event(false);
+
+        unsafe {
+            MS_SINCE_LAUNCH = Some(Instant::now());
+            USER_MEMORY.load(
+                binary.image.clone(),
+                fault::stack_guard_end(core1_stack_start()),
+            );
+        }
+        log::info!("Executing Binary");
+        match unsafe { fault::run_user(binary.entry) } {
+            Ok(()) => (),
+            Err(fault) if fault.kind == FaultKind::Killed => {
+                log::info!("user app force quit at {:#x}", fault.pc);
+            }
+            Err(fault) => {
+                log::error!(
+                    "user app {} at {:#x}, address: {:?}, cfsr: {:#x}",
+                    fault.kind,
+                    fault.pc,
+                    fault.address,
+                    fault.cfsr
+                );
+                APP_FAULT.signal(fault);
+            }
         }
 
-        unsafe { MS_SINCE_LAUNCH = Some(Instant::now()) };
-        #[cfg(feature = "defmt")]
-        defmt::info!("Executing Binary");
-        entry();
+        // release whatever the app left behind, it may not have exited cleanly
+        free_user_allocations();
+        STATUS_OVERLAY.store(false, Ordering::Release);
+        unsafe {
+            USER_MEMORY.unload();
+            while KEY_CACHE.dequeue().is_some() {}
+        }
+        drop(binary);
 
         // enable kernel ui
         {
-            AUDIO_BUFFER_WRITTEN.store(false, Ordering::Release);
-            clear_audio_buffers();
+            // the background service may be playing
+            if !service::audio_taken() {
+                AUDIO_BUFFER_WRITTEN.store(false, Ordering::Release);
+                clear_audio_buffers();
+            }
 
             ENABLE_UI.store(true, Ordering::Release);
             UI_CHANGE.signal(());
@@ -286,12 +370,13 @@ struct Mcu {
     data: Peri<'static, PIN_6>,
 }
 
-async fn setup_mcu(mcu: Mcu) {
+// returns whether to start in safe mode
+async fn setup_mcu(mcu: Mcu) -> bool {
     // MCU i2c bus for peripherals( keyboard)
     let mut config = i2c::Config::default();
     config.frequency = 400_000;
     let i2c1 = I2c::new_async(mcu.i2c, mcu.clk, mcu.data, Irqs, config);
-    conf_peripherals(i2c1).await;
+    conf_peripherals(i2c1).await
 }
 
 async fn setup_display(display: Display, spawner: Spawner) {
@@ -343,7 +428,7 @@ async fn setup_qmi_psram() {
     }
 }
 
-async fn setup_sd(sd: Sd) {
+async fn setup_sd(sd: Sd, spawner: Spawner) {
     let mut config = spi::Config::default();
     config.frequency = 400_000;
     let spi = Spi::new_blocking(sd.spi, sd.clk, sd.mosi, sd.miso, config.clone());
@@ -355,7 +440,8 @@ async fn setup_sd(sd: Sd) {
 
     config.frequency = 32_000_000;
     sdcard.spi(|dev| dev.bus_mut().set_config(&config));
-    SDCARD.get().lock().await.replace(SdCard::new(sdcard, det));
+    SDCARD.get().lock().await.replace(SdCard::new(sdcard, &det));
+    spawner.spawn(sd_detect_handler(det)).unwrap();
 }
 
 #[embassy_executor::task]
@@ -376,29 +462,91 @@ async fn kernel_task(
     #[cfg(feature = "defmt")]
     defmt::info!("Clock: {}", embassy_rp::clocks::clk_sys_freq());
 
-    setup_mcu(mcu).await;
+    log::info!("PicoCalc OS {} on {}", KERNEL_VERSION, BOARD);
 
-    #[cfg(feature = "defmt")]
-    defmt::info!("setting up psram");
-    Timer::after_millis(100).await;
+    // a key held at power-on skips the settings and the autostart app, in
+    // case either keeps the device from being usable
+    let safe_mode = setup_mcu(mcu).await;
+    if safe_mode {
+        log::warn!("key held at power-on, starting in safe mode");
+    }
 
-    // setup_psram(psram).await;
+    // the framebuffer is in psram when there is psram, so the splash can
+    // only show once it's set up
     #[cfg(feature = "psram")]
-    setup_qmi_psram().await;
+    {
+        #[cfg(feature = "defmt")]
+        defmt::info!("setting up psram");
+        Timer::after_millis(100).await;
+
+        // setup_psram(psram).await;
+        setup_qmi_psram().await;
+    }
 
     Timer::after_millis(100).await;
     setup_display(display, spawner).await;
-    setup_sd(sd).await;
+
+    draw_splash("Starting the SD card", safe_mode);
+    setup_sd(sd, spawner).await;
+    spawner.spawn(status_handler()).unwrap();
+
+    draw_splash("Loading settings", safe_mode);
+    load_settings(safe_mode).await;
+    {
+        // after a power cycle the clock speed is back to the default
+        let settings = SETTINGS.lock().await;
+        if restart_needed(&settings) {
+            restart(settings.clock_mhz);
+        }
+    }
+    restore_launcher().await;
+
+    // the last reset may have been a crash, keep its report on the sd card
+    let mut crash_report = take_report();
+    if let Some(report) = &crash_report {
+        log::error!("recovered crash report: {}", report);
+
+        if let Some(sd) = SDCARD.get().lock().await.as_mut()
+            && let Err(e) = sd.append_file(CRASH_LOG, format!("{}\n\n", report).as_bytes())
+        {
+            log::warn!("failed to write crash log: {:?}", e);
+        }
+    }
 
     spawner.spawn(audio_handler(audio)).unwrap();
 
-    let _usb = embassy_rp_usb::Driver::new(usb, Irqs);
-    // spawner.spawn(usb_handler(usb)).unwrap();
+    let usb = embassy_rp_usb::Driver::new(usb, Irqs);
+    spawner.spawn(usb_handler(usb)).unwrap();
+
+    // not after a crash either, the app may be what crashed
+    let mut autostart = if safe_mode || crash_report.is_some() {
+        None
+    } else {
+        SETTINGS.lock().await.autostart.clone()
+    };
 
     loop {
         let ui_enabled = ENABLE_UI.load(Ordering::Relaxed);
-        if ui_enabled {
-            select(join(ui_handler(), prog_search_handler()), UI_CHANGE.wait()).await;
+        if ui_enabled && MSC_ACTIVE.load(Ordering::Acquire) {
+            select(show_usb_connected(), UI_CHANGE.wait()).await;
+
+            unsafe { FRAMEBUFFER.as_mut().unwrap().clear(Rgb565::BLACK).unwrap() };
+            SELECTIONS.lock().await.set_changed(true);
+        } else if ui_enabled {
+            if let Some(report) = crash_report.take() {
+                show_message(&report).await;
+            }
+            if let Some(fault) = APP_FAULT.try_take() {
+                show_fault(fault).await;
+            }
+            if let Some(path) = autostart.take() {
+                launch_autostart(&path).await;
+            }
+            select(
+                join3(ui_handler(), prog_search_handler(), log::flush_handler()),
+                UI_CHANGE.wait(),
+            )
+            .await;
         } else {
             select(key_handler(), UI_CHANGE.wait()).await;
         }
@@ -408,27 +556,66 @@ async fn kernel_task(
 async fn prog_search_handler() {
     loop {
         {
+            let apps_dir = String::from(SELECTIONS.lock().await.apps_dir());
             let mut guard = SDCARD.get().lock().await;
-            let sd = guard.as_mut().unwrap();
-
-            let mut files = sd.list_files_by_extension(".bin").unwrap();
-            files.sort();
-            let mut select = SELECTIONS.lock().await;
 
-            if *select.selections() != files {
-                select.update_selections(files);
-                select.reset();
+            // the usb host may have the card
+            if let Some(sd) = guard.as_mut() {
+                match find_apps(sd, &apps_dir) {
+                    Ok(mut files) => {
+                        files.sort();
+                        let mut select = SELECTIONS.lock().await;
+
+                        if !select.lists(&files) {
+                            let mut apps = Vec::with_capacity(files.len());
+                            for file in files {
+                                let meta = match read_meta(sd, &file.path).await {
+                                    Ok(meta) => meta,
+                                    Err(e) => {
+                                        log::warn!("unable to read {}: {:?}", file.long_name, e);
+                                        None
+                                    }
+                                };
+                                apps.push(App { file, meta });
+                            }
+                            select.update_selections(apps);
+                        }
+                    }
+                    Err(e) => log::error!("failed to list programs: {:?}", e),
+                }
             }
         }
-        Timer::after_secs(5).await;
+        select(Timer::after_secs(5), REFRESH_PROGRAMS.wait()).await;
+    }
+}
+
+// Apps in the root directory, and in `apps_dir` two levels deep, e.g.
+// "/apps/games/snake.bin"
+fn find_apps(sd: &mut SdCard, apps_dir: &str) -> Result<Vec<FileName>, SdCardError> {
+    let mut files = sd.find_files("/", "bin", 0)?;
+    match sd.find_files(apps_dir, "bin", 2) {
+        Ok(apps) => files.extend(apps),
+        Err(SdCardError::NotFound) => (),
+        Err(e) => return Err(e),
     }
+    Ok(files)
 }
 
 async fn key_handler() {
     loop {
         if let Some(event) = read_keyboard_fifo().await {
-            unsafe {
-                let _ = KEY_CACHE.enqueue(event);
+            // holding Break force quits the app, even one that never reads
+            // keys, or the background service if it's what hogs the cpu
+            if event.key == KeyCode::Break && event.state == KeyState::Hold {
+                if service::overran() {
+                    fault::request_service_kill();
+                } else {
+                    fault::request_kill();
+                }
+            } else {
+                unsafe {
+                    let _ = KEY_CACHE.enqueue(event);
+                }
             }
         }
         Timer::after_millis(50).await;
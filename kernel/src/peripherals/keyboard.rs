@@ -1,4 +1,13 @@
-use crate::peripherals::PERIPHERAL_BUS;
+use crate::{
+    peripherals::PERIPHERAL_BUS,
+    settings::{DEFAULT_KEY_REPEAT_DELAY, DEFAULT_KEY_REPEAT_RATE},
+};
+use core::{
+    cell::Cell,
+    sync::atomic::{AtomicU8, AtomicU16, Ordering},
+};
+use embassy_sync::blocking_mutex::{Mutex, raw::CriticalSectionRawMutex};
+use embassy_time::{Duration, Instant};
 pub use userlib_sys::keyboard::{KeyCode, KeyEvent, KeyState, Modifiers};
 
 const REG_ID_KEY: u8 = 0x04;
@@ -8,7 +17,72 @@ const KEY_CAPSLOCK: u8 = 1 << 5;
 const KEY_NUMLOCK: u8 = 1 << 6;
 const KEY_COUNT_MASK: u8 = 0x1F; // 0x1F == 31
 
+// the mcu doesn't repeat keys, so held keys are repeated here, see
+// `set_key_repeat`
+static REPEAT_DELAY_MS: AtomicU16 = AtomicU16::new(DEFAULT_KEY_REPEAT_DELAY);
+static REPEAT_RATE: AtomicU8 = AtomicU8::new(DEFAULT_KEY_REPEAT_RATE);
+// the key held down, and when it repeats next
+static HELD: Mutex<CriticalSectionRawMutex, Cell<Option<(KeyCode, Instant)>>> =
+    Mutex::new(Cell::new(None));
+
+/// Sets how long a key has to be held before it repeats, and how many times a
+/// second it does then. A rate of 0 turns repeating off.
+pub fn set_key_repeat(delay_ms: u16, rate: u8) {
+    REPEAT_DELAY_MS.store(delay_ms, Ordering::Relaxed);
+    REPEAT_RATE.store(rate, Ordering::Relaxed);
+}
+
+/// Reads the next key event. The last key pressed repeats as more presses
+/// while it's held, as often as this is polled.
 pub async fn read_keyboard_fifo() -> Option<KeyEvent> {
+    let event = read_fifo().await;
+    let now = Instant::now();
+
+    HELD.lock(|held| match event {
+        Some(event) => {
+            match event.state {
+                KeyState::Pressed if repeats(event.key) => {
+                    let delay = REPEAT_DELAY_MS.load(Ordering::Relaxed);
+                    held.set(Some((event.key, now + Duration::from_millis(delay as u64))));
+                }
+                KeyState::Released if held.get().is_some_and(|(key, _)| key == event.key) => {
+                    held.set(None)
+                }
+                _ => (),
+            }
+            Some(event)
+        }
+        None => {
+            let (key, due) = held.get()?;
+            let rate = REPEAT_RATE.load(Ordering::Relaxed);
+            if rate == 0 || now < due {
+                return None;
+            }
+            held.set(Some((key, now + Duration::from_millis(1000 / rate as u64))));
+            Some(KeyEvent {
+                key,
+                state: KeyState::Pressed,
+                mods: Modifiers::NONE,
+            })
+        }
+    })
+}
+
+// modifiers only change other keys, and Break is held to force quit
+fn repeats(key: KeyCode) -> bool {
+    !matches!(
+        key,
+        KeyCode::ModAlt
+            | KeyCode::ModShiftLeft
+            | KeyCode::ModShiftRight
+            | KeyCode::ModSym
+            | KeyCode::ModCtrl
+            | KeyCode::CapsLock
+            | KeyCode::Break
+    )
+}
+
+async fn read_fifo() -> Option<KeyEvent> {
     let mut i2c = PERIPHERAL_BUS.get().lock().await;
     let i2c = i2c.as_mut().unwrap();
 
@@ -50,10 +124,10 @@ pub async fn configure_keyboard(debounce: u8, poll_freq: u8) {
     let i2c = i2c.as_mut().unwrap();
 
     let _ = i2c
-        .write_read_async(super::MCU_ADDR, [REG_ID_DEB], &mut [debounce])
+        .write_async(super::MCU_ADDR, [REG_ID_DEB | super::WRITE_FLAG, debounce])
         .await;
 
     let _ = i2c
-        .write_read_async(super::MCU_ADDR, [REG_ID_FRQ], &mut [poll_freq])
+        .write_async(super::MCU_ADDR, [REG_ID_FRQ | super::WRITE_FLAG, poll_freq])
         .await;
 }
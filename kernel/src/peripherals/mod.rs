@@ -12,7 +12,10 @@ use embassy_time::Timer;
 
 pub mod keyboard;
 
-use crate::peripherals::keyboard::{configure_keyboard, read_keyboard_fifo};
+use crate::{
+    peripherals::keyboard::{KeyState, configure_keyboard, read_keyboard_fifo},
+    settings::{DEFAULT_KEY_DEBOUNCE, DEFAULT_KEY_POLL},
+};
 
 const MCU_ADDR: u8 = 0x1F;
 
@@ -24,18 +27,32 @@ const REG_ID_VER: u8 = 0x01;
 const REG_ID_RST: u8 = 0x08;
 const REG_ID_INT: u8 = 0x03;
 
-pub async fn conf_peripherals(i2c: I2CBUS) {
+/// Set on a register id to write it instead of reading it
+const WRITE_FLAG: u8 = 0x80;
+
+/// Returns whether a key was held down at power-on, which starts safe mode
+pub async fn conf_peripherals(i2c: I2CBUS) -> bool {
     Timer::after(embassy_time::Duration::from_millis(100)).await;
 
     PERIPHERAL_BUS.get().lock().await.replace(i2c);
 
-    configure_keyboard(200, 100).await;
+    // until the settings are read from the sd card
+    configure_keyboard(DEFAULT_KEY_DEBOUNCE, DEFAULT_KEY_POLL).await;
 
-    // empty keys
-    while read_keyboard_fifo().await.is_some() {}
+    // empty keys, minding whether the last one is still down
+    let mut held = false;
+    while let Some(event) = read_keyboard_fifo().await {
+        held = match event.state {
+            KeyState::Pressed | KeyState::Hold => true,
+            KeyState::Released => false,
+            _ => held,
+        };
+    }
 
     // set_lcd_backlight(255).await;
     set_key_backlight(0).await;
+
+    held
 }
 
 /// return major & minor mcu version
@@ -55,7 +72,7 @@ pub async fn set_lcd_backlight(brightness: u8) {
     let i2c = i2c.as_mut().unwrap();
 
     let _ = i2c
-        .write_read_async(MCU_ADDR, [REG_ID_BKL], &mut [brightness])
+        .write_async(MCU_ADDR, [REG_ID_BKL | WRITE_FLAG, brightness])
         .await;
 }
 pub async fn get_lcd_backlight() -> u8 {
@@ -74,7 +91,7 @@ pub async fn set_key_backlight(brightness: u8) {
     let i2c = i2c.as_mut().unwrap();
 
     let _ = i2c
-        .write_read_async(MCU_ADDR, [REG_ID_BK2], &mut [brightness])
+        .write_async(MCU_ADDR, [REG_ID_BK2 | WRITE_FLAG, brightness])
         .await;
 }
 pub async fn get_key_backlight() -> u8 {
@@ -0,0 +1,253 @@
+//! Remote control from a PC over a second USB serial port, spoken by the
+//! `picocalc` CLI. See `remote_protocol` for the frames.
+//!
+//! The sd card is only used while the launcher is up, since syscalls expect to
+//! be the only ones using it while an app runs. Requests needing it are
+//! refused as busy until the app quits, or while the usb host has the card.
+
+use crate::{
+    BINARY_CH, ENABLE_UI,
+    display::{FRAMEBUFFER, SCREEN_HEIGHT, SCREEN_WIDTH},
+    elf::{LoadError, load_binary},
+    fault, log,
+    storage::{SDCARD, SdCard, SdCardError},
+    ui::REFRESH_PROGRAMS,
+};
+use core::sync::atomic::Ordering;
+use embassy_futures::select::{Either, select};
+use embassy_rp::{peripherals::USB, usb::Driver};
+use embassy_time::Timer;
+use embassy_usb::{class::cdc_acm::CdcAcmClass, driver::EndpointError};
+use heapless::Vec;
+use remote_protocol::{
+    DirEntry, ErrorCode, FrameReader, MAX_DATA, MAX_FRAME, Request, Response, VERSION, encode_pixel,
+};
+
+pub const PACKET_SIZE: u16 = 64;
+
+// how often new log entries are sent while the host streams the log
+const LOG_POLL_MS: u64 = 100;
+
+type Class<'d> = CdcAcmClass<'d, Driver<'d, USB>>;
+
+/// Payload of the `Ok` answering a request, or why it failed
+type Reply = Result<Vec<u8, 4>, ErrorCode>;
+
+pub async fn run_remote<'d>(class: &mut Class<'d>) -> ! {
+    loop {
+        class.wait_connection().await;
+        log::info!("usb remote connected");
+        let _ = serve(class).await;
+    }
+}
+
+async fn serve<'d>(class: &mut Class<'d>) -> Result<(), EndpointError> {
+    let mut reader = FrameReader::new();
+    let mut packet = [0_u8; PACKET_SIZE as usize];
+    // next log position to send, while the host streams the log
+    let mut log_pos = None;
+
+    loop {
+        let len = match &mut log_pos {
+            None => class.read_packet(&mut packet).await?,
+            Some(pos) => {
+                match select(
+                    class.read_packet(&mut packet),
+                    Timer::after_millis(LOG_POLL_MS),
+                )
+                .await
+                {
+                    Either::First(len) => len?,
+                    Either::Second(()) => {
+                        send_log(class, pos).await?;
+                        continue;
+                    }
+                }
+            }
+        };
+
+        for &byte in &packet[..len] {
+            // corrupted frames are dropped, the host times out and retries
+            let Some(Ok(frame)) = reader.push(byte) else {
+                continue;
+            };
+            let seq = frame.seq;
+
+            let reply = match Request::parse(&frame) {
+                Ok(request) => handle(class, seq, request, &mut log_pos).await?,
+                Err(code) => Err(code),
+            };
+            let response = match &reply {
+                Ok(result) => Response::Ok(result),
+                Err(code) => Response::Error(*code),
+            };
+            send(class, seq, response).await?;
+        }
+    }
+}
+
+// sends the `Data` frames answering a request, and returns how it ended
+async fn handle<'d>(
+    class: &mut Class<'d>,
+    seq: u8,
+    request: Request<'_>,
+    log_pos: &mut Option<usize>,
+) -> Result<Reply, EndpointError> {
+    Ok(match request {
+        Request::Ping => Ok([VERSION].into_iter().collect()),
+        Request::ListDir { path } => return list_dir(class, seq, path).await,
+        Request::ReadFile { path } => return read_file(class, seq, path).await,
+        Request::WriteFile { path, offset, data } => {
+            let written = with_sd(|sd| sd.write_at(path, offset, data)).await;
+            // the launcher picks up new apps right away
+            REFRESH_PROGRAMS.signal(());
+            written.map(|()| Vec::new())
+        }
+        Request::Launch { path } => launch(path).await,
+        Request::Stop => {
+            if ENABLE_UI.load(Ordering::Acquire) {
+                Err(ErrorCode::NotRunning)
+            } else {
+                fault::request_kill();
+                Ok(Vec::new())
+            }
+        }
+        Request::Screenshot => return screenshot(class, seq).await,
+        Request::StreamLog { enable } => {
+            // start from the oldest entry still buffered, like the console
+            *log_pos = enable.then_some(0);
+            Ok(Vec::new())
+        }
+    })
+}
+
+async fn send<'d>(
+    class: &mut Class<'d>,
+    seq: u8,
+    response: Response<'_>,
+) -> Result<(), EndpointError> {
+    let mut frame = [0_u8; MAX_FRAME];
+    let len = response
+        .encode(seq, &mut frame)
+        .map_err(|_| EndpointError::BufferOverflow)?;
+
+    for packet in frame[..len].chunks(PACKET_SIZE as usize) {
+        class.write_packet(packet).await?;
+    }
+    // a full sized last packet doesn't end the transfer by itself
+    if len % PACKET_SIZE as usize == 0 {
+        class.write_packet(&[]).await?;
+    }
+    Ok(())
+}
+
+async fn send_log<'d>(class: &mut Class<'d>, pos: &mut usize) -> Result<(), EndpointError> {
+    let mut buf = [0_u8; MAX_DATA];
+    loop {
+        let len = log::read_since(pos, &mut buf);
+        if len == 0 {
+            return Ok(());
+        }
+        send(class, 0, Response::Log(&buf[..len])).await?;
+    }
+}
+
+// Runs `access` on the sd card, unless an app is running or the usb host has it
+async fn with_sd<R>(
+    access: impl FnOnce(&mut SdCard) -> Result<R, SdCardError>,
+) -> Result<R, ErrorCode> {
+    if !ENABLE_UI.load(Ordering::Acquire) {
+        return Err(ErrorCode::Busy);
+    }
+    let mut guard = SDCARD.get().lock().await;
+    let sd = guard.as_mut().ok_or(ErrorCode::Busy)?;
+
+    access(sd).map_err(|e| match e {
+        SdCardError::NotFound => ErrorCode::NotFound,
+        SdCardError::InvalidName => ErrorCode::InvalidName,
+        _ => ErrorCode::Io,
+    })
+}
+
+async fn list_dir<'d>(class: &mut Class<'d>, seq: u8, path: &str) -> Result<Reply, EndpointError> {
+    let entries = match with_sd(|sd| sd.list_dir(path)).await {
+        Ok(entries) => entries,
+        Err(code) => return Ok(Err(code)),
+    };
+
+    let mut data = [0_u8; MAX_DATA];
+    for entry in &entries {
+        let len = DirEntry {
+            name: &entry.name,
+            size: entry.size,
+            is_dir: entry.is_dir,
+        }
+        .encode(&mut data);
+        send(class, seq, Response::Data(&data[..len])).await?;
+    }
+    Ok(Ok(Vec::new()))
+}
+
+// sends the file a frame at a time, the card is only locked while reading each
+async fn read_file<'d>(class: &mut Class<'d>, seq: u8, path: &str) -> Result<Reply, EndpointError> {
+    let mut data = [0_u8; MAX_DATA];
+    let mut offset = 0;
+
+    loop {
+        let read = match with_sd(|sd| sd.read_at(path, offset, &mut data)).await {
+            Ok(read) => read,
+            Err(code) => return Ok(Err(code)),
+        };
+        if read == 0 {
+            return Ok(Ok(Vec::new()));
+        }
+
+        send(class, seq, Response::Data(&data[..read])).await?;
+        if read < data.len() {
+            return Ok(Ok(Vec::new()));
+        }
+        offset += read as u32;
+    }
+}
+
+// Loads an app, like the launcher, and hands it to core1
+async fn launch(path: &str) -> Reply {
+    let binary = match unsafe { load_binary(path).await } {
+        Ok(binary) => binary,
+        // the usb host took the card in the meantime
+        Err(LoadError::SdCardUnavailable) => return Err(ErrorCode::Busy),
+        Err(LoadError::NotFound) => return Err(ErrorCode::NotFound),
+        Err(e) => {
+            log::warn!("unable to load {}: {:?}", path, e);
+            return Err(ErrorCode::LoadFailed);
+        }
+    };
+
+    // the launcher may have started one in the meantime
+    if !ENABLE_UI.load(Ordering::Acquire) || BINARY_CH.try_send(binary).is_err() {
+        return Err(ErrorCode::Busy);
+    }
+    log::info!("launching {} from usb remote", path);
+    Ok(Vec::new())
+}
+
+async fn screenshot<'d>(class: &mut Class<'d>, seq: u8) -> Result<Reply, EndpointError> {
+    let Some(fb) = (unsafe { FRAMEBUFFER.as_ref() }) else {
+        return Ok(Err(ErrorCode::Io));
+    };
+
+    // the screen keeps changing while it is sent, some tearing is expected
+    let mut data = [0_u8; MAX_DATA];
+    for pixels in fb.pixels().chunks(MAX_DATA / 2) {
+        for (bytes, pixel) in data.chunks_exact_mut(2).zip(pixels) {
+            // the framebuffer keeps them byte swapped, in the display's order
+            bytes.copy_from_slice(&encode_pixel(pixel.swap_bytes()));
+        }
+        send(class, seq, Response::Data(&data[..pixels.len() * 2])).await?;
+    }
+
+    let mut size = Vec::new();
+    let _ = size.extend_from_slice(&(SCREEN_WIDTH as u16).to_le_bytes());
+    let _ = size.extend_from_slice(&(SCREEN_HEIGHT as u16).to_le_bytes());
+    Ok(Ok(size))
+}
@@ -0,0 +1,175 @@
+use core::sync::atomic::{AtomicBool, Ordering};
+use embassy_futures::select::{Either, select};
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::lazy_lock::LazyLock;
+use embassy_sync::signal::Signal;
+use embassy_usb::Builder;
+use embassy_usb::driver::Driver;
+use embedded_sdmmc::{Block, BlockIdx};
+use mass_storage::{
+    BLOCK_SIZE, BlockDevice, BulkOnly, CLASS_MASS_STORAGE, PROTOCOL_BULK_ONLY,
+    scsi::{SUBCLASS_SCSI, ScsiCommand},
+};
+
+use crate::{
+    ENABLE_UI, UI_CHANGE, log,
+    storage::{SDCARD, SdCard},
+    ui::REFRESH_PROGRAMS,
+};
+
+const BULK_ENDPOINT_PACKET_SIZE: u16 = 64;
+
+/// Set while the host has the sd card, the launcher shows it is connected to
+/// a PC and can't launch apps until the card is handed back
+pub static MSC_ACTIVE: AtomicBool = AtomicBool::new(false);
+
+/// Hands the sd card back to the kernel when the host goes away, i.e. usb
+/// power is gone or the host dropped the configuration. A suspend isn't one,
+/// the host keeps the card mounted while it sleeps.
+pub static MSC_RELEASE: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+// number of blocks to read from sd at once
+// higher is better, but is larger. Size is 2 * BLOCKS * 512 bytes
+const BLOCKS: usize = 16;
+static mut TRANSFER_BUF: [u8; BLOCKS * BLOCK_SIZE] = [0; BLOCKS * BLOCK_SIZE];
+static mut BLOCK_BUF: LazyLock<[Block; BLOCKS]> =
+    LazyLock::new(|| core::array::from_fn(|_| Block::new()));
+
+/// The sd card, while the host has it
+struct HostCard {
+    sd: Option<SdCard>,
+    blocks: &'static mut [Block; BLOCKS],
+}
+
+impl BlockDevice for HostCard {
+    type Error = ();
+
+    fn is_present(&self) -> bool {
+        self.sd.as_ref().is_some_and(|sd| sd.is_attached())
+    }
+
+    fn num_blocks(&self) -> u64 {
+        self.sd
+            .as_ref()
+            .map_or(0, |sd| sd.size() / SdCard::BLOCK_SIZE as u64)
+    }
+
+    fn read_blocks(&mut self, lba: u64, buf: &mut [u8]) -> Result<(), ()> {
+        let sd = self.sd.as_ref().ok_or(())?;
+        let blocks = &mut self.blocks[..buf.len() / BLOCK_SIZE];
+
+        sd.read_blocks(blocks, BlockIdx(lba as u32))?;
+        for (block, chunk) in blocks.iter().zip(buf.chunks_mut(BLOCK_SIZE)) {
+            chunk.copy_from_slice(&block.contents);
+        }
+        Ok(())
+    }
+
+    fn write_blocks(&mut self, lba: u64, buf: &[u8]) -> Result<(), ()> {
+        let sd = self.sd.as_ref().ok_or(())?;
+        let blocks = &mut self.blocks[..buf.len() / BLOCK_SIZE];
+
+        for (block, chunk) in blocks.iter_mut().zip(buf.chunks(BLOCK_SIZE)) {
+            block.contents.copy_from_slice(chunk);
+        }
+        sd.write_blocks(blocks, BlockIdx(lba as u32))
+    }
+
+    // writes go straight to the card
+    fn flush(&mut self) -> Result<(), ()> {
+        Ok(())
+    }
+}
+
+pub struct MassStorageClass<'d, D: Driver<'d>> {
+    card: HostCard, // temporary owns sdcard while the host has it
+    // the host ejected the card, don't take it again until it reconnects
+    ejected: bool,
+    // the host locked the medium in, allowing removal again is an unmount
+    prevented: bool,
+    transport: BulkOnly<'static, D::EndpointIn, D::EndpointOut>,
+}
+
+impl<'d, D: Driver<'d>> MassStorageClass<'d, D> {
+    pub fn new(builder: &mut Builder<'d, D>) -> Self {
+        let mut function = builder.function(CLASS_MASS_STORAGE, SUBCLASS_SCSI, PROTOCOL_BULK_ONLY);
+        let mut interface = function.interface();
+        let mut alt =
+            interface.alt_setting(CLASS_MASS_STORAGE, SUBCLASS_SCSI, PROTOCOL_BULK_ONLY, None);
+
+        let bulk_out = alt.endpoint_bulk_out(None, BULK_ENDPOINT_PACKET_SIZE);
+        let bulk_in = alt.endpoint_bulk_in(None, BULK_ENDPOINT_PACKET_SIZE);
+
+        Self {
+            card: HostCard {
+                sd: None,
+                blocks: unsafe { BLOCK_BUF.get_mut() },
+            },
+            ejected: false,
+            prevented: false,
+            transport: BulkOnly::new(bulk_in, bulk_out, unsafe { &mut TRANSFER_BUF }),
+        }
+    }
+
+    pub async fn poll(&mut self) -> ! {
+        loop {
+            if let Either::Second(()) = select(self.handle_cbw(), MSC_RELEASE.wait()).await {
+                // the host went away, it may take the card again once it is back
+                self.ejected = false;
+                self.prevented = false;
+                self.release_card().await;
+            }
+        }
+    }
+
+    // Takes the sd card for the host, unless an app is running or the host
+    // ejected it
+    async fn take_card(&mut self) {
+        if self.card.sd.is_some() || self.ejected || !ENABLE_UI.load(Ordering::Acquire) {
+            return;
+        }
+
+        if let Some(sd) = SDCARD.get().lock().await.take() {
+            self.card.sd = Some(sd);
+            // the host may have seen no medium until now
+            self.transport.medium_changed();
+            MSC_ACTIVE.store(true, Ordering::Release);
+            UI_CHANGE.signal(());
+            log::info!("sd card handed to usb host");
+        }
+    }
+
+    async fn release_card(&mut self) {
+        if let Some(sd) = self.card.sd.take() {
+            SDCARD.get().lock().await.replace(sd);
+            MSC_ACTIVE.store(false, Ordering::Release);
+            UI_CHANGE.signal(());
+            REFRESH_PROGRAMS.signal(());
+            log::info!("sd card returned from usb host");
+        }
+    }
+
+    async fn handle_cbw(&mut self) {
+        let cbw = self.transport.read_cbw().await;
+        self.take_card().await;
+
+        match self.transport.execute(&cbw, &mut self.card).await {
+            Ok(ScsiCommand::StartStopUnit {
+                start: false,
+                load_eject: true,
+            }) => {
+                self.ejected = true;
+                self.release_card().await;
+            }
+            Ok(ScsiCommand::PreventAllowMediumRemoval { prevent: true }) => self.prevented = true,
+            // e.g. linux allows removal once the last user unmounted it
+            Ok(ScsiCommand::PreventAllowMediumRemoval { prevent: false }) if self.prevented => {
+                self.prevented = false;
+                self.ejected = true;
+                self.release_card().await;
+            }
+            Ok(_) => (),
+            Err(e) => log::warn!("usb mass storage transfer failed: {:?}", e),
+        }
+    }
+}
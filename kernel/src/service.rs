@@ -0,0 +1,331 @@
+//! Background services: apps that keep running on core1 next to the
+//! foreground app, e.g. a music player.
+//!
+//! A service runs on a stack of its own, and is scheduled cooperatively. It
+//! gives the cpu back in `wait_event`, and is resumed once what it waits for
+//! happened, either while core1 has nothing else to do, or when the foreground
+//! app waits in a syscall (`sleep`, `wait_event`, and the calls apps poll in
+//! their main loop). `switch_stack` saves the callee-saved registers of one
+//! context on its stack and restores the other's, like a function call that
+//! returns on another stack.
+//!
+//! While a service is loaded it has the audio output, and it can't draw or
+//! read keys. Only one runs at a time.
+//!
+//! Its stack is on the kernel heap, with a stack guard below it on top of the
+//! size it asked for. Like on the core1 stack, a frame bigger than the guard
+//! can skip it, and would then write into the heap below.
+
+use crate::{
+    UI_CHANGE,
+    audio::{AUDIO_BUFFER_WRITTEN, clear_audio_buffers},
+    elf::LoadedBinary,
+    fault::{self, APP_FAULT, FaultInfo, FaultKind, ParkedContext, SERVICE_GUARD_REGION},
+    log, storage,
+    syscalls::{MS_SINCE_LAUNCH, free_allocations, pending_events},
+    user_memory::{USER_MEMORY, UserMemory},
+};
+use alloc::{string::String, vec::Vec};
+use core::{
+    arch::global_asm,
+    cell::RefCell,
+    sync::atomic::{AtomicBool, AtomicU32, Ordering},
+};
+use embassy_sync::blocking_mutex::{Mutex, raw::CriticalSectionRawMutex};
+use embassy_time::{Duration, Instant, Timer};
+use userlib_sys::{EntryFn, Events, WAIT_FOREVER};
+
+/// Stack services get when their `AppMeta` doesn't ask for a size
+const DEFAULT_STACK_SIZE: usize = 8 * 1024;
+
+/// How long a service may keep the cpu before holding Break stops it, rather
+/// than the foreground app
+const MAX_SLICE_MS: u32 = 1000;
+
+// registers `switch_stack` keeps on a parked stack: d8-d15, padding, r4-r11
+// and the return address
+const SWITCH_FRAME_WORDS: usize = 16 + 1 + 8 + 1;
+
+// only touched from core1
+static mut SERVICE: Option<Service> = None;
+// stack pointer of whatever resumed the service, to go back to
+static mut RESUMER_SP: u32 = 0;
+
+// for core0: the path of the running service, and whether it has the cpu
+static RUNNING_PATH: Mutex<CriticalSectionRawMutex, RefCell<Option<String>>> =
+    Mutex::new(RefCell::new(None));
+static RESUMED: AtomicBool = AtomicBool::new(false);
+static RESUMED_AT_MS: AtomicU32 = AtomicU32::new(0);
+
+struct Service {
+    path: String,
+    entry: EntryFn,
+    _stack: Vec<u64>,
+    sp: u32,
+    state: State,
+    // what the wait it's parked in returns
+    fired: Events,
+    // what the fault handler, pointer checks and `get_ms` know about it,
+    // swapped in while it runs
+    memory: UserMemory,
+    context: ParkedContext,
+    launched: Option<Instant>,
+    // keeps the image loaded
+    _binary: LoadedBinary,
+}
+
+enum State {
+    Waiting {
+        events: Events,
+        deadline: Option<Instant>,
+    },
+    Exited(Result<(), FaultInfo>),
+}
+
+unsafe extern "C" {
+    // saves the callee-saved registers and stack pointer to `save_sp`, and
+    // returns to whatever called `switch_stack` on the stack at `sp`
+    fn switch_stack(save_sp: *mut u32, sp: u32);
+}
+
+global_asm!(
+    ".section .text.switch_stack, \"ax\"",
+    ".global switch_stack",
+    ".type switch_stack, %function",
+    ".thumb_func",
+    "switch_stack:",
+    "push {{r4-r11, lr}}",
+    "sub sp, sp, #4", // keep the stack 8 byte aligned
+    "vpush {{d8-d15}}",
+    "str sp, [r0]",
+    "mov sp, r1",
+    "vpop {{d8-d15}}",
+    "add sp, sp, #4",
+    "pop {{r4-r11, pc}}",
+);
+
+/// The path of the running background service, if any
+pub fn running_service() -> Option<String> {
+    RUNNING_PATH.lock(|path| path.borrow().clone())
+}
+
+/// Whether the service has had the cpu for too long, e.g. stuck in a loop
+/// without waiting
+pub fn overran() -> bool {
+    let now = Instant::now().as_millis() as u32;
+    RESUMED.load(Ordering::Acquire)
+        && now.wrapping_sub(RESUMED_AT_MS.load(Ordering::Acquire)) > MAX_SLICE_MS
+}
+
+/// Whether the foreground app has to leave the audio output to the service
+pub fn audio_taken() -> bool {
+    let loaded = unsafe { SERVICE.is_some() };
+    loaded && !fault::in_service()
+}
+
+/// Starts `binary` as the background service, stopping the one running
+///
+/// # Safety
+/// Must only be called from core1, outside of the service
+pub unsafe fn start(binary: LoadedBinary) {
+    unsafe { stop() };
+
+    let stack_size = match binary.meta.map_or(0, |meta| meta.stack_size as usize) {
+        0 => DEFAULT_STACK_SIZE,
+        size => size,
+    };
+    // the guard starts at the first 32 byte boundary
+    let stack_size = stack_size + fault::STACK_GUARD_SIZE + 32;
+    let mut stack = Vec::new();
+    if stack
+        .try_reserve_exact(stack_size.div_ceil(size_of::<u64>()))
+        .is_err()
+    {
+        log::error!("no memory for the stack of {}", binary.path);
+        return;
+    }
+    stack.resize(stack.capacity(), 0);
+
+    // parked in `switch_stack`, about to return into `service_main`
+    let words = stack.as_mut_ptr() as *mut u32;
+    let top = stack.len() * 2;
+    let sp = unsafe {
+        let frame = words.add(top - SWITCH_FRAME_WORDS);
+        *frame.add(SWITCH_FRAME_WORDS - 1) = service_main as usize as u32;
+        frame as u32
+    };
+
+    let stack_start = stack.as_ptr() as usize;
+    let mut memory = UserMemory::new();
+    memory.load(binary.image.clone(), fault::stack_guard_end(stack_start));
+    unsafe { fault::set_stack_guard(SERVICE_GUARD_REGION, Some(stack_start)) };
+
+    // card changes from before it started are stale
+    storage::clear_app_event(true);
+    log::info!("starting background service {}", binary.path);
+    RUNNING_PATH.lock(|path| path.replace(Some(binary.path.clone())));
+    unsafe {
+        SERVICE = Some(Service {
+            path: binary.path.clone(),
+            entry: binary.entry,
+            _stack: stack,
+            sp,
+            // due right away
+            state: State::Waiting {
+                events: Events::empty(),
+                deadline: Some(Instant::now()),
+            },
+            fired: Events::empty(),
+            memory,
+            context: ParkedContext::service(),
+            launched: Some(Instant::now()),
+            _binary: binary,
+        });
+    }
+}
+
+/// Asks core1 to stop the background service
+pub fn request_stop() {
+    fault::request_service_kill();
+}
+
+/// Runs the service until it waits again, if what it waits for happened.
+/// Stops it instead if that was asked for.
+///
+/// # Safety
+/// Must only be called from core1, outside of the service
+pub unsafe fn run_due() {
+    unsafe {
+        if fault::in_service() || SERVICE.is_none() {
+            return;
+        }
+        if fault::take_service_kill() {
+            stop();
+            return;
+        }
+
+        let Some(service) = SERVICE.as_mut() else {
+            return;
+        };
+        let State::Waiting { events, deadline } = service.state else {
+            return;
+        };
+        let fired = pending_events(events, true);
+        if fired.is_empty() && deadline.is_none_or(|deadline| Instant::now() < deadline) {
+            return;
+        }
+        service.fired = fired;
+
+        swap_context(service);
+        RESUMED_AT_MS.store(Instant::now().as_millis() as u32, Ordering::Release);
+        RESUMED.store(true, Ordering::Release);
+
+        switch_stack(&raw mut RESUMER_SP, service.sp);
+
+        RESUMED.store(false, Ordering::Release);
+        let Some(service) = SERVICE.as_mut() else {
+            return;
+        };
+        swap_context(service);
+
+        if let State::Exited(result) = service.state {
+            match result {
+                Ok(()) => log::info!("background service {} exited", service.path),
+                Err(fault) if fault.kind == FaultKind::Killed => {
+                    log::info!(
+                        "background service {} stopped at {:#x}",
+                        service.path,
+                        fault.pc
+                    );
+                }
+                Err(fault) => {
+                    log::error!(
+                        "background service {} {} at {:#x}, address: {:?}, cfsr: {:#x}",
+                        service.path,
+                        fault.kind,
+                        fault.pc,
+                        fault.address,
+                        fault.cfsr
+                    );
+                    APP_FAULT.signal(fault);
+                    // for the launcher to report it now
+                    UI_CHANGE.signal(());
+                }
+            }
+            stop();
+        }
+    }
+}
+
+/// Runs the service whenever it's due, while core1 has nothing else to do
+pub async fn run_while_idle() -> ! {
+    loop {
+        // only core1 starts one, not while this runs
+        if unsafe { SERVICE.is_none() } {
+            core::future::pending::<()>().await;
+        }
+        unsafe { run_due() };
+        Timer::after_millis(1).await;
+    }
+}
+
+/// Parks the service until one of `events` happens or `timeout_ms` passes,
+/// returning the events that happened
+///
+/// # Safety
+/// Must only be called from the service
+pub unsafe fn wait(events: Events, timeout_ms: u32) -> Events {
+    unsafe {
+        let Some(service) = SERVICE.as_mut() else {
+            return Events::empty();
+        };
+        let deadline = (timeout_ms != WAIT_FOREVER)
+            .then(|| Instant::now() + Duration::from_millis(timeout_ms as u64));
+        service.state = State::Waiting { events, deadline };
+
+        switch_stack(&raw mut service.sp, RESUMER_SP);
+
+        SERVICE.as_mut().map_or(Events::empty(), |service| {
+            core::mem::take(&mut service.fired)
+        })
+    }
+}
+
+// the bottom of a service's stack
+extern "C" fn service_main() -> ! {
+    unsafe {
+        if let Some(entry) = SERVICE.as_ref().map(|service| service.entry) {
+            let result = fault::run_user(entry);
+
+            if let Some(service) = SERVICE.as_mut() {
+                service.state = State::Exited(result);
+                switch_stack(&raw mut service.sp, RESUMER_SP);
+            }
+        }
+    }
+    unreachable!("an exited service was resumed")
+}
+
+// trades the running app's state for the parked service's, and back
+unsafe fn swap_context(service: &mut Service) {
+    unsafe {
+        core::mem::swap(&mut USER_MEMORY, &mut service.memory);
+        core::mem::swap(&mut MS_SINCE_LAUNCH, &mut service.launched);
+        fault::swap_context(&mut service.context);
+    }
+}
+
+// tears down the parked service, if any
+unsafe fn stop() {
+    let Some(mut service) = (unsafe { SERVICE.take() }) else {
+        return;
+    };
+
+    free_allocations(&mut service.memory);
+    unsafe { fault::set_stack_guard(SERVICE_GUARD_REGION, None) };
+    AUDIO_BUFFER_WRITTEN.store(false, Ordering::Release);
+    clear_audio_buffers();
+
+    RUNNING_PATH.lock(|path| path.replace(None));
+    log::info!("background service {} unloaded", service.path);
+}
@@ -0,0 +1,389 @@
+//! System settings: backlights, keyboard, volume, clock speed, the app to
+//! autostart and the time of day.
+//!
+//! Settings are kept in `SETTINGS_FILE`, one `key=value` per line like the
+//! launcher's file, read at boot and saved when the settings screen closes.
+//! The time isn't saved, there is no real time clock to keep it.
+//!
+//! The clock speed can only be set before the kernel starts, so changing it
+//! resets the device. The speed to boot at, and the time, are left in the
+//! watchdog scratch registers, which survive the reset.
+
+use crate::{
+    audio::VOLUME,
+    log,
+    peripherals::{
+        get_lcd_backlight,
+        keyboard::{configure_keyboard, set_key_repeat},
+        set_key_backlight, set_lcd_backlight,
+    },
+    storage::{SDCARD, SdCardError},
+};
+use alloc::{format, string::String};
+use core::sync::atomic::{AtomicU32, Ordering};
+use cortex_m::peripheral::SCB;
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, mutex::Mutex};
+use embassy_time::Instant;
+
+pub const SETTINGS_FILE: &str = "SETTINGS.TXT";
+
+/// Clock speeds to pick from, in MHz. 150 is the rp2350's own.
+pub const CLOCK_SPEEDS: [u32; 4] = [150, 200, 250, 300];
+#[cfg(feature = "overclock")]
+const DEFAULT_CLOCK_MHZ: u32 = 300;
+#[cfg(not(feature = "overclock"))]
+const DEFAULT_CLOCK_MHZ: u32 = 150;
+
+pub const DEFAULT_KEY_DEBOUNCE: u8 = 200;
+pub const DEFAULT_KEY_POLL: u8 = 100;
+pub const DEFAULT_KEY_REPEAT_DELAY: u16 = 500;
+pub const DEFAULT_KEY_REPEAT_RATE: u8 = 10;
+// keys are polled every 50 ms
+const MAX_KEY_REPEAT_RATE: u8 = 20;
+
+pub static SETTINGS: Mutex<CriticalSectionRawMutex, Settings> = Mutex::new(Settings::new());
+
+// watchdog scratch registers, the bootrom uses 4 to 7
+const SCRATCH_CLOCK: usize = 0;
+const SCRATCH_TIME: usize = 1;
+// marks the scratch registers as written by `restart`, in the top half
+const SCRATCH_MAGIC: u32 = 0x5E7C_0000;
+
+// the clock speed this boot runs at
+static BOOT_CLOCK_MHZ: AtomicU32 = AtomicU32::new(DEFAULT_CLOCK_MHZ);
+// seconds since midnight when `Instant` was 0, plus 1. 0 while the time isn't set.
+static MIDNIGHT_OFFSET: AtomicU32 = AtomicU32::new(0);
+
+const DAY_SECS: u32 = 24 * 60 * 60;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Settings {
+    pub brightness: u8,
+    pub key_backlight: u8,
+    /// Keyboard debounce time, in ms
+    pub key_debounce: u8,
+    /// How often the keyboard is scanned, in ms
+    pub key_poll: u8,
+    /// How long a key is held before it repeats, in ms
+    pub key_repeat_delay: u16,
+    /// Repeats a second of a held key, 0 for none
+    pub key_repeat_rate: u8,
+    /// Percent
+    pub volume: u8,
+    pub clock_mhz: u32,
+    /// Path of the app to launch after boot
+    pub autostart: Option<String>,
+}
+
+impl Settings {
+    pub const fn new() -> Self {
+        Self {
+            brightness: u8::MAX,
+            key_backlight: 0,
+            key_debounce: DEFAULT_KEY_DEBOUNCE,
+            key_poll: DEFAULT_KEY_POLL,
+            key_repeat_delay: DEFAULT_KEY_REPEAT_DELAY,
+            key_repeat_rate: DEFAULT_KEY_REPEAT_RATE,
+            volume: 100,
+            clock_mhz: DEFAULT_CLOCK_MHZ,
+            autostart: None,
+        }
+    }
+
+    /// The contents of `SETTINGS_FILE`
+    pub fn to_text(&self) -> String {
+        let mut text = format!(
+            "brightness={}\nkey_backlight={}\nkey_debounce={}\nkey_poll={}\nkey_repeat_delay={}\nkey_repeat_rate={}\nvolume={}\nclock_mhz={}\n",
+            self.brightness,
+            self.key_backlight,
+            self.key_debounce,
+            self.key_poll,
+            self.key_repeat_delay,
+            self.key_repeat_rate,
+            self.volume,
+            self.clock_mhz
+        );
+        if let Some(autostart) = &self.autostart {
+            text += &format!("autostart={}\n", autostart);
+        }
+        text
+    }
+
+    /// Reads what `to_text` wrote, unknown keys and bad values are ignored
+    pub fn parse(&mut self, text: &str) {
+        for line in text.lines() {
+            let Some((key, value)) = line.split_once('=') else {
+                continue;
+            };
+            let value = value.trim();
+            let number = value.parse::<u32>().ok();
+            let byte = number.and_then(|n| u8::try_from(n).ok());
+
+            match key.trim() {
+                "brightness" => self.brightness = byte.unwrap_or(self.brightness),
+                "key_backlight" => self.key_backlight = byte.unwrap_or(self.key_backlight),
+                "key_debounce" => self.key_debounce = byte.unwrap_or(self.key_debounce),
+                "key_poll" => self.key_poll = byte.unwrap_or(self.key_poll),
+                "key_repeat_delay" => {
+                    if let Some(ms) = number.and_then(|n| u16::try_from(n).ok()) {
+                        self.key_repeat_delay = ms;
+                    }
+                }
+                "key_repeat_rate" => {
+                    self.key_repeat_rate =
+                        byte.map_or(self.key_repeat_rate, |rate| rate.min(MAX_KEY_REPEAT_RATE))
+                }
+                "volume" => self.volume = byte.map_or(self.volume, |v| v.min(100)),
+                "clock_mhz" => {
+                    if let Some(mhz) = number.filter(|mhz| CLOCK_SPEEDS.contains(mhz)) {
+                        self.clock_mhz = mhz;
+                    }
+                }
+                "autostart" => {
+                    self.autostart = Some(String::from(value)).filter(|path| !path.is_empty())
+                }
+                _ => (),
+            }
+        }
+    }
+}
+
+/// A row of the settings screen
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Setting {
+    Brightness,
+    KeyBacklight,
+    KeyDebounce,
+    KeyPoll,
+    KeyRepeatDelay,
+    KeyRepeatRate,
+    Volume,
+    ClockSpeed,
+    Autostart,
+    Hour,
+    Minute,
+}
+
+impl Setting {
+    pub const ALL: [Setting; 11] = [
+        Setting::Brightness,
+        Setting::KeyBacklight,
+        Setting::KeyDebounce,
+        Setting::KeyPoll,
+        Setting::KeyRepeatDelay,
+        Setting::KeyRepeatRate,
+        Setting::Volume,
+        Setting::ClockSpeed,
+        Setting::Autostart,
+        Setting::Hour,
+        Setting::Minute,
+    ];
+
+    pub fn label(self) -> &'static str {
+        match self {
+            Setting::Brightness => "Brightness",
+            Setting::KeyBacklight => "Key backlight",
+            Setting::KeyDebounce => "Key debounce",
+            Setting::KeyPoll => "Key poll",
+            Setting::KeyRepeatDelay => "Key repeat delay",
+            Setting::KeyRepeatRate => "Key repeat rate",
+            Setting::Volume => "Volume",
+            Setting::ClockSpeed => "Clock speed",
+            Setting::Autostart => "Autostart",
+            Setting::Hour => "Hour",
+            Setting::Minute => "Minute",
+        }
+    }
+
+    pub fn value(self, settings: &Settings) -> String {
+        let time = seconds_since_midnight();
+        match self {
+            Setting::Brightness => format!("{}%", settings.brightness as u32 * 100 / 255),
+            Setting::KeyBacklight => format!("{}%", settings.key_backlight as u32 * 100 / 255),
+            Setting::KeyDebounce => format!("{} ms", settings.key_debounce),
+            Setting::KeyPoll => format!("{} ms", settings.key_poll),
+            Setting::KeyRepeatDelay => format!("{} ms", settings.key_repeat_delay),
+            Setting::KeyRepeatRate => match settings.key_repeat_rate {
+                0 => String::from("Off"),
+                rate => format!("{}/s", rate),
+            },
+            Setting::Volume => format!("{}%", settings.volume),
+            Setting::ClockSpeed => format!("{} MHz", settings.clock_mhz),
+            // the path may not fit
+            Setting::Autostart => match &settings.autostart {
+                Some(path) => String::from(path.rsplit('/').next().unwrap_or(path)),
+                None => String::from("None"),
+            },
+            Setting::Hour => time.map_or(String::from("--"), |secs| format!("{:02}", secs / 3600)),
+            Setting::Minute => {
+                time.map_or(String::from("--"), |secs| format!("{:02}", secs / 60 % 60))
+            }
+        }
+    }
+
+    /// Steps the setting up or down by `by`. The autostart app is picked from
+    /// `apps`, the time is set right away.
+    pub fn adjust(self, settings: &mut Settings, by: i32, apps: &[&str]) {
+        let step = |value: u8, step: i32, min: i32| -> u8 {
+            (value as i32 + by * step).clamp(min, u8::MAX as i32) as u8
+        };
+
+        match self {
+            // fully dark would leave no way to see the screen
+            Setting::Brightness => settings.brightness = step(settings.brightness, 16, 16),
+            Setting::KeyBacklight => settings.key_backlight = step(settings.key_backlight, 16, 0),
+            Setting::KeyDebounce => settings.key_debounce = step(settings.key_debounce, 10, 10),
+            Setting::KeyPoll => settings.key_poll = step(settings.key_poll, 10, 10),
+            Setting::KeyRepeatDelay => {
+                settings.key_repeat_delay =
+                    (settings.key_repeat_delay as i32 + by * 50).clamp(100, 2000) as u16
+            }
+            Setting::KeyRepeatRate => {
+                settings.key_repeat_rate = (settings.key_repeat_rate as i32 + by)
+                    .clamp(0, MAX_KEY_REPEAT_RATE as i32)
+                    as u8
+            }
+            Setting::Volume => {
+                settings.volume = (settings.volume as i32 + by * 10).clamp(0, 100) as u8
+            }
+            Setting::ClockSpeed => {
+                let index = CLOCK_SPEEDS
+                    .iter()
+                    .position(|&mhz| mhz == settings.clock_mhz)
+                    .unwrap_or(0) as i32;
+                let index = (index + by).clamp(0, CLOCK_SPEEDS.len() as i32 - 1);
+                settings.clock_mhz = CLOCK_SPEEDS[index as usize];
+            }
+            Setting::Autostart => {
+                // none, then each app, wrapping around
+                let current = settings
+                    .autostart
+                    .as_deref()
+                    .and_then(|path| apps.iter().position(|&app| app == path))
+                    .map_or(0, |index| index as i32 + 1);
+                let options = apps.len() as i32 + 1;
+                settings.autostart = match (current + by).rem_euclid(options) {
+                    0 => None,
+                    index => Some(String::from(apps[index as usize - 1])),
+                };
+            }
+            Setting::Hour | Setting::Minute => {
+                let unit = if self == Setting::Hour { 60 * 60 } else { 60 };
+                let secs = seconds_since_midnight().unwrap_or(0) as i32;
+                set_time((secs + by * unit).rem_euclid(DAY_SECS as i32) as u32);
+            }
+        }
+    }
+}
+
+/// Reads `SETTINGS_FILE` and applies it. Settings it doesn't have keep what
+/// the hardware has. In safe mode the file is left alone.
+pub async fn load_settings(safe_mode: bool) {
+    let mut settings = Settings::new();
+    settings.brightness = get_lcd_backlight().await;
+
+    if !safe_mode {
+        read_settings(&mut settings).await;
+    }
+
+    apply(&settings).await;
+    *SETTINGS.lock().await = settings;
+}
+
+async fn read_settings(settings: &mut Settings) {
+    let mut buf = [0; 512];
+    let read = match SDCARD.get().lock().await.as_mut() {
+        Some(sd) => sd.read_at(SETTINGS_FILE, 0, &mut buf),
+        None => Err(SdCardError::NotFound),
+    };
+    match read {
+        Ok(len) => match core::str::from_utf8(&buf[..len]) {
+            Ok(text) => settings.parse(text),
+            Err(_) => log::warn!("{} is not text, ignoring it", SETTINGS_FILE),
+        },
+        // never changed
+        Err(SdCardError::NotFound) => (),
+        Err(e) => log::warn!("failed to read {}: {:?}", SETTINGS_FILE, e),
+    }
+}
+
+pub async fn save_settings() {
+    let text = SETTINGS.lock().await.to_text();
+    if let Some(sd) = SDCARD.get().lock().await.as_mut()
+        && let Err(e) = sd.write_at(SETTINGS_FILE, 0, text.as_bytes())
+    {
+        log::warn!("failed to write {}: {:?}", SETTINGS_FILE, e);
+    }
+}
+
+/// Applies everything but the clock speed, see `restart_needed`
+pub async fn apply(settings: &Settings) {
+    set_lcd_backlight(settings.brightness).await;
+    set_key_backlight(settings.key_backlight).await;
+    configure_keyboard(settings.key_debounce, settings.key_poll).await;
+    set_key_repeat(settings.key_repeat_delay, settings.key_repeat_rate);
+    VOLUME.store(settings.volume, Ordering::Release);
+}
+
+/// Whether the clock speed changed, which takes a `restart`
+pub fn restart_needed(settings: &Settings) -> bool {
+    settings.clock_mhz != BOOT_CLOCK_MHZ.load(Ordering::Acquire)
+}
+
+/// Resets the device to run at `clock_mhz`, keeping the time
+pub fn restart(clock_mhz: u32) -> ! {
+    log::info!("restarting at {} MHz", clock_mhz);
+
+    let watchdog = embassy_rp::pac::WATCHDOG;
+    watchdog
+        .scratch(SCRATCH_CLOCK)
+        .write_value(SCRATCH_MAGIC | clock_mhz);
+    let time = match seconds_since_midnight() {
+        Some(secs) => SCRATCH_MAGIC | (secs / 2),
+        None => 0,
+    };
+    watchdog.scratch(SCRATCH_TIME).write_value(time);
+
+    cortex_m::asm::dsb();
+    SCB::sys_reset()
+}
+
+/// The clock speed to boot at, in MHz, called before the kernel starts.
+/// Also picks up the time from before a `restart`.
+pub fn boot_clock_mhz() -> u32 {
+    let watchdog = embassy_rp::pac::WATCHDOG;
+
+    let mhz = scratch_value(SCRATCH_CLOCK)
+        .filter(|mhz| CLOCK_SPEEDS.contains(mhz))
+        .unwrap_or(DEFAULT_CLOCK_MHZ);
+    BOOT_CLOCK_MHZ.store(mhz, Ordering::Release);
+
+    // in 2 second steps to fit, the reset takes about that anyway. `Instant`
+    // starts at 0 once the kernel does.
+    if let Some(time) = scratch_value(SCRATCH_TIME) {
+        MIDNIGHT_OFFSET.store((time * 2) % DAY_SECS + 1, Ordering::Release);
+        watchdog.scratch(SCRATCH_TIME).write_value(0);
+    }
+    mhz
+}
+
+// what `restart` left in a scratch register
+fn scratch_value(index: usize) -> Option<u32> {
+    let value = embassy_rp::pac::WATCHDOG.scratch(index).read();
+    (value & 0xFFFF_0000 == SCRATCH_MAGIC).then_some(value & 0xFFFF)
+}
+
+/// Seconds since midnight, if the time was set
+pub fn seconds_since_midnight() -> Option<u32> {
+    match MIDNIGHT_OFFSET.load(Ordering::Acquire) {
+        0 => None,
+        offset => Some((offset - 1 + Instant::now().as_secs() as u32) % DAY_SECS),
+    }
+}
+
+/// Sets the time to `secs` since midnight
+pub fn set_time(secs: u32) {
+    let uptime = Instant::now().as_secs() as u32 % DAY_SECS;
+    let offset = (secs % DAY_SECS + DAY_SECS - uptime) % DAY_SECS;
+    MIDNIGHT_OFFSET.store(offset + 1, Ordering::Release);
+}
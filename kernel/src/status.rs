@@ -0,0 +1,215 @@
+//! The status bar: battery, clock, sd card, usb and free memory in a strip
+//! along the top of the screen.
+//!
+//! The launcher always shows it. Apps opt into it with the `set_status_bar`
+//! syscall, in which case it's laid over whatever they draw there when the
+//! framebuffer is pushed to the display, rather than drawn into it.
+
+use crate::{
+    ENABLE_UI,
+    display::{FRAMEBUFFER, SCREEN_WIDTH},
+    peripherals::get_battery,
+    settings::seconds_since_midnight,
+    storage::card_present,
+    usb::USB_ACTIVE,
+};
+use core::{
+    fmt::Write,
+    sync::atomic::{AtomicBool, Ordering},
+};
+use embassy_time::Timer;
+use embedded_graphics::{
+    Drawable, Pixel,
+    draw_target::DrawTarget,
+    geometry::Point,
+    mono_font::{MonoTextStyle, ascii::FONT_6X10},
+    pixelcolor::Rgb565,
+    prelude::{IntoStorage, OriginDimensions, RgbColor, Size, WebColors},
+    primitives::Rectangle,
+    text::{Baseline, Text},
+};
+
+pub use userlib_sys::STATUS_BAR_HEIGHT;
+
+/// Whether the running app asked for the status bar
+pub static STATUS_OVERLAY: AtomicBool = AtomicBool::new(false);
+
+pub static mut STATUS_CANVAS: StatusCanvas = StatusCanvas::new();
+
+const REFRESH_SECS: u64 = 1;
+const BACKGROUND: Rgb565 = Rgb565::new(4, 8, 4);
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+struct Status {
+    /// Percent, and whether it's charging
+    battery: (u8, bool),
+    /// Since midnight, `None` until the time is set
+    minutes: Option<u32>,
+    sd_card: bool,
+    usb: bool,
+    /// In KiB
+    free_memory: usize,
+}
+
+impl Status {
+    async fn read() -> Self {
+        // the battery register holds the percentage, with the top bit set
+        // while charging
+        let battery = get_battery().await;
+
+        Self {
+            battery: (battery & 0x7F, battery & 0x80 != 0),
+            minutes: seconds_since_midnight().map(|secs| secs / 60),
+            sd_card: card_present(),
+            usb: USB_ACTIVE.load(Ordering::Acquire),
+            free_memory: free_memory() / 1024,
+        }
+    }
+}
+
+// what apps can still allocate
+fn free_memory() -> usize {
+    #[cfg(feature = "psram")]
+    {
+        unsafe { crate::heap::HEAP.free() }
+    }
+
+    #[cfg(not(feature = "psram"))]
+    {
+        crate::ALLOCATOR.lock().get_counters().available_bytes
+    }
+}
+
+/// Redraws the status bar whenever what it shows changes
+#[embassy_executor::task]
+pub async fn status_handler() {
+    let mut last = None;
+    loop {
+        let status = Status::read().await;
+        if last != Some(status) {
+            unsafe { STATUS_CANVAS.draw_status(&status) };
+            last = Some(status);
+        }
+
+        // the framebuffer may have been cleared since, e.g. by a launched app
+        if ENABLE_UI.load(Ordering::Acquire) {
+            draw_status_bar();
+        } else if STATUS_OVERLAY.load(Ordering::Acquire) {
+            show_overlay();
+        }
+
+        Timer::after_secs(REFRESH_SECS).await;
+    }
+}
+
+/// Draws the status bar into the framebuffer, for the launcher
+pub fn draw_status_bar() {
+    unsafe { FRAMEBUFFER.as_mut().unwrap().draw_status_into_fb() };
+}
+
+/// Pushes the status bar over the app on the next frame
+pub fn show_overlay() {
+    unsafe {
+        FRAMEBUFFER
+            .as_mut()
+            .unwrap()
+            .mark_tiles_dirty(StatusCanvas::area())
+    };
+}
+
+pub struct StatusCanvas {
+    pub canvas: [u16; STATUS_BAR_HEIGHT * SCREEN_WIDTH],
+}
+
+impl StatusCanvas {
+    const fn new() -> Self {
+        Self {
+            canvas: [0; STATUS_BAR_HEIGHT * SCREEN_WIDTH],
+        }
+    }
+
+    pub fn area() -> Rectangle {
+        Rectangle::new(
+            Point::zero(),
+            Size::new(SCREEN_WIDTH as u32, STATUS_BAR_HEIGHT as u32),
+        )
+    }
+
+    fn draw_status(&mut self, status: &Status) {
+        let background = BACKGROUND.into_storage().swap_bytes();
+        self.canvas.fill(background);
+
+        let text = MonoTextStyle::new(&FONT_6X10, Rgb565::WHITE);
+        let dim = MonoTextStyle::new(&FONT_6X10, Rgb565::CSS_GRAY);
+        let y = 1;
+
+        let mut clock: heapless::String<8> = heapless::String::new();
+        match status.minutes {
+            Some(minutes) => {
+                let _ = write!(clock, "{:02}:{:02}", minutes / 60, minutes % 60);
+            }
+            None => {
+                let _ = clock.push_str("--:--");
+            }
+        }
+        let _ = Text::with_baseline(&clock, Point::new(4, y), text, Baseline::Top).draw(self);
+
+        // right to left: battery, memory, usb, sd card
+        let mut right = SCREEN_WIDTH as i32 - 4;
+        let mut draw_right = |canvas: &mut Self, s: &str, style: MonoTextStyle<'_, Rgb565>| {
+            right -= s.len() as i32 * 6;
+            let _ = Text::with_baseline(s, Point::new(right, y), style, Baseline::Top).draw(canvas);
+            right -= 2 * 6;
+        };
+
+        let (percent, charging) = status.battery;
+        let mut battery: heapless::String<8> = heapless::String::new();
+        let _ = write!(battery, "{}{}%", if charging { "+" } else { "" }, percent);
+        let battery_style = match percent {
+            0..=15 if !charging => MonoTextStyle::new(&FONT_6X10, Rgb565::RED),
+            _ => text,
+        };
+        draw_right(self, &battery, battery_style);
+
+        let mut memory: heapless::String<12> = heapless::String::new();
+        let _ = write!(memory, "{}K free", status.free_memory);
+        draw_right(self, &memory, dim);
+
+        if status.usb {
+            draw_right(self, "USB", text);
+        }
+        draw_right(self, "SD", if status.sd_card { text } else { dim });
+    }
+}
+
+impl DrawTarget for StatusCanvas {
+    type Error = ();
+    type Color = Rgb565;
+
+    fn draw_iter<I>(&mut self, pixels: I) -> Result<(), Self::Error>
+    where
+        I: IntoIterator<Item = Pixel<Self::Color>>,
+    {
+        for Pixel(point, color) in pixels {
+            if point.x < 0
+                || point.x >= SCREEN_WIDTH as i32
+                || point.y < 0
+                || point.y >= STATUS_BAR_HEIGHT as i32
+            {
+                continue;
+            }
+
+            let index = point.y as usize * SCREEN_WIDTH + point.x as usize;
+            // Pre-swapped to match the main framebuffer's storage, like the
+            // fps canvas
+            self.canvas[index] = color.into_storage().swap_bytes();
+        }
+        Ok(())
+    }
+}
+
+impl OriginDimensions for StatusCanvas {
+    fn size(&self) -> Size {
+        Size::new(SCREEN_WIDTH as u32, STATUS_BAR_HEIGHT as u32)
+    }
+}
@@ -1,22 +1,26 @@
-use alloc::{string::String, vec::Vec};
-use core::str::FromStr;
+use crate::{ENABLE_UI, log, service::running_service, ui::REFRESH_PROGRAMS};
+use alloc::{format, string::String, vec::Vec};
+use core::sync::atomic::{AtomicBool, AtomicU8, AtomicU32, Ordering};
 use embassy_rp::gpio::{Input, Output};
 use embassy_rp::peripherals::SPI0;
 use embassy_rp::spi::{Blocking, Spi};
 use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
 use embassy_sync::lazy_lock::LazyLock;
 use embassy_sync::mutex::Mutex;
-use embassy_time::Delay;
+use embassy_time::{Delay, Timer};
 use embedded_hal_bus::spi::ExclusiveDevice;
 use embedded_sdmmc::{
-    Block, BlockDevice, BlockIdx, Directory, SdCard as SdmmcSdCard, TimeSource, Timestamp,
-    VolumeIdx, VolumeManager, sdcard::Error,
+    Block, BlockDevice, BlockIdx, DirEntry, Directory, SdCard as SdmmcSdCard, TimeSource,
+    Timestamp, VolumeIdx, VolumeManager, sdcard::Error,
 };
 use embedded_sdmmc::{File as SdFile, LfnBuffer, Mode, ShortFileName};
+use userlib_sys::SdCardEvent;
 
 pub const MAX_DIRS: usize = 4;
 pub const MAX_FILES: usize = 5;
 pub const MAX_VOLUMES: usize = 1;
+// where the volume manager starts numbering its handles
+const HANDLE_ID_OFFSET: u32 = 5000;
 
 type Device = ExclusiveDevice<Spi<'static, SPI0, Blocking>, Output<'static>, embassy_time::Delay>;
 type SD = SdmmcSdCard<Device, Delay>;
@@ -27,6 +31,117 @@ pub type File<'a> = SdFile<'a, SD, DummyTimeSource, MAX_DIRS, MAX_FILES, MAX_VOL
 pub static SDCARD: LazyLock<Mutex<CriticalSectionRawMutex, Option<SdCard>>> =
     LazyLock::new(|| Mutex::new(None));
 
+// kept by `sd_detect_handler`, so it can be read without taking the card
+static CARD_PRESENT: AtomicBool = AtomicBool::new(false);
+// counts the times a card was put in, to tell when it needs initializing again
+static INSERTIONS: AtomicU32 = AtomicU32::new(0);
+// what the foreground app and the background service haven't been told about
+// yet, as `REMOVED` and `INSERTED` bits, see `record_event`
+static APP_EVENT: AtomicU8 = AtomicU8::new(0);
+static SERVICE_EVENT: AtomicU8 = AtomicU8::new(0);
+
+const REMOVED: u8 = 1;
+const INSERTED: u8 = 2;
+
+// the switch in the socket bounces
+const DEBOUNCE_MS: u64 = 100;
+
+/// Whether a card is in the socket
+pub fn card_present() -> bool {
+    CARD_PRESENT.load(Ordering::Acquire)
+}
+
+fn event_slot(service: bool) -> &'static AtomicU8 {
+    if service { &SERVICE_EVENT } else { &APP_EVENT }
+}
+
+// A removal sticks until it's taken, so an app whose card was pulled and put
+// back between two polls still learns its open files are gone, and hears of
+// the insertion on the next poll. An insertion followed by a removal is
+// just the removal.
+fn record_event(slot: &AtomicU8, event: SdCardEvent) {
+    match event {
+        SdCardEvent::Removed => slot.store(REMOVED, Ordering::Release),
+        SdCardEvent::Inserted => {
+            slot.fetch_or(INSERTED, Ordering::AcqRel);
+        }
+        SdCardEvent::Unchanged => (),
+    }
+}
+
+/// What happened to the card since the foreground app, or the background
+/// service, last asked, see `sd_card_changed`
+pub fn take_app_event(service: bool) -> SdCardEvent {
+    let slot = event_slot(service);
+    let taken = match slot.fetch_update(Ordering::AcqRel, Ordering::Acquire, |bits| {
+        // the removal first, an insertion after it waits for the next poll
+        Some(if bits & REMOVED != 0 {
+            bits & !REMOVED
+        } else {
+            0
+        })
+    }) {
+        Ok(bits) | Err(bits) => bits,
+    };
+
+    if taken & REMOVED != 0 {
+        SdCardEvent::Removed
+    } else if taken & INSERTED != 0 {
+        SdCardEvent::Inserted
+    } else {
+        SdCardEvent::Unchanged
+    }
+}
+
+/// Forgets the changes the foreground app, or the background service, wasn't
+/// told about, e.g. the previous app's
+pub fn clear_app_event(service: bool) {
+    event_slot(service).store(0, Ordering::Release);
+}
+
+/// Whether `take_app_event` has anything to report
+pub fn app_event_pending(service: bool) -> bool {
+    event_slot(service).load(Ordering::Acquire) != 0
+}
+
+// The DET pin is active-low via mechanical switch in the socket.
+fn card_in(det: &Input) -> bool {
+    det.is_low()
+}
+
+/// Follows the card being pulled and put back: the card is initialized again
+/// on its next use, the launcher rescans it and the running app is told
+#[embassy_executor::task]
+pub async fn sd_detect_handler(mut det: Input<'static>) {
+    loop {
+        det.wait_for_any_edge().await;
+        Timer::after_millis(DEBOUNCE_MS).await;
+
+        let present = card_in(&det);
+        if present == CARD_PRESENT.swap(present, Ordering::AcqRel) {
+            continue;
+        }
+
+        let event = if present {
+            log::info!("sd card inserted");
+            INSERTIONS.fetch_add(1, Ordering::AcqRel);
+            SdCardEvent::Inserted
+        } else {
+            log::warn!("sd card removed");
+            SdCardEvent::Removed
+        };
+
+        // for the app and background service running
+        if !ENABLE_UI.load(Ordering::Acquire) {
+            record_event(&APP_EVENT, event);
+        }
+        if running_service().is_some() {
+            record_event(&SERVICE_EVENT, event);
+        }
+        REFRESH_PROGRAMS.signal(());
+    }
+}
+
 pub struct DummyTimeSource {}
 impl TimeSource for DummyTimeSource {
     fn get_timestamp(&self) -> Timestamp {
@@ -37,7 +152,8 @@ impl TimeSource for DummyTimeSource {
 #[derive(Clone, PartialEq, Eq)]
 pub struct FileName {
     pub long_name: String,
-    pub short_name: ShortFileName,
+    /// e.g. "/apps/games/snake.bin", long names all the way
+    pub path: String,
 }
 
 impl PartialOrd for FileName {
@@ -48,44 +164,132 @@ impl PartialOrd for FileName {
 
 impl Ord for FileName {
     fn cmp(&self, other: &Self) -> core::cmp::Ordering {
-        self.long_name.cmp(&other.long_name)
+        self.long_name
+            .cmp(&other.long_name)
+            .then_with(|| self.path.cmp(&other.path))
     }
 }
 
+/// A file or directory, as listed for the usb host
+pub struct Entry {
+    pub name: String,
+    pub size: u32,
+    pub is_dir: bool,
+}
+
 #[derive(Debug)]
 pub enum SdCardError {
     Volume0Missing,
     RootDirMissing,
     FileOpenFailed,
+    FileReadFailed,
+    FileWriteFailed,
+    DirReadFailed,
+    NotFound,
+    /// New files need a short (8.3) name
+    InvalidName,
+}
+
+// Splits a path like "/apps/snake.bin" into its directories and its last component
+fn split_path(path: &str) -> Result<(Vec<&str>, &str), SdCardError> {
+    let mut components: Vec<&str> = path.split('/').filter(|c| !c.is_empty()).collect();
+    let name = components.pop().ok_or(SdCardError::NotFound)?;
+    Ok((components, name))
+}
+
+// Finds `name` in `dir`, by its long or short name
+fn find_entry(dir: &Dir, name: &str) -> Result<DirEntry, SdCardError> {
+    let mut lfn_storage = [0; 256];
+    let mut lfn_buffer = LfnBuffer::new(&mut lfn_storage);
+
+    let mut found = None;
+    dir.iterate_dir_lfn(&mut lfn_buffer, |entry, long_name| {
+        if found.is_none()
+            && (long_name == Some(name) || entry.name.to_string().eq_ignore_ascii_case(name))
+        {
+            found = Some(entry.clone());
+        }
+    })
+    .map_err(|_| SdCardError::DirReadFailed)?;
+
+    found.ok_or(SdCardError::NotFound)
 }
 
 pub struct SdCard {
-    det: Input<'static>,
-    volume_mgr: VolMgr,
+    // only `None` while it's replaced, see `reopen_volumes_if_swapped`
+    volume_mgr: Option<VolMgr>,
+    // `INSERTIONS` when the card was last initialized
+    insertion: AtomicU32,
+    // `INSERTIONS` when `volume_mgr` was made
+    volumes_insertion: u32,
 }
 
 impl SdCard {
     pub const BLOCK_SIZE: u16 = 512;
 
-    pub fn new(sdcard: SD, det: Input<'static>) -> Self {
+    /// `det` is then watched by `sd_detect_handler`
+    pub fn new(sdcard: SD, det: &Input<'static>) -> Self {
+        CARD_PRESENT.store(card_in(det), Ordering::Release);
+
         let volume_mgr = VolumeManager::<_, _, MAX_DIRS, MAX_FILES, MAX_VOLUMES>::new_with_limits(
             sdcard,
             DummyTimeSource {},
-            5000,
+            HANDLE_ID_OFFSET,
         );
-        Self { det, volume_mgr }
+        let insertions = INSERTIONS.load(Ordering::Acquire);
+        Self {
+            volume_mgr: Some(volume_mgr),
+            insertion: AtomicU32::new(insertions),
+            volumes_insertion: insertions,
+        }
+    }
+
+    fn volumes(&self) -> &VolMgr {
+        self.volume_mgr.as_ref().unwrap()
     }
 
     /// Returns true if an SD card is inserted.
-    /// The DET pin is active-low via mechanical switch in the socket.
     pub fn is_attached(&self) -> bool {
-        self.det.is_low()
+        card_present()
+    }
+
+    // A card put in since the last use may not be the same one, and isn't
+    // initialized either way
+    fn remount_if_swapped(&self) {
+        let insertions = INSERTIONS.load(Ordering::Acquire);
+        if self.insertion.swap(insertions, Ordering::AcqRel) != insertions {
+            self.volumes().device(|sd| {
+                sd.mark_card_uninit();
+                DummyTimeSource {}
+            });
+        }
+    }
+
+    // Volumes, directories and files left open on a card that was pulled in
+    // the middle of an operation can't be closed anymore. Starting over with
+    // a new volume manager forgets them, rather than running out of handles.
+    fn reopen_volumes_if_swapped(&mut self) {
+        let insertions = INSERTIONS.load(Ordering::Acquire);
+        if self.volumes_insertion == insertions {
+            return;
+        }
+        self.volumes_insertion = insertions;
+
+        if let Some(volume_mgr) = self.volume_mgr.take() {
+            let (sdcard, time_source) = volume_mgr.free();
+            self.volume_mgr = Some(VolumeManager::new_with_limits(
+                sdcard,
+                time_source,
+                HANDLE_ID_OFFSET,
+            ));
+        }
     }
 
     pub fn size(&self) -> u64 {
+        self.remount_if_swapped();
         let mut result = 0;
 
-        self.volume_mgr.device(|sd| {
+        self.volumes().device(|sd| {
             result = sd.num_bytes().unwrap_or(0);
             DummyTimeSource {}
         });
@@ -94,8 +298,9 @@ impl SdCard {
     }
 
     pub fn read_blocks(&self, blocks: &mut [Block], start_block_idx: BlockIdx) -> Result<(), ()> {
+        self.remount_if_swapped();
         let mut res: Result<(), Error> = Ok(());
-        self.volume_mgr.device(|sd| {
+        self.volumes().device(|sd| {
             res = sd.read(blocks, start_block_idx);
             DummyTimeSource {}
         });
@@ -103,20 +308,20 @@ impl SdCard {
     }
 
     pub fn write_blocks(&self, blocks: &mut [Block], start_block_idx: BlockIdx) -> Result<(), ()> {
+        self.remount_if_swapped();
         let mut res: Result<(), Error> = Ok(());
-        self.volume_mgr.device(|sd| {
+        self.volumes().device(|sd| {
             res = sd.write(blocks, start_block_idx);
             DummyTimeSource {}
         });
         res.map_err(|_| ())
     }
 
-    pub fn access_root_dir<R>(
-        &mut self,
-        mut access: impl FnMut(Dir) -> R,
-    ) -> Result<R, SdCardError> {
+    pub fn access_root_dir<R>(&mut self, access: impl FnOnce(Dir) -> R) -> Result<R, SdCardError> {
+        self.remount_if_swapped();
+        self.reopen_volumes_if_swapped();
         let volume0 = self
-            .volume_mgr
+            .volumes()
             .open_volume(VolumeIdx(0))
             .map_err(|_| SdCardError::Volume0Missing)?;
         let root_dir = volume0
@@ -126,22 +331,188 @@ impl SdCard {
         Ok(access(root_dir))
     }
 
+    /// Opens the file at `path`, e.g. "/apps/snake.bin", for `access`
     pub async fn read_file<R>(
         &mut self,
-        name: &ShortFileName,
-        mut access: impl FnMut(File) -> R,
+        path: &str,
+        access: impl FnOnce(File) -> R,
     ) -> Result<R, SdCardError> {
+        let (dirs, name) = split_path(path)?;
+
+        self.access_dir(&dirs, |dir| {
+            let entry = find_entry(&dir, name)?;
+            let file = dir
+                .open_file_in_dir(&entry.name, Mode::ReadOnly)
+                .map_err(|_| SdCardError::FileOpenFailed)?;
+
+            Ok(access(file))
+        })
+    }
+
+    /// Appends `data` to a file in the root directory, creating it if needed
+    pub fn append_file(&mut self, name: &str, data: &[u8]) -> Result<(), SdCardError> {
+        self.access_root_dir(|root_dir| {
+            let file = root_dir
+                .open_file_in_dir(name, Mode::ReadWriteCreateOrAppend)
+                .map_err(|_| SdCardError::FileOpenFailed)?;
+            file.write(data).map_err(|_| SdCardError::FileWriteFailed)?;
+            file.close().map_err(|_| SdCardError::FileWriteFailed)
+        })?
+    }
+
+    /// Returns the length of a file in the root directory
+    pub fn file_len(&mut self, name: &str) -> Result<u32, SdCardError> {
+        self.access_root_dir(|root_dir| {
+            root_dir
+                .open_file_in_dir(name, Mode::ReadOnly)
+                .map(|file| file.length())
+                .map_err(|_| SdCardError::FileOpenFailed)
+        })?
+    }
+
+    /// Moves the contents of `name` to `old_name`, replacing it, and empties `name`.
+    /// The filesystem has no rename, so the contents are copied over.
+    pub fn rotate_file(&mut self, name: &str, old_name: &str) -> Result<(), SdCardError> {
         self.access_root_dir(|root_dir| {
             let file = root_dir
                 .open_file_in_dir(name, Mode::ReadOnly)
                 .map_err(|_| SdCardError::FileOpenFailed)?;
+            let old_file = root_dir
+                .open_file_in_dir(old_name, Mode::ReadWriteCreateOrTruncate)
+                .map_err(|_| SdCardError::FileOpenFailed)?;
 
-            Ok(access(file))
+            let mut buf = [0_u8; Self::BLOCK_SIZE as usize];
+            while !file.is_eof() {
+                let read = file
+                    .read(&mut buf)
+                    .map_err(|_| SdCardError::FileOpenFailed)?;
+                old_file
+                    .write(&buf[..read])
+                    .map_err(|_| SdCardError::FileWriteFailed)?;
+            }
+            old_file.close().map_err(|_| SdCardError::FileWriteFailed)?;
+            file.close().map_err(|_| SdCardError::FileOpenFailed)?;
+
+            root_dir
+                .open_file_in_dir(name, Mode::ReadWriteCreateOrTruncate)
+                .map_err(|_| SdCardError::FileOpenFailed)?
+                .close()
+                .map_err(|_| SdCardError::FileWriteFailed)
+        })?
+    }
+
+    // Opens the directories in `dirs` one after another, from the root directory
+    fn access_dir<R>(
+        &mut self,
+        dirs: &[&str],
+        access: impl FnOnce(Dir) -> Result<R, SdCardError>,
+    ) -> Result<R, SdCardError> {
+        self.access_root_dir(|root| {
+            let mut dir = root;
+            for name in dirs {
+                let entry = find_entry(&dir, name)?;
+                if !entry.attributes.is_directory() {
+                    return Err(SdCardError::NotFound);
+                }
+                dir = dir
+                    .open_dir(&entry.name)
+                    .map_err(|_| SdCardError::DirReadFailed)?;
+            }
+            access(dir)
         })?
     }
 
-    /// Returns a Vec of file names (long format) that match the given extension (e.g., "BIN")
-    pub fn list_files_by_extension(&mut self, ext: &str) -> Result<Vec<FileName>, SdCardError> {
+    /// Lists a directory, e.g. "/" or "/apps". Names in the path may be long or short.
+    pub fn list_dir(&mut self, path: &str) -> Result<Vec<Entry>, SdCardError> {
+        let dirs: Vec<&str> = path.split('/').filter(|c| !c.is_empty()).collect();
+        let mut entries = Vec::new();
+
+        self.access_dir(&dirs, |dir| {
+            let mut lfn_storage = [0; 256];
+            let mut lfn_buffer = LfnBuffer::new(&mut lfn_storage);
+
+            dir.iterate_dir_lfn(&mut lfn_buffer, |entry, long_name| {
+                if entry.attributes.is_volume()
+                    || entry.name == ShortFileName::this_dir()
+                    || entry.name == ShortFileName::parent_dir()
+                {
+                    return;
+                }
+                entries.push(Entry {
+                    name: long_name.map_or_else(|| entry.name.to_string(), String::from),
+                    size: entry.size,
+                    is_dir: entry.attributes.is_directory(),
+                });
+            })
+            .map_err(|_| SdCardError::DirReadFailed)
+        })?;
+
+        Ok(entries)
+    }
+
+    /// Reads a file from `offset` into `buf`, returns how much was read
+    pub fn read_at(
+        &mut self,
+        path: &str,
+        offset: u32,
+        buf: &mut [u8],
+    ) -> Result<usize, SdCardError> {
+        let (dirs, name) = split_path(path)?;
+
+        self.access_dir(&dirs, |dir| {
+            let entry = find_entry(&dir, name)?;
+            let file = dir
+                .open_file_in_dir(&entry.name, Mode::ReadOnly)
+                .map_err(|_| SdCardError::FileOpenFailed)?;
+            file.seek_from_start(offset)
+                .map_err(|_| SdCardError::FileReadFailed)?;
+
+            let mut read = 0;
+            while read < buf.len() && !file.is_eof() {
+                read += file
+                    .read(&mut buf[read..])
+                    .map_err(|_| SdCardError::FileReadFailed)?;
+            }
+            Ok(read)
+        })
+    }
+
+    /// Writes `data` into a file at `offset`. Writing at 0 creates the file,
+    /// or empties it if it exists.
+    pub fn write_at(&mut self, path: &str, offset: u32, data: &[u8]) -> Result<(), SdCardError> {
+        let (dirs, name) = split_path(path)?;
+
+        self.access_dir(&dirs, |dir| {
+            let file = match find_entry(&dir, name) {
+                Ok(entry) if offset == 0 => {
+                    dir.open_file_in_dir(&entry.name, Mode::ReadWriteCreateOrTruncate)
+                }
+                Ok(entry) => dir.open_file_in_dir(&entry.name, Mode::ReadWriteAppend),
+                Err(SdCardError::NotFound) if offset == 0 => {
+                    // the filesystem can't create long names
+                    let name = ShortFileName::create_from_str(name)
+                        .map_err(|_| SdCardError::InvalidName)?;
+                    dir.open_file_in_dir(name, Mode::ReadWriteCreate)
+                }
+                Err(e) => return Err(e),
+            }
+            .map_err(|_| SdCardError::FileOpenFailed)?;
+
+            file.seek_from_start(offset)
+                .map_err(|_| SdCardError::FileWriteFailed)?;
+            file.write(data).map_err(|_| SdCardError::FileWriteFailed)?;
+            file.close().map_err(|_| SdCardError::FileWriteFailed)
+        })
+    }
+
+    /// Finds the files named `*.{ext}` in `dir`, and in its subdirectories
+    /// down to `depth` levels
+    pub fn find_files(
+        &mut self,
+        dir: &str,
+        ext: &str,
+        depth: usize,
+    ) -> Result<Vec<FileName>, SdCardError> {
         let mut result = Vec::new();
 
         // Only proceed if card is inserted
@@ -149,23 +520,28 @@ impl SdCard {
             return Ok(result);
         }
 
-        let mut lfn_storage = [0; 50];
-        let mut lfn_buffer = LfnBuffer::new(&mut lfn_storage);
-
-        self.access_root_dir(|dir| {
-            dir.iterate_dir_lfn(&mut lfn_buffer, |entry, name| {
-                if let Some(name) = name {
-                    let name = String::from_str(name).unwrap();
-                    if name.contains(ext) {
-                        result.push(FileName {
-                            long_name: name,
-                            short_name: entry.name.clone(),
-                        });
-                    }
+        for entry in self.list_dir(dir)? {
+            // e.g. the "._snake.bin" files macOS leaves behind
+            if entry.name.starts_with('.') {
+                continue;
+            }
+            let path = format!("{}/{}", dir.trim_end_matches('/'), entry.name);
+
+            if entry.is_dir {
+                if depth > 0 {
+                    result.extend(self.find_files(&path, ext, depth - 1)?);
                 }
-            })
-            .unwrap()
-        })?;
+            } else if entry
+                .name
+                .rsplit_once('.')
+                .is_some_and(|(_, e)| e.eq_ignore_ascii_case(ext))
+            {
+                result.push(FileName {
+                    long_name: entry.name,
+                    path,
+                });
+            }
+        }
 
         Ok(result)
     }
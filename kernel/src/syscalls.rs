@@ -1,7 +1,7 @@
 use alloc::{string::ToString, vec::Vec};
-use core::{ffi::c_char, ptr, slice, sync::atomic::Ordering};
-use embassy_rp::clocks::{RoscRng, clk_sys_freq};
-use embassy_time::Instant;
+use core::{alloc::Layout, ffi::c_char, ptr, sync::atomic::Ordering};
+use embassy_rp::clocks::RoscRng;
+use embassy_time::{Duration, Instant};
 use embedded_graphics::{
     Pixel,
     draw_target::DrawTarget,
@@ -13,8 +13,11 @@ use embedded_sdmmc::LfnBuffer;
 use heapless::spsc::Queue;
 use userlib_sys::{
     AUDIO_BUFFER_SAMPLES, Alloc, AudioBufferReady, Blit, CLayout, CPixel, Dealloc, DrawIter,
-    FileLen, FillRect, GenRand, GetMs, ListDir, Print, ReadFile, ReconfigureAudioSampleRate,
-    RngRequest, SendAudioBuffer, SleepMs, WriteFile, keyboard::*,
+    Events, FileLen, FillRect, GenRand, GetMs, ListDir, Print, ReadFile, ReadLog,
+    ReconfigureAudioSampleRate, RngRequest, SdCardChanged, SdCardEvent, SendAudioBuffer,
+    SetStatusBar, SleepMs, SyscallError, WAIT_FOREVER, WaitEvent, WriteFile, YieldNow,
+    keyboard::*,
+    midi::{MidiPacket, ReceiveMidi, SendMidi},
 };
 
 #[cfg(feature = "psram")]
@@ -26,90 +29,176 @@ use core::alloc::GlobalAlloc;
 use crate::{
     audio::{AUDIO_BUFFER, AUDIO_BUFFER_READY, AUDIO_BUFFER_SAMPLE_RATE, AUDIO_BUFFER_WRITTEN},
     display::FRAMEBUFFER,
+    fault,
     framebuffer::FB_PAUSED,
-    storage::{Dir, File, SDCARD},
+    log, service,
+    status::{self, STATUS_OVERLAY},
+    storage::{self, Dir, File, SDCARD, SdCard},
+    usb::{MIDI_IN, MIDI_OUT},
+    user_memory::{USER_MEMORY, UserMemory, user_slice, user_slice_mut, user_str},
 };
 
+/// Unwraps a checked user argument, or returns its error code from the syscall
+macro_rules! user_arg {
+    ($arg:expr) => {
+        match $arg {
+            Ok(arg) => arg,
+            Err(err) => return err.into(),
+        }
+    };
+}
+
 const _: Alloc = alloc;
 pub extern "C" fn alloc(layout: CLayout) -> *mut u8 {
+    let _syscall = fault::Syscall::enter();
+    let layout: Layout = layout.into();
+
     // SAFETY: caller guarantees layout is valid
-    unsafe {
+    let ptr = unsafe {
         #[cfg(feature = "psram")]
         {
-            return HEAP.alloc(layout.into());
+            HEAP.alloc(layout)
         }
 
         #[cfg(not(feature = "psram"))]
         {
-            alloc::alloc::alloc(layout.into())
+            alloc::alloc::alloc(layout)
         }
-    }
+    };
+
+    unsafe { USER_MEMORY.track_alloc(ptr, layout) };
+    ptr
 }
 
 const _: Dealloc = dealloc;
-pub extern "C" fn dealloc(ptr: *mut u8, layout: CLayout) {
-    // SAFETY: caller guarantees ptr and layout are valid
+pub extern "C" fn dealloc(ptr: *mut u8, _layout: CLayout) {
+    let _syscall = fault::Syscall::enter();
+    // only free what was handed out by `alloc`, with the layout it was
+    // allocated with, rather than trusting the app's pointer and layout
+    let Some(layout) = (unsafe { USER_MEMORY.untrack_alloc(ptr) }) else {
+        #[cfg(feature = "defmt")]
+        defmt::warn!("dealloc: {:#x} is not a user allocation", ptr as usize);
+        return;
+    };
+
+    unsafe { free(ptr, layout) };
+}
+
+unsafe fn free(ptr: *mut u8, layout: Layout) {
     #[cfg(feature = "psram")]
     {
-        unsafe { HEAP.dealloc(ptr, layout.into()) }
+        unsafe { HEAP.dealloc(ptr, layout) }
     }
 
     #[cfg(not(feature = "psram"))]
     {
-        unsafe { alloc::alloc::dealloc(ptr, layout.into()) }
+        unsafe { alloc::alloc::dealloc(ptr, layout) }
+    }
+}
+
+/// Frees whatever the last app left allocated, e.g. after it was force quit
+pub fn free_user_allocations() {
+    free_allocations(unsafe { &mut USER_MEMORY });
+}
+
+/// Frees what the app `memory` describes left allocated
+pub fn free_allocations(memory: &mut UserMemory) {
+    for (ptr, layout) in memory.take_allocations() {
+        unsafe { free(ptr as *mut u8, layout) };
     }
 }
 
 const _: Print = print;
-pub extern "C" fn print(ptr: *const u8, len: usize) {
-    // SAFETY: caller guarantees `ptr` is valid for `len` bytes
-    let slice = unsafe { core::slice::from_raw_parts(ptr, len) };
+pub extern "C" fn print(ptr: *const u8, len: usize) -> isize {
+    let _syscall = fault::Syscall::enter();
+    let slice = user_arg!(unsafe { user_slice(ptr, len) });
 
-    if let Ok(_msg) = core::str::from_utf8(slice) {
-        #[cfg(feature = "defmt")]
-        defmt::info!("print: {}", _msg);
+    if let Ok(msg) = core::str::from_utf8(slice) {
+        log::info!("print: {}", msg);
     } else {
-        #[cfg(feature = "defmt")]
-        defmt::warn!("print: <invalid utf8>");
+        log::warn!("print: <invalid utf8>");
     }
+    0
+}
+
+const _: ReadLog = read_log;
+pub extern "C" fn read_log(buf: *mut u8, len: usize) -> isize {
+    let _syscall = fault::Syscall::enter();
+    let buf = user_arg!(unsafe { user_slice_mut(buf, len) });
+    log::read_latest(buf) as isize
 }
 
 const _: SleepMs = sleep;
 pub extern "C" fn sleep(ms: u64) {
-    let cycles_per_ms = clk_sys_freq() / 1000;
-    let total_cycles = ms * cycles_per_ms as u64;
+    let _syscall = fault::Syscall::enter();
+    if fault::in_service() {
+        unsafe { service::wait(Events::empty(), ms.min(WAIT_FOREVER as u64 - 1) as u32) };
+        return;
+    }
 
-    for _ in 0..total_cycles {
-        cortex_m::asm::nop();
+    // timed rather than counted in cycles, the service may run in between
+    let until = Instant::now()
+        .checked_add(Duration::from_millis(ms))
+        .unwrap_or(Instant::MAX);
+    while Instant::now() < until {
+        if fault::kill_requested() {
+            return;
+        }
+        unsafe { service::run_due() };
+        core::hint::spin_loop();
     }
 }
 
+const _: YieldNow = yield_now;
+pub extern "C" fn yield_now() {
+    let _syscall = fault::Syscall::enter();
+    if fault::in_service() {
+        // due again right away, once the foreground app had its turn
+        unsafe { service::wait(Events::empty(), 0) };
+        return;
+    }
+    unsafe { service::run_due() };
+}
+
 pub static mut MS_SINCE_LAUNCH: Option<Instant> = None;
 
 const _: GetMs = get_ms;
 pub extern "C" fn get_ms() -> u64 {
+    let _syscall = fault::Syscall::enter();
+    // apps poll it in their main loop
+    unsafe { service::run_due() };
+
     Instant::now()
         .duration_since(unsafe { MS_SINCE_LAUNCH.unwrap() })
         .as_millis()
 }
 
 const _: DrawIter = draw_iter;
-pub extern "C" fn draw_iter(cpixels: *const CPixel, len: usize) {
-    let pixels: &[Pixel<Rgb565>] = unsafe { slice::from_raw_parts(cpixels.cast(), len) };
+pub extern "C" fn draw_iter(cpixels: *const CPixel, len: usize) -> isize {
+    let _syscall = fault::Syscall::enter();
+    if fault::in_service() {
+        return SyscallError::NotAllowed.into();
+    }
+    let cpixels = user_arg!(unsafe { user_slice(cpixels, len) });
 
     FB_PAUSED.store(true, Ordering::Release);
     unsafe {
         FRAMEBUFFER
             .as_mut()
             .unwrap()
-            .draw_iter(pixels.iter().copied())
+            .draw_iter(cpixels.iter().map(|&p| Pixel::<Rgb565>::from(p)))
             .unwrap()
     }
     FB_PAUSED.store(false, Ordering::Release);
+    0
 }
 
 const _: FillRect = fill_rect;
 pub extern "C" fn fill_rect(x: u16, y: u16, w: u16, h: u16, color: u16) {
+    let _syscall = fault::Syscall::enter();
+    if fault::in_service() {
+        return;
+    }
     let area = Rectangle::new(
         Point::new(x as i32, y as i32),
         Size::new(w as u32, h as u32),
@@ -128,12 +217,16 @@ pub extern "C" fn fill_rect(x: u16, y: u16, w: u16, h: u16, color: u16) {
 }
 
 const _: Blit = blit;
-pub extern "C" fn blit(x: u16, y: u16, w: u16, h: u16, colors: *const u16, len: usize) {
+pub extern "C" fn blit(x: u16, y: u16, w: u16, h: u16, colors: *const u16, len: usize) -> isize {
+    let _syscall = fault::Syscall::enter();
+    if fault::in_service() {
+        return SyscallError::NotAllowed.into();
+    }
     let area = Rectangle::new(
         Point::new(x as i32, y as i32),
         Size::new(w as u32, h as u32),
     );
-    let raw: &[u16] = unsafe { slice::from_raw_parts(colors, len) };
+    let raw = user_arg!(unsafe { user_slice(colors, len) });
 
     FB_PAUSED.store(true, Ordering::Release);
     unsafe {
@@ -144,13 +237,25 @@ pub extern "C" fn blit(x: u16, y: u16, w: u16, h: u16, colors: *const u16, len:
             .unwrap()
     }
     FB_PAUSED.store(false, Ordering::Release);
+    0
 }
 
 pub static mut KEY_CACHE: Queue<KeyEvent, 32> = Queue::new();
 
 const _: GetKey = get_key;
 pub extern "C" fn get_key() -> KeyEventC {
-    if let Some(event) = unsafe { KEY_CACHE.dequeue() } {
+    let _syscall = fault::Syscall::enter();
+    // keys are the foreground app's
+    let event = if fault::in_service() {
+        None
+    } else {
+        unsafe {
+            service::run_due();
+            KEY_CACHE.dequeue()
+        }
+    };
+
+    if let Some(event) = event {
         event.into()
     } else {
         KeyEvent {
@@ -163,22 +268,25 @@ pub extern "C" fn get_key() -> KeyEventC {
 }
 
 const _: GenRand = gen_rand;
-pub extern "C" fn gen_rand(req: &mut RngRequest) {
+pub extern "C" fn gen_rand(req: *mut RngRequest) -> isize {
+    let _syscall = fault::Syscall::enter();
+    let req = &mut user_arg!(unsafe { user_slice_mut(req, 1) })[0];
     let mut rng = RoscRng;
 
     match req {
         RngRequest::U32(i) => *i = rng.next_u32(),
         RngRequest::U64(i) => *i = rng.next_u64(),
         RngRequest::Bytes { ptr, len } => {
-            // SAFETY: caller guarantees `ptr` is valid for `len` bytes
-            let slice: &mut [u8] = unsafe { core::slice::from_raw_parts_mut(*ptr, *len) };
+            let slice = user_arg!(unsafe { user_slice_mut(*ptr, *len) });
             rng.fill_bytes(slice);
         }
     }
+    0
 }
 
+// `dest` must have been validated for `max_str_len` bytes
 unsafe fn copy_entry_to_user_buf(name: &[u8], dest: *mut c_char, max_str_len: usize) {
-    if !dest.is_null() {
+    if !dest.is_null() && max_str_len > 0 {
         let len = name.len().min(max_str_len - 1);
         unsafe {
             ptr::copy_nonoverlapping(name.as_ptr(), dest, len);
@@ -187,7 +295,30 @@ unsafe fn copy_entry_to_user_buf(name: &[u8], dest: *mut c_char, max_str_len: us
     }
 }
 
-unsafe fn get_dir_entries(dir: &Dir, entries: &mut [*mut c_char], max_str_len: usize) -> usize {
+// Waits out core0's brief uses of the sd card, e.g. flushing the log
+fn with_sd<R>(
+    access: impl FnOnce(&mut SdCard) -> Result<R, SyscallError>,
+) -> Result<R, SyscallError> {
+    loop {
+        if let Ok(mut guard) = SDCARD.get().try_lock() {
+            // pulled, or taken by the usb host
+            return match guard.as_mut() {
+                Some(sd) if sd.is_attached() => access(sd),
+                _ => Err(SyscallError::NoSdCard),
+            };
+        }
+        if fault::kill_requested() {
+            return Err(SyscallError::NoSdCard);
+        }
+        core::hint::spin_loop();
+    }
+}
+
+unsafe fn get_dir_entries(
+    dir: &Dir,
+    entries: &mut [*mut c_char],
+    max_str_len: usize,
+) -> Result<usize, SyscallError> {
     let mut b = [0; 25];
     let mut buf = LfnBuffer::new(&mut b);
     let mut i = 0;
@@ -201,22 +332,26 @@ unsafe fn get_dir_entries(dir: &Dir, entries: &mut [*mut c_char], max_str_len: u
             i += 1;
         }
     })
-    .unwrap();
-    i
+    .map_err(|_| SyscallError::IoFailed)?;
+    Ok(i)
 }
 
+// a missing directory lists as empty
 unsafe fn recurse_dir(
     dir: &Dir,
     dirs: &[&str],
     entries: &mut [*mut c_char],
     max_str_len: usize,
-) -> usize {
+) -> Result<usize, SyscallError> {
     if dirs.is_empty() {
         return unsafe { get_dir_entries(dir, entries, max_str_len) };
     }
 
-    let dir = dir.open_dir(dirs[0]).unwrap();
-    unsafe { recurse_dir(&dir, &dirs[1..], entries, max_str_len) }
+    match dir.open_dir(dirs[0]) {
+        Ok(dir) => unsafe { recurse_dir(&dir, &dirs[1..], entries, max_str_len) },
+        Err(embedded_sdmmc::Error::NotFound) => Ok(0),
+        Err(_) => Err(SyscallError::IoFailed),
+    }
 }
 
 const _: ListDir = list_dir;
@@ -226,101 +361,113 @@ pub extern "C" fn list_dir(
     entries: *mut *mut c_char,
     files_len: usize,
     max_entry_str_len: usize,
-) -> usize {
-    // SAFETY: caller guarantees `ptr` is valid for `len` bytes
-    let files = unsafe { core::slice::from_raw_parts_mut(entries, files_len) };
-    // SAFETY: caller guarantees `ptr` is valid for `len` bytes
-    let dir = unsafe { core::str::from_raw_parts(dir, len) };
+) -> isize {
+    let _syscall = fault::Syscall::enter();
+    let files = user_arg!(unsafe { user_slice_mut(entries, files_len) });
+    for &entry in files.iter() {
+        user_arg!(unsafe { USER_MEMORY.validate(entry, max_entry_str_len) });
+    }
+    let dir = user_arg!(unsafe { user_str(dir, len) });
     let dirs: Vec<&str> = dir.split('/').collect();
 
-    let mut guard = SDCARD.get().try_lock().expect("Failed to get sdcard");
-    let sd = guard.as_mut().unwrap();
-
-    let mut wrote = 0;
-    let _ = sd.access_root_dir(|root| {
-        if dirs[0].is_empty() && dirs.len() >= 2 {
+    let wrote = user_arg!(with_sd(|sd| {
+        sd.access_root_dir(|root| {
+            if !(dirs[0].is_empty() && dirs.len() >= 2) {
+                return Ok(0);
+            }
             unsafe {
                 if dir == "/" {
-                    wrote = get_dir_entries(&root, files, max_entry_str_len);
+                    get_dir_entries(&root, files, max_entry_str_len)
                 } else {
-                    wrote = recurse_dir(&root, &dirs[1..], files, max_entry_str_len);
+                    recurse_dir(&root, &dirs[1..], files, max_entry_str_len)
                 }
             }
-        }
-    });
-    wrote
+        })
+        .map_err(|_| SyscallError::IoFailed)?
+    }));
+    wrote as isize
 }
 
+// Runs `access` on the file at the end of `dirs`, `None` if there is no such file
 fn recurse_file<T>(
     dir: &Dir,
     dirs: &[&str],
-    mut access: impl FnMut(&mut File) -> T,
-) -> Result<T, ()> {
-    if dirs.len() == 1 {
+    access: impl FnOnce(&mut File) -> Result<T, SyscallError>,
+) -> Result<Option<T>, SyscallError> {
+    let Some((&name, rest)) = dirs.split_first() else {
+        return Ok(None);
+    };
+
+    if rest.is_empty() {
         let mut b = [0_u8; 50];
         let mut buf = LfnBuffer::new(&mut b);
         let mut short_name = None;
-        dir.iterate_dir_lfn(&mut buf, |entry, name| {
-            if let Some(name) = name
-                && (name == dirs[0] || entry.name.to_string().as_str() == dirs[0])
-            {
+        dir.iterate_dir_lfn(&mut buf, |entry, long_name| {
+            if long_name == Some(name) || entry.name.to_string().as_str() == name {
                 short_name = Some(entry.name.clone());
             }
         })
-        .expect("Failed to iterate dir");
-
-        if let Some(name) = short_name {
-            let mut file = dir
-                .open_file_in_dir(name, embedded_sdmmc::Mode::ReadWriteAppend)
-                .map_err(|_| ())?;
-            return Ok(access(&mut file));
-        }
-        return Err(());
+        .map_err(|_| SyscallError::IoFailed)?;
+
+        let Some(short_name) = short_name else {
+            return Ok(None);
+        };
+        let mut file = dir
+            .open_file_in_dir(short_name, embedded_sdmmc::Mode::ReadWriteAppend)
+            .map_err(|_| SyscallError::IoFailed)?;
+        return access(&mut file).map(Some);
     }
 
-    let dir = dir.open_dir(dirs[0]).unwrap();
-    recurse_file(&dir, &dirs[1..], access)
+    match dir.open_dir(name) {
+        Ok(dir) => recurse_file(&dir, rest, access),
+        Err(embedded_sdmmc::Error::NotFound) => Ok(None),
+        Err(_) => Err(SyscallError::IoFailed),
+    }
 }
 
-const _: ReadFile = read_file;
-pub extern "C" fn read_file(
-    str: *const u8,
-    len: usize,
-    start_from: usize,
-    buf: *mut u8,
-    buf_len: usize,
-) -> usize {
-    // SAFETY: caller guarantees `ptr` is valid for `len` bytes
-    let file = unsafe { core::str::from_raw_parts(str, len) };
-
+// Runs `access` on the file at `path`, e.g. "/music/song.wav". A missing file
+// reads as empty, as it always has.
+fn with_file<T: Default>(
+    path: &str,
+    access: impl FnOnce(&mut File) -> Result<T, SyscallError>,
+) -> Result<T, SyscallError> {
     let mut components: [&str; 8] = [""; 8];
     let mut count = 0;
-    for part in file.split('/') {
+    for part in path.split('/') {
         if count >= components.len() {
             break;
         }
         components[count] = part;
         count += 1;
     }
+    if path.is_empty() {
+        return Ok(T::default());
+    }
 
-    // SAFETY: caller guarantees `ptr` is valid for `len` bytes
-    let buf = unsafe { core::slice::from_raw_parts_mut(buf, buf_len) };
-
-    let mut read = 0;
+    with_sd(|sd| {
+        sd.access_root_dir(|root| recurse_file(&root, &components[1..count], access))
+            .map_err(|_| SyscallError::IoFailed)?
+            .map(Option::unwrap_or_default)
+    })
+}
 
-    let mut guard = SDCARD.get().try_lock().expect("Failed to get sdcard");
-    let sd = guard.as_mut().unwrap();
-    if !file.is_empty() {
-        let _ = sd.access_root_dir(|root| {
-            if let Ok(result) = recurse_file(&root, &components[1..count], |file| {
-                file.seek_from_start(start_from as u32).unwrap_or(());
-                file.read(buf).unwrap()
-            }) {
-                read = result
-            };
-        });
-    }
-    read
+const _: ReadFile = read_file;
+pub extern "C" fn read_file(
+    str: *const u8,
+    len: usize,
+    start_from: usize,
+    buf: *mut u8,
+    buf_len: usize,
+) -> isize {
+    let _syscall = fault::Syscall::enter();
+    let file = user_arg!(unsafe { user_str(str, len) });
+    let buf = user_arg!(unsafe { user_slice_mut(buf, buf_len) });
+
+    let read = user_arg!(with_file(file, |file| {
+        file.seek_from_start(start_from as u32).unwrap_or(());
+        file.read(buf).map_err(|_| SyscallError::IoFailed)
+    }));
+    read as isize
 }
 
 const _: WriteFile = write_file;
@@ -330,73 +477,109 @@ pub extern "C" fn write_file(
     start_from: usize,
     buf: *const u8,
     buf_len: usize,
-) {
-    // SAFETY: caller guarantees str ptr is valid for `len` bytes
-    let file = unsafe { core::str::from_raw_parts(str, len) };
+) -> isize {
+    let _syscall = fault::Syscall::enter();
+    let file = user_arg!(unsafe { user_str(str, len) });
+    let buf = user_arg!(unsafe { user_slice(buf, buf_len) });
+
+    let wrote = user_arg!(with_file(file, |file| {
+        file.seek_from_start(start_from as u32)
+            .map_err(|_| SyscallError::IoFailed)?;
+        file.write(buf).map_err(|_| SyscallError::IoFailed)?;
+        Ok(buf.len())
+    }));
+    wrote as isize
+}
 
-    let mut components: [&str; 8] = [""; 8];
-    let mut count = 0;
-    for part in file.split('/') {
-        if count >= components.len() {
-            break;
-        }
-        components[count] = part;
-        count += 1;
-    }
+const _: FileLen = file_len;
+pub extern "C" fn file_len(str: *const u8, len: usize) -> isize {
+    let _syscall = fault::Syscall::enter();
+    let file = user_arg!(unsafe { user_str(str, len) });
+
+    let len = user_arg!(with_file(file, |file| Ok(file.length())));
+    len as isize
+}
 
-    // SAFETY: caller guarantees buf ptr is valid for `buf_len` bytes
-    let buf = unsafe { core::slice::from_raw_parts(buf, buf_len) };
+const _: SdCardChanged = sd_card_changed;
+pub extern "C" fn sd_card_changed() -> SdCardEvent {
+    let _syscall = fault::Syscall::enter();
+    storage::take_app_event(fault::in_service())
+}
 
-    let mut guard = SDCARD.get().try_lock().expect("Failed to get sdcard");
-    let sd = guard.as_mut().unwrap();
-    if !file.is_empty() {
-        let _ = sd.access_root_dir(|root| {
-            recurse_file(&root, &components[1..count], |file| {
-                file.seek_from_start(start_from as u32).unwrap();
-                file.write(buf).unwrap()
-            })
-            .unwrap_or(())
-        });
-    };
+/// Which of `events` happened, for the foreground app or the background
+/// `service`
+pub fn pending_events(events: Events, service: bool) -> Events {
+    let mut pending = Events::empty();
+    if !service && unsafe { !KEY_CACHE.is_empty() } {
+        pending |= Events::KEY;
+    }
+    let audio_ours = service || !service::audio_taken();
+    if audio_ours && AUDIO_BUFFER_READY.load(Ordering::Acquire) {
+        pending |= Events::AUDIO_BUFFER_READY;
+    }
+    if storage::app_event_pending(service) {
+        pending |= Events::SD_CARD;
+    }
+    pending & events
 }
 
-const _: FileLen = file_len;
-pub extern "C" fn file_len(str: *const u8, len: usize) -> usize {
-    // SAFETY: caller guarantees str ptr is valid for `len` bytes
-    let file = unsafe { core::str::from_raw_parts(str, len) };
-    let file: Vec<&str> = file.split('/').collect();
-
-    let mut len = 0;
-
-    let mut guard = SDCARD.get().try_lock().expect("Failed to get sdcard");
-    let sd = guard.as_mut().unwrap();
-    if !file.is_empty() {
-        let _ = sd.access_root_dir(|root| {
-            if let Ok(result) = recurse_file(&root, &file[1..], |file| file.length()) {
-                len = result
-            }
-        });
+const _: WaitEvent = wait_event;
+pub extern "C" fn wait_event(events: Events, timeout_ms: u32) -> Events {
+    let _syscall = fault::Syscall::enter();
+    if fault::in_service() {
+        return unsafe { service::wait(events, timeout_ms) };
+    }
+
+    let started = Instant::now();
+    loop {
+        let pending = pending_events(events, false);
+        if !pending.is_empty() || fault::kill_requested() {
+            return pending;
+        }
+        if timeout_ms != WAIT_FOREVER && started.elapsed().as_millis() >= timeout_ms as u64 {
+            return Events::empty();
+        }
+        unsafe { service::run_due() };
+        core::hint::spin_loop();
     }
-    len as usize
 }
 
 const _: ReconfigureAudioSampleRate = reconfigure_audio_sample_rate;
 pub extern "C" fn reconfigure_audio_sample_rate(sample_rate: u32) {
+    let _syscall = fault::Syscall::enter();
     AUDIO_BUFFER_SAMPLE_RATE.store(sample_rate, Ordering::Release);
 }
 
 const _: AudioBufferReady = audio_buffer_ready;
 pub extern "C" fn audio_buffer_ready() -> bool {
+    let _syscall = fault::Syscall::enter();
+    unsafe { service::run_due() };
+
+    // a background service has the audio output
+    if service::audio_taken() {
+        return false;
+    }
     AUDIO_BUFFER_READY.load(Ordering::Acquire)
 }
 
 const _: SendAudioBuffer = send_audio_buffer;
-pub extern "C" fn send_audio_buffer(ptr: *const u8, len: usize) {
-    // SAFETY: caller guarantees `ptr` is valid for `len` bytes
-    let buf = unsafe { core::slice::from_raw_parts(ptr, len) };
+pub extern "C" fn send_audio_buffer(ptr: *const u8, len: usize) -> isize {
+    let _syscall = fault::Syscall::enter();
+    let buf = user_arg!(unsafe { user_slice(ptr, len) });
+    if service::audio_taken() {
+        return SyscallError::NotAllowed.into();
+    }
 
     while !AUDIO_BUFFER_READY.load(Ordering::Acquire) {
-        core::hint::spin_loop();
+        if fault::kill_requested() {
+            return 0;
+        }
+        if fault::in_service() {
+            unsafe { service::wait(Events::AUDIO_BUFFER_READY, WAIT_FOREVER) };
+        } else {
+            unsafe { service::run_due() };
+            core::hint::spin_loop();
+        }
     }
 
     if buf.len() == AUDIO_BUFFER_SAMPLES * 2 {
@@ -409,6 +592,47 @@ pub extern "C" fn send_audio_buffer(ptr: *const u8, len: usize) {
             "user audio stream was wrong size: {} should be {}",
             buf.len(),
             AUDIO_BUFFER_SAMPLES * 2
-        )
+        );
+        return SyscallError::InvalidArgument.into();
+    }
+    0
+}
+
+const _: SendMidi = send_midi;
+pub extern "C" fn send_midi(packets: *const MidiPacket, len: usize) -> isize {
+    let _syscall = fault::Syscall::enter();
+    let packets = user_arg!(unsafe { user_slice(packets, len) });
+
+    // never waits for the host, packets past a full queue are refused
+    packets
+        .iter()
+        .take_while(|&&packet| MIDI_OUT.try_send(packet).is_ok())
+        .count() as isize
+}
+
+const _: ReceiveMidi = receive_midi;
+pub extern "C" fn receive_midi(packets: *mut MidiPacket, len: usize) -> isize {
+    let _syscall = fault::Syscall::enter();
+    let packets = user_arg!(unsafe { user_slice_mut(packets, len) });
+
+    let mut received = 0;
+    for packet in packets {
+        match MIDI_IN.try_receive() {
+            Ok(next) => *packet = next,
+            Err(_) => break,
+        }
+        received += 1;
+    }
+    received
+}
+
+const _: SetStatusBar = set_status_bar;
+pub extern "C" fn set_status_bar(shown: bool) {
+    let _syscall = fault::Syscall::enter();
+    if fault::in_service() {
+        return;
     }
+    STATUS_OVERLAY.store(shown, Ordering::Release);
+    // pushes the bar, or what the app drew under it
+    status::show_overlay();
 }
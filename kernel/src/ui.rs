@@ -1,94 +1,461 @@
 use crate::{
-    BINARY_CH, display::FRAMEBUFFER, elf::load_binary, framebuffer::FB_PAUSED,
-    peripherals::keyboard, storage::FileName,
+    BINARY_CH, BOARD, KERNEL_VERSION,
+    display::FRAMEBUFFER,
+    elf::{LoadError, load_binary},
+    fault::FaultInfo,
+    framebuffer::FB_PAUSED,
+    launcher::{App, COLUMNS, Folder, LAUNCHER_FILE, SelectionList, Tile},
+    log,
+    peripherals::keyboard,
+    service,
+    settings::{SETTINGS, Setting, Settings, apply, restart, restart_needed, save_settings},
+    status::{STATUS_BAR_HEIGHT, draw_status_bar},
+    storage::{SDCARD, SdCardError},
 };
-use alloc::{str::FromStr, string::String, vec::Vec};
+use alloc::{format, string::String, vec, vec::Vec};
 use core::sync::atomic::Ordering;
-use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, mutex::Mutex};
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, mutex::Mutex, signal::Signal};
+use embassy_time::{Instant, Timer};
 use embedded_graphics::{
     Drawable,
-    mono_font::{MonoTextStyle, ascii::FONT_10X20},
-    pixelcolor::Rgb565,
-    prelude::{Dimensions, Point, Primitive, RgbColor, Size},
-    primitives::{PrimitiveStyle, Rectangle},
-    text::Text,
-};
-use embedded_layout::{
-    align::{horizontal, vertical},
-    layout::linear::{FixedMargin, LinearLayout},
-    prelude::*,
+    mono_font::{
+        MonoTextStyle,
+        ascii::{FONT_6X10, FONT_10X20},
+    },
+    pixelcolor::{Rgb565, raw::RawU16},
+    prelude::{Dimensions, DrawTarget, Point, Primitive, RgbColor, Size, WebColors},
+    primitives::{PrimitiveStyle, Rectangle, RoundedRectangle},
+    text::{Alignment, Baseline, Text},
 };
 use embedded_text::TextBox;
-use userlib_sys::keyboard::{KeyCode, KeyState};
+use userlib_sys::{
+    keyboard::{KeyCode, KeyState},
+    meta::APP_ICON_SIZE,
+};
 
 pub static SELECTIONS: Mutex<CriticalSectionRawMutex, SelectionList> =
     Mutex::new(SelectionList::new());
 
+/// Rescans the sd card for programs right away, e.g. after the usb host changed it
+pub static REFRESH_PROGRAMS: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// How long the selection has to stay put before it's saved, so moving
+/// through the grid doesn't write the sd card on every key
+const SAVE_SELECTION_DELAY_MS: u64 = 2000;
+
 pub async fn ui_handler() {
+    // when the selection last moved, while that isn't saved yet
+    let mut moved_at: Option<Instant> = None;
+
     loop {
         if let Some(event) = keyboard::read_keyboard_fifo().await
             && let KeyState::Pressed = event.state
         {
+            if matches!(
+                event.key,
+                KeyCode::Up | KeyCode::Down | KeyCode::Left | KeyCode::Right
+            ) {
+                moved_at = Some(Instant::now());
+            }
+
             match event.key {
-                KeyCode::Up => {
+                KeyCode::Up => SELECTIONS.lock().await.move_by(-(COLUMNS as isize)),
+                KeyCode::Down => SELECTIONS.lock().await.move_by(COLUMNS as isize),
+                KeyCode::Left => SELECTIONS.lock().await.move_by(-1),
+                KeyCode::Right => SELECTIONS.lock().await.move_by(1),
+                KeyCode::Enter => {
                     let mut selections = SELECTIONS.lock().await;
-                    selections.up();
+                    match selections.selected().cloned() {
+                        Some(Tile::Folder(folder)) => {
+                            selections.open(folder);
+                            let state = selections.state();
+                            drop(selections);
+                            save_launcher(state).await;
+                        }
+                        Some(Tile::App(index)) => {
+                            let app = selections.app(index).clone();
+                            drop(selections);
+                            launch(&app).await;
+                        }
+                        None => (),
+                    }
                 }
-                KeyCode::Down => {
+                KeyCode::Esc | KeyCode::Backspace => {
                     let mut selections = SELECTIONS.lock().await;
-                    selections.down();
+                    if selections.back() {
+                        let state = selections.state();
+                        drop(selections);
+                        save_launcher(state).await;
+                    }
                 }
-                KeyCode::Enter | KeyCode::Right => {
-                    let selections = SELECTIONS.lock().await;
-                    let selection =
-                        selections.selections[selections.current_selection as usize].clone();
-
-                    let entry = unsafe {
-                        match load_binary(&selection.short_name).await {
-                            Ok(entry) => entry,
-                            Err(e) => panic!("unable to load binary: {:?}", e),
-                        }
-                    };
-                    BINARY_CH.send(entry).await;
+                KeyCode::F2 => {
+                    let mut selections = SELECTIONS.lock().await;
+                    selections.toggle_favorite();
+                    let state = selections.state();
+                    drop(selections);
+                    save_launcher(state).await;
                 }
+                KeyCode::F3 => show_settings().await,
+                #[cfg(feature = "usb-hid")]
+                KeyCode::F1 => show_keyboard_mode().await,
                 _ => (),
             }
         }
 
-        let changed = SELECTIONS.lock().await.changed;
+        if moved_at.is_some_and(|at| at.elapsed().as_millis() >= SAVE_SELECTION_DELAY_MS) {
+            moved_at = None;
+            let state = SELECTIONS.lock().await.state();
+            save_launcher(state).await;
+        }
+
+        let changed = SELECTIONS.lock().await.changed();
         if changed {
-            clear_selection().await;
             draw_selection().await;
         }
     }
 }
 
-pub async fn clear_selection() {
-    let sel = SELECTIONS.lock().await;
+async fn launch(app: &App) {
+    // Enter on the running background service stops it
+    if service::running_service().as_deref() == Some(app.file.path.as_str()) {
+        service::request_stop();
+        wait_for_service(|running| running.is_none()).await;
+        return;
+    }
+
+    let started = Instant::now();
+    let binary = unsafe {
+        match load_binary(&app.file.path).await {
+            Ok(binary) => binary,
+            // the usb host took the card in the meantime
+            Err(LoadError::SdCardUnavailable) => return,
+            Err(e) => {
+                log::error!("unable to load {}: {:?}", app.name(), e);
+                let reason = format!("{}", e);
+                SELECTIONS.lock().await.failed(app, reason.clone());
+                show_message(&format!("Unable to load {}\n\n{}", app.name(), reason)).await;
+                return;
+            }
+        }
+    };
+    let load_ms = started.elapsed().as_millis() as u32;
+    log::info!("launching {}, loaded in {} ms", app.name(), load_ms);
+
+    // only apps that actually start make it into recents
+    let mut selections = SELECTIONS.lock().await;
+    selections.launched(app);
+    selections.loaded(app, load_ms);
+    let state = selections.state();
+    drop(selections);
+    save_launcher(state).await;
+    let background = binary.is_background();
+    BINARY_CH.send(binary).await;
+    // the launcher stays up, and marks it once it runs
+    if background {
+        wait_for_service(|running| running == Some(app.file.path.as_str())).await;
+    }
+}
 
-    if let Some(area) = sel.last_bounds {
-        Rectangle::new(area.top_left, area.size)
-            .into_styled(PrimitiveStyle::with_fill(Rgb565::BLACK))
+// polls until core1 started or stopped the background service, then redraws
+async fn wait_for_service(done: impl Fn(Option<&str>) -> bool) {
+    let started = Instant::now();
+    while !done(service::running_service().as_deref()) && started.elapsed().as_millis() < 1000 {
+        Timer::after_millis(10).await;
+    }
+    SELECTIONS.lock().await.set_changed(true);
+}
+
+/// Launches the app set to start after boot, by its path
+pub async fn launch_autostart(path: &str) {
+    log::info!("autostarting {}", path);
+    match unsafe { load_binary(path).await } {
+        Ok(binary) => BINARY_CH.send(binary).await,
+        Err(e) => {
+            log::error!("unable to autostart {}: {:?}", path, e);
+            show_message(&format!("Unable to autostart {}\n\n{}", path, e)).await;
+        }
+    }
+}
+
+/// Shown while the kernel starts, `step` says what it's doing
+pub fn draw_splash(step: &str, safe_mode: bool) {
+    let title_style = MonoTextStyle::new(&FONT_10X20, Rgb565::WHITE);
+    let info_style = MonoTextStyle::new(&FONT_6X10, Rgb565::CSS_GRAY);
+    let display_area = unsafe { FRAMEBUFFER.as_mut().unwrap().bounding_box() };
+    let center = display_area.center();
+
+    FB_PAUSED.store(true, Ordering::Release);
+    unsafe { FRAMEBUFFER.as_mut().unwrap().clear(Rgb565::BLACK).unwrap() };
+
+    let version = format!("v{}  {}", KERNEL_VERSION, BOARD);
+    let mut lines = vec![
+        ("PicoCalc OS", title_style, -30),
+        (version.as_str(), info_style, -8),
+        (step, info_style, 30),
+    ];
+    if safe_mode {
+        lines.push((
+            "Safe mode: settings and autostart skipped",
+            MonoTextStyle::new(&FONT_6X10, Rgb565::YELLOW),
+            50,
+        ));
+    }
+    for (text, style, y) in lines {
+        Text::with_alignment(text, center + Point::new(0, y), style, Alignment::Center)
             .draw(unsafe { &mut *FRAMEBUFFER.as_mut().unwrap() })
             .unwrap();
     }
+
+    FB_PAUSED.store(false, Ordering::Release);
 }
 
+/// Shown instead of the launcher while the usb host has the sd card
+pub async fn show_usb_connected() {
+    let text_style = MonoTextStyle::new(&FONT_10X20, Rgb565::WHITE);
+    let display_area = unsafe { FRAMEBUFFER.as_mut().unwrap().bounding_box() };
+
+    const USB_CONNECTED: &str = "Connected to PC\n\nThe SD card is in use by the computer. Eject it there to launch programs again.";
+
+    unsafe { FRAMEBUFFER.as_mut().unwrap().clear(Rgb565::BLACK).unwrap() };
+    TextBox::new(
+        USB_CONNECTED,
+        Rectangle::new(
+            Point::new(25, 25),
+            Size::new(display_area.size.width - 50, display_area.size.height - 50),
+        ),
+        text_style,
+    )
+    .draw(unsafe { &mut *FRAMEBUFFER.as_mut().unwrap() })
+    .unwrap();
+
+    // discard keys, nothing can be launched until the card is back
+    loop {
+        let _ = keyboard::read_keyboard_fifo().await;
+        Timer::after_millis(50).await;
+    }
+}
+
+/// Reports a user app that crashed, until any key is pressed
+pub async fn show_fault(fault: FaultInfo) {
+    let address = match fault.address {
+        Some(address) => format!("{:#010x}", address),
+        None => String::from("unknown"),
+    };
+    let report = format!(
+        "The program crashed\n\n{}\npc: {:#010x}\naddress: {}",
+        fault.kind, fault.pc, address
+    );
+    show_message(&report).await;
+}
+
+/// Shows `message` over the launcher, until any key is pressed
+pub async fn show_message(message: &str) {
+    let text = format!("{}\n\nPress any key to continue", message);
+    let area = draw_message(&text);
+
+    loop {
+        if let Some(event) = keyboard::read_keyboard_fifo().await
+            && let KeyState::Pressed = event.state
+        {
+            break;
+        }
+        Timer::after_millis(50).await;
+    }
+
+    clear_message(area).await;
+}
+
+/// Sends keys to the usb host as a keyboard, until Break is held
+#[cfg(feature = "usb-hid")]
+async fn show_keyboard_mode() {
+    const KEYBOARD_MODE: &str =
+        "USB keyboard mode\n\nKeys are sent to the computer. Hold Break to return to the launcher.";
+
+    // the launcher may be drawn over the whole screen
+    clear_selection().await;
+    let area = draw_message(KEYBOARD_MODE);
+    log::info!("usb keyboard mode started");
+
+    crate::hid::forward_keys().await;
+
+    log::info!("usb keyboard mode stopped");
+    clear_message(area).await;
+}
+
+/// The settings screen, until Esc or Enter. Changes apply right away and are
+/// saved on the way out.
+async fn show_settings() {
+    let apps: Vec<String> = SELECTIONS
+        .lock()
+        .await
+        .apps()
+        .iter()
+        .map(|app| app.file.path.clone())
+        .collect();
+    let apps: Vec<&str> = apps.iter().map(String::as_str).collect();
+    let mut settings = SETTINGS.lock().await.clone();
+    let mut selected = 0;
+
+    draw_settings(&settings, selected);
+    loop {
+        let Some(event) = keyboard::read_keyboard_fifo().await else {
+            Timer::after_millis(50).await;
+            continue;
+        };
+        if !matches!(event.state, KeyState::Pressed) {
+            continue;
+        }
+
+        let by = match event.key {
+            KeyCode::Up => {
+                selected = selected.saturating_sub(1);
+                0
+            }
+            KeyCode::Down => {
+                selected = (selected + 1).min(Setting::ALL.len() - 1);
+                0
+            }
+            KeyCode::Left => -1,
+            KeyCode::Right => 1,
+            KeyCode::Esc | KeyCode::Enter => break,
+            _ => continue,
+        };
+        if by != 0 {
+            Setting::ALL[selected].adjust(&mut settings, by, &apps);
+            apply(&settings).await;
+            *SETTINGS.lock().await = settings.clone();
+        }
+        draw_settings(&settings, selected);
+    }
+
+    save_settings().await;
+    clear_selection().await;
+    if restart_needed(&settings) {
+        show_message(&format!(
+            "The device restarts to run at {} MHz",
+            settings.clock_mhz
+        ))
+        .await;
+        restart(settings.clock_mhz);
+    }
+    SELECTIONS.lock().await.set_changed(true);
+}
+
+const SETTING_HEIGHT: u32 = 22;
+
+fn draw_settings(settings: &Settings, selected: usize) {
+    let title_style = MonoTextStyle::new(&FONT_10X20, Rgb565::WHITE);
+    let hint_style = MonoTextStyle::new(&FONT_6X10, Rgb565::CSS_GRAY);
+    let value_style = MonoTextStyle::new(&FONT_10X20, Rgb565::CSS_LIGHT_SKY_BLUE);
+    let width = unsafe { FRAMEBUFFER.as_mut().unwrap().bounding_box() }
+        .size
+        .width;
+
+    FB_PAUSED.store(true, Ordering::Release);
+    unsafe { FRAMEBUFFER.as_mut().unwrap().clear(Rgb565::BLACK).unwrap() };
+    draw_status_bar();
+
+    Text::with_baseline(
+        "Settings",
+        Point::new(8, STATUS_BAR_HEIGHT as i32 + 2),
+        title_style,
+        Baseline::Top,
+    )
+    .draw(unsafe { &mut *FRAMEBUFFER.as_mut().unwrap() })
+    .unwrap();
+    Text::with_alignment(
+        "Left/Right change  Esc done",
+        Point::new(width as i32 - 8, STATUS_BAR_HEIGHT as i32 + 14),
+        hint_style,
+        Alignment::Right,
+    )
+    .draw(unsafe { &mut *FRAMEBUFFER.as_mut().unwrap() })
+    .unwrap();
+
+    for (i, setting) in Setting::ALL.iter().enumerate() {
+        let top = (GRID_TOP + i as u32 * SETTING_HEIGHT) as i32;
+        if i == selected {
+            Rectangle::new(Point::new(2, top), Size::new(width - 4, SETTING_HEIGHT))
+                .into_styled(PrimitiveStyle::with_stroke(Rgb565::WHITE, 1))
+                .draw(unsafe { &mut *FRAMEBUFFER.as_mut().unwrap() })
+                .unwrap();
+        }
+        Text::with_baseline(
+            setting.label(),
+            Point::new(8, top + 1),
+            title_style,
+            Baseline::Top,
+        )
+        .draw(unsafe { &mut *FRAMEBUFFER.as_mut().unwrap() })
+        .unwrap();
+        Text::with_alignment(
+            &setting.value(settings),
+            Point::new(width as i32 - 8, top + 16),
+            value_style,
+            Alignment::Right,
+        )
+        .draw(unsafe { &mut *FRAMEBUFFER.as_mut().unwrap() })
+        .unwrap();
+    }
+
+    FB_PAUSED.store(false, Ordering::Release);
+}
+
+// draws `text` over the launcher, returns the area to clear afterwards
+fn draw_message(text: &str) -> Rectangle {
+    let text_style = MonoTextStyle::new(&FONT_10X20, Rgb565::WHITE);
+    let display_area = unsafe { FRAMEBUFFER.as_mut().unwrap().bounding_box() };
+
+    let area = Rectangle::new(
+        Point::new(25, 25),
+        Size::new(display_area.size.width - 50, display_area.size.height - 50),
+    );
+    // the launcher grid may be under it
+    area.into_styled(PrimitiveStyle::with_fill(Rgb565::BLACK))
+        .draw(unsafe { &mut *FRAMEBUFFER.as_mut().unwrap() })
+        .unwrap();
+    TextBox::new(text, area, text_style)
+        .draw(unsafe { &mut *FRAMEBUFFER.as_mut().unwrap() })
+        .unwrap();
+    area
+}
+
+async fn clear_message(area: Rectangle) {
+    area.into_styled(PrimitiveStyle::with_fill(Rgb565::BLACK))
+        .draw(unsafe { &mut *FRAMEBUFFER.as_mut().unwrap() })
+        .unwrap();
+    SELECTIONS.lock().await.set_changed(true);
+}
+
+pub async fn clear_selection() {
+    unsafe { FRAMEBUFFER.as_mut().unwrap().clear(Rgb565::BLACK).unwrap() };
+}
+
+// the status bar and the folder's title above the grid, the selected tile's
+// details below it
+const HEADER_HEIGHT: u32 = 24;
+const GRID_TOP: u32 = STATUS_BAR_HEIGHT as u32 + HEADER_HEIGHT;
+const DETAILS_HEIGHT: u32 = 40;
+const TILE_WIDTH: u32 = 80;
+const TILE_HEIGHT: u32 = 80;
+const ICON_SCALE: u32 = 2;
+const ICON_SIZE: u32 = APP_ICON_SIZE as u32 * ICON_SCALE;
+
 async fn draw_selection() {
     let mut guard = SELECTIONS.lock().await;
-    let file_names = guard.selections.clone();
 
     let text_style = MonoTextStyle::new(&FONT_10X20, Rgb565::WHITE);
     let display_area = unsafe { FRAMEBUFFER.as_mut().unwrap().bounding_box() };
 
-    const NO_BINS: &str = "No Programs found on SD Card. Ensure programs end with '.bin', and are located in the root directory";
-    let no_bins = String::from_str(NO_BINS).unwrap();
+    const NO_BINS: &str = "No Programs found on SD Card. Ensure programs end with '.bin', and are located in the apps directory or the root directory";
 
     FB_PAUSED.store(true, Ordering::Release); // ensure all elements show up at once
+    clear_selection().await;
+    draw_status_bar();
 
-    if file_names.is_empty() {
+    if guard.is_empty() {
         TextBox::new(
-            &no_bins,
+            NO_BINS,
             Rectangle::new(
                 Point::new(25, 25),
                 Size::new(display_area.size.width - 50, display_area.size.width - 50),
@@ -98,91 +465,320 @@ async fn draw_selection() {
         .draw(unsafe { &mut *FRAMEBUFFER.as_mut().unwrap() })
         .unwrap();
     } else {
-        let mut views: alloc::vec::Vec<Text<MonoTextStyle<Rgb565>>> = Vec::new();
+        let grid_height = display_area.size.height - GRID_TOP - DETAILS_HEIGHT;
+        let rows = (grid_height / TILE_HEIGHT) as usize;
+        let scroll = guard.scroll(rows);
 
-        for i in &file_names {
-            views.push(Text::new(&i.long_name, Point::zero(), text_style));
-        }
+        draw_header(&guard, display_area.size.width);
 
-        let views_group = Views::new(views.as_mut_slice());
-
-        let layout = LinearLayout::vertical(views_group)
-            .with_alignment(horizontal::Center)
-            .with_spacing(FixedMargin(5))
-            .arrange()
-            .align_to(&display_area, horizontal::Center, vertical::Center);
+        let first = scroll * COLUMNS;
+        for (i, tile) in guard
+            .tiles()
+            .iter()
+            .enumerate()
+            .skip(first)
+            .take(rows * COLUMNS)
+        {
+            let (column, row) = ((i - first) % COLUMNS, (i - first) / COLUMNS);
+            let area = Rectangle::new(
+                Point::new(
+                    column as i32 * TILE_WIDTH as i32,
+                    (GRID_TOP + row as u32 * TILE_HEIGHT) as i32,
+                ),
+                Size::new(TILE_WIDTH, TILE_HEIGHT),
+            );
+            draw_tile(&guard, tile, area, i == guard.current_selection());
+        }
 
-        // draw selected box
-        let selected_bounds = layout
-            .inner()
-            .get(guard.current_selection as usize)
-            .expect("Selected binary missing")
-            .bounding_box();
-        Rectangle::new(selected_bounds.top_left, selected_bounds.size)
-            .into_styled(PrimitiveStyle::with_stroke(Rgb565::WHITE, 1))
+        // how far down the grid is scrolled
+        let total_rows = guard.tiles().len().div_ceil(COLUMNS);
+        if total_rows > rows {
+            let bar_height = grid_height * rows as u32 / total_rows as u32;
+            let bar_top = GRID_TOP + grid_height * scroll as u32 / total_rows as u32;
+            Rectangle::new(
+                Point::new(display_area.size.width as i32 - 3, bar_top as i32),
+                Size::new(2, bar_height),
+            )
+            .into_styled(PrimitiveStyle::with_fill(Rgb565::CSS_GRAY))
             .draw(unsafe { &mut *FRAMEBUFFER.as_mut().unwrap() })
             .unwrap();
+        }
 
-        guard.last_bounds = Some(layout.bounds());
-
-        layout
-            .draw(unsafe { &mut *FRAMEBUFFER.as_mut().unwrap() })
-            .unwrap();
+        draw_details(
+            &guard,
+            Rectangle::new(
+                Point::new(0, (display_area.size.height - DETAILS_HEIGHT) as i32),
+                Size::new(display_area.size.width, DETAILS_HEIGHT),
+            ),
+        );
     }
 
-    guard.changed = false;
+    guard.set_changed(false);
     FB_PAUSED.store(false, Ordering::Release); // ensure all elements show up at once
 }
 
-#[derive(Clone)]
-pub struct SelectionList {
-    // allows easy clearing of selection ui,
-    // based on previous bounds
-    last_bounds: Option<Rectangle>,
-    current_selection: u16,
-    selections: Vec<FileName>,
-    changed: bool,
+fn draw_header(selections: &SelectionList, width: u32) {
+    let title_style = MonoTextStyle::new(&FONT_10X20, Rgb565::WHITE);
+    let hint_style = MonoTextStyle::new(&FONT_6X10, Rgb565::CSS_GRAY);
+
+    Text::with_baseline(
+        selections.folder().title(),
+        Point::new(8, STATUS_BAR_HEIGHT as i32 + 2),
+        title_style,
+        Baseline::Top,
+    )
+    .draw(unsafe { &mut *FRAMEBUFFER.as_mut().unwrap() })
+    .unwrap();
+
+    let hint = match selections.folder() {
+        Folder::Home => "F2 pin  F3 settings",
+        _ => "F2 pin  F3 settings  Esc back",
+    };
+    Text::with_alignment(
+        hint,
+        Point::new(width as i32 - 8, STATUS_BAR_HEIGHT as i32 + 14),
+        hint_style,
+        Alignment::Right,
+    )
+    .draw(unsafe { &mut *FRAMEBUFFER.as_mut().unwrap() })
+    .unwrap();
 }
 
-impl SelectionList {
-    pub const fn new() -> Self {
-        Self {
-            last_bounds: None,
-            selections: Vec::new(),
-            current_selection: 0,
-            changed: false,
+fn draw_tile(selections: &SelectionList, tile: &Tile, area: Rectangle, selected: bool) {
+    let label_style = MonoTextStyle::new(&FONT_6X10, Rgb565::WHITE);
+    let icon = Rectangle::new(
+        area.top_left + Point::new(((TILE_WIDTH - ICON_SIZE) / 2) as i32, 10),
+        Size::new(ICON_SIZE, ICON_SIZE),
+    );
+
+    let label = match tile {
+        Tile::App(index) => {
+            let app = selections.app(*index);
+            draw_app_icon(app, icon);
+            if selections.is_favorite(app) {
+                Text::with_baseline(
+                    "*",
+                    area.top_left + Point::new(TILE_WIDTH as i32 - 14, 4),
+                    MonoTextStyle::new(&FONT_10X20, Rgb565::YELLOW),
+                    Baseline::Top,
+                )
+                .draw(unsafe { &mut *FRAMEBUFFER.as_mut().unwrap() })
+                .unwrap();
+            }
+            if service::running_service().as_deref() == Some(app.file.path.as_str()) {
+                Text::with_baseline(
+                    ">",
+                    area.top_left + Point::new(6, 4),
+                    MonoTextStyle::new(&FONT_10X20, Rgb565::GREEN),
+                    Baseline::Top,
+                )
+                .draw(unsafe { &mut *FRAMEBUFFER.as_mut().unwrap() })
+                .unwrap();
+            }
+            if selections.failure(app).is_some() {
+                Text::with_baseline(
+                    "!",
+                    area.top_left + Point::new(6, 4),
+                    MonoTextStyle::new(&FONT_10X20, Rgb565::RED),
+                    Baseline::Top,
+                )
+                .draw(unsafe { &mut *FRAMEBUFFER.as_mut().unwrap() })
+                .unwrap();
+            }
+            app.name()
         }
-    }
+        Tile::Folder(folder) => {
+            draw_folder_icon(icon);
+            folder.title()
+        }
+    };
 
-    pub fn set_changed(&mut self, changed: bool) {
-        self.changed = changed
-    }
+    // as much of the label as fits under the icon
+    let max_chars = (TILE_WIDTH / 6 - 1) as usize;
+    let label = match label.char_indices().nth(max_chars) {
+        Some((end, _)) => &label[..end],
+        None => label,
+    };
+    Text::with_alignment(
+        label,
+        area.top_left + Point::new(TILE_WIDTH as i32 / 2, (10 + ICON_SIZE + 16) as i32),
+        label_style,
+        Alignment::Center,
+    )
+    .draw(unsafe { &mut *FRAMEBUFFER.as_mut().unwrap() })
+    .unwrap();
 
-    pub fn update_selections(&mut self, selections: Vec<FileName>) {
-        self.selections = selections;
-        self.changed = true;
+    if selected {
+        Rectangle::new(
+            area.top_left + Point::new(2, 2),
+            area.size - Size::new(4, 4),
+        )
+        .into_styled(PrimitiveStyle::with_stroke(Rgb565::WHITE, 1))
+        .draw(unsafe { &mut *FRAMEBUFFER.as_mut().unwrap() })
+        .unwrap();
     }
+}
+
+// the app's icon scaled up, or its initial on a tile for apps without one
+fn draw_app_icon(app: &App, area: Rectangle) {
+    let fb = unsafe { &mut *FRAMEBUFFER.as_mut().unwrap() };
 
-    pub fn selections(&self) -> &Vec<FileName> {
-        &self.selections
+    if let Some(meta) = app.meta.as_ref().filter(|meta| meta.has_icon()) {
+        let size = ICON_SIZE as usize;
+        let pixels = (0..size * size).map(|i| {
+            let (x, y) = (
+                i % size / ICON_SCALE as usize,
+                i / size / ICON_SCALE as usize,
+            );
+            let offset = (y * APP_ICON_SIZE + x) * 2;
+            let raw = u16::from_le_bytes([meta.icon[offset], meta.icon[offset + 1]]);
+            Rgb565::from(RawU16::new(raw))
+        });
+        fb.fill_contiguous(&area, pixels).unwrap();
+        return;
     }
 
-    pub fn reset(&mut self) {
-        self.current_selection = 0;
-        self.changed = true;
+    RoundedRectangle::with_equal_corners(area, Size::new(6, 6))
+        .into_styled(PrimitiveStyle::with_fill(Rgb565::CSS_DARK_SLATE_BLUE))
+        .draw(fb)
+        .unwrap();
+    let initial = app
+        .name()
+        .chars()
+        .next()
+        .unwrap_or('?')
+        .to_ascii_uppercase();
+    let mut buf = [0; 4];
+    Text::with_alignment(
+        initial.encode_utf8(&mut buf),
+        area.center() + Point::new(0, 6),
+        MonoTextStyle::new(&FONT_10X20, Rgb565::WHITE),
+        Alignment::Center,
+    )
+    .draw(fb)
+    .unwrap();
+}
+
+fn draw_folder_icon(area: Rectangle) {
+    let style = PrimitiveStyle::with_fill(Rgb565::CSS_GOLDENROD);
+    let tab = Rectangle::new(
+        area.top_left + Point::new(0, 2),
+        Size::new(area.size.width / 2, 6),
+    );
+    let body = Rectangle::new(
+        area.top_left + Point::new(0, 6),
+        area.size - Size::new(0, 8),
+    );
+    for part in [tab, body] {
+        part.into_styled(style)
+            .draw(unsafe { &mut *FRAMEBUFFER.as_mut().unwrap() })
+            .unwrap();
     }
+}
 
-    fn up(&mut self) {
-        if self.current_selection > 0 {
-            self.current_selection -= 1;
-            self.changed = true;
+// below the grid, what the selected app says about itself
+fn draw_details(selections: &SelectionList, area: Rectangle) {
+    let text_style = MonoTextStyle::new(&FONT_6X10, Rgb565::WHITE);
+
+    let lines = match selections.selected() {
+        Some(Tile::App(index)) => {
+            let app = selections.app(*index);
+            match selections.failure(app) {
+                Some(reason) => vec![
+                    format!("{} failed to load", app.name()),
+                    String::from(reason),
+                ],
+                None => app_details(app, selections.load_time(app)),
+            }
         }
+        Some(Tile::Folder(folder)) => {
+            vec![
+                String::from(folder.title()),
+                format!("{} apps", selections.count(folder)),
+            ]
+        }
+        None => Vec::new(),
+    };
+
+    for (line, text) in lines.iter().enumerate() {
+        Text::with_baseline(
+            text,
+            area.top_left + Point::new(8, 2 + line as i32 * 12),
+            text_style,
+            Baseline::Top,
+        )
+        .draw(unsafe { &mut *FRAMEBUFFER.as_mut().unwrap() })
+        .unwrap();
     }
+}
+
+// the title, description, and a line of notes, all the strip has room for
+fn app_details(app: &App, load_ms: Option<u32>) -> Vec<String> {
+    let mut title = String::from(app.name());
+    let mut lines = Vec::new();
+    let mut notes = Vec::new();
+
+    if let Some(meta) = &app.meta {
+        if !meta.version_str().is_empty() {
+            title = format!("{} {}", title, meta.version_str());
+        }
+        if !meta.author_str().is_empty() {
+            title = format!("{} by {}", title, meta.author_str());
+        }
+        lines.push(String::from(meta.description_str()));
+
+        let mut needs = vec![];
+        if meta.min_heap > 0 {
+            needs.push(format!("{} KiB heap", meta.min_heap.div_ceil(1024)));
+        }
+        if meta.stack_size > 0 {
+            needs.push(format!("{} KiB stack", meta.stack_size.div_ceil(1024)));
+        }
+        if !needs.is_empty() {
+            notes.push(format!("needs {}", needs.join(", ")));
+        }
 
-    fn down(&mut self) {
-        if self.current_selection + 1 < self.selections.len() as u16 {
-            self.current_selection += 1;
-            self.changed = true;
+        if service::running_service().as_deref() == Some(app.file.path.as_str()) {
+            notes.push(String::from("running in the background, Enter stops it"));
+        } else if meta.is_background() {
+            notes.push(String::from("runs in the background"));
         }
     }
+    if let Some(ms) = load_ms {
+        notes.push(format!("loaded in {} ms", ms));
+    }
+
+    lines.insert(0, title);
+    if !notes.is_empty() {
+        lines.push(notes.join(", "));
+    }
+    lines
+}
+
+/// Restores favorites, recents and the selection saved by the last boot
+pub async fn restore_launcher() {
+    let mut buf = [0; 1024];
+    let read = match SDCARD.get().lock().await.as_mut() {
+        Some(sd) => sd.read_at(LAUNCHER_FILE, 0, &mut buf),
+        None => return,
+    };
+
+    match read {
+        Ok(len) => match core::str::from_utf8(&buf[..len]) {
+            Ok(state) => SELECTIONS.lock().await.restore_state(state),
+            Err(_) => log::warn!("{} is not text, ignoring it", LAUNCHER_FILE),
+        },
+        // first boot
+        Err(SdCardError::NotFound) => (),
+        Err(e) => log::warn!("failed to read {}: {:?}", LAUNCHER_FILE, e),
+    }
+}
+
+// saves favorites, recents and the selection for the next boot
+async fn save_launcher(state: String) {
+    if let Some(sd) = SDCARD.get().lock().await.as_mut()
+        && let Err(e) = sd.write_at(LAUNCHER_FILE, 0, state.as_bytes())
+    {
+        log::warn!("failed to write {}: {:?}", LAUNCHER_FILE, e);
+    }
 }
@@ -1,11 +1,63 @@
-use crate::{scsi::MassStorageClass, storage::SdCard};
-use core::sync::atomic::{AtomicBool, Ordering};
-use embassy_futures::select::select;
+#[cfg(feature = "usb-remote")]
+use crate::remote::{self, run_remote};
+use crate::{
+    log::{self, LOG_UPDATED},
+    scsi::{MSC_RELEASE, MassStorageClass},
+};
+use core::{
+    future::pending,
+    sync::atomic::{AtomicBool, Ordering},
+};
+use embassy_futures::select::{Either, select, select3, select4};
 use embassy_rp::{peripherals::USB, usb::Driver};
-use embassy_usb::{Builder, Config, UsbDevice};
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, channel::Channel};
+use embassy_usb::{
+    Builder, Config, Handler, UsbDevice,
+    class::{
+        cdc_acm::{self, CdcAcmClass},
+        hid::{self, HidBootProtocol, HidSubclass, HidWriter},
+        midi::{self, MidiClass},
+    },
+    driver::EndpointError,
+};
+use heapless::Vec;
+use usbd_hid::descriptor::{KeyboardReport, SerializedDescriptor};
+use userlib_sys::midi::MidiPacket;
 
 pub static USB_ACTIVE: AtomicBool = AtomicBool::new(false);
 
+/// Keyboard reports for the host, sent over the HID interface
+pub static HID_REPORTS: Channel<CriticalSectionRawMutex, KeyboardReport, 8> = Channel::new();
+
+/// Midi packets queued by apps for the host
+pub static MIDI_OUT: Channel<CriticalSectionRawMutex, MidiPacket, 64> = Channel::new();
+
+/// Midi packets received from the host, until an app takes them
+pub static MIDI_IN: Channel<CriticalSectionRawMutex, MidiPacket, 64> = Channel::new();
+
+// embassy-usb has room for as many interfaces and control handlers as its
+// `max-interface-count-N` and `max-handler-count-N` features say, the builder
+// panics at boot on one more. Each function's share, by feature:
+// cdc-acm: 2 interfaces and a handler, msc: 1, hid: 1 and a handler,
+// midi: 2 (audio control and midi streaming), and `HostPresence`.
+const MAX_INTERFACES: usize = 8;
+const MAX_HANDLERS: usize = 8;
+const INTERFACES: usize = 2 * cfg!(feature = "usb-serial") as usize
+    + cfg!(feature = "usb-msc") as usize
+    + cfg!(feature = "usb-hid") as usize
+    + 2 * cfg!(feature = "usb-midi") as usize
+    + 2 * cfg!(feature = "usb-remote") as usize;
+const HANDLERS: usize = 1
+    + cfg!(feature = "usb-serial") as usize
+    + cfg!(feature = "usb-hid") as usize
+    + cfg!(feature = "usb-remote") as usize;
+const _: () = assert!(INTERFACES <= MAX_INTERFACES, "too many usb interfaces");
+const _: () = assert!(HANDLERS <= MAX_HANDLERS, "too many usb control handlers");
+
+const CONSOLE_PACKET_SIZE: u16 = 64;
+const HID_PACKET_SIZE: u16 = 8;
+const MIDI_PACKET_SIZE: u16 = 64;
+
 #[embassy_executor::task]
 pub async fn usb_handler(driver: Driver<'static, USB>) {
     let mut config = Config::new(0xc0de, 0xbabe);
@@ -15,9 +67,16 @@ pub async fn usb_handler(driver: Driver<'static, USB>) {
     config.max_power = 100;
     config.max_packet_size_0 = 64;
 
-    let mut config_descriptor = [0; 256];
+    // composite device, each class is grouped by an interface association descriptor
+    config.device_class = 0xEF;
+    config.device_sub_class = 0x02;
+    config.device_protocol = 0x01;
+    config.composite_with_iads = true;
+
+    let mut config_descriptor = [0; 512];
     let mut bos_descriptor = [0; 64];
     let mut control_buf = [0; 64];
+    let mut presence = HostPresence;
 
     let mut builder = Builder::new(
         driver,
@@ -27,12 +86,70 @@ pub async fn usb_handler(driver: Driver<'static, USB>) {
         &mut [],
         &mut control_buf,
     );
+    builder.handler(&mut presence);
+
+    // each function is only added to the configuration if its feature is enabled
+    #[cfg(feature = "usb-serial")]
+    let mut console_state = cdc_acm::State::new();
+    #[cfg(feature = "usb-serial")]
+    let mut console = CdcAcmClass::new(&mut builder, &mut console_state, CONSOLE_PACKET_SIZE);
+
+    #[cfg(feature = "usb-msc")]
+    let mut msc = MassStorageClass::new(&mut builder);
+
+    #[cfg(feature = "usb-hid")]
+    let mut hid_state = hid::State::new();
+    #[cfg(feature = "usb-hid")]
+    let hid = HidWriter::<_, { HID_PACKET_SIZE as usize }>::new(
+        &mut builder,
+        &mut hid_state,
+        hid::Config {
+            report_descriptor: KeyboardReport::desc(),
+            request_handler: None,
+            poll_ms: 10,
+            max_packet_size: HID_PACKET_SIZE,
+            hid_subclass: HidSubclass::Boot,
+            hid_boot_protocol: HidBootProtocol::Keyboard,
+        },
+    );
+
+    #[cfg(feature = "usb-midi")]
+    let midi = MidiClass::new(&mut builder, 1, 1, MIDI_PACKET_SIZE);
+
+    // added last, so it shows up after the console, e.g. as /dev/ttyACM1
+    #[cfg(feature = "usb-remote")]
+    let mut remote_state = cdc_acm::State::new();
+    #[cfg(feature = "usb-remote")]
+    let mut remote = CdcAcmClass::new(&mut builder, &mut remote_state, remote::PACKET_SIZE);
 
-    let temp_sd: Option<SdCard> = None;
-    let mut scsi = MassStorageClass::new(&mut builder, temp_sd);
     let usb = builder.build();
 
-    select(run(usb), scsi.poll()).await;
+    #[cfg(feature = "usb-serial")]
+    let console = run_console(&mut console);
+    #[cfg(not(feature = "usb-serial"))]
+    let console = pending::<()>();
+
+    #[cfg(feature = "usb-msc")]
+    let msc = msc.poll();
+    #[cfg(not(feature = "usb-msc"))]
+    let msc = pending::<()>();
+
+    #[cfg(feature = "usb-hid")]
+    let hid = run_hid(hid);
+    #[cfg(not(feature = "usb-hid"))]
+    let hid = pending::<()>();
+
+    #[cfg(feature = "usb-midi")]
+    let midi = run_midi(midi);
+    #[cfg(not(feature = "usb-midi"))]
+    let midi = pending::<()>();
+
+    #[cfg(feature = "usb-remote")]
+    let remote = run_remote(&mut remote);
+    #[cfg(not(feature = "usb-remote"))]
+    let remote = pending::<()>();
+
+    select4(run(usb), console, msc, select3(hid, midi, remote)).await;
 }
 
 async fn run<'d>(mut usb: UsbDevice<'d, Driver<'d, USB>>) -> ! {
@@ -40,6 +157,134 @@ async fn run<'d>(mut usb: UsbDevice<'d, Driver<'d, USB>>) -> ! {
         usb.wait_resume().await;
         USB_ACTIVE.store(true, Ordering::Release);
         usb.run_until_suspend().await;
+        // a suspended host, e.g. one that went to sleep, still has the sd
+        // card mounted, it's only handed back on eject or a disconnect
         USB_ACTIVE.store(false, Ordering::Release);
     }
 }
+
+// Hands the sd card back once the host can't have it mounted anymore: usb
+// power went away, or the host dropped the configuration, e.g. it reset the
+// bus after the cable was plugged back in
+struct HostPresence;
+
+impl Handler for HostPresence {
+    fn enabled(&mut self, enabled: bool) {
+        if !enabled {
+            MSC_RELEASE.signal(());
+        }
+    }
+
+    fn configured(&mut self, configured: bool) {
+        if !configured {
+            MSC_RELEASE.signal(());
+        }
+    }
+}
+
+// Streams the kernel log, which includes everything apps print, to whatever
+// opens the serial port on the host, e.g. `cat /dev/ttyACM0`
+async fn run_console<'d>(class: &mut CdcAcmClass<'d, Driver<'d, USB>>) -> ! {
+    loop {
+        class.wait_connection().await;
+        log::info!("usb console connected");
+
+        // start from the oldest entry still buffered, so a new terminal
+        // gets some history
+        let mut pos = 0;
+        let _ = stream_log(class, &mut pos).await;
+    }
+}
+
+async fn stream_log<'d>(
+    class: &mut CdcAcmClass<'d, Driver<'d, USB>>,
+    pos: &mut usize,
+) -> Result<(), EndpointError> {
+    // half a packet, so expanding every newline to "\r\n" still fits, and
+    // packets are never full sized, so each ends a transfer without needing
+    // a zero length packet
+    let mut buf = [0_u8; CONSOLE_PACKET_SIZE as usize / 2 - 1];
+
+    loop {
+        let len = log::read_since(pos, &mut buf);
+        if len == 0 {
+            LOG_UPDATED.wait().await;
+            continue;
+        }
+
+        let mut packet: Vec<u8, { CONSOLE_PACKET_SIZE as usize }> = Vec::new();
+        for &byte in &buf[..len] {
+            if byte == b'\n' {
+                let _ = packet.push(b'\r');
+            }
+            let _ = packet.push(byte);
+        }
+        class.write_packet(&packet).await?;
+    }
+}
+
+// Sends keyboard reports to the host as they are queued
+async fn run_hid<'d>(
+    mut writer: HidWriter<'d, Driver<'d, USB>, { HID_PACKET_SIZE as usize }>,
+) -> ! {
+    loop {
+        let report = HID_REPORTS.receive().await;
+        if let Err(e) = writer.write_serialize(&report).await {
+            log::warn!("failed to send hid report: {:?}", e);
+        }
+    }
+}
+
+async fn run_midi<'d>(class: MidiClass<'d, Driver<'d, USB>>) -> ! {
+    let (mut sender, mut receiver) = class.split();
+
+    match select(midi_out(&mut sender), midi_in(&mut receiver)).await {
+        Either::First(never) | Either::Second(never) => never,
+    }
+}
+
+// Sends the packets apps queue, batching whatever is queued at once
+async fn midi_out<'d>(sender: &mut midi::Sender<'d, Driver<'d, USB>>) -> ! {
+    loop {
+        sender.wait_connection().await;
+        let _ = send_midi_packets(sender).await;
+    }
+}
+
+async fn send_midi_packets<'d>(
+    sender: &mut midi::Sender<'d, Driver<'d, USB>>,
+) -> Result<(), EndpointError> {
+    // one event short of a full packet, so each ends a transfer without
+    // needing a zero length packet
+    let mut packet: Vec<u8, { MIDI_PACKET_SIZE as usize }> = Vec::new();
+
+    loop {
+        packet.clear();
+        let _ = packet.extend_from_slice(&MIDI_OUT.receive().await.0);
+        while packet.len() < MIDI_PACKET_SIZE as usize - 4
+            && let Ok(next) = MIDI_OUT.try_receive()
+        {
+            let _ = packet.extend_from_slice(&next.0);
+        }
+        sender.write_packet(&packet).await?;
+    }
+}
+
+// Queues packets from the host for apps, dropping them once the queue is full
+async fn midi_in<'d>(receiver: &mut midi::Receiver<'d, Driver<'d, USB>>) -> ! {
+    let mut buf = [0_u8; MIDI_PACKET_SIZE as usize];
+
+    loop {
+        receiver.wait_connection().await;
+        while let Ok(len) = receiver.read_packet(&mut buf).await {
+            for event in buf[..len].chunks_exact(4) {
+                let mut packet = MidiPacket::default();
+                packet.0.copy_from_slice(event);
+                // all zero packets are padding
+                if packet != MidiPacket::default() {
+                    let _ = MIDI_IN.try_send(packet);
+                }
+            }
+        }
+    }
+}
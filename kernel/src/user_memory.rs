@@ -0,0 +1,134 @@
+//! Tracks the memory a running user app owns, so pointer arguments handed to
+//! syscalls can be checked before the kernel dereferences them.
+//!
+//! An app may only pass pointers into its loaded image, its live heap
+//! allocations (made through the `alloc` syscall) or the part of the stack it
+//! runs on below where it was entered. The kernel's own frames are above that.
+
+use alloc::collections::BTreeMap;
+use core::{alloc::Layout, ops::Range};
+use userlib_sys::SyscallError;
+
+// only touched from core1, by `userland_task` and the syscalls it runs
+pub static mut USER_MEMORY: UserMemory = UserMemory::new();
+
+pub struct UserMemory {
+    image: Range<usize>,
+    stack: Range<usize>,
+    // start address -> layout of every live user allocation
+    allocations: BTreeMap<usize, Layout>,
+}
+
+impl UserMemory {
+    pub const fn new() -> Self {
+        Self {
+            image: 0..0,
+            stack: 0..0,
+            allocations: BTreeMap::new(),
+        }
+    }
+
+    /// Registers the regions of an app that is about to run on the stack
+    /// starting at `stack_start`, see `entered` for where it ends
+    pub fn load(&mut self, image: Range<usize>, stack_start: usize) {
+        self.image = image;
+        self.stack = stack_start..stack_start;
+        self.allocations.clear();
+    }
+
+    /// Gives the app the stack below `sp`, where it was entered
+    pub fn entered(&mut self, sp: usize) {
+        self.stack.end = sp.max(self.stack.start);
+    }
+
+    /// Forgets the regions of an app that has exited
+    pub fn unload(&mut self) {
+        self.image = 0..0;
+        self.stack = 0..0;
+        self.allocations.clear();
+    }
+
+    pub fn track_alloc(&mut self, ptr: *mut u8, layout: Layout) {
+        if !ptr.is_null() {
+            self.allocations.insert(ptr as usize, layout);
+        }
+    }
+
+    /// Returns the layout `ptr` was allocated with, if it is a live user allocation
+    pub fn untrack_alloc(&mut self, ptr: *mut u8) -> Option<Layout> {
+        self.allocations.remove(&(ptr as usize))
+    }
+
+    /// Stops tracking every live allocation, handing them back to be freed
+    pub fn take_allocations(&mut self) -> BTreeMap<usize, Layout> {
+        core::mem::take(&mut self.allocations)
+    }
+
+    /// Whether `addr` lies within the app's loaded image
+    pub fn in_image(&self, addr: usize) -> bool {
+        self.image.contains(&addr)
+    }
+
+    fn contains(&self, start: usize, len: usize) -> bool {
+        let Some(end) = start.checked_add(len) else {
+            return false;
+        };
+        let within = |region: &Range<usize>| start >= region.start && end <= region.end;
+
+        if within(&self.image) || within(&self.stack) {
+            return true;
+        }
+
+        self.allocations
+            .range(..=start)
+            .next_back()
+            .is_some_and(|(&alloc_start, layout)| end <= alloc_start + layout.size())
+    }
+
+    /// Checks that `len` values of `T` starting at `ptr` lie entirely within
+    /// memory owned by the app
+    pub fn validate<T>(&self, ptr: *const T, len: usize) -> Result<(), SyscallError> {
+        if ptr.is_null() || !ptr.is_aligned() {
+            return Err(SyscallError::InvalidPointer);
+        }
+        // nothing is dereferenced for empty slices, e.g. `Vec::new().as_ptr()`
+        if len == 0 {
+            return Ok(());
+        }
+
+        let bytes = len
+            .checked_mul(size_of::<T>())
+            .ok_or(SyscallError::InvalidPointer)?;
+
+        if self.contains(ptr as usize, bytes) {
+            Ok(())
+        } else {
+            Err(SyscallError::InvalidPointer)
+        }
+    }
+}
+
+/// # Safety
+/// Must be called from a syscall, while the app that passed `ptr` is running
+pub unsafe fn user_slice<'a, T>(ptr: *const T, len: usize) -> Result<&'a [T], SyscallError> {
+    unsafe {
+        USER_MEMORY.validate(ptr, len)?;
+        Ok(core::slice::from_raw_parts(ptr, len))
+    }
+}
+
+/// # Safety
+/// Must be called from a syscall, while the app that passed `ptr` is running
+pub unsafe fn user_slice_mut<'a, T>(ptr: *mut T, len: usize) -> Result<&'a mut [T], SyscallError> {
+    unsafe {
+        USER_MEMORY.validate(ptr, len)?;
+        Ok(core::slice::from_raw_parts_mut(ptr, len))
+    }
+}
+
+/// # Safety
+/// Must be called from a syscall, while the app that passed `ptr` is running
+pub unsafe fn user_str<'a>(ptr: *const u8, len: usize) -> Result<&'a str, SyscallError> {
+    let bytes = unsafe { user_slice(ptr, len)? };
+    core::str::from_utf8(bytes).map_err(|_| SyscallError::InvalidArgument)
+}
@@ -0,0 +1,556 @@
+use crate::{
+    BLOCK_SIZE, BlockDevice,
+    scsi::{ScsiCommand, parse_cb},
+    sense::Sense,
+};
+use embassy_usb_driver::{EndpointError, EndpointIn, EndpointOut};
+
+const CBW_SIGNATURE: u32 = 0x43425355; // "USBC"
+const CSW_SIGNATURE: u32 = 0x53425355; // "USBS"
+const CBW_LEN: usize = 31;
+// large enough for a full speed packet holding a command block wrapper
+const CBW_PACKET_LEN: usize = 64;
+
+const VENDOR: &[u8; 8] = b"LEGTCMPR";
+const PRODUCT: &[u8; 16] = b"Pico Calc Sdcard";
+const VERSION: &[u8; 4] = b"1.00";
+const SERIAL: &[u8] = b"Pico Calc";
+const DEVICE_ID: &[u8] = b"SdCard";
+
+#[repr(u8)]
+#[derive(Copy, Clone)]
+enum CommandStatus {
+    Passed = 0x00,
+    Failed = 0x01,
+    PhaseError = 0x02,
+}
+
+enum Error {
+    /// the command failed, the host reads why with REQUEST_SENSE
+    Check(Sense),
+    /// the data stage the host asked for doesn't match the command
+    Phase,
+    Endpoint(EndpointError),
+}
+
+impl From<Sense> for Error {
+    fn from(sense: Sense) -> Self {
+        Error::Check(sense)
+    }
+}
+
+impl From<EndpointError> for Error {
+    fn from(e: EndpointError) -> Self {
+        Error::Endpoint(e)
+    }
+}
+
+/// Runs SCSI commands sent by the host against a `BlockDevice`
+pub struct BulkOnly<'b, I: EndpointIn, O: EndpointOut> {
+    bulk_in: I,
+    bulk_out: O,
+    // blocks on their way to or from the device
+    buf: &'b mut [u8],
+    sense: Sense,
+    medium_changed: bool,
+    // data stage of the current command
+    data_in: bool,
+    expected: u32,
+    transferred: u32,
+}
+
+impl<'b, I: EndpointIn, O: EndpointOut> BulkOnly<'b, I, O> {
+    /// `buf` must hold at least two blocks, a larger buffer means fewer,
+    /// larger transfers to and from the device.
+    pub fn new(bulk_in: I, bulk_out: O, buf: &'b mut [u8]) -> Self {
+        assert!(buf.len() >= 2 * BLOCK_SIZE && buf.len() % BLOCK_SIZE == 0);
+
+        Self {
+            bulk_in,
+            bulk_out,
+            buf,
+            sense: Sense::NO_SENSE,
+            medium_changed: false,
+            data_in: false,
+            expected: 0,
+            transferred: 0,
+        }
+    }
+
+    /// Why the last command failed, `Sense::NO_SENSE` if it didn't
+    pub fn sense(&self) -> Sense {
+        self.sense
+    }
+
+    /// Tells the host the medium was swapped. The next command fails with
+    /// UNIT ATTENTION, so the host rereads the capacity and partitions.
+    pub fn medium_changed(&mut self) {
+        self.medium_changed = true;
+    }
+
+    /// Waits for the next command from the host, skipping anything that isn't
+    /// a valid command block wrapper
+    pub async fn read_cbw(&mut self) -> CommandBlockWrapper {
+        let mut packet = [0u8; CBW_PACKET_LEN];
+        loop {
+            match self.bulk_out.read(&mut packet).await {
+                Ok(n) => {
+                    if let Some(cbw) = CommandBlockWrapper::parse(&packet[..n]) {
+                        return cbw;
+                    }
+                }
+                Err(EndpointError::Disabled) => self.bulk_out.wait_enabled().await,
+                Err(EndpointError::BufferOverflow) => (),
+            }
+        }
+    }
+
+    /// Runs the command in `cbw` against `device`, and reports the result to
+    /// the host. Returns the command, so the caller can act on e.g. an eject.
+    pub async fn execute(
+        &mut self,
+        cbw: &CommandBlockWrapper,
+        device: &mut impl BlockDevice,
+    ) -> Result<ScsiCommand, EndpointError> {
+        let command = cbw.command();
+        self.data_in = cbw.data_in();
+        self.expected = cbw.data_len();
+        self.transferred = 0;
+
+        let result = match command {
+            ScsiCommand::Inquiry { .. } | ScsiCommand::RequestSense { .. } => {
+                self.run(command, device).await
+            }
+            _ if self.medium_changed => {
+                self.medium_changed = false;
+                Err(Sense::MEDIUM_CHANGED.into())
+            }
+            _ => self.run(command, device).await,
+        };
+
+        let status = match result {
+            Ok(()) => {
+                self.sense = Sense::NO_SENSE;
+                CommandStatus::Passed
+            }
+            Err(Error::Check(sense)) => {
+                #[cfg(feature = "defmt")]
+                defmt::warn!("scsi command {} failed: {}", command, sense);
+                self.sense = sense;
+                CommandStatus::Failed
+            }
+            Err(Error::Phase) => CommandStatus::PhaseError,
+            Err(Error::Endpoint(e)) => return Err(e),
+        };
+
+        let residue = self.expected - self.transferred;
+        if !self.data_in {
+            // the host sends its whole data stage regardless
+            self.discard_data().await?;
+        }
+        self.send_csw(cbw.tag(), status, residue).await?;
+
+        Ok(command)
+    }
+
+    async fn run(
+        &mut self,
+        command: ScsiCommand,
+        device: &mut impl BlockDevice,
+    ) -> Result<(), Error> {
+        match command {
+            ScsiCommand::Unknown => Err(Sense::INVALID_COMMAND.into()),
+            ScsiCommand::Inquiry {
+                evpd,
+                page_code,
+                alloc_len,
+            } => {
+                let mut response = [0u8; 36];
+                let len = if !evpd {
+                    response[0] = 0x00; // Direct-access block device
+                    response[1] = 0x80; // Removable
+                    response[2] = 0x05; // SPC-3 compliance
+                    response[3] = 0x02; // Response data format
+                    response[4] = 36 - 5; // Additional length
+                    response[8..16].copy_from_slice(VENDOR);
+                    response[16..32].copy_from_slice(PRODUCT);
+                    response[32..36].copy_from_slice(VERSION); // 4-byte firmware version
+                    36
+                } else {
+                    match page_code {
+                        0x00 => {
+                            let page = [
+                                0x00, // Peripheral Qualifier + Peripheral Device Type (0x00 = Direct-access block device)
+                                0x00, // Page Code (same as requested: 0x00)
+                                0x00, 0x03, // Page Length: 3 bytes follow
+                                0x00, // Supported VPD Page: 0x00 (this one — the "Supported VPD Pages" page itself)
+                                0x80, // Supported VPD Page: 0x80 (Unit Serial Number)
+                                0x83, // Supported VPD Page: 0x83 (Device Identification)
+                            ];
+                            response[..page.len()].copy_from_slice(&page);
+                            page.len()
+                        }
+                        0x80 => {
+                            response[..4].copy_from_slice(&[
+                                0x00, // Peripheral Qualifier & Device Type
+                                0x80, // Page Code = 0x80 (Unit Serial Number)
+                                0x00, // Reserved
+                                SERIAL.len() as u8,
+                            ]);
+                            response[4..4 + SERIAL.len()].copy_from_slice(SERIAL);
+                            4 + SERIAL.len()
+                        }
+                        0x83 => {
+                            response[..8].copy_from_slice(&[
+                                0x00,
+                                0x83, // Page code
+                                0x00,
+                                (4 + DEVICE_ID.len()) as u8, // Length
+                                0x02,                        // ASCII identifier
+                                0x01,                        // Identifier type
+                                0x00,                        // Reserved
+                                DEVICE_ID.len() as u8,
+                            ]);
+                            response[8..8 + DEVICE_ID.len()].copy_from_slice(DEVICE_ID);
+                            8 + DEVICE_ID.len()
+                        }
+                        _ => return Err(Sense::INVALID_FIELD_IN_CDB.into()),
+                    }
+                };
+
+                let len = len.min(alloc_len as usize);
+                self.send(&response[..len]).await
+            }
+            ScsiCommand::TestUnitReady => {
+                ready(device)?;
+                Ok(())
+            }
+            ScsiCommand::RequestSense { desc: _, alloc_len } => {
+                // only fixed format sense data is supported
+                let sense = if self.medium_changed {
+                    self.medium_changed = false;
+                    Sense::MEDIUM_CHANGED
+                } else {
+                    self.sense
+                };
+
+                let response = sense.to_fixed_format();
+                let len = response.len().min(alloc_len as usize);
+                self.send(&response[..len]).await
+            }
+            ScsiCommand::ModeSense6 {
+                dbd: _,
+                page_control: _,
+                page_code: _,
+                subpage_code: _,
+                alloc_len,
+            } => {
+                // DBD=0, no block descriptors; total length = 4
+                let response = [
+                    0x03, // Mode data length (excluding this byte): 3
+                    0x00, // Medium type
+                    0x00, // Device-specific parameter
+                    0x00, // Block descriptor length = 0 (DBD = 1)
+                ];
+
+                let len = alloc_len.min(response.len() as u8) as usize;
+                self.send(&response[..len]).await
+            }
+            ScsiCommand::ModeSense10 {
+                dbd: _,
+                page_control: _,
+                page_code: _,
+                subpage_code: _,
+                alloc_len,
+            } => {
+                let response = [
+                    0x00, 0x06, // Mode data length = 6
+                    0x00, // Medium type
+                    0x00, // Device-specific parameter
+                    0x00, 0x00, // Reserved
+                    0x00, 0x00, // Block descriptor length = 0
+                ];
+
+                let len = alloc_len.min(response.len() as u16) as usize;
+                self.send(&response[..len]).await
+            }
+            ScsiCommand::ReadCapacity10 => {
+                let total_blocks = ready(device)?.num_blocks();
+                // a capacity that doesn't fit tells the host to use READ_CAPACITY_16
+                let last_lba = total_blocks.saturating_sub(1).min(u32::MAX as u64) as u32;
+
+                let mut response = [0u8; 8];
+                response[..4].copy_from_slice(&last_lba.to_be_bytes());
+                response[4..].copy_from_slice(&(BLOCK_SIZE as u32).to_be_bytes());
+                self.send(&response).await
+            }
+            ScsiCommand::ReadCapacity16 { alloc_len } => {
+                let total_blocks = ready(device)?.num_blocks();
+                let last_lba = total_blocks.saturating_sub(1);
+
+                let mut response = [0u8; 32]; // 20 reserved bytes zeroed
+                response[..8].copy_from_slice(&last_lba.to_be_bytes()); // 8 bytes last LBA
+                response[8..12].copy_from_slice(&(BLOCK_SIZE as u32).to_be_bytes()); // 4 bytes block length
+
+                let len = alloc_len.min(response.len() as u32) as usize;
+                self.send(&response[..len]).await
+            }
+            ScsiCommand::ReadFormatCapacities { alloc_len } => {
+                let num_blocks = ready(device)?.num_blocks().min(u32::MAX as u64) as u32;
+
+                let mut response = [0u8; 12];
+
+                // Capacity List Length (8 bytes follows)
+                response[3] = 8;
+
+                // Descriptor
+                response[4..8].copy_from_slice(&num_blocks.to_be_bytes());
+                response[8] = 0x03; // formatted media
+                response[9..12].copy_from_slice(&(BLOCK_SIZE as u32).to_be_bytes()[1..4]); // only 3 bytes
+
+                let len = alloc_len.min(response.len() as u16) as usize;
+                self.send(&response[..len]).await
+            }
+            ScsiCommand::Read { lba, len } => {
+                check_range(device, lba, len)?;
+                self.check_data_stage(len, true)?;
+
+                let mut lba = lba;
+                let mut remaining = len;
+                while remaining > 0 {
+                    let blocks = remaining.min((self.buf.len() / BLOCK_SIZE) as u64);
+                    let bytes = blocks as usize * BLOCK_SIZE;
+
+                    device
+                        .read_blocks(lba, &mut self.buf[..bytes])
+                        .map_err(|_| Sense::UNRECOVERED_READ_ERROR)?;
+                    self.send_buf(bytes).await?;
+
+                    lba += blocks;
+                    remaining -= blocks;
+                }
+                Ok(())
+            }
+            ScsiCommand::Write { lba, len } => {
+                check_range(device, lba, len)?;
+                self.check_data_stage(len, false)?;
+
+                let mut lba = lba;
+                let mut remaining = len;
+                while remaining > 0 {
+                    let blocks = remaining.min((self.buf.len() / BLOCK_SIZE) as u64);
+                    let bytes = blocks as usize * BLOCK_SIZE;
+
+                    self.receive(bytes).await?;
+                    device
+                        .write_blocks(lba, &self.buf[..bytes])
+                        .map_err(|_| Sense::WRITE_ERROR)?;
+
+                    lba += blocks;
+                    remaining -= blocks;
+                }
+                Ok(())
+            }
+            ScsiCommand::Verify {
+                lba,
+                len,
+                byte_check,
+            } => {
+                let compare = match byte_check {
+                    0 => false,
+                    1 => true,
+                    _ => return Err(Sense::INVALID_FIELD_IN_CDB.into()),
+                };
+                check_range(device, lba, len)?;
+                if compare {
+                    self.check_data_stage(len, false)?;
+                }
+
+                // the host's data goes in the first half of the buffer, the
+                // device's in the second
+                let half = self.buf.len() / BLOCK_SIZE / 2 * BLOCK_SIZE;
+                let mut lba = lba;
+                let mut remaining = len;
+                while remaining > 0 {
+                    let blocks = remaining.min((half / BLOCK_SIZE) as u64);
+                    let bytes = blocks as usize * BLOCK_SIZE;
+
+                    if compare {
+                        self.receive(bytes).await?;
+                    }
+                    device
+                        .read_blocks(lba, &mut self.buf[half..half + bytes])
+                        .map_err(|_| Sense::UNRECOVERED_READ_ERROR)?;
+                    if compare && self.buf[..bytes] != self.buf[half..half + bytes] {
+                        return Err(Sense::MISCOMPARE.into());
+                    }
+
+                    lba += blocks;
+                    remaining -= blocks;
+                }
+                Ok(())
+            }
+            ScsiCommand::SynchronizeCache => {
+                ready(device)?.flush().map_err(|_| Sense::WRITE_ERROR)?;
+                Ok(())
+            }
+            ScsiCommand::PreventAllowMediumRemoval { prevent: _prevent } => Ok(()),
+            ScsiCommand::StartStopUnit { .. } => Ok(()),
+        }
+    }
+
+    // makes sure the host asked for a data stage that fits `blocks` blocks in
+    // the direction the command moves them
+    fn check_data_stage(&self, blocks: u64, data_in: bool) -> Result<(), Error> {
+        if blocks == 0 {
+            return Ok(());
+        }
+        let bytes = blocks * BLOCK_SIZE as u64;
+        if self.data_in != data_in || bytes > (self.expected - self.transferred) as u64 {
+            return Err(Error::Phase);
+        }
+        Ok(())
+    }
+
+    // sends as much of `data` as the host asked for
+    async fn send(&mut self, data: &[u8]) -> Result<(), Error> {
+        let len = data.len().min((self.expected - self.transferred) as usize);
+        if !self.data_in || len == 0 {
+            return Ok(());
+        }
+
+        write_packets(&mut self.bulk_in, &data[..len]).await?;
+        self.transferred += len as u32;
+        Ok(())
+    }
+
+    // sends the first `len` bytes of the block buffer
+    async fn send_buf(&mut self, len: usize) -> Result<(), Error> {
+        write_packets(&mut self.bulk_in, &self.buf[..len]).await?;
+        self.transferred += len as u32;
+        Ok(())
+    }
+
+    // fills the first `len` bytes of the block buffer from the host
+    async fn receive(&mut self, len: usize) -> Result<(), Error> {
+        let packet_size = self.bulk_out.info().max_packet_size as usize;
+        let mut received = 0;
+        while received < len {
+            let end = (received + packet_size).min(len);
+            let n = self.bulk_out.read(&mut self.buf[received..end]).await?;
+            let short = received + n < end;
+            received += n;
+            self.transferred += n as u32;
+
+            // a short packet ends the data stage early
+            if short {
+                return Err(Error::Phase);
+            }
+        }
+        Ok(())
+    }
+
+    // reads and drops whatever the host still has to send for this command
+    async fn discard_data(&mut self) -> Result<(), EndpointError> {
+        let packet_size = self.bulk_out.info().max_packet_size as usize;
+        while self.transferred < self.expected {
+            let n = self.bulk_out.read(&mut self.buf[..packet_size]).await?;
+            self.transferred += n as u32;
+            if n < packet_size {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    async fn send_csw(
+        &mut self,
+        tag: u32,
+        status: CommandStatus,
+        residue: u32,
+    ) -> Result<(), EndpointError> {
+        let mut csw = [0u8; 13];
+        csw[0..4].copy_from_slice(&CSW_SIGNATURE.to_le_bytes());
+        csw[4..8].copy_from_slice(&tag.to_le_bytes());
+        csw[8..12].copy_from_slice(&residue.to_le_bytes());
+        csw[12] = status as u8;
+        self.bulk_in.write(&csw).await
+    }
+}
+
+async fn write_packets(bulk_in: &mut impl EndpointIn, data: &[u8]) -> Result<(), EndpointError> {
+    let packet_size = bulk_in.info().max_packet_size as usize;
+    for packet in data.chunks(packet_size) {
+        bulk_in.write(packet).await?;
+    }
+    Ok(())
+}
+
+fn ready<B: BlockDevice>(device: &mut B) -> Result<&mut B, Sense> {
+    if device.is_present() {
+        Ok(device)
+    } else {
+        Err(Sense::MEDIUM_NOT_PRESENT)
+    }
+}
+
+fn check_range(device: &mut impl BlockDevice, lba: u64, len: u64) -> Result<(), Sense> {
+    let num_blocks = ready(device)?.num_blocks();
+    match lba.checked_add(len) {
+        Some(end) if end <= num_blocks => Ok(()),
+        _ => Err(Sense::LBA_OUT_OF_RANGE),
+    }
+}
+
+/// A command from the host, with the data stage it expects
+#[repr(C, packed)]
+#[allow(non_snake_case)]
+pub struct CommandBlockWrapper {
+    dCBWSignature: u32,
+    dCBWTag: u32,
+    dCBWDataTransferLength: u32,
+    bmCBWFlags: u8,
+    bCBWLUN: u8,
+    bCBWCBLength: u8,
+    CBWCB: [u8; 16],
+}
+
+#[allow(non_snake_case)]
+impl CommandBlockWrapper {
+    pub fn parse(buf: &[u8]) -> Option<Self> {
+        if buf.len() != CBW_LEN {
+            return None;
+        }
+        let dCBWSignature = u32::from_le_bytes(buf[0..4].try_into().ok()?);
+        if dCBWSignature != CBW_SIGNATURE {
+            return None; // invalid signature
+        }
+        Some(Self {
+            dCBWSignature,
+            dCBWTag: u32::from_le_bytes(buf[4..8].try_into().ok()?),
+            dCBWDataTransferLength: u32::from_le_bytes(buf[8..12].try_into().ok()?),
+            bmCBWFlags: buf[12],
+            bCBWLUN: buf[13],
+            bCBWCBLength: buf[14],
+            CBWCB: buf[15..31].try_into().ok()?,
+        })
+    }
+
+    pub fn tag(&self) -> u32 {
+        self.dCBWTag
+    }
+
+    /// Length of the data stage the host expects
+    pub fn data_len(&self) -> u32 {
+        self.dCBWDataTransferLength
+    }
+
+    /// Whether the data stage goes from the device to the host
+    pub fn data_in(&self) -> bool {
+        self.bmCBWFlags & 0x80 != 0
+    }
+
+    pub fn command(&self) -> ScsiCommand {
+        parse_cb(&self.CBWCB)
+    }
+}
@@ -0,0 +1,43 @@
+//! USB mass storage over the bulk-only transport, using the SCSI transparent
+//! command set.
+//!
+//! Nothing here knows about the SD card or the USB peripheral. The kernel
+//! hands `BulkOnly` the class's bulk endpoints and a `BlockDevice`, which keeps
+//! the command handling testable on the host.
+
+#![no_std]
+
+mod bulk_only;
+pub mod scsi;
+mod sense;
+
+pub use bulk_only::{BulkOnly, CommandBlockWrapper};
+pub use sense::{Sense, SenseKey};
+
+/// USB interface class for mass storage
+pub const CLASS_MASS_STORAGE: u8 = 0x08;
+/// USB interface protocol for the bulk-only transport
+pub const PROTOCOL_BULK_ONLY: u8 = 0x50;
+
+/// Size of every block exposed to the host
+pub const BLOCK_SIZE: usize = 512;
+
+/// Storage exposed to the host, addressed in `BLOCK_SIZE` blocks
+pub trait BlockDevice {
+    type Error;
+
+    /// Whether there is a medium to access, block commands fail with
+    /// NOT READY while there isn't
+    fn is_present(&self) -> bool;
+
+    fn num_blocks(&self) -> u64;
+
+    /// Reads `buf.len() / BLOCK_SIZE` blocks starting at `lba`
+    fn read_blocks(&mut self, lba: u64, buf: &mut [u8]) -> Result<(), Self::Error>;
+
+    /// Writes `buf.len() / BLOCK_SIZE` blocks starting at `lba`
+    fn write_blocks(&mut self, lba: u64, buf: &[u8]) -> Result<(), Self::Error>;
+
+    /// Makes sure everything written so far is on the medium
+    fn flush(&mut self) -> Result<(), Self::Error>;
+}
@@ -19,7 +19,14 @@ const READ_10: u8 = 0x28;
 const READ_16: u8 = 0x88;
 const READ_CAPACITY_10: u8 = 0x25;
 const READ_CAPACITY_16: u8 = 0x9E;
+const READ_12: u8 = 0xA8;
 const WRITE_10: u8 = 0x2A;
+const WRITE_12: u8 = 0xAA;
+const WRITE_16: u8 = 0x8A;
+const VERIFY_10: u8 = 0x2F;
+const VERIFY_16: u8 = 0x8F;
+const SYNCHRONIZE_CACHE_10: u8 = 0x35;
+const SYNCHRONIZE_CACHE_16: u8 = 0x91;
 
 /* MMC */
 const READ_FORMAT_CAPACITIES: u8 = 0x23;
@@ -75,6 +82,14 @@ pub enum ScsiCommand {
         lba: u64,
         len: u64,
     },
+    /// `byte_check` 0 only checks the blocks can be read, 1 compares them
+    /// against data sent by the host
+    Verify {
+        lba: u64,
+        len: u64,
+        byte_check: u8,
+    },
+    SynchronizeCache,
 
     /* MMC */
     ReadFormatCapacities {
@@ -102,8 +117,7 @@ pub enum PageControl {
     SavedValues = 0b11,
 }
 
-#[allow(dead_code)]
-pub fn parse_cb(cb: &[u8]) -> ScsiCommand {
+pub fn parse_cb(cb: &[u8; 16]) -> ScsiCommand {
     match cb[0] {
         TEST_UNIT_READY => ScsiCommand::TestUnitReady,
         INQUIRY => ScsiCommand::Inquiry {
@@ -127,10 +141,33 @@ pub fn parse_cb(cb: &[u8]) -> ScsiCommand {
             lba: u64::from_be_bytes((&cb[2..10]).try_into().unwrap()),
             len: u32::from_be_bytes((&cb[10..14]).try_into().unwrap()) as u64,
         },
+        READ_12 => ScsiCommand::Read {
+            lba: u32::from_be_bytes([cb[2], cb[3], cb[4], cb[5]]) as u64,
+            len: u32::from_be_bytes([cb[6], cb[7], cb[8], cb[9]]) as u64,
+        },
         WRITE_10 => ScsiCommand::Write {
             lba: u32::from_be_bytes([cb[2], cb[3], cb[4], cb[5]]) as u64,
             len: u16::from_be_bytes([cb[7], cb[8]]) as u64,
         },
+        WRITE_12 => ScsiCommand::Write {
+            lba: u32::from_be_bytes([cb[2], cb[3], cb[4], cb[5]]) as u64,
+            len: u32::from_be_bytes([cb[6], cb[7], cb[8], cb[9]]) as u64,
+        },
+        WRITE_16 => ScsiCommand::Write {
+            lba: u64::from_be_bytes((&cb[2..10]).try_into().unwrap()),
+            len: u32::from_be_bytes((&cb[10..14]).try_into().unwrap()) as u64,
+        },
+        VERIFY_10 => ScsiCommand::Verify {
+            lba: u32::from_be_bytes([cb[2], cb[3], cb[4], cb[5]]) as u64,
+            len: u16::from_be_bytes([cb[7], cb[8]]) as u64,
+            byte_check: (cb[1] >> 1) & 0b11,
+        },
+        VERIFY_16 => ScsiCommand::Verify {
+            lba: u64::from_be_bytes((&cb[2..10]).try_into().unwrap()),
+            len: u32::from_be_bytes((&cb[10..14]).try_into().unwrap()) as u64,
+            byte_check: (cb[1] >> 1) & 0b11,
+        },
+        SYNCHRONIZE_CACHE_10 | SYNCHRONIZE_CACHE_16 => ScsiCommand::SynchronizeCache,
         MODE_SENSE_6 => ScsiCommand::ModeSense6 {
             dbd: (cb[1] & 0b00001000) != 0,
             page_control: PageControl::try_from_primitive(cb[2] >> 6).unwrap(),
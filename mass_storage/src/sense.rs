@@ -0,0 +1,50 @@
+/// Sense key, the broad class of the last error
+#[repr(u8)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum SenseKey {
+    NoSense = 0x00,
+    NotReady = 0x02,
+    MediumError = 0x03,
+    IllegalRequest = 0x05,
+    UnitAttention = 0x06,
+    Miscompare = 0x0E,
+}
+
+/// Why the last command failed, reported to the host by REQUEST_SENSE
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Sense {
+    pub key: SenseKey,
+    /// additional sense code
+    pub asc: u8,
+    /// additional sense code qualifier
+    pub ascq: u8,
+}
+
+impl Sense {
+    pub const NO_SENSE: Self = Self::new(SenseKey::NoSense, 0x00, 0x00);
+    pub const MEDIUM_NOT_PRESENT: Self = Self::new(SenseKey::NotReady, 0x3A, 0x00);
+    pub const UNRECOVERED_READ_ERROR: Self = Self::new(SenseKey::MediumError, 0x11, 0x00);
+    pub const WRITE_ERROR: Self = Self::new(SenseKey::MediumError, 0x0C, 0x00);
+    pub const INVALID_COMMAND: Self = Self::new(SenseKey::IllegalRequest, 0x20, 0x00);
+    pub const LBA_OUT_OF_RANGE: Self = Self::new(SenseKey::IllegalRequest, 0x21, 0x00);
+    pub const INVALID_FIELD_IN_CDB: Self = Self::new(SenseKey::IllegalRequest, 0x24, 0x00);
+    pub const MEDIUM_CHANGED: Self = Self::new(SenseKey::UnitAttention, 0x28, 0x00);
+    pub const MISCOMPARE: Self = Self::new(SenseKey::Miscompare, 0x1D, 0x00);
+
+    pub const fn new(key: SenseKey, asc: u8, ascq: u8) -> Self {
+        Self { key, asc, ascq }
+    }
+
+    /// Fixed format sense data, as returned by REQUEST_SENSE
+    pub fn to_fixed_format(self) -> [u8; 18] {
+        let mut data = [0u8; 18];
+        data[0] = 0x70; // current error, fixed format
+        data[2] = self.key as u8;
+        data[7] = 10; // additional sense length
+        data[12] = self.asc;
+        data[13] = self.ascq;
+        data
+    }
+}
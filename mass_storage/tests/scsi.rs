@@ -0,0 +1,477 @@
+use std::{cell::RefCell, collections::VecDeque, rc::Rc};
+
+use embassy_futures::block_on;
+use embassy_usb_driver::{
+    Direction, Endpoint, EndpointAddress, EndpointError, EndpointIn, EndpointInfo, EndpointOut,
+    EndpointType,
+};
+use mass_storage::{
+    BLOCK_SIZE, BlockDevice, BulkOnly, Sense, SenseKey,
+    scsi::{ScsiCommand, parse_cb},
+};
+
+const PACKET_SIZE: u16 = 64;
+
+const STATUS_PASSED: u8 = 0x00;
+const STATUS_FAILED: u8 = 0x01;
+const STATUS_PHASE_ERROR: u8 = 0x02;
+
+/// Packets queued by the host, and everything the device sent back
+#[derive(Default)]
+struct Bus {
+    to_device: VecDeque<Vec<u8>>,
+    to_host: Vec<u8>,
+}
+
+struct In(Rc<RefCell<Bus>>, EndpointInfo);
+struct Out(Rc<RefCell<Bus>>, EndpointInfo);
+
+fn info(dir: Direction) -> EndpointInfo {
+    EndpointInfo {
+        addr: EndpointAddress::from_parts(1, dir),
+        ep_type: EndpointType::Bulk,
+        max_packet_size: PACKET_SIZE,
+        interval_ms: 0,
+    }
+}
+
+impl Endpoint for In {
+    fn info(&self) -> &EndpointInfo {
+        &self.1
+    }
+
+    async fn wait_enabled(&mut self) {}
+}
+
+impl EndpointIn for In {
+    async fn write(&mut self, buf: &[u8]) -> Result<(), EndpointError> {
+        assert!(buf.len() <= PACKET_SIZE as usize);
+        self.0.borrow_mut().to_host.extend_from_slice(buf);
+        Ok(())
+    }
+}
+
+impl Endpoint for Out {
+    fn info(&self) -> &EndpointInfo {
+        &self.1
+    }
+
+    async fn wait_enabled(&mut self) {
+        panic!("the device read more than the host sent");
+    }
+}
+
+impl EndpointOut for Out {
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, EndpointError> {
+        let packet = self
+            .0
+            .borrow_mut()
+            .to_device
+            .pop_front()
+            .ok_or(EndpointError::Disabled)?;
+        if packet.len() > buf.len() {
+            return Err(EndpointError::BufferOverflow);
+        }
+        buf[..packet.len()].copy_from_slice(&packet);
+        Ok(packet.len())
+    }
+}
+
+struct RamDisk {
+    data: Vec<u8>,
+    present: bool,
+    flushes: usize,
+}
+
+impl RamDisk {
+    fn new(blocks: usize) -> Self {
+        Self {
+            data: (0..blocks * BLOCK_SIZE)
+                .map(|i| (i / BLOCK_SIZE) as u8)
+                .collect(),
+            present: true,
+            flushes: 0,
+        }
+    }
+
+    fn block(&self, lba: usize) -> &[u8] {
+        &self.data[lba * BLOCK_SIZE..(lba + 1) * BLOCK_SIZE]
+    }
+}
+
+impl BlockDevice for RamDisk {
+    type Error = ();
+
+    fn is_present(&self) -> bool {
+        self.present
+    }
+
+    fn num_blocks(&self) -> u64 {
+        (self.data.len() / BLOCK_SIZE) as u64
+    }
+
+    fn read_blocks(&mut self, lba: u64, buf: &mut [u8]) -> Result<(), ()> {
+        let start = lba as usize * BLOCK_SIZE;
+        buf.copy_from_slice(&self.data[start..start + buf.len()]);
+        Ok(())
+    }
+
+    fn write_blocks(&mut self, lba: u64, buf: &[u8]) -> Result<(), ()> {
+        let start = lba as usize * BLOCK_SIZE;
+        self.data[start..start + buf.len()].copy_from_slice(buf);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), ()> {
+        self.flushes += 1;
+        Ok(())
+    }
+}
+
+struct Csw {
+    tag: u32,
+    residue: u32,
+    status: u8,
+}
+
+struct Harness {
+    bus: Rc<RefCell<Bus>>,
+    msc: BulkOnly<'static, In, Out>,
+    disk: RamDisk,
+    next_tag: u32,
+}
+
+impl Harness {
+    fn new(disk: RamDisk) -> Self {
+        let bus = Rc::new(RefCell::new(Bus::default()));
+        let buf = Box::leak(vec![0u8; 4 * BLOCK_SIZE].into_boxed_slice());
+        let msc = BulkOnly::new(
+            In(bus.clone(), info(Direction::In)),
+            Out(bus.clone(), info(Direction::Out)),
+            buf,
+        );
+        Self {
+            bus,
+            msc,
+            disk,
+            next_tag: 1,
+        }
+    }
+
+    fn queue(&self, data: &[u8]) {
+        let mut bus = self.bus.borrow_mut();
+        for packet in data.chunks(PACKET_SIZE as usize) {
+            bus.to_device.push_back(packet.to_vec());
+        }
+    }
+
+    /// Sends a command, with `data_out` as its data stage if the host expects
+    /// to send `data_len` bytes, and returns the data sent back and the CSW
+    fn command(
+        &mut self,
+        cb: &[u8],
+        data_len: u32,
+        data_in: bool,
+        data_out: &[u8],
+    ) -> (Vec<u8>, Csw) {
+        let tag = self.next_tag;
+        self.next_tag += 1;
+
+        self.queue(&cbw(tag, data_len, data_in, cb));
+        self.queue(data_out);
+
+        let cbw = block_on(self.msc.read_cbw());
+        block_on(self.msc.execute(&cbw, &mut self.disk)).expect("endpoint error");
+
+        let mut bus = self.bus.borrow_mut();
+        assert!(bus.to_device.is_empty(), "data stage was not consumed");
+        let sent = std::mem::take(&mut bus.to_host);
+        let (data, csw) = sent.split_at(sent.len() - 13);
+
+        assert_eq!(&csw[0..4], b"USBS");
+        let csw = Csw {
+            tag: u32::from_le_bytes(csw[4..8].try_into().unwrap()),
+            residue: u32::from_le_bytes(csw[8..12].try_into().unwrap()),
+            status: csw[12],
+        };
+        assert_eq!(csw.tag, tag);
+        (data.to_vec(), csw)
+    }
+
+    fn request_sense(&mut self) -> Sense {
+        let (data, csw) = self.command(&[0x03, 0, 0, 0, 18], 18, true, &[]);
+        assert_eq!(csw.status, STATUS_PASSED);
+        assert_eq!(data.len(), 18);
+        assert_eq!(data[0], 0x70);
+
+        let key = match data[2] {
+            0x00 => SenseKey::NoSense,
+            0x02 => SenseKey::NotReady,
+            0x03 => SenseKey::MediumError,
+            0x05 => SenseKey::IllegalRequest,
+            0x06 => SenseKey::UnitAttention,
+            0x0E => SenseKey::Miscompare,
+            key => panic!("unexpected sense key {key:#x}"),
+        };
+        Sense::new(key, data[12], data[13])
+    }
+}
+
+fn cbw(tag: u32, data_len: u32, data_in: bool, cb: &[u8]) -> Vec<u8> {
+    let mut cbw = vec![0u8; 31];
+    cbw[0..4].copy_from_slice(b"USBC");
+    cbw[4..8].copy_from_slice(&tag.to_le_bytes());
+    cbw[8..12].copy_from_slice(&data_len.to_le_bytes());
+    cbw[12] = if data_in { 0x80 } else { 0x00 };
+    cbw[14] = cb.len() as u8;
+    cbw[15..15 + cb.len()].copy_from_slice(cb);
+    cbw
+}
+
+fn cdb10(opcode: u8, flags: u8, lba: u32, len: u16) -> Vec<u8> {
+    let mut cb = vec![opcode, flags];
+    cb.extend_from_slice(&lba.to_be_bytes());
+    cb.push(0);
+    cb.extend_from_slice(&len.to_be_bytes());
+    cb.push(0);
+    cb
+}
+
+fn cdb12(opcode: u8, lba: u32, len: u32) -> Vec<u8> {
+    let mut cb = vec![opcode, 0];
+    cb.extend_from_slice(&lba.to_be_bytes());
+    cb.extend_from_slice(&len.to_be_bytes());
+    cb.extend_from_slice(&[0, 0]);
+    cb
+}
+
+fn cdb16(opcode: u8, flags: u8, lba: u64, len: u32) -> Vec<u8> {
+    let mut cb = vec![opcode, flags];
+    cb.extend_from_slice(&lba.to_be_bytes());
+    cb.extend_from_slice(&len.to_be_bytes());
+    cb.extend_from_slice(&[0, 0]);
+    cb
+}
+
+fn padded(cb: &[u8]) -> [u8; 16] {
+    let mut padded = [0u8; 16];
+    padded[..cb.len()].copy_from_slice(cb);
+    padded
+}
+
+#[test]
+fn parses_new_commands() {
+    assert!(matches!(
+        parse_cb(&padded(&cdb12(0xA8, 7, 3))),
+        ScsiCommand::Read { lba: 7, len: 3 }
+    ));
+    assert!(matches!(
+        parse_cb(&padded(&cdb12(0xAA, 7, 3))),
+        ScsiCommand::Write { lba: 7, len: 3 }
+    ));
+    assert!(matches!(
+        parse_cb(&padded(&cdb16(0x8A, 0, 1 << 33, 3))),
+        ScsiCommand::Write {
+            lba: 0x2_0000_0000,
+            len: 3
+        }
+    ));
+    assert!(matches!(
+        parse_cb(&padded(&cdb10(0x2F, 0b010, 7, 3))),
+        ScsiCommand::Verify {
+            lba: 7,
+            len: 3,
+            byte_check: 1
+        }
+    ));
+    assert!(matches!(
+        parse_cb(&padded(&cdb16(0x8F, 0, 7, 3))),
+        ScsiCommand::Verify {
+            lba: 7,
+            len: 3,
+            byte_check: 0
+        }
+    ));
+    assert!(matches!(
+        parse_cb(&padded(&[0x35])),
+        ScsiCommand::SynchronizeCache
+    ));
+    assert!(matches!(
+        parse_cb(&padded(&[0x91])),
+        ScsiCommand::SynchronizeCache
+    ));
+    assert!(matches!(parse_cb(&padded(&[0xFF])), ScsiCommand::Unknown));
+}
+
+#[test]
+fn invalid_cbw_is_skipped() {
+    let mut harness = Harness::new(RamDisk::new(8));
+    harness.queue(b"not a command block wrapper");
+
+    let (_, csw) = harness.command(&[0x00], 0, false, &[]);
+    assert_eq!(csw.status, STATUS_PASSED);
+}
+
+#[test]
+fn write_16_then_read_12() {
+    // larger than the transfer buffer, so it takes several rounds
+    let blocks = 6;
+    let mut harness = Harness::new(RamDisk::new(16));
+    let data: Vec<u8> = (0..blocks * BLOCK_SIZE).map(|i| (i % 251) as u8).collect();
+
+    let (_, csw) = harness.command(
+        &cdb16(0x8A, 0, 3, blocks as u32),
+        data.len() as u32,
+        false,
+        &data,
+    );
+    assert_eq!(csw.status, STATUS_PASSED);
+    assert_eq!(csw.residue, 0);
+    assert_eq!(
+        &harness.disk.data[3 * BLOCK_SIZE..9 * BLOCK_SIZE],
+        &data[..]
+    );
+    assert_eq!(harness.disk.block(2), &[2; BLOCK_SIZE]);
+    assert_eq!(harness.disk.block(9), &[9; BLOCK_SIZE]);
+
+    let (read, csw) = harness.command(&cdb12(0xA8, 3, blocks as u32), data.len() as u32, true, &[]);
+    assert_eq!(csw.status, STATUS_PASSED);
+    assert_eq!(read, data);
+}
+
+#[test]
+fn write_12() {
+    let mut harness = Harness::new(RamDisk::new(8));
+    let data = vec![0xAB; BLOCK_SIZE];
+
+    let (_, csw) = harness.command(&cdb12(0xAA, 5, 1), BLOCK_SIZE as u32, false, &data);
+    assert_eq!(csw.status, STATUS_PASSED);
+    assert_eq!(harness.disk.block(5), &data[..]);
+}
+
+#[test]
+fn unknown_command_sets_illegal_request() {
+    let mut harness = Harness::new(RamDisk::new(8));
+
+    let (_, csw) = harness.command(&[0xFF], 0, false, &[]);
+    assert_eq!(csw.status, STATUS_FAILED);
+    assert_eq!(harness.request_sense(), Sense::INVALID_COMMAND);
+
+    // sense data only describes the last command
+    assert_eq!(harness.request_sense(), Sense::NO_SENSE);
+}
+
+#[test]
+fn missing_medium_is_not_ready() {
+    let mut disk = RamDisk::new(8);
+    disk.present = false;
+    let mut harness = Harness::new(disk);
+
+    let (_, csw) = harness.command(&[0x00], 0, false, &[]);
+    assert_eq!(csw.status, STATUS_FAILED);
+    assert_eq!(harness.request_sense(), Sense::MEDIUM_NOT_PRESENT);
+
+    // the data stage of a failed write is still consumed
+    let data = vec![0; 2 * BLOCK_SIZE];
+    let (_, csw) = harness.command(&cdb10(0x2A, 0, 0, 2), data.len() as u32, false, &data);
+    assert_eq!(csw.status, STATUS_FAILED);
+    assert_eq!(csw.residue, data.len() as u32);
+    assert_eq!(harness.request_sense(), Sense::MEDIUM_NOT_PRESENT);
+}
+
+#[test]
+fn out_of_range_is_rejected() {
+    let mut harness = Harness::new(RamDisk::new(8));
+
+    let (_, csw) = harness.command(&cdb10(0x28, 0, 7, 2), 2 * BLOCK_SIZE as u32, true, &[]);
+    assert_eq!(csw.status, STATUS_FAILED);
+    assert_eq!(harness.request_sense(), Sense::LBA_OUT_OF_RANGE);
+
+    let data = vec![0xFF; BLOCK_SIZE];
+    let (_, csw) = harness.command(
+        &cdb16(0x8A, 0, u64::MAX, 1),
+        data.len() as u32,
+        false,
+        &data,
+    );
+    assert_eq!(csw.status, STATUS_FAILED);
+    assert_eq!(harness.request_sense(), Sense::LBA_OUT_OF_RANGE);
+    assert!(harness.disk.data.iter().all(|&b| b != 0xFF));
+}
+
+#[test]
+fn verify_checks_bytes() {
+    let mut harness = Harness::new(RamDisk::new(8));
+
+    let (_, csw) = harness.command(&cdb10(0x2F, 0, 0, 8), 0, false, &[]);
+    assert_eq!(csw.status, STATUS_PASSED);
+
+    let matching = harness.disk.data[2 * BLOCK_SIZE..5 * BLOCK_SIZE].to_vec();
+    let (_, csw) = harness.command(
+        &cdb10(0x2F, 0b010, 2, 3),
+        matching.len() as u32,
+        false,
+        &matching,
+    );
+    assert_eq!(csw.status, STATUS_PASSED);
+
+    let mut different = matching.clone();
+    different[2 * BLOCK_SIZE + 17] ^= 1;
+    let (_, csw) = harness.command(
+        &cdb16(0x8F, 0b010, 2, 3),
+        different.len() as u32,
+        false,
+        &different,
+    );
+    assert_eq!(csw.status, STATUS_FAILED);
+    assert_eq!(harness.request_sense(), Sense::MISCOMPARE);
+}
+
+#[test]
+fn synchronize_cache_flushes() {
+    let mut harness = Harness::new(RamDisk::new(8));
+
+    let (_, csw) = harness.command(&cdb10(0x35, 0, 0, 0), 0, false, &[]);
+    assert_eq!(csw.status, STATUS_PASSED);
+    let (_, csw) = harness.command(&cdb16(0x91, 0, 0, 0), 0, false, &[]);
+    assert_eq!(csw.status, STATUS_PASSED);
+    assert_eq!(harness.disk.flushes, 2);
+}
+
+#[test]
+fn medium_change_is_reported_once() {
+    let mut harness = Harness::new(RamDisk::new(8));
+    harness.msc.medium_changed();
+
+    let (_, csw) = harness.command(&[0x00], 0, false, &[]);
+    assert_eq!(csw.status, STATUS_FAILED);
+    assert_eq!(harness.request_sense(), Sense::MEDIUM_CHANGED);
+
+    let (_, csw) = harness.command(&[0x00], 0, false, &[]);
+    assert_eq!(csw.status, STATUS_PASSED);
+}
+
+#[test]
+fn short_data_stage_is_a_phase_error() {
+    let mut harness = Harness::new(RamDisk::new(8));
+
+    // the host only expects one of the two blocks
+    let (data, csw) = harness.command(&cdb10(0x28, 0, 0, 2), BLOCK_SIZE as u32, true, &[]);
+    assert_eq!(csw.status, STATUS_PHASE_ERROR);
+    assert!(data.is_empty());
+}
+
+#[test]
+fn read_capacity() {
+    let mut harness = Harness::new(RamDisk::new(8));
+
+    let (data, csw) = harness.command(&[0x25], 8, true, &[]);
+    assert_eq!(csw.status, STATUS_PASSED);
+    assert_eq!(data, [0, 0, 0, 7, 0, 0, 2, 0]);
+
+    // allocation length truncates the response, the rest is residue
+    let (data, csw) = harness.command(&cdb16(0x9E, 0x10, 0, 12), 32, true, &[]);
+    assert_eq!(csw.status, STATUS_PASSED);
+    assert_eq!(data, [0, 0, 0, 0, 0, 0, 0, 7, 0, 0, 2, 0]);
+    assert_eq!(csw.residue, 20);
+}
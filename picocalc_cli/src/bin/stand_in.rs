@@ -0,0 +1,28 @@
+//! Serves a directory over a pseudo terminal like a PicoCalc would, to try
+//! the `picocalc` CLI without one, e.g.
+//! `picocalc-stand-in ./sdcard` and then `picocalc --port /dev/pts/5 ls`
+
+use std::path::PathBuf;
+
+use anyhow::Context;
+use picocalc_cli::StandIn;
+use serialport::{SerialPort, TTYPort};
+
+fn main() -> anyhow::Result<()> {
+    let root = std::env::args_os()
+        .nth(1)
+        .map_or_else(|| PathBuf::from("."), PathBuf::from);
+
+    let (mut device, host) = TTYPort::pair().context("failed to open a pseudo terminal")?;
+    println!(
+        "serving {} on {}",
+        root.display(),
+        host.name().unwrap_or_default()
+    );
+
+    // `host` is kept open, so the device side doesn't see a hangup every time
+    // the CLI closes the port
+    StandIn::new(root).serve(&mut device)?;
+    drop(host);
+    Ok(())
+}
@@ -0,0 +1,277 @@
+use std::{
+    collections::VecDeque,
+    fmt,
+    io::{self, Read, Write},
+};
+
+use remote_protocol::{
+    DirEntry, ErrorCode, FrameError, FrameReader, MAX_DATA, MAX_FRAME, Request, Response,
+    decode_pixel,
+};
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    /// A corrupted frame was received
+    Frame(FrameError),
+    /// The device refused the request
+    Device(ErrorCode),
+    /// The device answered with something that doesn't fit the request
+    Protocol(&'static str),
+    /// The port was closed
+    Disconnected,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "{e}"),
+            Error::Frame(e) => write!(f, "bad frame: {e:?}"),
+            Error::Device(code) => write!(f, "device: {code}"),
+            Error::Protocol(e) => write!(f, "protocol error: {e}"),
+            Error::Disconnected => f.write_str("device disconnected"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+/// A file or directory on the device
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Entry {
+    pub name: String,
+    pub size: u32,
+    pub is_dir: bool,
+}
+
+/// The device's screen, row by row
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Screenshot {
+    pub width: u16,
+    pub height: u16,
+    /// RGB565
+    pub pixels: Vec<u16>,
+}
+
+impl Screenshot {
+    /// The pixels as 8 bit RGB, e.g. for saving as a PNG
+    pub fn to_rgb8(&self) -> Vec<u8> {
+        let mut rgb = Vec::with_capacity(self.pixels.len() * 3);
+        for &pixel in &self.pixels {
+            let r = (pixel >> 11) & 0x1F;
+            let g = (pixel >> 5) & 0x3F;
+            let b = pixel & 0x1F;
+            rgb.extend([
+                (r * 255 / 31) as u8,
+                (g * 255 / 63) as u8,
+                (b * 255 / 31) as u8,
+            ]);
+        }
+        rgb
+    }
+}
+
+// a response, copied out of the frame reader
+enum Reply {
+    Ok(Vec<u8>),
+    Error(ErrorCode),
+    Data(Vec<u8>),
+    Log(Vec<u8>),
+}
+
+/// Sends requests to a device and waits for their answers
+pub struct Client<P> {
+    port: P,
+    reader: FrameReader,
+    buf: [u8; 256],
+    start: usize,
+    end: usize,
+    seq: u8,
+    // log text received while waiting for an answer
+    logs: VecDeque<Vec<u8>>,
+}
+
+impl<P: Read + Write> Client<P> {
+    pub fn new(port: P) -> Self {
+        Self {
+            port,
+            reader: FrameReader::new(),
+            buf: [0; 256],
+            start: 0,
+            end: 0,
+            seq: 0,
+            logs: VecDeque::new(),
+        }
+    }
+
+    /// Checks that the device answers, returns its protocol version
+    pub fn ping(&mut self) -> Result<u8, Error> {
+        match self.request(&Request::Ping, |_| Ok(()))?[..] {
+            [version] => Ok(version),
+            _ => Err(Error::Protocol("bad ping reply")),
+        }
+    }
+
+    pub fn list_dir(&mut self, path: &str) -> Result<Vec<Entry>, Error> {
+        let mut entries = Vec::new();
+        self.request(&Request::ListDir { path }, |data| {
+            let entry = DirEntry::parse(data).ok_or(Error::Protocol("bad directory entry"))?;
+            entries.push(Entry {
+                name: entry.name.into(),
+                size: entry.size,
+                is_dir: entry.is_dir,
+            });
+            Ok(())
+        })?;
+        Ok(entries)
+    }
+
+    pub fn read_file(&mut self, path: &str) -> Result<Vec<u8>, Error> {
+        let mut contents = Vec::new();
+        self.request(&Request::ReadFile { path }, |data| {
+            contents.extend_from_slice(data);
+            Ok(())
+        })?;
+        Ok(contents)
+    }
+
+    /// Creates or replaces a file with `contents`
+    pub fn write_file(&mut self, path: &str, contents: &[u8]) -> Result<(), Error> {
+        // an empty file still needs one write to create it
+        let mut chunks = contents.chunks(MAX_DATA).peekable();
+        if chunks.peek().is_none() {
+            return self.write_chunk(path, 0, &[]);
+        }
+
+        for (i, data) in chunks.enumerate() {
+            self.write_chunk(path, (i * MAX_DATA) as u32, data)?;
+        }
+        Ok(())
+    }
+
+    fn write_chunk(&mut self, path: &str, offset: u32, data: &[u8]) -> Result<(), Error> {
+        let request = Request::WriteFile { path, offset, data };
+        self.request(&request, |_| Ok(())).map(drop)
+    }
+
+    /// Runs an app, once it is loaded
+    pub fn launch(&mut self, path: &str) -> Result<(), Error> {
+        self.request(&Request::Launch { path }, |_| Ok(()))
+            .map(drop)
+    }
+
+    /// Force quits the running app
+    pub fn stop(&mut self) -> Result<(), Error> {
+        self.request(&Request::Stop, |_| Ok(())).map(drop)
+    }
+
+    pub fn screenshot(&mut self) -> Result<Screenshot, Error> {
+        let mut bytes = Vec::new();
+        let size = self.request(&Request::Screenshot, |data| {
+            bytes.extend_from_slice(data);
+            Ok(())
+        })?;
+
+        let [w0, w1, h0, h1] = size[..] else {
+            return Err(Error::Protocol("bad screenshot size"));
+        };
+        let (width, height) = (u16::from_le_bytes([w0, w1]), u16::from_le_bytes([h0, h1]));
+        if bytes.len() != width as usize * height as usize * 2 {
+            return Err(Error::Protocol("screenshot size doesn't match its pixels"));
+        }
+
+        Ok(Screenshot {
+            width,
+            height,
+            pixels: bytes
+                .chunks_exact(2)
+                .map(|pixel| decode_pixel([pixel[0], pixel[1]]))
+                .collect(),
+        })
+    }
+
+    /// Starts or stops the device sending its log, see `next_log`
+    pub fn stream_log(&mut self, enable: bool) -> Result<(), Error> {
+        self.request(&Request::StreamLog { enable }, |_| Ok(()))
+            .map(drop)
+    }
+
+    /// Waits for more log text, once streaming is enabled
+    pub fn next_log(&mut self) -> Result<Vec<u8>, Error> {
+        loop {
+            if let Some(text) = self.logs.pop_front() {
+                return Ok(text);
+            }
+            match self.receive() {
+                Ok((_, Reply::Log(text))) => return Ok(text),
+                // answers to a request that was given up on
+                Ok(_) => (),
+                // the log may be quiet for a long time
+                Err(Error::Io(e)) if e.kind() == io::ErrorKind::TimedOut => (),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    // Sends a request, and hands each `Data` frame answering it to `on_data`.
+    // Returns the payload of the final `Ok`.
+    fn request(
+        &mut self,
+        request: &Request,
+        mut on_data: impl FnMut(&[u8]) -> Result<(), Error>,
+    ) -> Result<Vec<u8>, Error> {
+        // 0 is left for log frames
+        self.seq = self.seq.wrapping_add(1).max(1);
+
+        let mut frame = [0_u8; MAX_FRAME];
+        let len = request.encode(self.seq, &mut frame).map_err(Error::Frame)?;
+        self.port.write_all(&frame[..len])?;
+        self.port.flush()?;
+
+        loop {
+            match self.receive()? {
+                (_, Reply::Log(text)) => self.logs.push_back(text),
+                // answers to a request that was given up on
+                (seq, _) if seq != self.seq => (),
+                (_, Reply::Data(data)) => on_data(&data)?,
+                (_, Reply::Ok(result)) => return Ok(result),
+                (_, Reply::Error(code)) => return Err(Error::Device(code)),
+            }
+        }
+    }
+
+    // waits for the next frame from the device
+    fn receive(&mut self) -> Result<(u8, Reply), Error> {
+        loop {
+            if self.start == self.end {
+                self.start = 0;
+                self.end = self.port.read(&mut self.buf)?;
+                if self.end == 0 {
+                    return Err(Error::Disconnected);
+                }
+            }
+
+            let byte = self.buf[self.start];
+            self.start += 1;
+
+            let Some(frame) = self.reader.push(byte) else {
+                continue;
+            };
+            let frame = frame.map_err(Error::Frame)?;
+            let reply = match Response::parse(&frame) {
+                Ok(Response::Ok(result)) => Reply::Ok(result.to_vec()),
+                Ok(Response::Error(code)) => Reply::Error(code),
+                Ok(Response::Data(data)) => Reply::Data(data.to_vec()),
+                Ok(Response::Log(text)) => Reply::Log(text.to_vec()),
+                Err(_) => return Err(Error::Protocol("unknown response")),
+            };
+            return Ok((frame.seq, reply));
+        }
+    }
+}
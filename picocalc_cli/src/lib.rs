@@ -0,0 +1,11 @@
+//! Host side of the PicoCalc remote control protocol, see `remote_protocol`.
+//!
+//! `Client` drives a device over anything that reads and writes bytes, usually
+//! its serial port. `StandIn` answers like a device, serving a directory on the
+//! PC as its sd card, so the protocol can be tested without a PicoCalc.
+
+mod client;
+mod stand_in;
+
+pub use client::{Client, Entry, Error, Screenshot};
+pub use stand_in::StandIn;
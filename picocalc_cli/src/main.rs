@@ -0,0 +1,151 @@
+use std::{
+    fs,
+    io::{self, BufWriter, Write},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::{Context, bail};
+use clap::{Parser, Subcommand};
+use picocalc_cli::{Client, Screenshot};
+
+/// Controls a PicoCalc over its USB remote serial port
+#[derive(Parser)]
+#[command(version, about)]
+struct Cli {
+    /// Serial port of the remote interface, the second one the PicoCalc adds
+    #[arg(short, long, default_value = "/dev/ttyACM1")]
+    port: String,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Checks that the PicoCalc answers
+    Ping,
+    /// Lists a directory on the sd card
+    Ls {
+        #[arg(default_value = "/")]
+        path: String,
+    },
+    /// Copies a file to the sd card, into the root directory by default
+    Push {
+        local: PathBuf,
+        remote: Option<String>,
+        /// Launches the file once it is copied
+        #[arg(long)]
+        run: bool,
+    },
+    /// Copies a file from the sd card, into the current directory by default
+    Pull {
+        remote: String,
+        local: Option<PathBuf>,
+    },
+    /// Launches an app
+    Run { path: String },
+    /// Force quits the running app
+    Stop,
+    /// Saves the screen as a PNG
+    Screenshot {
+        #[arg(default_value = "screenshot.png")]
+        out: PathBuf,
+    },
+    /// Prints the kernel log and app output as it is written
+    Log,
+}
+
+fn main() -> anyhow::Result<()> {
+    let cli = Cli::parse();
+
+    let mut port = serialport::new(&cli.port, 115_200)
+        .timeout(Duration::from_secs(5))
+        .open()
+        .with_context(|| format!("failed to open {}", cli.port))?;
+    // the device only answers once DTR is set, pseudo terminals like the
+    // stand-in's have no DTR
+    let _ = port.write_data_terminal_ready(true);
+    let mut client = Client::new(port);
+
+    match cli.command {
+        Command::Ping => {
+            let version = client.ping()?;
+            println!("PicoCalc answered, protocol version {version}");
+        }
+        Command::Ls { path } => {
+            for entry in client.list_dir(&path)? {
+                if entry.is_dir {
+                    println!("{:>10}  {}/", "", entry.name);
+                } else {
+                    println!("{:>10}  {}", entry.size, entry.name);
+                }
+            }
+        }
+        Command::Push { local, remote, run } => {
+            let contents =
+                fs::read(&local).with_context(|| format!("failed to read {}", local.display()))?;
+            let remote = match remote {
+                Some(remote) => remote,
+                None => format!("/{}", file_name(&local)?),
+            };
+
+            client.write_file(&remote, &contents)?;
+            println!("copied {} bytes to {remote}", contents.len());
+            if run {
+                client.launch(&remote)?;
+            }
+        }
+        Command::Pull { remote, local } => {
+            let contents = client.read_file(&remote)?;
+            let local = match local {
+                Some(local) => local,
+                None => PathBuf::from(file_name(Path::new(&remote))?),
+            };
+
+            fs::write(&local, &contents)
+                .with_context(|| format!("failed to write {}", local.display()))?;
+            println!("copied {} bytes to {}", contents.len(), local.display());
+        }
+        Command::Run { path } => client.launch(&path)?,
+        Command::Stop => client.stop()?,
+        Command::Screenshot { out } => {
+            let screenshot = client.screenshot()?;
+            save_png(&screenshot, &out)?;
+            println!("saved {}", out.display());
+        }
+        Command::Log => {
+            client.stream_log(true)?;
+            let mut stdout = io::stdout();
+            loop {
+                stdout.write_all(&client.next_log()?)?;
+                stdout.flush()?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn file_name(path: &Path) -> anyhow::Result<String> {
+    match path.file_name() {
+        Some(name) => Ok(name.to_string_lossy().into_owned()),
+        None => bail!("{} has no file name", path.display()),
+    }
+}
+
+fn save_png(screenshot: &Screenshot, path: &Path) -> anyhow::Result<()> {
+    let file =
+        fs::File::create(path).with_context(|| format!("failed to create {}", path.display()))?;
+
+    let mut encoder = png::Encoder::new(
+        BufWriter::new(file),
+        screenshot.width as u32,
+        screenshot.height as u32,
+    );
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_depth(png::BitDepth::Eight);
+
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(&screenshot.to_rgb8())?;
+    Ok(())
+}
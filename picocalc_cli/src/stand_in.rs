@@ -0,0 +1,264 @@
+use std::{
+    fs,
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::{Component, Path, PathBuf},
+};
+
+use remote_protocol::{
+    DirEntry, ErrorCode, FrameReader, MAX_DATA, MAX_FRAME, Request, Response, VERSION, encode_pixel,
+};
+
+const ELF_MAGIC: &[u8] = b"\x7fELF";
+
+/// Answers requests like a device would, serving `root` as its sd card.
+///
+/// Launched apps don't run, they are only recorded until stopped. Like on the
+/// device, the sd card is busy while one is "running".
+pub struct StandIn {
+    root: PathBuf,
+    running: Option<String>,
+    streaming: bool,
+    log: Vec<String>,
+    // lines of `log` already streamed
+    streamed: usize,
+}
+
+impl StandIn {
+    pub const WIDTH: u16 = 320;
+    pub const HEIGHT: u16 = 320;
+
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self {
+            root: root.into(),
+            running: None,
+            streaming: false,
+            log: Vec::new(),
+            streamed: 0,
+        }
+    }
+
+    /// The app launched last, until it is stopped
+    pub fn running(&self) -> Option<&str> {
+        self.running.as_deref()
+    }
+
+    /// The screen, a test pattern
+    pub fn screen() -> Vec<u16> {
+        (0..Self::HEIGHT)
+            .flat_map(|y| (0..Self::WIDTH).map(move |x| (x ^ y).wrapping_mul(0x0821)))
+            .collect()
+    }
+
+    /// Answers requests until the port is closed
+    pub fn serve<P: Read + Write>(&mut self, mut port: P) -> io::Result<()> {
+        let mut reader = FrameReader::new();
+        let mut buf = [0_u8; 256];
+
+        loop {
+            let len = match port.read(&mut buf) {
+                Ok(0) => return Ok(()),
+                Ok(len) => len,
+                Err(e) if e.kind() == io::ErrorKind::TimedOut => continue,
+                Err(e) => return Err(e),
+            };
+
+            for &byte in &buf[..len] {
+                // corrupted frames are dropped, the host times out
+                let Some(Ok(frame)) = reader.push(byte) else {
+                    continue;
+                };
+                let seq = frame.seq;
+                match Request::parse(&frame) {
+                    Ok(request) => {
+                        let result = self.handle(&mut port, seq, request)?;
+                        let response = match &result {
+                            Ok(result) => Response::Ok(result),
+                            Err(code) => Response::Error(*code),
+                        };
+                        send(&mut port, seq, response)?;
+                    }
+                    Err(code) => send(&mut port, seq, Response::Error(code))?,
+                }
+                self.stream_log(&mut port)?;
+            }
+        }
+    }
+
+    // Sends the `Data` frames answering a request, and returns the payload of
+    // its final `Ok`, or why it failed
+    fn handle(
+        &mut self,
+        port: &mut impl Write,
+        seq: u8,
+        request: Request,
+    ) -> io::Result<Result<Vec<u8>, ErrorCode>> {
+        let busy = self.running.is_some();
+
+        Ok(match request {
+            Request::Ping => Ok(vec![VERSION]),
+            Request::ListDir { .. } | Request::ReadFile { .. } | Request::WriteFile { .. }
+                if busy =>
+            {
+                Err(ErrorCode::Busy)
+            }
+            Request::ListDir { path } => match self.list_dir(path) {
+                Ok(entries) => {
+                    let mut data = [0_u8; MAX_DATA];
+                    for (name, size, is_dir) in entries {
+                        let entry = DirEntry {
+                            name: &name,
+                            size,
+                            is_dir,
+                        };
+                        let len = entry.encode(&mut data);
+                        send(port, seq, Response::Data(&data[..len]))?;
+                    }
+                    Ok(Vec::new())
+                }
+                Err(code) => Err(code),
+            },
+            Request::ReadFile { path } => match self.resolve(path).and_then(read) {
+                Ok(contents) => {
+                    for chunk in contents.chunks(MAX_DATA) {
+                        send(port, seq, Response::Data(chunk))?;
+                    }
+                    Ok(Vec::new())
+                }
+                Err(code) => Err(code),
+            },
+            Request::WriteFile { path, offset, data } => self
+                .resolve(path)
+                .and_then(|path| write_at(&path, offset, data))
+                .map(|()| Vec::new()),
+            Request::Launch { .. } if busy => Err(ErrorCode::Busy),
+            Request::Launch { path } => match self.resolve(path).and_then(read) {
+                Ok(binary) if binary.starts_with(ELF_MAGIC) => {
+                    self.log.push(format!("launching {path}"));
+                    self.running = Some(path.into());
+                    Ok(Vec::new())
+                }
+                Ok(_) => Err(ErrorCode::LoadFailed),
+                Err(code) => Err(code),
+            },
+            Request::Stop => match self.running.take() {
+                Some(path) => {
+                    self.log.push(format!("{path} force quit"));
+                    Ok(Vec::new())
+                }
+                None => Err(ErrorCode::NotRunning),
+            },
+            Request::Screenshot => {
+                let bytes: Vec<u8> = Self::screen()
+                    .iter()
+                    .flat_map(|&pixel| encode_pixel(pixel))
+                    .collect();
+                for chunk in bytes.chunks(MAX_DATA) {
+                    send(port, seq, Response::Data(chunk))?;
+                }
+
+                Ok([Self::WIDTH.to_le_bytes(), Self::HEIGHT.to_le_bytes()].concat())
+            }
+            Request::StreamLog { enable } => {
+                self.streaming = enable;
+                // like the device, a new stream starts with what is buffered
+                self.streamed = 0;
+                Ok(Vec::new())
+            }
+        })
+    }
+
+    fn stream_log(&mut self, port: &mut impl Write) -> io::Result<()> {
+        if !self.streaming {
+            return Ok(());
+        }
+        for line in &self.log[self.streamed..] {
+            send(port, 0, Response::Log(format!("{line}\n").as_bytes()))?;
+        }
+        self.streamed = self.log.len();
+        Ok(())
+    }
+
+    // Entries of a directory, sorted by name
+    fn list_dir(&self, path: &str) -> Result<Vec<(String, u32, bool)>, ErrorCode> {
+        let dir = self.resolve(path)?;
+        let mut entries = Vec::new();
+        for entry in fs::read_dir(dir).map_err(error_code)? {
+            let entry = entry.map_err(error_code)?;
+            let meta = entry.metadata().map_err(error_code)?;
+            entries.push((
+                entry.file_name().to_string_lossy().into_owned(),
+                meta.len() as u32,
+                meta.is_dir(),
+            ));
+        }
+        entries.sort();
+        Ok(entries)
+    }
+
+    // The path on the PC for a path on the "sd card"
+    fn resolve(&self, path: &str) -> Result<PathBuf, ErrorCode> {
+        let mut resolved = self.root.clone();
+        for component in Path::new(path).components() {
+            match component {
+                Component::RootDir | Component::CurDir => (),
+                Component::Normal(name) => resolved.push(name),
+                // nothing outside of root is served
+                _ => return Err(ErrorCode::NotFound),
+            }
+        }
+        Ok(resolved)
+    }
+}
+
+fn send(port: &mut impl Write, seq: u8, response: Response) -> io::Result<()> {
+    let mut frame = [0_u8; MAX_FRAME];
+    let len = response
+        .encode(seq, &mut frame)
+        .map_err(|e| io::Error::other(format!("{e:?}")))?;
+    port.write_all(&frame[..len])?;
+    port.flush()
+}
+
+fn read(path: PathBuf) -> Result<Vec<u8>, ErrorCode> {
+    fs::read(path).map_err(error_code)
+}
+
+// like the device, writes at offset 0 create or truncate the file, and writes
+// past its end fail
+fn write_at(path: &Path, offset: u32, data: &[u8]) -> Result<(), ErrorCode> {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    if !path.exists() && !is_short_name(&name) {
+        return Err(ErrorCode::InvalidName);
+    }
+
+    let mut file = if offset == 0 {
+        fs::File::create(path)
+    } else {
+        fs::OpenOptions::new().write(true).open(path)
+    }
+    .map_err(error_code)?;
+
+    if offset as u64 > file.metadata().map_err(error_code)?.len() {
+        return Err(ErrorCode::Io);
+    }
+    file.seek(SeekFrom::Start(offset as u64))
+        .and_then(|_| file.write_all(data))
+        .map_err(error_code)
+}
+
+fn error_code(e: io::Error) -> ErrorCode {
+    match e.kind() {
+        io::ErrorKind::NotFound => ErrorCode::NotFound,
+        _ => ErrorCode::Io,
+    }
+}
+
+// whether FAT can create a file with this name without a long name entry
+fn is_short_name(name: &str) -> bool {
+    let (base, ext) = name.split_once('.').unwrap_or((name, ""));
+    let valid = |part: &str| {
+        part.bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"!#$%&'()-@^_`{}~".contains(&b))
+    };
+    (1..=8).contains(&base.len()) && ext.len() <= 3 && valid(base) && valid(ext)
+}
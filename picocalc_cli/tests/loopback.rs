@@ -0,0 +1,189 @@
+//! Runs the client against the stand-in device, over a socket pair
+
+use std::{
+    fs,
+    io::{Read, Write},
+    os::unix::net::UnixStream,
+    path::Path,
+    thread,
+    time::Duration,
+};
+
+use picocalc_cli::{Client, Error, StandIn};
+use remote_protocol::{
+    ErrorCode, Frame, FrameReader, MAX_DATA, MAX_FRAME, Response, ResponseKind, VERSION,
+};
+use tempfile::TempDir;
+
+fn connect(root: &Path) -> Client<UnixStream> {
+    let (host, device) = UnixStream::pair().unwrap();
+    host.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+
+    let root = root.to_path_buf();
+    thread::spawn(move || StandIn::new(root).serve(device));
+    Client::new(host)
+}
+
+fn sd_card() -> TempDir {
+    let dir = tempfile::tempdir().unwrap();
+    fs::create_dir(dir.path().join("apps")).unwrap();
+    fs::write(dir.path().join("apps/snake.bin"), b"\x7fELF snake").unwrap();
+    fs::write(dir.path().join("notes.txt"), b"hello").unwrap();
+    dir
+}
+
+#[test]
+fn ping_reports_the_protocol_version() {
+    let sd = sd_card();
+    assert_eq!(connect(sd.path()).ping().unwrap(), VERSION);
+}
+
+#[test]
+fn lists_directories() {
+    let sd = sd_card();
+    let mut client = connect(sd.path());
+
+    let root: Vec<_> = client
+        .list_dir("/")
+        .unwrap()
+        .into_iter()
+        .map(|e| (e.name, e.is_dir))
+        .collect();
+    assert_eq!(root, [("apps".into(), true), ("notes.txt".into(), false)]);
+
+    let apps = client.list_dir("/apps").unwrap();
+    assert_eq!(apps.len(), 1);
+    assert_eq!(apps[0].name, "snake.bin");
+    assert_eq!(apps[0].size, 10);
+
+    assert!(matches!(
+        client.list_dir("/missing"),
+        Err(Error::Device(ErrorCode::NotFound))
+    ));
+}
+
+#[test]
+fn pushed_files_can_be_pulled() {
+    let sd = sd_card();
+    let mut client = connect(sd.path());
+
+    // several frames, the last one partly filled
+    let contents: Vec<u8> = (0..MAX_DATA * 3 + 100).map(|i| (i * 31) as u8).collect();
+    client.write_file("/apps/big.bin", &contents).unwrap();
+    assert_eq!(fs::read(sd.path().join("apps/big.bin")).unwrap(), contents);
+    assert_eq!(client.read_file("/apps/big.bin").unwrap(), contents);
+
+    // replacing a file truncates it
+    client.write_file("/apps/big.bin", b"small").unwrap();
+    assert_eq!(client.read_file("/apps/big.bin").unwrap(), b"small");
+
+    client.write_file("/empty", &[]).unwrap();
+    assert_eq!(client.read_file("/empty").unwrap(), b"");
+
+    assert!(matches!(
+        client.read_file("/nope.bin"),
+        Err(Error::Device(ErrorCode::NotFound))
+    ));
+    assert!(matches!(
+        client.write_file("/no/such/dir.bin", b"x"),
+        Err(Error::Device(ErrorCode::NotFound))
+    ));
+    assert!(matches!(
+        client.write_file("/a long name.bin", b"x"),
+        Err(Error::Device(ErrorCode::InvalidName))
+    ));
+}
+
+#[test]
+fn launched_apps_keep_the_sd_card_busy_until_stopped() {
+    let sd = sd_card();
+    let mut client = connect(sd.path());
+
+    assert!(matches!(
+        client.launch("/notes.txt"),
+        Err(Error::Device(ErrorCode::LoadFailed))
+    ));
+    assert!(matches!(
+        client.stop(),
+        Err(Error::Device(ErrorCode::NotRunning))
+    ));
+
+    client.launch("/apps/snake.bin").unwrap();
+    assert!(matches!(
+        client.read_file("/notes.txt"),
+        Err(Error::Device(ErrorCode::Busy))
+    ));
+    assert!(matches!(
+        client.launch("/apps/snake.bin"),
+        Err(Error::Device(ErrorCode::Busy))
+    ));
+
+    client.stop().unwrap();
+    assert_eq!(client.read_file("/notes.txt").unwrap(), b"hello");
+}
+
+#[test]
+fn screenshots_hold_every_pixel() {
+    let sd = sd_card();
+    let screenshot = connect(sd.path()).screenshot().unwrap();
+
+    assert_eq!(screenshot.width, StandIn::WIDTH);
+    assert_eq!(screenshot.height, StandIn::HEIGHT);
+    assert_eq!(screenshot.pixels, StandIn::screen());
+    assert_eq!(
+        screenshot.to_rgb8().len(),
+        StandIn::WIDTH as usize * StandIn::HEIGHT as usize * 3
+    );
+}
+
+#[test]
+fn logs_stream_between_answers() {
+    let sd = sd_card();
+    let mut client = connect(sd.path());
+
+    client.launch("/apps/snake.bin").unwrap();
+    client.stream_log(true).unwrap();
+    // buffered entries come first
+    assert_eq!(client.next_log().unwrap(), b"launching /apps/snake.bin\n");
+
+    // entries sent while waiting for an answer are kept for later
+    client.stop().unwrap();
+    client.ping().unwrap();
+    assert_eq!(client.next_log().unwrap(), b"/apps/snake.bin force quit\n");
+}
+
+#[test]
+fn device_survives_garbage_and_unknown_commands() {
+    let sd = sd_card();
+    let (mut host, device) = UnixStream::pair().unwrap();
+    host.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let root = sd.path().to_path_buf();
+    thread::spawn(move || StandIn::new(root).serve(device));
+
+    // noise, then a command from a newer protocol version
+    let mut out = [0_u8; MAX_FRAME];
+    host.write_all(&[0x42, 0x13, 0x00]).unwrap();
+    let len = Frame {
+        kind: 0x7F,
+        seq: 9,
+        payload: &[],
+    }
+    .encode(&mut out)
+    .unwrap();
+    host.write_all(&out[..len]).unwrap();
+
+    let mut reader = FrameReader::new();
+    let mut byte = [0_u8];
+    let (seq, reply) = loop {
+        host.read_exact(&mut byte).unwrap();
+        if let Some(frame) = reader.push(byte[0]) {
+            let frame = frame.unwrap();
+            break (frame.seq, Response::parse(&frame).unwrap().kind());
+        }
+    };
+    assert_eq!(seq, 9);
+    assert_eq!(reply, ResponseKind::Error);
+
+    let mut client = Client::new(host);
+    assert_eq!(client.ping().unwrap(), VERSION);
+}
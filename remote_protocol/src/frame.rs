@@ -0,0 +1,212 @@
+use crate::{MAX_FRAME, MAX_PAYLOAD};
+
+/// Frame kind and sequence number
+pub(crate) const HEADER_LEN: usize = 2;
+const CRC_LEN: usize = 2;
+
+/// Longest COBS encoding of `len` bytes, without the delimiter
+pub(crate) const fn encoded_len(len: usize) -> usize {
+    len + len / 254 + 1
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum FrameError {
+    /// The payload is longer than `MAX_PAYLOAD`
+    TooLong,
+    /// The frame doesn't fit the buffer it is encoded into or read into
+    Overflow,
+    /// Invalid COBS encoding
+    Encoding,
+    /// Too short to hold a header and checksum
+    TooShort,
+    /// The checksum doesn't match, the frame was corrupted
+    Crc,
+}
+
+/// CRC-16/CCITT-FALSE, the checksum ending every frame
+pub fn crc16(data: &[u8]) -> u16 {
+    crc16_update(0xFFFF, data)
+}
+
+fn crc16_update(mut crc: u16, data: &[u8]) -> u16 {
+    for &byte in data {
+        crc ^= (byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+/// A frame as sent on the wire, before COBS encoding:
+/// kind, sequence number, payload, then the CRC of all of those, little endian
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Frame<'a> {
+    /// A `Command` for requests, a `ResponseKind` for responses
+    pub kind: u8,
+    /// Picked by the host, and repeated in every frame answering the request
+    pub seq: u8,
+    pub payload: &'a [u8],
+}
+
+impl<'a> Frame<'a> {
+    /// Encodes the frame into `out`, delimiter included, and returns its length
+    pub fn encode(&self, out: &mut [u8]) -> Result<usize, FrameError> {
+        if self.payload.len() > MAX_PAYLOAD {
+            return Err(FrameError::TooLong);
+        }
+        let raw_len = HEADER_LEN + self.payload.len() + CRC_LEN;
+        if out.len() < encoded_len(raw_len) + 1 {
+            return Err(FrameError::Overflow);
+        }
+
+        let header = [self.kind, self.seq];
+        let crc = crc16_update(crc16(&header), self.payload).to_le_bytes();
+
+        let mut encoder = Encoder::new(out);
+        for &byte in header.iter().chain(self.payload).chain(&crc) {
+            encoder.push(byte);
+        }
+        Ok(encoder.finish())
+    }
+
+    /// Decodes a frame in place, `buf` is everything received before the delimiter
+    pub fn decode(buf: &'a mut [u8]) -> Result<Self, FrameError> {
+        let len = decode_in_place(buf)?;
+        let buf: &'a [u8] = buf;
+        if len < HEADER_LEN + CRC_LEN {
+            return Err(FrameError::TooShort);
+        }
+
+        let (body, crc) = buf[..len].split_at(len - CRC_LEN);
+        if crc16(body).to_le_bytes() != crc {
+            return Err(FrameError::Crc);
+        }
+        Ok(Self {
+            kind: body[0],
+            seq: body[1],
+            payload: &body[HEADER_LEN..],
+        })
+    }
+}
+
+// COBS encoder, each block starts with its length, and ends with an implied
+// zero unless it is a full block
+struct Encoder<'a> {
+    out: &'a mut [u8],
+    // where the length of the current block goes
+    code_pos: usize,
+    pos: usize,
+}
+
+impl<'a> Encoder<'a> {
+    fn new(out: &'a mut [u8]) -> Self {
+        Self {
+            out,
+            code_pos: 0,
+            pos: 1,
+        }
+    }
+
+    fn push(&mut self, byte: u8) {
+        if byte == 0 {
+            self.finish_block();
+            return;
+        }
+
+        self.out[self.pos] = byte;
+        self.pos += 1;
+        if self.pos - self.code_pos == 0xFF {
+            self.finish_block();
+        }
+    }
+
+    fn finish_block(&mut self) {
+        self.out[self.code_pos] = (self.pos - self.code_pos) as u8;
+        self.code_pos = self.pos;
+        self.pos += 1;
+    }
+
+    // returns the encoded length, delimiter included
+    fn finish(self) -> usize {
+        self.out[self.code_pos] = (self.pos - self.code_pos) as u8;
+        self.out[self.pos] = 0;
+        self.pos + 1
+    }
+}
+
+// decoding never grows the data, so it can be written over what was read
+fn decode_in_place(buf: &mut [u8]) -> Result<usize, FrameError> {
+    let mut read = 0;
+    let mut write = 0;
+
+    while read < buf.len() {
+        let code = buf[read] as usize;
+        if code == 0 || read + code > buf.len() {
+            return Err(FrameError::Encoding);
+        }
+
+        buf.copy_within(read + 1..read + code, write);
+        write += code - 1;
+        read += code;
+
+        if code < 0xFF && read < buf.len() {
+            buf[write] = 0;
+            write += 1;
+        }
+    }
+    Ok(write)
+}
+
+/// Splits a received byte stream into frames
+pub struct FrameReader {
+    buf: [u8; MAX_FRAME],
+    len: usize,
+    // the frame being read didn't fit, it is dropped at its delimiter
+    overflowed: bool,
+}
+
+impl FrameReader {
+    pub const fn new() -> Self {
+        Self {
+            buf: [0; MAX_FRAME],
+            len: 0,
+            overflowed: false,
+        }
+    }
+
+    /// Adds a received byte, returning the frame it completes, if any
+    pub fn push(&mut self, byte: u8) -> Option<Result<Frame<'_>, FrameError>> {
+        if byte != 0 {
+            match self.buf.get_mut(self.len) {
+                Some(slot) => {
+                    *slot = byte;
+                    self.len += 1;
+                }
+                None => self.overflowed = true,
+            }
+            return None;
+        }
+
+        let len = core::mem::take(&mut self.len);
+        if core::mem::take(&mut self.overflowed) {
+            return Some(Err(FrameError::Overflow));
+        }
+        // back to back delimiters, e.g. sent to resynchronize
+        if len == 0 {
+            return None;
+        }
+        Some(Frame::decode(&mut self.buf[..len]))
+    }
+}
+
+impl Default for FrameReader {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,31 @@
+//! Remote control of the PicoCalc from a PC, over a USB serial port.
+//!
+//! The host sends a request frame and waits for the reply. Every request is
+//! answered by any number of `Data` frames and then one `Ok` or `Error` frame,
+//! all carrying the request's sequence number. `Log` frames may arrive at any
+//! time once log streaming is enabled.
+//!
+//! Frames are COBS encoded and end with a zero byte, so either side can find
+//! the start of the next frame after garbage or a dropped byte.
+
+#![no_std]
+
+mod frame;
+mod message;
+
+pub use frame::{Frame, FrameError, FrameReader, crc16};
+pub use message::{
+    Command, DirEntry, ErrorCode, Request, Response, ResponseKind, decode_pixel, encode_pixel,
+};
+
+/// Bumped whenever the device and host stop understanding each other
+pub const VERSION: u8 = 1;
+
+/// Most file or screenshot bytes carried by one frame
+pub const MAX_DATA: usize = 512;
+/// Longest path in a request
+pub const MAX_PATH: usize = 128;
+/// Longest payload of any frame, a file write with the longest path
+pub const MAX_PAYLOAD: usize = 5 + MAX_PATH + MAX_DATA;
+/// Longest encoded frame, delimiter included
+pub const MAX_FRAME: usize = frame::encoded_len(frame::HEADER_LEN + MAX_PAYLOAD + 2) + 1;
@@ -0,0 +1,318 @@
+use core::fmt;
+use num_enum::TryFromPrimitive;
+
+use crate::{
+    MAX_DATA, MAX_PATH, MAX_PAYLOAD,
+    frame::{Frame, FrameError},
+};
+
+/// Request frame kinds
+#[repr(u8)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, TryFromPrimitive)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Command {
+    /// Answered with `Ok([VERSION])`
+    Ping = 0x01,
+    /// Payload: path. Answered with a `Data` frame per `DirEntry`
+    ListDir = 0x02,
+    /// Payload: path. Answered with `Data` frames holding the whole file
+    ReadFile = 0x03,
+    /// Payload: offset (u32), path length (u8), path, data
+    WriteFile = 0x04,
+    /// Payload: path of the app to run
+    Launch = 0x05,
+    /// Force quits the running app
+    Stop = 0x06,
+    /// Answered with `Data` frames holding the screen as little endian RGB565
+    /// pixels, row by row, and then `Ok` with its width and height (u16 each)
+    Screenshot = 0x07,
+    /// Payload: 1 to start sending `Log` frames, 0 to stop
+    StreamLog = 0x08,
+}
+
+/// Response frame kinds
+#[repr(u8)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, TryFromPrimitive)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ResponseKind {
+    Ok = 0x80,
+    Error = 0x81,
+    Data = 0x82,
+    Log = 0x83,
+}
+
+/// Why a request failed, the payload of `Error` frames
+#[repr(u8)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, TryFromPrimitive)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ErrorCode {
+    /// The request couldn't be parsed
+    Malformed = 0x01,
+    UnknownCommand = 0x02,
+    NotFound = 0x03,
+    /// The sd card is in use, by a running app or the usb host
+    Busy = 0x04,
+    Io = 0x05,
+    /// Stop was requested while no app is running
+    NotRunning = 0x06,
+    /// The app was found, but couldn't be loaded
+    LoadFailed = 0x07,
+    /// New files need a short (8.3) name
+    InvalidName = 0x08,
+}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ErrorCode::Malformed => "malformed request",
+            ErrorCode::UnknownCommand => "unknown command",
+            ErrorCode::NotFound => "not found",
+            ErrorCode::Busy => "sd card busy, quit the running app or eject it on the PC",
+            ErrorCode::Io => "sd card error",
+            ErrorCode::NotRunning => "no app is running",
+            ErrorCode::LoadFailed => "not a valid app",
+            ErrorCode::InvalidName => "new files need a short name, e.g. SNAKE.BIN",
+        })
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Request<'a> {
+    Ping,
+    ListDir {
+        path: &'a str,
+    },
+    ReadFile {
+        path: &'a str,
+    },
+    /// Writing at offset 0 creates the file, or truncates it if it exists.
+    /// Larger files are written in `MAX_DATA` chunks, one after another.
+    WriteFile {
+        path: &'a str,
+        offset: u32,
+        data: &'a [u8],
+    },
+    Launch {
+        path: &'a str,
+    },
+    Stop,
+    /// Answered by the screen's pixels in `Data` frames, see `encode_pixel`,
+    /// and its width and height (u16 each) in the `Ok` frame
+    Screenshot,
+    StreamLog {
+        enable: bool,
+    },
+}
+
+impl<'a> Request<'a> {
+    pub fn command(&self) -> Command {
+        match self {
+            Request::Ping => Command::Ping,
+            Request::ListDir { .. } => Command::ListDir,
+            Request::ReadFile { .. } => Command::ReadFile,
+            Request::WriteFile { .. } => Command::WriteFile,
+            Request::Launch { .. } => Command::Launch,
+            Request::Stop => Command::Stop,
+            Request::Screenshot => Command::Screenshot,
+            Request::StreamLog { .. } => Command::StreamLog,
+        }
+    }
+
+    pub fn parse(frame: &Frame<'a>) -> Result<Self, ErrorCode> {
+        let command = Command::try_from(frame.kind).map_err(|_| ErrorCode::UnknownCommand)?;
+        let payload = frame.payload;
+
+        Ok(match command {
+            Command::Ping => Request::Ping,
+            Command::ListDir => Request::ListDir {
+                path: parse_path(payload)?,
+            },
+            Command::ReadFile => Request::ReadFile {
+                path: parse_path(payload)?,
+            },
+            Command::WriteFile => {
+                let (offset, rest) = payload.split_first_chunk().ok_or(ErrorCode::Malformed)?;
+                let (&path_len, rest) = rest.split_first().ok_or(ErrorCode::Malformed)?;
+                if rest.len() < path_len as usize {
+                    return Err(ErrorCode::Malformed);
+                }
+                let (path, data) = rest.split_at(path_len as usize);
+                Request::WriteFile {
+                    path: parse_path(path)?,
+                    offset: u32::from_le_bytes(*offset),
+                    data,
+                }
+            }
+            Command::Launch => Request::Launch {
+                path: parse_path(payload)?,
+            },
+            Command::Stop => Request::Stop,
+            Command::Screenshot => Request::Screenshot,
+            Command::StreamLog => match payload {
+                [enable] => Request::StreamLog {
+                    enable: *enable != 0,
+                },
+                _ => return Err(ErrorCode::Malformed),
+            },
+        })
+    }
+
+    /// Encodes the request as a frame into `out`, delimiter included
+    pub fn encode(&self, seq: u8, out: &mut [u8]) -> Result<usize, FrameError> {
+        let mut payload = [0_u8; MAX_PAYLOAD];
+        let len = match *self {
+            Request::Ping | Request::Stop | Request::Screenshot => 0,
+            Request::ListDir { path } | Request::ReadFile { path } | Request::Launch { path } => {
+                put_path(&mut payload, path)?
+            }
+            Request::WriteFile { path, offset, data } => {
+                if data.len() > MAX_DATA {
+                    return Err(FrameError::TooLong);
+                }
+                payload[..4].copy_from_slice(&offset.to_le_bytes());
+                let path_len = put_path(&mut payload[5..], path)?;
+                payload[4] = path_len as u8;
+
+                let data_start = 5 + path_len;
+                payload[data_start..data_start + data.len()].copy_from_slice(data);
+                data_start + data.len()
+            }
+            Request::StreamLog { enable } => {
+                payload[0] = enable as u8;
+                1
+            }
+        };
+
+        Frame {
+            kind: self.command() as u8,
+            seq,
+            payload: &payload[..len],
+        }
+        .encode(out)
+    }
+}
+
+fn parse_path(bytes: &[u8]) -> Result<&str, ErrorCode> {
+    if bytes.len() > MAX_PATH {
+        return Err(ErrorCode::Malformed);
+    }
+    core::str::from_utf8(bytes).map_err(|_| ErrorCode::Malformed)
+}
+
+fn put_path(out: &mut [u8], path: &str) -> Result<usize, FrameError> {
+    if path.len() > MAX_PATH {
+        return Err(FrameError::TooLong);
+    }
+    out[..path.len()].copy_from_slice(path.as_bytes());
+    Ok(path.len())
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Response<'a> {
+    /// The request is done, with a result for some commands
+    Ok(&'a [u8]),
+    Error(ErrorCode),
+    /// Part of the answer, followed by more `Data` and then `Ok`
+    Data(&'a [u8]),
+    /// New kernel log text, sent with sequence number 0 while streaming
+    Log(&'a [u8]),
+}
+
+impl<'a> Response<'a> {
+    pub fn kind(&self) -> ResponseKind {
+        match self {
+            Response::Ok(_) => ResponseKind::Ok,
+            Response::Error(_) => ResponseKind::Error,
+            Response::Data(_) => ResponseKind::Data,
+            Response::Log(_) => ResponseKind::Log,
+        }
+    }
+
+    /// Whether this is the last frame answering a request
+    pub fn is_final(&self) -> bool {
+        matches!(self, Response::Ok(_) | Response::Error(_))
+    }
+
+    pub fn parse(frame: &Frame<'a>) -> Result<Self, ErrorCode> {
+        let kind = ResponseKind::try_from(frame.kind).map_err(|_| ErrorCode::UnknownCommand)?;
+
+        Ok(match kind {
+            ResponseKind::Ok => Response::Ok(frame.payload),
+            ResponseKind::Error => match frame.payload {
+                [code] => {
+                    Response::Error(ErrorCode::try_from(*code).map_err(|_| ErrorCode::Malformed)?)
+                }
+                _ => return Err(ErrorCode::Malformed),
+            },
+            ResponseKind::Data => Response::Data(frame.payload),
+            ResponseKind::Log => Response::Log(frame.payload),
+        })
+    }
+
+    /// Encodes the response as a frame into `out`, delimiter included
+    pub fn encode(&self, seq: u8, out: &mut [u8]) -> Result<usize, FrameError> {
+        let code;
+        let payload = match *self {
+            Response::Ok(payload) | Response::Data(payload) | Response::Log(payload) => payload,
+            Response::Error(error) => {
+                code = [error as u8];
+                &code
+            }
+        };
+
+        Frame {
+            kind: self.kind() as u8,
+            seq,
+            payload,
+        }
+        .encode(out)
+    }
+}
+
+/// How a screenshot pixel is sent: RGB565, red in the top bits, little
+/// endian. Pixels go row by row, from the top left.
+pub fn encode_pixel(rgb565: u16) -> [u8; 2] {
+    rgb565.to_le_bytes()
+}
+
+/// The RGB565 pixel `encode_pixel` made `bytes` from
+pub fn decode_pixel(bytes: [u8; 2]) -> u16 {
+    u16::from_le_bytes(bytes)
+}
+
+/// A directory entry, the payload of each `Data` frame answering `ListDir`:
+/// flags (1 for directories), size (u32), then the name
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct DirEntry<'a> {
+    pub name: &'a str,
+    pub size: u32,
+    pub is_dir: bool,
+}
+
+impl<'a> DirEntry<'a> {
+    const FLAG_DIR: u8 = 0x01;
+
+    pub fn parse(payload: &'a [u8]) -> Option<Self> {
+        let (&flags, rest) = payload.split_first()?;
+        let (size, name) = rest.split_first_chunk()?;
+        Some(Self {
+            name: core::str::from_utf8(name).ok()?,
+            size: u32::from_le_bytes(*size),
+            is_dir: flags & Self::FLAG_DIR != 0,
+        })
+    }
+
+    /// Encodes the entry into `out`, returning its length. Names that don't
+    /// fit are cut short.
+    pub fn encode(&self, out: &mut [u8; MAX_DATA]) -> usize {
+        out[0] = if self.is_dir { Self::FLAG_DIR } else { 0 };
+        out[1..5].copy_from_slice(&self.size.to_le_bytes());
+
+        let mut name_len = self.name.len().min(MAX_DATA - 5);
+        while !self.name.is_char_boundary(name_len) {
+            name_len -= 1;
+        }
+        out[5..5 + name_len].copy_from_slice(&self.name.as_bytes()[..name_len]);
+        5 + name_len
+    }
+}
@@ -0,0 +1,244 @@
+use remote_protocol::{
+    Command, DirEntry, ErrorCode, Frame, FrameError, FrameReader, MAX_DATA, MAX_FRAME, MAX_PATH,
+    Request, Response, crc16, decode_pixel, encode_pixel,
+};
+
+fn encode_frame(frame: &Frame) -> Vec<u8> {
+    let mut out = [0_u8; MAX_FRAME];
+    let len = frame.encode(&mut out).unwrap();
+    out[..len].to_vec()
+}
+
+// kind, sequence number and payload
+type Received = Result<(u8, u8, Vec<u8>), FrameError>;
+
+// feeds `bytes` to a reader, collecting every frame or error
+fn read_all(bytes: &[u8]) -> Vec<Received> {
+    let mut reader = FrameReader::new();
+    let mut frames = Vec::new();
+    for &byte in bytes {
+        if let Some(frame) = reader.push(byte) {
+            frames.push(frame.map(|f| (f.kind, f.seq, f.payload.to_vec())));
+        }
+    }
+    frames
+}
+
+#[test]
+fn crc_matches_ccitt_false() {
+    assert_eq!(crc16(b"123456789"), 0x29B1);
+}
+
+#[test]
+fn frames_round_trip_through_cobs() {
+    let payloads: Vec<Vec<u8>> = vec![
+        vec![],
+        vec![0],
+        vec![0, 0, 0],
+        (1..=255).collect(),
+        // full blocks, with and without a zero right after them
+        vec![0xAA; 253],
+        vec![0xAA; 254],
+        vec![0xAA; 255],
+        [vec![0x55; 254], vec![0], vec![0x55; 300]].concat(),
+        (0..MAX_DATA).map(|i| i as u8).collect(),
+    ];
+
+    for payload in payloads {
+        let encoded = encode_frame(&Frame {
+            kind: 0x82,
+            seq: 7,
+            payload: &payload,
+        });
+
+        // the delimiter only ever ends the frame
+        assert_eq!(
+            encoded.iter().position(|&b| b == 0),
+            Some(encoded.len() - 1)
+        );
+        assert_eq!(read_all(&encoded), vec![Ok((0x82, 7, payload))]);
+    }
+}
+
+#[test]
+fn corrupted_frames_are_rejected() {
+    let mut encoded = encode_frame(&Frame {
+        kind: 0x01,
+        seq: 1,
+        payload: b"hello",
+    });
+    encoded[3] ^= 0x10;
+    assert_eq!(read_all(&encoded), vec![Err(FrameError::Crc)]);
+
+    assert_eq!(
+        read_all(&[0x02, 0x01, 0x00]),
+        vec![Err(FrameError::TooShort)]
+    );
+    // block length past the end of the frame
+    assert_eq!(
+        read_all(&[0x09, 0x01, 0x00]),
+        vec![Err(FrameError::Encoding)]
+    );
+}
+
+#[test]
+fn reader_resynchronizes_after_garbage() {
+    let frame = Frame {
+        kind: 0x01,
+        seq: 2,
+        payload: &[],
+    };
+
+    let mut bytes = vec![0x13, 0x37, 0x00];
+    bytes.extend(vec![0xFF; MAX_FRAME + 10]);
+    bytes.push(0);
+    bytes.extend([0, 0]);
+    bytes.extend(encode_frame(&frame));
+
+    let frames = read_all(&bytes);
+    assert_eq!(frames.len(), 3);
+    assert!(frames[0].is_err());
+    assert_eq!(frames[1], Err(FrameError::Overflow));
+    assert_eq!(frames[2], Ok((0x01, 2, vec![])));
+}
+
+#[test]
+fn requests_round_trip() {
+    let data: Vec<u8> = (0..MAX_DATA).map(|i| (i % 7) as u8).collect();
+    let requests = [
+        Request::Ping,
+        Request::ListDir { path: "/apps" },
+        Request::ReadFile {
+            path: "/KERNEL.LOG",
+        },
+        Request::WriteFile {
+            path: "/snake.bin",
+            offset: 0x1234_5678,
+            data: &data,
+        },
+        Request::WriteFile {
+            path: "/empty",
+            offset: 0,
+            data: &[],
+        },
+        Request::Launch { path: "/snake.bin" },
+        Request::Stop,
+        Request::Screenshot,
+        Request::StreamLog { enable: true },
+        Request::StreamLog { enable: false },
+    ];
+
+    for (seq, request) in requests.iter().enumerate() {
+        let mut out = [0_u8; MAX_FRAME];
+        let len = request.encode(seq as u8, &mut out).unwrap();
+
+        // everything before the delimiter
+        let frame = Frame::decode(&mut out[..len - 1]).unwrap();
+        assert_eq!(frame.seq, seq as u8);
+        assert_eq!(frame.kind, request.command() as u8);
+        assert_eq!(Request::parse(&frame), Ok(*request));
+    }
+}
+
+fn parse(kind: u8, payload: &[u8]) -> Result<Request<'_>, ErrorCode> {
+    Request::parse(&Frame {
+        kind,
+        seq: 0,
+        payload,
+    })
+}
+
+#[test]
+fn invalid_requests_are_reported() {
+    assert_eq!(parse(0x7F, &[]), Err(ErrorCode::UnknownCommand));
+    assert_eq!(
+        parse(Command::ListDir as u8, &[0xFF, 0xFE]),
+        Err(ErrorCode::Malformed)
+    );
+    assert_eq!(
+        parse(Command::ReadFile as u8, &[b'a'; MAX_PATH + 1]),
+        Err(ErrorCode::Malformed)
+    );
+    assert_eq!(
+        parse(Command::StreamLog as u8, &[]),
+        Err(ErrorCode::Malformed)
+    );
+    // path length past the end of the payload
+    assert_eq!(
+        parse(Command::WriteFile as u8, &[0, 0, 0, 0, 9, b'a']),
+        Err(ErrorCode::Malformed)
+    );
+
+    let mut out = [0_u8; MAX_FRAME];
+    let long_path = "a".repeat(MAX_PATH + 1);
+    assert_eq!(
+        Request::Launch { path: &long_path }.encode(0, &mut out),
+        Err(FrameError::TooLong)
+    );
+    assert_eq!(
+        Request::WriteFile {
+            path: "/a",
+            offset: 0,
+            data: &[0; MAX_DATA + 1],
+        }
+        .encode(0, &mut out),
+        Err(FrameError::TooLong)
+    );
+}
+
+#[test]
+fn responses_round_trip() {
+    let responses = [
+        Response::Ok(&[1]),
+        Response::Ok(&[]),
+        Response::Error(ErrorCode::Busy),
+        Response::Data(&[0, 1, 2, 0]),
+        Response::Log(b"[    1.000] INFO  hello\n"),
+    ];
+
+    for response in responses {
+        let mut out = [0_u8; MAX_FRAME];
+        let len = response.encode(3, &mut out).unwrap();
+
+        // everything before the delimiter
+        let frame = Frame::decode(&mut out[..len - 1]).unwrap();
+        assert_eq!(Response::parse(&frame), Ok(response));
+    }
+
+    assert!(Response::Error(ErrorCode::Io).is_final());
+    assert!(!Response::Data(&[]).is_final());
+}
+
+#[test]
+fn dir_entries_round_trip() {
+    let mut out = [0_u8; MAX_DATA];
+    let entry = DirEntry {
+        name: "Long File Name.bin",
+        size: 123_456,
+        is_dir: false,
+    };
+    let len = entry.encode(&mut out);
+    assert_eq!(DirEntry::parse(&out[..len]), Some(entry));
+
+    let dir = DirEntry {
+        name: "APPS",
+        size: 0,
+        is_dir: true,
+    };
+    let len = dir.encode(&mut out);
+    assert_eq!(DirEntry::parse(&out[..len]), Some(dir));
+
+    assert_eq!(DirEntry::parse(&[0, 1, 2]), None);
+}
+
+#[test]
+fn pixels_are_little_endian_rgb565() {
+    // red is in the top five bits, so its bits are in the second byte
+    assert_eq!(encode_pixel(0xF800), [0x00, 0xF8]);
+    assert_eq!(encode_pixel(0x001F), [0x1F, 0x00]);
+    assert_eq!(decode_pixel([0xE0, 0x07]), 0x07E0);
+
+    for pixel in [0x0000, 0x1234, 0xF81F, 0xFFFF] {
+        assert_eq!(decode_pixel(encode_pixel(pixel)), pixel);
+    }
+}
@@ -26,10 +26,17 @@ use userlib::{
     println,
 };
 
+userlib::app_meta! {
+    name: "Calculator",
+    version: env!("CARGO_PKG_VERSION"),
+    category: "Tools",
+    description: "Evaluates the expressions you type",
+}
+
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
     println!("user panic: {} @ {:?}", info.message(), info.location(),);
-    loop {}
+    userlib::abort()
 }
 
 #[unsafe(no_mangle)]
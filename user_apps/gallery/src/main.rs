@@ -18,10 +18,17 @@ use userlib::{
     println,
 };
 
+userlib::app_meta! {
+    name: "Gallery",
+    version: env!("CARGO_PKG_VERSION"),
+    category: "Media",
+    description: "Shows the BMP images in /images",
+}
+
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
     println!("user panic: {} @ {:?}", info.message(), info.location());
-    loop {}
+    userlib::abort()
 }
 
 #[unsafe(no_mangle)]
@@ -42,7 +49,7 @@ pub fn main() {
     let mut images_drawn = 0;
 
     let mut entries = Entries::new();
-    let files_num = list_dir("/images", &mut entries);
+    let files_num = list_dir("/images", &mut entries).expect("Failed to list /images");
 
     for file in &entries.entries()[2..files_num] {
         if images_drawn >= grid_cols * grid_rows {
@@ -53,7 +60,7 @@ pub fn main() {
         if file.extension().unwrap_or("") == "bmp" || file.extension().unwrap_or("") == "BMP" {
             let file_path = format!("/images/{file}");
 
-            let read = read_file(&file_path, 0, &mut bmp_buf[..]);
+            let read = read_file(&file_path, 0, &mut bmp_buf[..]).unwrap_or(0);
             if read > 0 {
                 let bmp = Bmp::from_slice(&bmp_buf).expect("failed to parse bmp");
 
@@ -21,10 +21,17 @@ use userlib::{
     println, sleep,
 };
 
+userlib::app_meta! {
+    name: "GIF Player",
+    version: env!("CARGO_PKG_VERSION"),
+    category: "Media",
+    description: "Plays the GIFs in /gifs",
+}
+
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
     println!("user panic: {} @ {:?}", info.message(), info.location(),);
-    loop {}
+    userlib::abort()
 }
 
 #[unsafe(no_mangle)]
@@ -37,7 +44,7 @@ pub fn main() {
     let mut display = Display::take().unwrap();
 
     let mut entries = Entries::new();
-    list_dir("/gifs", &mut entries);
+    list_dir("/gifs", &mut entries).expect("Failed to list /gifs");
 
     let mut files = entries.entries();
     files.retain(|e| e.extension().unwrap_or("") == "gif");
@@ -64,9 +71,9 @@ pub fn main() {
     assert!(selection.is_some());
 
     let file_name = format!("/gifs/{}", gifs[selection.unwrap()]);
-    let size = file_len(&file_name);
+    let size = file_len(&file_name).expect("Failed to get gif size");
     let mut buf = vec![0_u8; size];
-    let read = read_file(&file_name, 0, &mut buf);
+    let read = read_file(&file_name, 0, &mut buf).expect("Failed to read gif");
     println!("read: {}, file size: {}", read, size);
     assert!(read == size);
 
@@ -11,6 +11,11 @@ SECTIONS
       *(.rodata .rodata.*);
   } > RAM
 
+  .picocalc_meta : ALIGN(4)
+  {
+      KEEP(*(.picocalc_meta));
+  } > RAM
+
   .data : ALIGN(4)
   {
       *(.data .data.*);
@@ -0,0 +1,94 @@
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+use alloc::{string::String, vec::Vec};
+use core::panic::PanicInfo;
+use embedded_audio::{AudioFile, wav::Wav};
+use userlib::{
+    Events, WAIT_FOREVER,
+    audio::{AUDIO_BUFFER_LEN, File, send_audio_buffer},
+    format,
+    fs::{Entries, list_dir},
+    println, wait_event,
+};
+
+userlib::app_meta! {
+    name: "Music",
+    version: env!("CARGO_PKG_VERSION"),
+    category: "Media",
+    description: "Plays the WAV files in /music in the background",
+    background: true,
+}
+
+// how long to wait before looking again when /music has no wavs to play
+const RESCAN_MS: u32 = 10_000;
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    println!("user panic: {} @ {:?}", info.message(), info.location(),);
+    userlib::abort()
+}
+
+#[unsafe(no_mangle)]
+pub extern "Rust" fn _start() {
+    main()
+}
+
+pub fn main() {
+    println!("Starting music service");
+
+    loop {
+        let wavs = wavs();
+        if wavs.is_empty() {
+            println!("No wavs in /music");
+            wait_event(Events::empty(), RESCAN_MS);
+            continue;
+        }
+
+        let mut played = false;
+        for name in wavs {
+            let Ok(mut wav) = Wav::new(File::new(format!("/music/{}", name))) else {
+                println!("{} is not a wav file", name);
+                continue;
+            };
+            println!("Now playing {}", name);
+
+            let mut buf = [0_u8; AUDIO_BUFFER_LEN];
+            while !wav.is_eof() {
+                // gives the foreground app the cpu until the audio wants more
+                wait_event(Events::AUDIO_BUFFER_READY, WAIT_FOREVER);
+                played = true;
+                if wav.read(&mut buf).is_err() {
+                    break;
+                }
+                // a dropped buffer is a skip, not a reason to stop the service
+                if let Err(err) = send_audio_buffer(&buf) {
+                    println!("Failed to send audio buffer: {:?}", err);
+                }
+            }
+        }
+
+        // none of them could be played, without waiting the foreground app
+        // would never get the cpu back
+        if !played {
+            wait_event(Events::empty(), RESCAN_MS);
+        }
+    }
+}
+
+fn wavs() -> Vec<String> {
+    let mut entries = Entries::new();
+    if list_dir("/music", &mut entries).is_err() {
+        return Vec::new();
+    }
+
+    let mut wavs: Vec<String> = entries
+        .entries()
+        .iter()
+        .filter(|e| e.extension().unwrap_or("") == "wav")
+        .map(|e| String::from(e.full_name()))
+        .collect();
+    wavs.sort();
+    wavs
+}
@@ -13,10 +13,17 @@ use userlib::{
     println, sleep,
 };
 
+userlib::app_meta! {
+    name: "Snake",
+    version: env!("CARGO_PKG_VERSION"),
+    category: "Games",
+    description: "Eat the apples, not yourself",
+}
+
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
     println!("user panic: {} @ {:?}", info.message(), info.location(),);
-    loop {}
+    userlib::abort()
 }
 
 #[unsafe(no_mangle)]
@@ -2,9 +2,9 @@
 #![no_main]
 
 extern crate alloc;
-use alloc::{string::String, vec::Vec};
+use alloc::vec::Vec;
 use core::panic::PanicInfo;
-use embedded_audio::{AudioFile, PlatformFile, PlatformFileError, wav::Wav};
+use embedded_audio::{AudioFile, wav::Wav};
 use embedded_graphics::{
     mono_font::{MonoTextStyle, ascii::FONT_6X10},
     pixelcolor::Rgb565,
@@ -12,19 +12,26 @@ use embedded_graphics::{
 };
 use selection_ui::{SelectionUi, SelectionUiError, draw_text_center};
 use userlib::{
-    audio::{AUDIO_BUFFER_LEN, audio_buffer_ready, send_audio_buffer},
+    audio::{AUDIO_BUFFER_LEN, File, audio_buffer_ready, send_audio_buffer},
     display::Display,
     format,
-    fs::{Entries, file_len, list_dir, read_file},
+    fs::{Entries, list_dir},
     get_key,
     keyboard::{KeyCode, KeyState},
     println,
 };
 
+userlib::app_meta! {
+    name: "WAV Player",
+    version: env!("CARGO_PKG_VERSION"),
+    category: "Media",
+    description: "Plays the WAV files in /music",
+}
+
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
     println!("user panic: {} @ {:?}", info.message(), info.location(),);
-    loop {}
+    userlib::abort()
 }
 
 #[unsafe(no_mangle)]
@@ -38,7 +45,7 @@ pub fn main() {
 
     loop {
         let mut entries = Entries::new();
-        list_dir("/music", &mut entries);
+        list_dir("/music", &mut entries).expect("Failed to list /music");
 
         let mut files = entries.entries();
         files.retain(|e| e.extension().unwrap_or("") == "wav");
@@ -86,7 +93,7 @@ pub fn main() {
                 }
 
                 let _read = wav.read(&mut buf).unwrap();
-                send_audio_buffer(&buf);
+                send_audio_buffer(&buf).expect("Failed to send audio buffer");
             }
 
             let event = get_key();
@@ -96,48 +103,3 @@ pub fn main() {
         }
     }
 }
-
-struct File {
-    current_pos: usize,
-    file: String,
-}
-
-impl File {
-    fn new(file: String) -> Self {
-        Self {
-            current_pos: 0,
-            file,
-        }
-    }
-}
-
-impl PlatformFile for File {
-    fn read(&mut self, buf: &mut [u8]) -> Result<usize, PlatformFileError> {
-        let read = read_file(&self.file, self.current_pos, buf);
-        self.current_pos += read;
-        Ok(read)
-    }
-
-    fn seek_from_current(&mut self, offset: i64) -> Result<(), PlatformFileError> {
-        if offset.is_positive() {
-            self.current_pos += offset as usize;
-        } else {
-            self.current_pos -= offset as usize;
-        }
-        Ok(())
-    }
-
-    fn seek_from_start(&mut self, offset: usize) -> Result<(), PlatformFileError> {
-        self.current_pos = offset;
-        Ok(())
-    }
-
-    fn seek_from_end(&mut self, offset: usize) -> Result<(), PlatformFileError> {
-        self.current_pos = self.length() - offset;
-        Ok(())
-    }
-
-    fn length(&mut self) -> usize {
-        file_len(&self.file)
-    }
-}
@@ -7,7 +7,7 @@ pub use alloc::format;
 use core::alloc::{GlobalAlloc, Layout};
 use rand_core::RngCore;
 use userlib_sys::{RngRequest, keyboard::KeyEvent};
-pub use userlib_sys::{keyboard, print};
+pub use userlib_sys::{Events, SyscallError, WAIT_FOREVER, keyboard, print};
 
 #[global_allocator]
 static ALLOC: Alloc = Alloc;
@@ -32,6 +32,41 @@ macro_rules! println {
     }};
 }
 
+/// Describes the app to the launcher, which reads it without loading the app.
+/// Every field is optional:
+///
+/// ```ignore
+/// userlib::app_meta! {
+///     name: "Snake",
+///     version: env!("CARGO_PKG_VERSION"),
+///     category: "Games",
+///     description: "Eat the apples, not yourself",
+///     icon: include_bytes!("../icon.rgb565"),
+///     min_heap: 16 * 1024,
+///     stack_size: 4 * 1024,
+/// }
+/// ```
+///
+/// `background: true` makes the app a background service, which keeps running
+/// while other apps do. It can't draw or read keys, and should spend its time
+/// in `wait_event`, the only place other apps get to run.
+#[macro_export]
+macro_rules! app_meta {
+    ($($field:ident: $value:expr),* $(,)?) => {
+        #[used]
+        #[unsafe(link_section = ".picocalc_meta")]
+        static PICOCALC_META: $crate::meta::AppMeta = $crate::meta::AppMeta::new()$(.$field($value))*;
+    };
+}
+
+/// Stops the app and returns to the launcher, reporting a panic.
+/// Meant to be called at the end of the app's `#[panic_handler]`.
+pub fn abort() -> ! {
+    unsafe {
+        core::arch::asm!("udf #{imm}", imm = const userlib_sys::PANIC_UDF, options(noreturn));
+    }
+}
+
 pub fn sleep(ms: u64) {
     userlib_sys::sleep(ms);
 }
@@ -44,6 +79,24 @@ pub fn get_key() -> KeyEvent {
     userlib_sys::keyboard::get_key().into()
 }
 
+/// Waits until one of `events` happens, or `timeout_ms` passes
+/// (`WAIT_FOREVER` for no timeout). Returns the events that happened, empty
+/// when it timed out.
+pub fn wait_event(events: Events, timeout_ms: u32) -> Events {
+    userlib_sys::wait_event(events, timeout_ms)
+}
+
+/// Reads the most recent kernel log entries, including everything printed by
+/// apps, into `buf`. Returns the number of bytes read, which start at a line
+/// boundary whenever a whole line fits.
+pub fn read_log(buf: &mut [u8]) -> Result<usize, SyscallError> {
+    SyscallError::check(userlib_sys::read_log(buf.as_mut_ptr(), buf.len()))
+}
+
+pub mod meta {
+    pub use userlib_sys::meta::{APP_ICON_LEN, APP_ICON_SIZE, AppMeta};
+}
+
 pub mod display {
     use core::sync::atomic::{AtomicBool, Ordering};
 
@@ -57,10 +110,17 @@ pub mod display {
         prelude::{DrawTarget, Size},
         primitives::Rectangle,
     };
-    use userlib_sys::CPixel;
+    use userlib_sys::{CPixel, SyscallError};
 
     pub const SCREEN_WIDTH: usize = 320;
     pub const SCREEN_HEIGHT: usize = 320;
+    pub use userlib_sys::STATUS_BAR_HEIGHT;
+
+    /// Lays the kernel's status bar over the top `STATUS_BAR_HEIGHT` rows of
+    /// the screen, or takes it away again
+    pub fn set_status_bar(shown: bool) {
+        userlib_sys::set_status_bar(shown)
+    }
 
     pub type Pixel565 = Pixel<Rgb565>;
 
@@ -113,13 +173,15 @@ pub mod display {
                 count += 1;
 
                 if count == BUF_SIZE - 1 {
-                    userlib_sys::draw_iter(unsafe { BUF.as_ptr() }, count);
+                    let ret = userlib_sys::draw_iter(unsafe { BUF.as_ptr() }, count);
+                    SyscallError::check(ret).map_err(|_| ())?;
                     count = 0;
                 }
             }
 
             if count > 0 {
-                userlib_sys::draw_iter(unsafe { BUF.as_ptr() }, count);
+                let ret = userlib_sys::draw_iter(unsafe { BUF.as_ptr() }, count);
+                SyscallError::check(ret).map_err(|_| ())?;
             }
 
             Ok(())
@@ -198,7 +260,7 @@ pub mod display {
                 if row_in_bounds {
                     let rows_buffered = chunk_count / clip_w;
                     if rows_buffered >= rows_per_chunk {
-                        unsafe {
+                        let ret = unsafe {
                             userlib_sys::blit(
                                 clip_x0 as u16,
                                 chunk_row0 as u16,
@@ -206,8 +268,9 @@ pub mod display {
                                 rows_buffered as u16,
                                 CHUNK.as_ptr(),
                                 chunk_count,
-                            );
-                        }
+                            )
+                        };
+                        SyscallError::check(ret).map_err(|_| ())?;
                         chunk_count = 0;
                     }
                 }
@@ -215,7 +278,7 @@ pub mod display {
 
             if chunk_count > 0 {
                 let rows_buffered = chunk_count / clip_w;
-                unsafe {
+                let ret = unsafe {
                     userlib_sys::blit(
                         clip_x0 as u16,
                         chunk_row0 as u16,
@@ -223,8 +286,9 @@ pub mod display {
                         rows_buffered as u16,
                         CHUNK.as_ptr(),
                         chunk_count,
-                    );
-                }
+                    )
+                };
+                SyscallError::check(ret).map_err(|_| ())?;
             }
 
             Ok(())
@@ -233,7 +297,8 @@ pub mod display {
 }
 
 fn gen_rand(req: &mut RngRequest) {
-    userlib_sys::gen_rand(req);
+    // requests built here always point into our own memory
+    let _ = userlib_sys::gen_rand(req);
 }
 
 pub struct Rng;
@@ -268,25 +333,26 @@ impl RngCore for Rng {
 pub mod fs {
     use alloc::vec::Vec;
     use core::fmt::Display;
+    use userlib_sys::SyscallError;
 
-    pub fn read_file(file: &str, start_from: usize, buf: &mut [u8]) -> usize {
-        userlib_sys::read_file(
+    pub fn read_file(file: &str, start_from: usize, buf: &mut [u8]) -> Result<usize, SyscallError> {
+        SyscallError::check(userlib_sys::read_file(
             file.as_ptr(),
             file.len(),
             start_from,
             buf.as_mut_ptr(),
             buf.len(),
-        )
+        ))
     }
 
-    pub fn write_file(file: &str, start_from: usize, buf: &[u8]) {
-        userlib_sys::write_file(
+    pub fn write_file(file: &str, start_from: usize, buf: &[u8]) -> Result<usize, SyscallError> {
+        SyscallError::check(userlib_sys::write_file(
             file.as_ptr(),
             file.len(),
             start_from,
             buf.as_ptr(),
             buf.len(),
-        )
+        ))
     }
 
     #[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
@@ -367,25 +433,450 @@ pub mod fs {
         }
     }
 
-    pub fn list_dir(path: &str, entries: &mut Entries) -> usize {
-        userlib_sys::list_dir(
+    pub fn list_dir(path: &str, entries: &mut Entries) -> Result<usize, SyscallError> {
+        SyscallError::check(userlib_sys::list_dir(
             path.as_ptr(),
             path.len(),
             entries.as_ptrs().as_mut_ptr(),
             MAX_ENTRIES,
             MAX_ENTRY_NAME_LEN,
-        )
+        ))
     }
 
-    pub fn file_len(str: &str) -> usize {
-        userlib_sys::file_len(str.as_ptr(), str.len())
+    pub fn file_len(str: &str) -> Result<usize, SyscallError> {
+        SyscallError::check(userlib_sys::file_len(str.as_ptr(), str.len()))
+    }
+
+    pub use userlib_sys::SdCardEvent;
+
+    /// Whether the sd card was pulled or put back since the last call
+    pub fn sd_card_changed() -> SdCardEvent {
+        userlib_sys::sd_card_changed()
     }
 }
 
 pub mod audio {
+    use userlib_sys::SyscallError;
     pub use userlib_sys::{AUDIO_BUFFER_LEN, AUDIO_BUFFER_SAMPLES, audio_buffer_ready};
 
-    pub fn send_audio_buffer(buf: &[u8]) {
-        userlib_sys::send_audio_buffer(buf.as_ptr(), buf.len())
+    pub fn send_audio_buffer(buf: &[u8]) -> Result<(), SyscallError> {
+        SyscallError::check(userlib_sys::send_audio_buffer(buf.as_ptr(), buf.len())).map(|_| ())
+    }
+
+    #[cfg(feature = "embedded-audio")]
+    pub use file::File;
+
+    #[cfg(feature = "embedded-audio")]
+    mod file {
+        use crate::fs::{file_len, read_file};
+        use alloc::string::String;
+        use embedded_audio::{PlatformFile, PlatformFileError};
+
+        /// A file on the sd card for `embedded_audio` to play
+        pub struct File {
+            current_pos: usize,
+            file: String,
+        }
+
+        impl File {
+            pub fn new(file: String) -> Self {
+                Self {
+                    current_pos: 0,
+                    file,
+                }
+            }
+        }
+
+        impl PlatformFile for File {
+            fn read(&mut self, buf: &mut [u8]) -> Result<usize, PlatformFileError> {
+                // a failed read is reported as end of file
+                let read = read_file(&self.file, self.current_pos, buf).unwrap_or(0);
+                self.current_pos += read;
+                Ok(read)
+            }
+
+            fn seek_from_current(&mut self, offset: i64) -> Result<(), PlatformFileError> {
+                if offset.is_positive() {
+                    self.current_pos += offset as usize;
+                } else {
+                    self.current_pos -= offset.unsigned_abs() as usize;
+                }
+                Ok(())
+            }
+
+            fn seek_from_start(&mut self, offset: usize) -> Result<(), PlatformFileError> {
+                self.current_pos = offset;
+                Ok(())
+            }
+
+            fn seek_from_end(&mut self, offset: usize) -> Result<(), PlatformFileError> {
+                self.current_pos = self.length() - offset;
+                Ok(())
+            }
+
+            fn length(&mut self) -> usize {
+                file_len(&self.file).unwrap_or(0)
+            }
+        }
+    }
+}
+
+pub mod midi {
+    use userlib_sys::SyscallError;
+    pub use userlib_sys::midi::MidiPacket;
+
+    /// Queues `packets` for the usb host. Returns how many were queued, fewer
+    /// than given if the queue is full, e.g. when no host is listening.
+    pub fn send(packets: &[MidiPacket]) -> Result<usize, SyscallError> {
+        SyscallError::check(userlib_sys::midi::send_midi(
+            packets.as_ptr(),
+            packets.len(),
+        ))
+    }
+
+    /// Takes packets received from the usb host, without waiting for any.
+    /// Returns how many were written to `packets`.
+    pub fn receive(packets: &mut [MidiPacket]) -> Result<usize, SyscallError> {
+        SyscallError::check(userlib_sys::midi::receive_midi(
+            packets.as_mut_ptr(),
+            packets.len(),
+        ))
+    }
+}
+
+/// A small executor, for apps that would rather `await` keys, audio buffers,
+/// timers and file reads than poll for them in a loop.
+///
+/// ```ignore
+/// use userlib::task::{Executor, next_key, sleep};
+///
+/// let mut executor = Executor::new();
+/// executor.spawn(async {
+///     loop {
+///         let event = next_key().await;
+///         // ...
+///     }
+/// });
+/// executor.spawn(async {
+///     loop {
+///         // redraw a clock
+///         sleep(1000).await;
+///     }
+/// });
+/// executor.run();
+/// ```
+///
+/// When no task can make progress, the executor waits in `wait_event` for
+/// whatever the waiting futures wait for, or until the first of their timers
+/// runs out, and wakes the tasks waiting on what happened. Tasks that woke
+/// themselves, e.g. in `yield_now`, are polled again after a `yield_now`
+/// syscall instead, and so are tasks waiting on something other than the
+/// kernel.
+pub mod task {
+    use crate::{fs::SdCardEvent, get_key, get_ms, keyboard::KeyState};
+    use alloc::{boxed::Box, rc::Rc, sync::Arc, task::Wake, vec::Vec};
+    use core::{
+        cell::Cell,
+        future::Future,
+        pin::Pin,
+        sync::atomic::{AtomicBool, Ordering},
+        task::{Context, Poll, Waker},
+    };
+    use userlib_sys::{Events, SyscallError, WAIT_FOREVER, keyboard::KeyEvent};
+
+    /// How much `read_file` reads before letting other tasks run
+    pub const READ_CHUNK: usize = 512;
+
+    // futures waiting on the kernel, woken by `park`. Apps are single
+    // threaded, only the executor's thread touches it.
+    static mut WAITING: Vec<Waiter> = Vec::new();
+    static mut NEXT_WAITER_ID: u32 = 0;
+
+    struct Waiter {
+        id: u32,
+        events: Events,
+        /// In `get_ms` time
+        deadline: Option<u64>,
+        waker: Waker,
+    }
+
+    /// A future waiting on the kernel until `ready` has its output. It waits
+    /// in `WAITING` while it's pending, and leaves once it's done or dropped,
+    /// e.g. as the losing side of a select.
+    struct KernelWait<F> {
+        events: Events,
+        deadline: Option<u64>,
+        ready: F,
+        id: Option<u32>,
+    }
+
+    impl<F> KernelWait<F> {
+        fn new(events: Events, deadline: Option<u64>, ready: F) -> Self {
+            Self {
+                events,
+                deadline,
+                ready,
+                id: None,
+            }
+        }
+
+        fn leave(&mut self) {
+            if let Some(id) = self.id.take() {
+                unsafe { WAITING.retain(|waiter| waiter.id != id) };
+            }
+        }
+    }
+
+    impl<T, F: FnMut() -> Option<T> + Unpin> Future for KernelWait<F> {
+        type Output = T;
+
+        fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<T> {
+            let this = self.get_mut();
+            if let Some(output) = (this.ready)() {
+                this.leave();
+                return Poll::Ready(output);
+            }
+
+            let id = *this.id.get_or_insert_with(|| unsafe {
+                NEXT_WAITER_ID = NEXT_WAITER_ID.wrapping_add(1);
+                NEXT_WAITER_ID
+            });
+            let waiting = unsafe { &mut WAITING };
+            match waiting.iter_mut().find(|waiter| waiter.id == id) {
+                Some(waiter) => {
+                    if !waiter.waker.will_wake(cx.waker()) {
+                        waiter.waker = cx.waker().clone();
+                    }
+                }
+                // first polled, or woken by `park` since
+                None => waiting.push(Waiter {
+                    id,
+                    events: this.events,
+                    deadline: this.deadline,
+                    waker: cx.waker().clone(),
+                }),
+            }
+            Poll::Pending
+        }
+    }
+
+    impl<F> Drop for KernelWait<F> {
+        fn drop(&mut self) {
+            self.leave();
+        }
+    }
+
+    // waits in the kernel until something a waiting future waits for
+    // happens, or only checks for it without `block`, and wakes the futures
+    // it was for. They wait again once they're polled.
+    fn park(block: bool) {
+        let waiting = unsafe { &mut WAITING };
+        if waiting.is_empty() {
+            return;
+        }
+
+        let events = waiting
+            .iter()
+            .fold(Events::empty(), |events, waiter| events | waiter.events);
+        let now = get_ms();
+        let timeout = if block {
+            waiting
+                .iter()
+                .filter_map(|waiter| waiter.deadline)
+                .min()
+                .map_or(WAIT_FOREVER, |deadline| {
+                    deadline.saturating_sub(now).min(WAIT_FOREVER as u64 - 1) as u32
+                })
+        } else {
+            0
+        };
+
+        let fired = crate::wait_event(events, timeout);
+
+        let now = get_ms();
+        waiting.retain(|waiter| {
+            let due =
+                waiter.events.intersects(fired) || waiter.deadline.is_some_and(|at| now >= at);
+            if due {
+                waiter.waker.wake_by_ref();
+            }
+            !due
+        });
+    }
+
+    struct Task {
+        future: Pin<Box<dyn Future<Output = ()>>>,
+        woken: Arc<Woken>,
+        waker: Waker,
+    }
+
+    struct Woken(AtomicBool);
+
+    impl Wake for Woken {
+        fn wake(self: Arc<Self>) {
+            self.0.store(true, Ordering::Release);
+        }
+
+        fn wake_by_ref(self: &Arc<Self>) {
+            self.0.store(true, Ordering::Release);
+        }
+    }
+
+    impl Task {
+        fn new(future: impl Future<Output = ()> + 'static) -> Self {
+            // polled the first time round
+            let woken = Arc::new(Woken(AtomicBool::new(true)));
+            Self {
+                future: Box::pin(future),
+                waker: Waker::from(woken.clone()),
+                woken,
+            }
+        }
+
+        // polls the task if it was woken, true once it's done
+        fn poll(&mut self) -> bool {
+            if !self.woken.0.swap(false, Ordering::AcqRel) {
+                return false;
+            }
+            self.future
+                .as_mut()
+                .poll(&mut Context::from_waker(&self.waker))
+                .is_ready()
+        }
+
+        fn is_woken(&self) -> bool {
+            self.woken.0.load(Ordering::Acquire)
+        }
+
+        // whether `park` wakes it
+        fn waits_on_kernel(&self) -> bool {
+            unsafe { WAITING.iter() }.any(|waiter| waiter.waker.will_wake(&self.waker))
+        }
+
+        fn wake(&self) {
+            self.woken.0.store(true, Ordering::Release);
+        }
+    }
+
+    /// Runs tasks on the app's thread until all of them are done
+    #[derive(Default)]
+    pub struct Executor {
+        tasks: Vec<Task>,
+    }
+
+    impl Executor {
+        pub const fn new() -> Self {
+            Self { tasks: Vec::new() }
+        }
+
+        /// Adds a task, polled from the next `run` on
+        pub fn spawn(&mut self, future: impl Future<Output = ()> + 'static) {
+            self.tasks.push(Task::new(future));
+        }
+
+        /// Polls the tasks until all of them are done
+        pub fn run(&mut self) {
+            loop {
+                self.tasks.retain_mut(|task| !task.poll());
+                if self.tasks.is_empty() {
+                    return;
+                }
+
+                if self.tasks.iter().any(Task::is_woken) {
+                    userlib_sys::yield_now();
+                } else if self.tasks.iter().all(Task::waits_on_kernel) {
+                    park(true);
+                } else {
+                    // some wait on something other than the kernel, e.g. a
+                    // future from another crate, poll them until it's ready
+                    userlib_sys::yield_now();
+                    park(false);
+                    self.tasks
+                        .iter()
+                        .filter(|task| !task.waits_on_kernel())
+                        .for_each(Task::wake);
+                }
+            }
+        }
+    }
+
+    /// Runs `future` to completion on the app's thread, waiting in the kernel
+    /// whenever it can't make progress
+    pub fn block_on<F: Future + 'static>(future: F) -> F::Output {
+        let output = Rc::new(Cell::new(None));
+        let result = output.clone();
+
+        let mut executor = Executor::new();
+        executor.spawn(async move { result.set(Some(future.await)) });
+        executor.run();
+
+        output.take().expect("the task finished")
+    }
+
+    /// Lets the other tasks, and the kernel, run before continuing
+    pub fn yield_now() -> impl Future<Output = ()> {
+        let mut yielded = false;
+        core::future::poll_fn(move |cx| {
+            if yielded {
+                return Poll::Ready(());
+            }
+            yielded = true;
+            cx.waker().wake_by_ref();
+            Poll::Pending
+        })
+    }
+
+    /// Waits until `ms` milliseconds have passed
+    pub fn sleep(ms: u64) -> impl Future<Output = ()> {
+        let deadline = get_ms() + ms;
+        KernelWait::new(Events::empty(), Some(deadline), move || {
+            (get_ms() >= deadline).then_some(())
+        })
+    }
+
+    /// Waits for the next key event
+    pub fn next_key() -> impl Future<Output = KeyEvent> {
+        KernelWait::new(Events::KEY, None, || {
+            let event = get_key();
+            (event.state != KeyState::Idle).then_some(event)
+        })
+    }
+
+    /// Waits until `send_audio_buffer` takes a buffer without waiting
+    pub fn audio_buffer_ready() -> impl Future<Output = ()> {
+        KernelWait::new(Events::AUDIO_BUFFER_READY, None, || {
+            userlib_sys::audio_buffer_ready().then_some(())
+        })
+    }
+
+    /// Waits until the sd card is pulled or put back
+    pub fn sd_card_changed() -> impl Future<Output = SdCardEvent> {
+        KernelWait::new(Events::SD_CARD, None, || {
+            match crate::fs::sd_card_changed() {
+                SdCardEvent::Unchanged => None,
+                event => Some(event),
+            }
+        })
+    }
+
+    /// Like `fs::read_file`, but reads `READ_CHUNK` bytes at a time and lets
+    /// the other tasks run in between, e.g. to keep drawing while a large
+    /// file loads
+    pub async fn read_file(
+        file: &str,
+        start_from: usize,
+        buf: &mut [u8],
+    ) -> Result<usize, SyscallError> {
+        let mut read = 0;
+        for chunk in buf.chunks_mut(READ_CHUNK) {
+            let len = chunk.len();
+            let got = crate::fs::read_file(file, start_from + read, chunk)?;
+            read += got;
+            if got < len {
+                break;
+            }
+            yield_now().await;
+        }
+        Ok(read)
     }
 }
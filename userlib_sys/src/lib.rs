@@ -8,11 +8,15 @@ use embedded_graphics::{
     pixelcolor::{Rgb565, raw::RawU16},
     prelude::{IntoStorage, Point},
 };
-use strum::{EnumCount, EnumIter};
+use strum::{EnumCount, EnumIter, IntoEnumIterator};
 
 pub type EntryFn = fn();
 
-pub const SYS_CALL_TABLE_COUNT: usize = 17;
+/// Immediate of the `udf` instruction apps execute to abort after a panic,
+/// letting the kernel tell a panic apart from any other fault
+pub const PANIC_UDF: u8 = 0x50;
+
+pub const SYS_CALL_TABLE_COUNT: usize = 24;
 const _: () = assert!(SYS_CALL_TABLE_COUNT == SyscallTable::COUNT);
 
 #[derive(Clone, Copy, EnumIter, EnumCount)]
@@ -35,12 +39,64 @@ pub enum SyscallTable {
     SendAudioBuffer = 14,
     FillRect = 15,
     Blit = 16,
+    ReadLog = 17,
+    SendMidi = 18,
+    ReceiveMidi = 19,
+    SetStatusBar = 20,
+    SdCardChanged = 21,
+    WaitEvent = 22,
+    YieldNow = 23,
 }
 
 #[unsafe(no_mangle)]
 #[unsafe(link_section = ".syscall_table")]
 pub static mut SYS_CALL_TABLE: [usize; SYS_CALL_TABLE_COUNT] = [0; SYS_CALL_TABLE_COUNT];
 
+/// Reasons the kernel can refuse a syscall.
+///
+/// Syscalls that can fail return an `isize`: non-negative values are the
+/// syscall's result (e.g. a byte count) and negative values are one of
+/// these codes, negated.
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, EnumIter)]
+#[repr(C)]
+pub enum SyscallError {
+    /// A pointer did not lie entirely within the app's own memory
+    InvalidPointer = 1,
+    /// An argument was malformed, e.g. a path that is not valid utf8
+    InvalidArgument = 2,
+    /// There is no sd card, it was pulled or the usb host has it
+    NoSdCard = 3,
+    /// The sd card failed to read or write
+    IoFailed = 4,
+    /// Not available to the app right now, e.g. drawing from a background
+    /// service, or audio while a background service has it
+    NotAllowed = 5,
+}
+
+impl SyscallError {
+    /// The raw value a syscall returns to report this error
+    pub const fn code(self) -> isize {
+        -(self as isize)
+    }
+
+    /// Splits a raw syscall return value into its result or error
+    pub fn check(ret: isize) -> Result<usize, SyscallError> {
+        if ret >= 0 {
+            return Ok(ret as usize);
+        }
+        Err(SyscallError::iter()
+            .find(|err| err.code() == ret)
+            .unwrap_or(SyscallError::InvalidArgument))
+    }
+}
+
+impl From<SyscallError> for isize {
+    fn from(err: SyscallError) -> Self {
+        err.code()
+    }
+}
+
 #[cfg(feature = "alloc")]
 #[repr(C)]
 pub struct CLayout {
@@ -82,13 +138,13 @@ pub extern "C" fn dealloc(ptr: *mut u8, layout: CLayout) {
     f(ptr, layout)
 }
 
-pub type Print = extern "C" fn(ptr: *const u8, len: usize);
+pub type Print = extern "C" fn(ptr: *const u8, len: usize) -> isize;
 
 #[unsafe(no_mangle)]
-pub extern "C" fn print(ptr: *const u8, len: usize) {
+pub extern "C" fn print(ptr: *const u8, len: usize) -> isize {
     let f: Print =
         unsafe { core::mem::transmute(SYS_CALL_TABLE[SyscallTable::PrintString as usize]) };
-    f(ptr, len);
+    f(ptr, len)
 }
 
 pub type SleepMs = extern "C" fn(ms: u64);
@@ -145,13 +201,13 @@ impl From<Pixel<Rgb565>> for CPixel {
     }
 }
 
-pub type DrawIter = extern "C" fn(ptr: *const CPixel, len: usize);
+pub type DrawIter = extern "C" fn(ptr: *const CPixel, len: usize) -> isize;
 
 #[unsafe(no_mangle)]
-pub extern "C" fn draw_iter(ptr: *const CPixel, len: usize) {
+pub extern "C" fn draw_iter(ptr: *const CPixel, len: usize) -> isize {
     let f: DrawIter =
         unsafe { core::mem::transmute(SYS_CALL_TABLE[SyscallTable::DrawIter as usize]) };
-    f(ptr, len);
+    f(ptr, len)
 }
 
 /// Fills an axis-aligned rectangle with a single color in one call, instead
@@ -167,12 +223,13 @@ pub extern "C" fn fill_rect(x: u16, y: u16, w: u16, h: u16, color: u16) {
 
 /// Writes a row-major block of raw RGB565 colors into a rectangle in one
 /// call, instead of marshaling it pixel-by-pixel through `draw_iter`.
-pub type Blit = extern "C" fn(x: u16, y: u16, w: u16, h: u16, colors: *const u16, len: usize);
+pub type Blit =
+    extern "C" fn(x: u16, y: u16, w: u16, h: u16, colors: *const u16, len: usize) -> isize;
 
 #[unsafe(no_mangle)]
-pub extern "C" fn blit(x: u16, y: u16, w: u16, h: u16, colors: *const u16, len: usize) {
+pub extern "C" fn blit(x: u16, y: u16, w: u16, h: u16, colors: *const u16, len: usize) -> isize {
     let f: Blit = unsafe { core::mem::transmute(SYS_CALL_TABLE[SyscallTable::Blit as usize]) };
-    f(x, y, w, h, colors, len);
+    f(x, y, w, h, colors, len)
 }
 
 pub mod keyboard {
@@ -411,10 +468,10 @@ pub enum RngRequest {
     Bytes { ptr: *mut u8, len: usize },
 }
 
-pub type GenRand = extern "C" fn(req: &mut RngRequest);
+pub type GenRand = extern "C" fn(req: *mut RngRequest) -> isize;
 
 #[unsafe(no_mangle)]
-pub extern "C" fn gen_rand(req: &mut RngRequest) {
+pub extern "C" fn gen_rand(req: &mut RngRequest) -> isize {
     unsafe {
         let ptr = SYS_CALL_TABLE[SyscallTable::GenRand as usize];
         let f: GenRand = core::mem::transmute(ptr);
@@ -428,7 +485,7 @@ pub type ListDir = extern "C" fn(
     entries: *mut *mut c_char,
     file_len: usize,
     max_entry_str_len: usize,
-) -> usize;
+) -> isize;
 
 #[unsafe(no_mangle)]
 pub extern "C" fn list_dir(
@@ -437,7 +494,7 @@ pub extern "C" fn list_dir(
     entries: *mut *mut c_char,
     entry_count: usize,
     max_entry_str_len: usize,
-) -> usize {
+) -> isize {
     unsafe {
         let ptr = SYS_CALL_TABLE[SyscallTable::ListDir as usize];
         let f: ListDir = core::mem::transmute(ptr);
@@ -451,7 +508,7 @@ pub type ReadFile = extern "C" fn(
     read_from: usize,
     buf: *mut u8,
     buf_len: usize,
-) -> usize;
+) -> isize;
 
 #[unsafe(no_mangle)]
 pub extern "C" fn read_file(
@@ -460,7 +517,7 @@ pub extern "C" fn read_file(
     read_from: usize,
     buf: *mut u8,
     buf_len: usize,
-) -> usize {
+) -> isize {
     unsafe {
         let ptr = SYS_CALL_TABLE[SyscallTable::ReadFile as usize];
         let f: ReadFile = core::mem::transmute(ptr);
@@ -468,8 +525,13 @@ pub extern "C" fn read_file(
     }
 }
 
-pub type WriteFile =
-    extern "C" fn(str: *const u8, len: usize, write_from: usize, buf: *const u8, buf_len: usize);
+pub type WriteFile = extern "C" fn(
+    str: *const u8,
+    len: usize,
+    write_from: usize,
+    buf: *const u8,
+    buf_len: usize,
+) -> isize;
 
 #[unsafe(no_mangle)]
 pub extern "C" fn write_file(
@@ -478,7 +540,7 @@ pub extern "C" fn write_file(
     write_from: usize,
     buf: *const u8,
     buf_len: usize,
-) {
+) -> isize {
     unsafe {
         let ptr = SYS_CALL_TABLE[SyscallTable::WriteFile as usize];
         let f: WriteFile = core::mem::transmute(ptr);
@@ -486,10 +548,10 @@ pub extern "C" fn write_file(
     }
 }
 
-pub type FileLen = extern "C" fn(str: *const u8, len: usize) -> usize;
+pub type FileLen = extern "C" fn(str: *const u8, len: usize) -> isize;
 
 #[unsafe(no_mangle)]
-pub extern "C" fn file_len(str: *const u8, len: usize) -> usize {
+pub extern "C" fn file_len(str: *const u8, len: usize) -> isize {
     unsafe {
         let ptr = SYS_CALL_TABLE[SyscallTable::FileLen as usize];
         let f: FileLen = core::mem::transmute(ptr);
@@ -522,13 +584,368 @@ pub fn audio_buffer_ready() -> bool {
 pub const AUDIO_BUFFER_SAMPLES: usize = 1024;
 pub const AUDIO_BUFFER_LEN: usize = AUDIO_BUFFER_SAMPLES * 2;
 
-pub type SendAudioBuffer = extern "C" fn(ptr: *const u8, len: usize);
+pub type SendAudioBuffer = extern "C" fn(ptr: *const u8, len: usize) -> isize;
 
 #[allow(unused)]
-pub fn send_audio_buffer(buf: *const u8, len: usize) {
+pub fn send_audio_buffer(buf: *const u8, len: usize) -> isize {
     unsafe {
         let ptr = SYS_CALL_TABLE[SyscallTable::SendAudioBuffer as usize];
         let f: SendAudioBuffer = core::mem::transmute(ptr);
         f(buf, len)
     }
 }
+
+pub type ReadLog = extern "C" fn(buf: *mut u8, len: usize) -> isize;
+
+#[unsafe(no_mangle)]
+pub extern "C" fn read_log(buf: *mut u8, len: usize) -> isize {
+    unsafe {
+        let ptr = SYS_CALL_TABLE[SyscallTable::ReadLog as usize];
+        let f: ReadLog = core::mem::transmute(ptr);
+        f(buf, len)
+    }
+}
+
+/// What happened to the sd card since an app last asked
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+pub enum SdCardEvent {
+    Unchanged = 0,
+    /// Files open on it are gone, they fail with `SyscallError::NoSdCard`.
+    /// Reported even if the card is back by now, the insertion comes next.
+    Removed = 1,
+    Inserted = 2,
+}
+
+pub type SdCardChanged = extern "C" fn() -> SdCardEvent;
+
+#[unsafe(no_mangle)]
+pub extern "C" fn sd_card_changed() -> SdCardEvent {
+    unsafe {
+        let ptr = SYS_CALL_TABLE[SyscallTable::SdCardChanged as usize];
+        let f: SdCardChanged = core::mem::transmute(ptr);
+        f()
+    }
+}
+
+/// Rows at the top of the screen the status bar covers, when shown
+pub const STATUS_BAR_HEIGHT: usize = 12;
+
+/// Shows the kernel's status bar over the top of the screen, or hides it
+pub type SetStatusBar = extern "C" fn(shown: bool);
+
+#[unsafe(no_mangle)]
+pub extern "C" fn set_status_bar(shown: bool) {
+    unsafe {
+        let ptr = SYS_CALL_TABLE[SyscallTable::SetStatusBar as usize];
+        let f: SetStatusBar = core::mem::transmute(ptr);
+        f(shown)
+    }
+}
+
+bitflags::bitflags! {
+    /// What `wait_event` can wait for
+    #[derive(Default, Debug, PartialEq, Eq, Clone, Copy)]
+    #[repr(C)]
+    pub struct Events: u32 {
+        /// A key event is queued for `get_key`, never for background services
+        const KEY = 1;
+        /// `send_audio_buffer` won't wait
+        const AUDIO_BUFFER_READY = 2;
+        /// `sd_card_changed` has something to report
+        const SD_CARD = 4;
+    }
+}
+
+/// A `wait_event` timeout that never runs out
+pub const WAIT_FOREVER: u32 = u32::MAX;
+
+/// Waits until one of `events` happens, or for `timeout_ms`, returning the
+/// events that happened, empty on a timeout. Background services give the cpu
+/// back to the kernel while they wait.
+pub type WaitEvent = extern "C" fn(events: Events, timeout_ms: u32) -> Events;
+
+#[unsafe(no_mangle)]
+pub extern "C" fn wait_event(events: Events, timeout_ms: u32) -> Events {
+    unsafe {
+        let ptr = SYS_CALL_TABLE[SyscallTable::WaitEvent as usize];
+        let f: WaitEvent = core::mem::transmute(ptr);
+        f(events, timeout_ms)
+    }
+}
+
+/// Gives the cpu to the kernel for a moment, e.g. to a background service,
+/// without waiting for anything
+pub type YieldNow = extern "C" fn();
+
+#[unsafe(no_mangle)]
+pub extern "C" fn yield_now() {
+    unsafe {
+        let ptr = SYS_CALL_TABLE[SyscallTable::YieldNow as usize];
+        let f: YieldNow = core::mem::transmute(ptr);
+        f()
+    }
+}
+
+pub mod midi {
+    use crate::{SYS_CALL_TABLE, SyscallTable};
+
+    /// A USB MIDI event packet: the cable number and code index in the first
+    /// byte, followed by up to three bytes of the MIDI message
+    #[cfg_attr(feature = "defmt", derive(defmt::Format))]
+    #[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+    #[repr(C)]
+    pub struct MidiPacket(pub [u8; 4]);
+
+    impl MidiPacket {
+        /// Packs a channel voice message, e.g. note on, for cable 0.
+        /// The code index is the high nibble of the status byte.
+        pub const fn channel_voice(status: u8, data1: u8, data2: u8) -> Self {
+            Self([status >> 4, status, data1 & 0x7F, data2 & 0x7F])
+        }
+
+        pub const fn note_on(channel: u8, note: u8, velocity: u8) -> Self {
+            Self::channel_voice(0x90 | (channel & 0x0F), note, velocity)
+        }
+
+        pub const fn note_off(channel: u8, note: u8, velocity: u8) -> Self {
+            Self::channel_voice(0x80 | (channel & 0x0F), note, velocity)
+        }
+
+        pub const fn control_change(channel: u8, control: u8, value: u8) -> Self {
+            Self::channel_voice(0xB0 | (channel & 0x0F), control, value)
+        }
+
+        pub const fn program_change(channel: u8, program: u8) -> Self {
+            Self::channel_voice(0xC0 | (channel & 0x0F), program, 0)
+        }
+
+        /// `value` is 14 bits, centered on 0x2000
+        pub const fn pitch_bend(channel: u8, value: u16) -> Self {
+            Self::channel_voice(0xE0 | (channel & 0x0F), value as u8, (value >> 7) as u8)
+        }
+
+        pub const fn cable(&self) -> u8 {
+            self.0[0] >> 4
+        }
+
+        pub const fn code_index(&self) -> u8 {
+            self.0[0] & 0x0F
+        }
+
+        /// The MIDI message, status byte first
+        pub fn message(&self) -> &[u8] {
+            let len = match self.code_index() {
+                0x5 | 0xF => 1,
+                0x2 | 0x6 | 0xC | 0xD => 2,
+                0x0 | 0x1 => 0, // reserved
+                _ => 3,
+            };
+            &self.0[1..1 + len]
+        }
+    }
+
+    /// Queues `len` packets for the usb host, returns how many were queued
+    pub type SendMidi = extern "C" fn(packets: *const MidiPacket, len: usize) -> isize;
+
+    #[unsafe(no_mangle)]
+    pub extern "C" fn send_midi(packets: *const MidiPacket, len: usize) -> isize {
+        unsafe {
+            let ptr = SYS_CALL_TABLE[SyscallTable::SendMidi as usize];
+            let f: SendMidi = core::mem::transmute(ptr);
+            f(packets, len)
+        }
+    }
+
+    /// Takes up to `len` packets received from the usb host, returns how many
+    /// were taken
+    pub type ReceiveMidi = extern "C" fn(packets: *mut MidiPacket, len: usize) -> isize;
+
+    #[unsafe(no_mangle)]
+    pub extern "C" fn receive_midi(packets: *mut MidiPacket, len: usize) -> isize {
+        unsafe {
+            let ptr = SYS_CALL_TABLE[SyscallTable::ReceiveMidi as usize];
+            let f: ReceiveMidi = core::mem::transmute(ptr);
+            f(packets, len)
+        }
+    }
+}
+
+pub mod meta {
+    /// Section apps keep their `AppMeta` in, see `userlib::app_meta!`
+    pub const APP_META_SECTION: &str = ".picocalc_meta";
+    pub const APP_META_MAGIC: [u8; 4] = *b"PCMT";
+    /// Bumped whenever the layout of `AppMeta` changes
+    pub const APP_META_FORMAT: u32 = 3;
+
+    /// The app is a background service, see `AppMeta::background`
+    pub const APP_FLAG_BACKGROUND: u32 = 1;
+
+    /// Icons are square, this many pixels wide
+    pub const APP_ICON_SIZE: usize = 16;
+    /// Rgb565 pixels, little endian, row by row
+    pub const APP_ICON_LEN: usize = APP_ICON_SIZE * APP_ICON_SIZE * 2;
+
+    /// What the launcher knows about an app without loading it. Strings are
+    /// utf-8, padded with zeros.
+    #[derive(Clone, Copy)]
+    #[repr(C)]
+    pub struct AppMeta {
+        pub magic: [u8; 4],
+        pub format: u32,
+        pub name: [u8; 32],
+        pub author: [u8; 32],
+        pub version: [u8; 16],
+        /// The launcher folder the app is shown in, e.g. "Games"
+        pub category: [u8; 16],
+        /// One line, shown below the launcher
+        pub description: [u8; 64],
+        /// Bytes of heap the app needs to run, 0 if unknown
+        pub min_heap: u32,
+        /// Bytes of stack the app needs, 0 if unknown
+        pub stack_size: u32,
+        /// `APP_FLAG_*` bits
+        pub flags: u32,
+        /// All black if the app has no icon
+        pub icon: [u8; APP_ICON_LEN],
+    }
+
+    impl Default for AppMeta {
+        fn default() -> Self {
+            Self::new()
+        }
+    }
+
+    impl AppMeta {
+        pub const fn new() -> Self {
+            Self {
+                magic: APP_META_MAGIC,
+                format: APP_META_FORMAT,
+                name: [0; 32],
+                author: [0; 32],
+                version: [0; 16],
+                category: [0; 16],
+                description: [0; 64],
+                min_heap: 0,
+                stack_size: 0,
+                flags: 0,
+                icon: [0; APP_ICON_LEN],
+            }
+        }
+
+        pub const fn name(mut self, name: &str) -> Self {
+            self.name = padded(name);
+            self
+        }
+
+        pub const fn author(mut self, author: &str) -> Self {
+            self.author = padded(author);
+            self
+        }
+
+        pub const fn version(mut self, version: &str) -> Self {
+            self.version = padded(version);
+            self
+        }
+
+        pub const fn category(mut self, category: &str) -> Self {
+            self.category = padded(category);
+            self
+        }
+
+        pub const fn description(mut self, description: &str) -> Self {
+            self.description = padded(description);
+            self
+        }
+
+        pub const fn min_heap(mut self, bytes: u32) -> Self {
+            self.min_heap = bytes;
+            self
+        }
+
+        pub const fn stack_size(mut self, bytes: u32) -> Self {
+            self.stack_size = bytes;
+            self
+        }
+
+        /// Runs the app as a background service: launching it leaves the
+        /// launcher up, and it keeps running while other apps do. It can't
+        /// draw or read keys, and gives the cpu back in `wait_event`.
+        pub const fn background(mut self, background: bool) -> Self {
+            if background {
+                self.flags |= APP_FLAG_BACKGROUND;
+            } else {
+                self.flags &= !APP_FLAG_BACKGROUND;
+            }
+            self
+        }
+
+        /// e.g. `include_bytes!("icon.rgb565")`
+        pub const fn icon(mut self, icon: &[u8; APP_ICON_LEN]) -> Self {
+            self.icon = *icon;
+            self
+        }
+
+        /// Reads the contents of an app's `APP_META_SECTION`, refusing
+        /// anything written for another layout
+        pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+            if bytes.len() < size_of::<Self>() || bytes[..4] != APP_META_MAGIC {
+                return None;
+            }
+            // SAFETY: every bit pattern is a valid AppMeta
+            let meta = unsafe { core::ptr::read_unaligned(bytes.as_ptr().cast::<Self>()) };
+            (meta.format == APP_META_FORMAT).then_some(meta)
+        }
+
+        pub fn name_str(&self) -> &str {
+            unpadded(&self.name)
+        }
+
+        pub fn author_str(&self) -> &str {
+            unpadded(&self.author)
+        }
+
+        pub fn version_str(&self) -> &str {
+            unpadded(&self.version)
+        }
+
+        pub fn category_str(&self) -> &str {
+            unpadded(&self.category)
+        }
+
+        pub fn description_str(&self) -> &str {
+            unpadded(&self.description)
+        }
+
+        pub fn is_background(&self) -> bool {
+            self.flags & APP_FLAG_BACKGROUND != 0
+        }
+
+        pub fn has_icon(&self) -> bool {
+            self.icon.iter().any(|&b| b != 0)
+        }
+    }
+
+    const fn padded<const N: usize>(s: &str) -> [u8; N] {
+        let bytes = s.as_bytes();
+        assert!(bytes.len() <= N, "app metadata string too long");
+
+        let mut out = [0; N];
+        let mut i = 0;
+        while i < bytes.len() {
+            out[i] = bytes[i];
+            i += 1;
+        }
+        out
+    }
+
+    fn unpadded(bytes: &[u8]) -> &str {
+        let len = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+        // keep what is valid, rather than losing the whole string
+        match core::str::from_utf8(&bytes[..len]) {
+            Ok(s) => s,
+            Err(e) => core::str::from_utf8(&bytes[..e.valid_up_to()]).unwrap_or_default(),
+        }
+    }
+}